@@ -1,16 +1,17 @@
 use clap::Parser;
 use core::time;
 use craytracer::{
+    cache::Cache,
     color::Color,
-    path_integrator,
+    render::render_pixel,
     sampling::samplers::{Sampler, SobolSampler},
     scene::Scene,
-    scene_parser::{scene_parser::parse_scene, tokenizer::ParserError},
+    scene_parser::{scene_parser::parse_scene_with_options, tokenizer::ParserError},
 };
 use log::{debug, error, info, LevelFilter};
 use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
 use std::{
-    ops::Range,
+    ops::{Range, RangeInclusive},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc, Mutex,
@@ -143,24 +144,6 @@ fn show_preview<C, F>(
     }
 }
 
-#[allow(non_snake_case)]
-#[inline]
-fn render_pixel<S>(sampler: &mut S, x: usize, y: usize, sample_index: usize, scene: &Scene) -> Color
-where
-    S: Sampler,
-{
-    sampler.start_pixel(x, y, sample_index);
-    let film_sample = sampler.sample_2d();
-    let lens_sample = sampler.sample_2d();
-
-    let ray = scene.camera.sample((film_sample, lens_sample), x, y);
-
-    // TODO: Allow picking integrator from command line
-    let L = path_integrator::estimate_Li(sampler, ray, &scene);
-    // let L = simple_integrator::estimate_Li(sampler, ray, &scene);
-    L
-}
-
 fn render_tile<S>(
     sampler: &mut S,
     tile: &(Range<usize>, Range<usize>, Range<usize>),
@@ -318,6 +301,22 @@ where
     });
 }
 
+/// Parses an inclusive range of frames like "1-24"
+fn parse_frame_range(value: &str) -> Result<RangeInclusive<usize>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| String::from("Expected frames as START-END"))?;
+    let start: usize = start.parse().map_err(|e| format!("Invalid start: {}", e))?;
+    let end: usize = end.parse().map_err(|e| format!("Invalid end: {}", e))?;
+    if start > end {
+        return Err(String::from("Start frame is after end frame"));
+    }
+    Ok(start..=end)
+}
+
+/// Placeholder for the frame number in the scene and output paths
+const FRAME_PLACEHOLDER: &str = "{frame}";
+
 #[derive(Parser)]
 struct Cli {
     #[clap(long, short)]
@@ -331,44 +330,109 @@ struct Cli {
 
     #[clap(long, default_value_t = 0)]
     seed: usize,
-}
 
-fn main() -> Result<(), ParserError> {
-    env_logger::Builder::new()
-        .filter(None, LevelFilter::Info)
-        .parse_default_env()
-        .init();
+    /// Renders each frame in START-END, replacing "{frame}" in the scene and
+    /// output paths with its number. The BVHs of the scene and of named
+    /// objects are refit between frames instead of being built again, but
+    /// each frame's scene file is parsed in full, along with its meshes unless
+    /// they are cached, and primitives with motion blur get new BVHs.
+    #[clap(long, value_parser = parse_frame_range)]
+    frames: Option<RangeInclusive<usize>>,
 
-    let args = Cli::parse();
+    /// Directory where parsed meshes and built BVHs are cached between runs
+    #[clap(long, default_value_t = String::from(".craytracer-cache"))]
+    cache_dir: String,
 
-    let start = Instant::now();
-    let input = std::fs::read_to_string(&args.scene).expect("Error reading scene file");
-    let scene = match parse_scene(&input) {
-        Ok(scene) => scene,
+    /// Parses meshes and builds BVHs without reading or writing the cache
+    #[clap(long)]
+    no_cache: bool,
+}
+
+/// Parses the scene file at `path`, reusing the BVH of the previous frame's
+/// scene if there is one. Errors are logged.
+fn load_scene(path: &str, previous: Option<Scene>, cache: Option<&Cache>) -> Option<Scene> {
+    let input = std::fs::read_to_string(path).expect("Error reading scene file");
+    match parse_scene_with_options(&input, previous, cache) {
+        Ok(scene) => Some(scene),
         Err(e) => {
             match e.location {
-                Some(location) => error!("{} at {}:{}", e.message, args.scene, location),
-                None => error!("{} in {}", e.message, args.scene),
+                Some(location) => error!("{} at {}:{}", e.message, path, location),
+                None => error!("{} in {}", e.message, path),
             }
-            return Ok(());
+            None
         }
-    };
-    info!("Scene constructed in {:?}", start.elapsed());
+    }
+}
 
+fn render_to_file(scene: &Scene, seed: usize, preview: bool, output: &str, start: Instant) {
     let (width, height) = scene.film_bounds();
 
     // Render to a buffer
-    let sampler = SobolSampler::new(args.seed, scene.num_samples);
-    render(&scene, sampler, args.preview, start, |pixels| {
+    let sampler = SobolSampler::new(seed, scene.num_samples);
+    render(scene, sampler, preview, start, |pixels| {
         eprintln!();
         info!("Rendering finished in {:.1?}", start.elapsed());
 
         // Save to file
         let image_buffer =
             image::Rgb32FImage::from_raw(width as u32, height as u32, pixels).unwrap();
-        image_buffer.save(&args.output).expect("Error saving file");
-        info!("Output written to {}", &args.output);
+        image_buffer.save(output).expect("Error saving file");
+        info!("Output written to {}", output);
     });
+}
+
+fn main() -> Result<(), ParserError> {
+    env_logger::Builder::new()
+        .filter(None, LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let args = Cli::parse();
+    let cache = match args.no_cache {
+        false => Some(Cache::new(&args.cache_dir)),
+        true => None,
+    };
+
+    let frames = match args.frames {
+        Some(frames) => frames,
+        None => {
+            let start = Instant::now();
+            let scene = match load_scene(&args.scene, None, cache.as_ref()) {
+                Some(scene) => scene,
+                None => return Ok(()),
+            };
+            info!("Scene constructed in {:?}", start.elapsed());
+            render_to_file(&scene, args.seed, args.preview, &args.output, start);
+            return Ok(());
+        }
+    };
+
+    if !args.scene.contains(FRAME_PLACEHOLDER) || !args.output.contains(FRAME_PLACEHOLDER) {
+        error!(
+            "Scene and output paths must contain {} when rendering frames",
+            FRAME_PLACEHOLDER
+        );
+        return Ok(());
+    }
+    if args.preview {
+        error!("Preview is not supported when rendering frames");
+        return Ok(());
+    }
+
+    let mut scene = None;
+    for frame in frames {
+        let frame_number = format!("{:04}", frame);
+        let scene_path = args.scene.replace(FRAME_PLACEHOLDER, &frame_number);
+        let output = args.output.replace(FRAME_PLACEHOLDER, &frame_number);
+
+        let start = Instant::now();
+        scene = match load_scene(&scene_path, scene, cache.as_ref()) {
+            Some(scene) => Some(scene),
+            None => return Ok(()),
+        };
+        info!("Frame {} constructed in {:?}", frame, start.elapsed());
+        render_to_file(scene.as_ref().unwrap(), args.seed, false, &output, start);
+    }
 
     Ok(())
 }
@@ -6,6 +6,10 @@ use crate::{
     ray::Ray,
 };
 
+/// Factor that the distance to the far side of bounds is scaled by to make up
+/// for rounding errors, so that bounds aren't missed by rays that graze them
+pub const FAR_DISTANCE_PADDING: f64 = 1.0 + 3.0 * f64::EPSILON;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Bounds {
     pub min: Point,
@@ -86,6 +90,52 @@ impl Bounds {
 
         ray.contains_distance(min_distance) || ray.contains_distance(max_distance)
     }
+    /// Whether the ray intersects the bounds or starts inside them, like
+    /// `intersects(ray) || contains(&ray.origin)`. This is faster when testing
+    /// many bounds against the same ray, as the inverse of its direction is
+    /// only found once.
+    ///
+    /// Source: https://pbr-book.org/3ed-2018/Shapes/Basic_Shape_Interface#Bounds3::IntersectP
+    pub fn intersects_fast(
+        &self,
+        ray: &Ray,
+        inv_direction: &Vector,
+        direction_is_negative: &[bool; 3],
+    ) -> bool {
+        let mut min_distance = f64::NEG_INFINITY;
+        let mut max_distance = f64::INFINITY;
+        for axis in AXES {
+            let (near, far) = match direction_is_negative[axis as usize] {
+                false => (self.min[axis], self.max[axis]),
+                true => (self.max[axis], self.min[axis]),
+            };
+            let near_distance = (near - ray.origin[axis]) * inv_direction[axis];
+            let far_distance =
+                (far - ray.origin[axis]) * inv_direction[axis] * FAR_DISTANCE_PADDING;
+            // Written so that NaNs, from rays in the plane of a face, leave
+            // the distances unchanged
+            if near_distance > min_distance {
+                min_distance = near_distance;
+            }
+            if far_distance < max_distance {
+                max_distance = far_distance;
+            }
+            if min_distance > max_distance {
+                return false;
+            }
+        }
+        hits_slabs(ray, min_distance, max_distance)
+    }
+}
+
+/// Whether a ray whose line is inside bounds from `min_distance` to
+/// `max_distance` along it hits them. Like `Bounds::intersects`, the ray has
+/// to enter or leave the bounds within its extent, unless it starts inside.
+pub(crate) fn hits_slabs(ray: &Ray, min_distance: f64, max_distance: f64) -> bool {
+    min_distance <= max_distance
+        && ((min_distance <= 0.0 && max_distance >= 0.0)
+            || ray.contains_distance(min_distance)
+            || ray.contains_distance(max_distance))
 }
 
 impl Add for Bounds {
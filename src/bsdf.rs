@@ -4,6 +4,7 @@ use crate::{
     geometry::{normal::Normal, traits::DotProduct, vector::Vector},
     pdf::Pdf,
     sampling::samplers::{Sample1d, Sample2d},
+    texture::TextureCoordinates,
 };
 
 #[derive(Debug)]
@@ -17,7 +18,7 @@ impl BSDF {
         (sample1, sample2): (Sample1d, Sample2d),
         w_o: &Vector,
         normal: &Normal,
-        uv: &(f64, f64),
+        uv: &TextureCoordinates,
     ) -> Option<SurfaceSample> {
         if self.bxdfs.len() == 0 {
             return None;
@@ -32,6 +33,18 @@ impl BSDF {
         }
 
         let sample = sample.unwrap();
+        if sample.is_specular {
+            // Specular lobes can't be evaluated in other directions, so only
+            // account for the probability of picking this BxDF
+            let pdf = match sample.pdf {
+                Pdf::NonDelta(pdf) => pdf,
+                Pdf::Delta => 1.0,
+            };
+            return Some(SurfaceSample {
+                pdf: Pdf::NonDelta(pdf / self.bxdfs.len() as f64),
+                ..sample
+            });
+        }
         if let Pdf::NonDelta(mut pdf) = sample.pdf {
             let mut f = sample.f;
             self.for_each_relevant_bsdf(w_o, &sample.w_i, normal, |other_idx, other_bxdf| {
@@ -70,7 +83,7 @@ impl BSDF {
         }
     }
 
-    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &(f64, f64)) -> Color {
+    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &TextureCoordinates) -> Color {
         let mut f = Color::BLACK;
         self.for_each_relevant_bsdf(w_o, w_i, normal, |_, bxdf| {
             f += bxdf.f(w_o, w_i, normal, uv);
@@ -79,6 +92,8 @@ impl BSDF {
     }
 
     pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal) -> Pdf {
+        // Each BxDF is sampled with equal probability, including ones that
+        // don't scatter towards `w_i`
         let mut pdf = 0.0;
         let mut num_matching_bxdfs = 0;
         self.for_each_relevant_bsdf(w_o, w_i, normal, |_, bxdf| {
@@ -91,7 +106,7 @@ impl BSDF {
             }
         });
         if num_matching_bxdfs > 0 {
-            Pdf::NonDelta(pdf / num_matching_bxdfs as f64)
+            Pdf::NonDelta(pdf / self.bxdfs.len() as f64)
         } else {
             Pdf::Delta
         }
@@ -1,14 +1,27 @@
-use std::{fmt::Display, ops::Add, sync::Arc};
+use std::{
+    cmp::Reverse,
+    convert::TryInto,
+    fmt::Display,
+    io::{self, Read, Write},
+    ops::{Add, Range},
+    sync::Arc,
+    thread,
+};
 
 use crate::{
-    bounds::Bounds,
-    geometry::{point::Point, Axis},
+    bounds::{hits_slabs, Bounds, FAR_DISTANCE_PADDING},
+    cache::{invalid_data, read_u32, read_u32s, read_u8, write_u32, write_u32s, write_u8},
+    geometry::{point::Point, vector::Vector, Axis, AXES},
     intersection::PrimitiveIntersection,
+    material::Material,
     primitive::Primitive,
     ray::Ray,
     util::partition_by,
 };
 
+/// Tree built by splitting the primitives, which is flattened into
+/// `LinearBvhNode`s once it is complete. Leaves refer to ranges of the
+/// primitives, which are reordered so that each leaf's are contiguous.
 #[derive(Debug)]
 pub enum BvhNode {
     InteriorNode {
@@ -19,141 +32,1143 @@ pub enum BvhNode {
     },
     LeafNode {
         bounds: Bounds,
-        primitives: Vec<Arc<Primitive>>,
+        first_primitive: usize,
+        num_primitives: usize,
     },
 }
 
+/// Node of the flattened tree. Nodes are stored in depth first order, so the
+/// first child of an interior node directly follows it.
+///
+/// Source: https://pbr-book.org/3ed-2018/Primitives_and_Intersection_Acceleration/Bounding_Volume_Hierarchies#CompactBVHForTraversal
+#[derive(Debug, Clone, Copy)]
+struct LinearBvhNode {
+    bounds: Bounds,
+    /// Index of the first primitive of a leaf, or of the second child of an
+    /// interior node
+    offset: u32,
+    /// Zero for interior nodes
+    num_primitives: u16,
+    split_axis: Axis,
+}
+
+/// Number of children of each node of a wide BVH
+const WIDTH: usize = 4;
+
+/// Node of a wide BVH, whose children's bounds are stored by axis so that a
+/// ray can be tested against all of them at once, with one AVX lane per child
+/// on CPUs that support it.
+///
+/// Source: Wald et al. 2008, "Getting Rid of Packets: Efficient SIMD
+/// Single-Ray Traversal using Multi-branching BVHs"
+#[derive(Debug, Clone, Copy)]
+struct WideBvhNode {
+    min: [[f64; WIDTH]; 3],
+    max: [[f64; WIDTH]; 3],
+    /// Index of each interior child's node, or of the first primitive of each
+    /// leaf child
+    offsets: [u32; WIDTH],
+    /// Zero for interior children, and for unused slots, whose bounds are
+    /// empty so that rays never hit them
+    num_primitives: [u16; WIDTH],
+}
+
+/// Maximum depth of the tree that can be traversed
+const MAX_DEPTH: usize = 64;
+
+/// Cost of testing a ray against a node's bounds, relative to intersecting a
+/// primitive, which the surface area heuristic weighs splits by
+const TRAVERSAL_TO_INTERSECTION_COST_RATIO: f64 = 1.0 / 8.0;
+
+/// Subtrees are rebuilt when refitting makes rays that hit them this much
+/// more expensive to trace than when they were built
+const REBUILD_COST_RATIO: f64 = 1.5;
+
+/// How primitives are divided between the nodes of a BVH while building it
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SplitMethod {
+    /// Splits at the median centroid along the longest axis
     Median,
+    /// Splits where the surface area heuristic estimates rays are cheapest to
+    /// trace, building large subtrees on separate threads
     SAH,
+    /// Sorts primitives along a Morton curve and splits where their codes
+    /// differ, which is much faster to build but slower to trace
+    Lbvh,
 }
 
-#[derive(Debug)]
+/// How the nodes of a BVH are laid out for traversal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BvhLayout {
+    /// Nodes have two children, which are tested one at a time
+    Binary,
+    /// Nodes have four children, which are tested together
+    Wide,
+}
+
+#[derive(Debug, Clone)]
+enum BvhNodes {
+    Binary(Vec<LinearBvhNode>),
+    Wide(Vec<WideBvhNode>),
+}
+
+#[derive(Debug, Clone)]
 pub struct Bvh {
-    pub root: BvhNode,
+    nodes: BvhNodes,
+    primitives: Vec<Arc<Primitive>>,
+    /// Index of each primitive in the list that the BVH was built from
+    primitive_indices: Vec<usize>,
+    /// Cost that each node's subtree adds to tracing a ray through the BVH
+    /// when it was built, which refitting is compared against to decide what
+    /// to rebuild
+    build_costs: Vec<f64>,
+    split_method: SplitMethod,
+    /// Threads that the BVH is built and rebuilt with
+    threads: BuildThreads,
     pub bounds: Bounds,
 }
 
+/// How many threads a BVH is built with, which is only worth changing from
+/// the default to test or benchmark the builders
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BuildThreads {
+    /// Threads that the subtrees are split between
+    pub num_threads: usize,
+    /// Subtrees with fewer primitives than this are built on the thread that
+    /// split them, since starting a thread would take longer than building
+    /// them
+    pub min_primitives_per_thread: usize,
+}
+
+impl BuildThreads {
+    /// Builds on a single thread
+    pub const SINGLE: BuildThreads = BuildThreads {
+        num_threads: 1,
+        min_primitives_per_thread: usize::MAX,
+    };
+
+    /// Both subtrees of a split get half of the threads
+    fn halve(self) -> (BuildThreads, BuildThreads) {
+        let left = self.num_threads / 2;
+        (
+            BuildThreads {
+                num_threads: left,
+                ..self
+            },
+            BuildThreads {
+                num_threads: self.num_threads - left,
+                ..self
+            },
+        )
+    }
+}
+
+impl Default for BuildThreads {
+    fn default() -> Self {
+        BuildThreads {
+            num_threads: num_cpus::get(),
+            min_primitives_per_thread: 16 * 1024,
+        }
+    }
+}
+
 impl Bvh {
     pub fn new(primitives: Vec<Arc<Primitive>>, split_method: SplitMethod) -> Self {
+        Bvh::with_layout(primitives, split_method, BvhLayout::Binary)
+    }
+
+    pub fn with_layout(
+        primitives: Vec<Arc<Primitive>>,
+        split_method: SplitMethod,
+        layout: BvhLayout,
+    ) -> Self {
+        Bvh::with_threads(primitives, split_method, layout, BuildThreads::default())
+    }
+
+    pub fn with_threads(
+        primitives: Vec<Arc<Primitive>>,
+        split_method: SplitMethod,
+        layout: BvhLayout,
+        threads: BuildThreads,
+    ) -> Self {
         let mut primitive_infos: Vec<_> = primitives
-            .iter()
-            .map(|p| PrimitiveInfo {
-                primitive: Arc::clone(p),
-                bounds: p.bounds(),
-                centroid: p.bounds().centroid(),
-            })
+            .into_iter()
+            .enumerate()
+            .map(|(index, primitive)| PrimitiveInfo::new(primitive, index))
             .collect();
 
-        let root = match split_method {
-            SplitMethod::Median => BvhNode::from_median_splitting(&mut primitive_infos),
-            SplitMethod::SAH => BvhNode::from_sah_splitting(&mut primitive_infos),
+        // The builders reorder the primitives so that each leaf's are
+        // contiguous, which is the order they are stored in
+        let root = BvhNode::build(&mut primitive_infos, 0, split_method, threads, 0);
+
+        let nodes = match layout {
+            BvhLayout::Binary => {
+                let mut nodes = Vec::new();
+                root.flatten(&mut nodes, 0);
+                BvhNodes::Binary(nodes)
+            }
+            BvhLayout::Wide => {
+                let mut nodes = Vec::new();
+                WideBvhNode::collapse(&root, &mut nodes, 0);
+                BvhNodes::Wide(nodes)
+            }
         };
 
-        let bounds: Bounds = primitive_infos.iter().map(|i| i.bounds).sum();
+        let (primitive_indices, primitives) = primitive_infos
+            .into_iter()
+            .map(|info| (info.index, info.primitive))
+            .unzip();
+        let bounds = *root.bounds();
+        Bvh {
+            build_costs: relative_costs(nodes.weighted_costs(), &bounds),
+            bounds,
+            nodes,
+            primitives,
+            primitive_indices,
+            split_method,
+            threads,
+        }
+    }
 
-        Bvh { root, bounds }
+    pub fn num_primitives(&self) -> usize {
+        self.primitives.len()
+    }
+
+    pub fn split_method(&self) -> SplitMethod {
+        self.split_method
+    }
+
+    pub fn layout(&self) -> BvhLayout {
+        match self.nodes {
+            BvhNodes::Binary(_) => BvhLayout::Binary,
+            BvhNodes::Wide(_) => BvhLayout::Wide,
+        }
     }
 
     pub fn intersect(&self, ray: &mut Ray) -> Option<PrimitiveIntersection> {
-        let mut q = Vec::with_capacity(32);
-        q.push(&self.root);
-        let mut current: Option<PrimitiveIntersection> = None;
-        while let Some(node) = q.pop() {
-            let bounds = match node {
-                BvhNode::LeafNode { bounds, .. } => bounds,
-                BvhNode::InteriorNode { bounds, .. } => bounds,
-            };
+        self.intersect_with_material(ray, None)
+    }
 
-            // If the ray doesn't intersect the bounds, it could still be contained
-            // within them, so check for both
-            if !bounds.intersects(ray) && !bounds.contains(&ray.origin) {
-                continue;
-            }
+    /// Finds the closest intersection, with `material_override` replacing the
+    /// materials of the primitives if given
+    pub(crate) fn intersect_with_material<'a>(
+        &'a self,
+        ray: &mut Ray,
+        material_override: Option<&'a Material>,
+    ) -> Option<PrimitiveIntersection<'a>> {
+        match &self.nodes {
+            BvhNodes::Binary(nodes) => self.intersect_binary(nodes, ray, material_override),
+            BvhNodes::Wide(nodes) => self.intersect_wide(nodes, ray, material_override),
+        }
+    }
 
-            match node {
-                BvhNode::LeafNode { primitives, .. } => {
-                    for primitive in primitives {
-                        if let Some(intersection) = primitive.intersect(ray) {
-                            if current.is_none()
-                                || intersection.distance < current.as_ref().unwrap().distance
-                            {
-                                current = Some(intersection);
-                            }
+    pub fn intersects(&self, ray: &Ray) -> bool {
+        match &self.nodes {
+            BvhNodes::Binary(nodes) => self.intersects_binary(nodes, ray),
+            BvhNodes::Wide(nodes) => self.intersects_wide(nodes, ray),
+        }
+    }
+
+    fn intersect_binary<'a>(
+        &'a self,
+        nodes: &[LinearBvhNode],
+        ray: &mut Ray,
+        material_override: Option<&'a Material>,
+    ) -> Option<PrimitiveIntersection<'a>> {
+        let (inv_direction, direction_is_negative) = inverse_direction(ray);
+        let mut to_visit = [0; MAX_DEPTH];
+        let mut num_to_visit = 0;
+        let mut current = 0;
+        let mut closest = None;
+        loop {
+            let node = &nodes[current];
+            if node
+                .bounds
+                .intersects_fast(ray, &inv_direction, &direction_is_negative)
+            {
+                let offset = node.offset as usize;
+                if node.num_primitives > 0 {
+                    // Primitives only return hits closer than the ray's max
+                    // distance, which they update
+                    for primitive in &self.primitives[offset..offset + node.num_primitives as usize]
+                    {
+                        if let Some(intersection) =
+                            primitive.intersect_with_material(ray, material_override)
+                        {
+                            closest = Some(intersection);
                         }
                     }
+                } else {
+                    // Visit the child closer to the ray's origin first
+                    let (near, far) = match direction_is_negative[node.split_axis as usize] {
+                        false => (current + 1, offset),
+                        true => (offset, current + 1),
+                    };
+                    to_visit[num_to_visit] = far;
+                    num_to_visit += 1;
+                    current = near;
+                    continue;
                 }
-                BvhNode::InteriorNode {
-                    left,
-                    right,
-                    split_axis,
-                    ..
-                } => {
-                    if ray.direction[*split_axis] < 0.0 {
-                        q.push(left);
-                        q.push(right);
-                    } else {
-                        q.push(right);
-                        q.push(left);
+            }
+            if num_to_visit == 0 {
+                break;
+            }
+            num_to_visit -= 1;
+            current = to_visit[num_to_visit];
+        }
+
+        closest
+    }
+
+    fn intersects_binary(&self, nodes: &[LinearBvhNode], ray: &Ray) -> bool {
+        let (inv_direction, direction_is_negative) = inverse_direction(ray);
+        let mut to_visit = [0; MAX_DEPTH];
+        let mut num_to_visit = 0;
+        let mut current = 0;
+        loop {
+            let node = &nodes[current];
+            if node
+                .bounds
+                .intersects_fast(ray, &inv_direction, &direction_is_negative)
+            {
+                let offset = node.offset as usize;
+                if node.num_primitives > 0 {
+                    if self.primitives[offset..offset + node.num_primitives as usize]
+                        .iter()
+                        .any(|primitive| primitive.intersects(ray))
+                    {
+                        return true;
+                    }
+                } else {
+                    let (near, far) = match direction_is_negative[node.split_axis as usize] {
+                        false => (current + 1, offset),
+                        true => (offset, current + 1),
                     };
+                    to_visit[num_to_visit] = far;
+                    num_to_visit += 1;
+                    current = near;
+                    continue;
                 }
             }
+            if num_to_visit == 0 {
+                return false;
+            }
+            num_to_visit -= 1;
+            current = to_visit[num_to_visit];
         }
+    }
+}
 
-        current
+impl Bvh {
+    /// Replaces the primitives with `primitives`, given in the order that the
+    /// BVH was built with, e.g. to move them to where they are in the next
+    /// frame of an animation. The tree is refit to their new bounds, and the
+    /// subtrees that have become too expensive to trace because primitives in
+    /// them moved apart are rebuilt. Returns the number of subtrees rebuilt.
+    ///
+    /// The BVH is correct for any primitives, but is only as good as a new one
+    /// if they are the same ones moved by small amounts.
+    pub fn update(&mut self, primitives: &[Arc<Primitive>]) -> usize {
+        assert_eq!(
+            primitives.len(),
+            self.primitives.len(),
+            "BVH must be updated with the same number of primitives"
+        );
+        for (primitive, &index) in self.primitives.iter_mut().zip(&self.primitive_indices) {
+            *primitive = Arc::clone(&primitives[index]);
+        }
+        self.refit();
+        self.rebuild_degraded()
     }
 
-    pub fn intersects(&self, ray: &Ray) -> bool {
-        let mut q = Vec::with_capacity(32);
-        q.push(&self.root);
-        while let Some(node) = q.pop() {
-            let bounds = match node {
-                BvhNode::LeafNode { bounds, .. } => bounds,
-                BvhNode::InteriorNode { bounds, .. } => bounds,
-            };
+    /// Recomputes the bounds of every node bottom-up from the primitives,
+    /// keeping the structure of the tree as it is
+    pub fn refit(&mut self) {
+        let primitives = &self.primitives;
+        let leaf_bounds = |offset: u32, num_primitives: u16| -> Bounds {
+            let offset = offset as usize;
+            primitives[offset..offset + num_primitives as usize]
+                .iter()
+                .map(|primitive| primitive.bounds())
+                .sum()
+        };
+        // Children are stored after their parents, so visiting the nodes in
+        // reverse updates the children first
+        self.bounds = match &mut self.nodes {
+            BvhNodes::Binary(nodes) => {
+                for i in (0..nodes.len()).rev() {
+                    let LinearBvhNode {
+                        offset,
+                        num_primitives,
+                        ..
+                    } = nodes[i];
+                    nodes[i].bounds = match num_primitives {
+                        0 => nodes[i + 1].bounds + nodes[offset as usize].bounds,
+                        _ => leaf_bounds(offset, num_primitives),
+                    };
+                }
+                nodes[0].bounds
+            }
+            BvhNodes::Wide(nodes) => {
+                for i in (0..nodes.len()).rev() {
+                    for child in 0..WIDTH {
+                        let offset = nodes[i].offsets[child];
+                        let bounds = match nodes[i].num_primitives[child] {
+                            _ if !nodes[i].is_used(child) => continue,
+                            0 => nodes[offset as usize].bounds(),
+                            num_primitives => leaf_bounds(offset, num_primitives),
+                        };
+                        nodes[i].set_child_bounds(child, &bounds);
+                    }
+                }
+                nodes[0].bounds()
+            }
+        };
+    }
 
-            // If the ray doesn't intersect the bounds, it could still be contained
-            // within them, so check for both
-            if !bounds.intersects(ray) && !bounds.contains(&ray.origin) {
+    /// Rebuilds the highest subtrees whose cost has grown by more than
+    /// `REBUILD_COST_RATIO` since they were built, returning how many there
+    /// were
+    fn rebuild_degraded(&mut self) -> usize {
+        let costs = relative_costs(self.nodes.weighted_costs(), &self.bounds);
+        let mut degraded = Vec::new();
+        let mut to_visit = vec![(0, 0)];
+        while let Some((index, depth)) = to_visit.pop() {
+            if costs[index] > self.build_costs[index] * REBUILD_COST_RATIO {
+                degraded.push((index, depth));
                 continue;
             }
+            to_visit.extend(
+                self.nodes
+                    .interior_children(index)
+                    .into_iter()
+                    .map(|child| (child, depth + 1)),
+            );
+        }
+
+        // Rebuilding a subtree changes the number of nodes in it, which moves
+        // the nodes after it, so the last subtrees are rebuilt first
+        degraded.sort_unstable_by_key(|&(index, _)| Reverse(index));
+        for &(index, depth) in &degraded {
+            self.rebuild_subtree(index, depth);
+        }
+        degraded.len()
+    }
+
+    /// Builds the subtree under the node at `index` again from its primitives
+    /// and splices it into the nodes in place of the old one
+    fn rebuild_subtree(&mut self, index: usize, depth: usize) {
+        let (node_range, primitive_range) = self.nodes.subtree_ranges(index);
+        let mut primitive_infos: Vec<_> = primitive_range
+            .clone()
+            .map(|i| PrimitiveInfo::new(Arc::clone(&self.primitives[i]), self.primitive_indices[i]))
+            .collect();
+        let root = BvhNode::build(
+            &mut primitive_infos,
+            primitive_range.start,
+            self.split_method,
+            self.threads,
+            depth,
+        );
+        for (i, info) in primitive_range.zip(primitive_infos) {
+            self.primitives[i] = info.primitive;
+            self.primitive_indices[i] = info.index;
+        }
+
+        // The new nodes are numbered from zero, so their costs can be found
+        // before they are moved to where the old ones were. Refitting has
+        // already given the root its final bounds.
+        let num_nodes = match &mut self.nodes {
+            BvhNodes::Binary(nodes) => {
+                let mut subtree = Vec::new();
+                root.flatten(&mut subtree, depth);
+                let costs = relative_costs(LinearBvhNode::weighted_costs(&subtree), &self.bounds);
+                self.build_costs.splice(node_range.clone(), costs);
+                for node in subtree.iter_mut().filter(|node| node.num_primitives == 0) {
+                    node.offset += index as u32;
+                }
+                let num_nodes = subtree.len();
+                nodes.splice(node_range.clone(), subtree);
+                num_nodes
+            }
+            BvhNodes::Wide(nodes) => {
+                let mut subtree = Vec::new();
+                WideBvhNode::collapse(&root, &mut subtree, depth);
+                let costs = relative_costs(WideBvhNode::weighted_costs(&subtree), &self.bounds);
+                self.build_costs.splice(node_range.clone(), costs);
+                for node in subtree.iter_mut() {
+                    for child in node.interior_children() {
+                        node.offsets[child] += index as u32;
+                    }
+                }
+                let num_nodes = subtree.len();
+                nodes.splice(node_range.clone(), subtree);
+                num_nodes
+            }
+        };
+
+        // Point the nodes outside the subtree at where the nodes after it
+        // have moved to
+        let new_end = (index + num_nodes) as u32;
+        let old_end = node_range.end as u32;
+        let moved = |offset: &mut u32| {
+            if *offset >= old_end {
+                *offset = *offset - old_end + new_end;
+            }
+        };
+        match &mut self.nodes {
+            BvhNodes::Binary(nodes) => {
+                let (before, after) = nodes.split_at_mut(index);
+                for node in before
+                    .iter_mut()
+                    .chain(after[num_nodes..].iter_mut())
+                    .filter(|node| node.num_primitives == 0)
+                {
+                    moved(&mut node.offset);
+                }
+            }
+            BvhNodes::Wide(nodes) => {
+                let (before, after) = nodes.split_at_mut(index);
+                for node in before.iter_mut().chain(after[num_nodes..].iter_mut()) {
+                    for child in node.interior_children() {
+                        moved(&mut node.offsets[child]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Bvh {
+    /// Writes the structure of the tree, without the primitives or the
+    /// bounds of the nodes, which are found again by `read`
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_u8(writer, self.split_method as u8)?;
+        let indices: Vec<u32> = self
+            .primitive_indices
+            .iter()
+            .map(|&index| index as u32)
+            .collect();
+        write_u32s(writer, &indices)?;
+        match &self.nodes {
+            BvhNodes::Binary(nodes) => {
+                write_u8(writer, BvhLayout::Binary as u8)?;
+                write_u32(writer, nodes.len() as u32)?;
+                for node in nodes {
+                    write_u32(writer, node.offset)?;
+                    write_u32(writer, node.num_primitives as u32)?;
+                    write_u8(writer, node.split_axis as u8)?;
+                }
+            }
+            BvhNodes::Wide(nodes) => {
+                write_u8(writer, BvhLayout::Wide as u8)?;
+                write_u32(writer, nodes.len() as u32)?;
+                for node in nodes {
+                    for i in 0..WIDTH {
+                        write_u32(writer, node.offsets[i])?;
+                        write_u32(writer, node.num_primitives[i] as u32)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a tree written by `write` for `primitives`, which must be in the
+    /// order it was built from, and refits it to them. Trees that don't fit
+    /// the primitives are rejected, so the result is always safe to traverse.
+    pub fn read<R: Read>(reader: &mut R, primitives: Vec<Arc<Primitive>>) -> io::Result<Bvh> {
+        let split_method = match read_u8(reader)? {
+            0 => SplitMethod::Median,
+            1 => SplitMethod::SAH,
+            2 => SplitMethod::Lbvh,
+            _ => return Err(invalid_data("unknown split method")),
+        };
+
+        // Each primitive must appear exactly once
+        let primitive_indices: Vec<usize> = read_u32s(reader)?
+            .into_iter()
+            .map(|index| index as usize)
+            .collect();
+        let mut seen = vec![false; primitives.len()];
+        if primitive_indices.len() != primitives.len()
+            || !primitive_indices
+                .iter()
+                .all(|&index| index < seen.len() && !std::mem::replace(&mut seen[index], true))
+        {
+            return Err(invalid_data("BVH is for different primitives"));
+        }
 
-            match node {
-                BvhNode::LeafNode { primitives, .. } => {
-                    for primitive in primitives {
-                        if primitive.intersects(ray) {
-                            return true;
+        let layout = read_u8(reader)?;
+        let num_nodes = read_u32(reader)? as usize;
+        if num_nodes == 0 {
+            return Err(invalid_data("BVH has no nodes"));
+        }
+        // Children must come after their parents, each node but the root must
+        // have exactly one parent, and leaves must refer to primitives that
+        // exist, which also limits how deep the tree can be
+        let mut depths = vec![0; num_nodes];
+        let mut has_parent = vec![false; num_nodes];
+        let mut check_child = |parent: usize, child: u32, num_primitives: u32| match num_primitives
+        {
+            0 if (child as usize) > parent && (child as usize) < num_nodes => {
+                if std::mem::replace(&mut has_parent[child as usize], true) {
+                    return Err(invalid_data("BVH node has more than one parent"));
+                }
+                depths[child as usize] = depths[parent] + 1;
+                match depths[child as usize] < MAX_DEPTH {
+                    true => Ok(()),
+                    false => Err(invalid_data("BVH is too deep")),
+                }
+            }
+            0 => Err(invalid_data("invalid child node")),
+            _ if num_primitives <= u16::MAX as u32
+                && child as usize + num_primitives as usize <= primitives.len() =>
+            {
+                Ok(())
+            }
+            _ => Err(invalid_data("invalid leaf")),
+        };
+        let nodes = match layout {
+            0 => {
+                let mut nodes = Vec::with_capacity(num_nodes);
+                for i in 0..num_nodes {
+                    let offset = read_u32(reader)?;
+                    let num_primitives = read_u32(reader)?;
+                    let split_axis = *AXES
+                        .get(read_u8(reader)? as usize)
+                        .ok_or_else(|| invalid_data("invalid split axis"))?;
+                    check_child(i, offset, num_primitives)?;
+                    if num_primitives == 0 {
+                        check_child(i, i as u32 + 1, 0)?;
+                    }
+                    nodes.push(LinearBvhNode {
+                        bounds: Bounds::new(Point(0.0, 0.0, 0.0), Point(0.0, 0.0, 0.0)),
+                        offset,
+                        num_primitives: num_primitives as u16,
+                        split_axis,
+                    });
+                }
+                BvhNodes::Binary(nodes)
+            }
+            1 => {
+                let mut nodes = Vec::with_capacity(num_nodes);
+                for i in 0..num_nodes {
+                    let mut node = WideBvhNode::EMPTY;
+                    for child in 0..WIDTH {
+                        node.offsets[child] = read_u32(reader)?;
+                        let num_primitives = read_u32(reader)?;
+                        node.num_primitives[child] = num_primitives as u16;
+                        if node.is_used(child) {
+                            check_child(i, node.offsets[child], num_primitives)?;
                         }
                     }
+                    nodes.push(node);
                 }
-                BvhNode::InteriorNode {
-                    left,
-                    right,
-                    split_axis,
+                BvhNodes::Wide(nodes)
+            }
+            _ => return Err(invalid_data("unknown BVH layout")),
+        };
+        if has_parent.iter().skip(1).any(|&has_parent| !has_parent) {
+            return Err(invalid_data("BVH node has no parent"));
+        }
+
+        let mut bvh = Bvh {
+            nodes,
+            primitives: primitive_indices
+                .iter()
+                .map(|&index| Arc::clone(&primitives[index]))
+                .collect(),
+            primitive_indices,
+            build_costs: Vec::new(),
+            split_method,
+            threads: BuildThreads::default(),
+            bounds: Bounds::new(Point(0.0, 0.0, 0.0), Point(0.0, 0.0, 0.0)),
+        };
+        bvh.refit();
+        bvh.build_costs = relative_costs(bvh.nodes.weighted_costs(), &bvh.bounds);
+        Ok(bvh)
+    }
+}
+
+impl BvhNodes {
+    fn weighted_costs(&self) -> Vec<f64> {
+        match self {
+            BvhNodes::Binary(nodes) => LinearBvhNode::weighted_costs(nodes),
+            BvhNodes::Wide(nodes) => WideBvhNode::weighted_costs(nodes),
+        }
+    }
+
+    fn interior_children(&self, index: usize) -> Vec<usize> {
+        match self {
+            BvhNodes::Binary(nodes) => match nodes[index].num_primitives {
+                0 => vec![index + 1, nodes[index].offset as usize],
+                _ => vec![],
+            },
+            BvhNodes::Wide(nodes) => nodes[index]
+                .interior_children()
+                .map(|child| nodes[index].offsets[child] as usize)
+                .collect(),
+        }
+    }
+
+    /// Range of the nodes in the subtree under the node at `index`, and of
+    /// the primitives in its leaves, which are both contiguous
+    fn subtree_ranges(&self, index: usize) -> (Range<usize>, Range<usize>) {
+        let mut nodes_end = index + 1;
+        let (mut primitives_start, mut primitives_end) = (usize::MAX, 0);
+        let mut to_visit = vec![index];
+        while let Some(current) = to_visit.pop() {
+            nodes_end = nodes_end.max(current + 1);
+            let leaves: Vec<(u32, u16)> = match self {
+                BvhNodes::Binary(nodes) => {
+                    let node = &nodes[current];
+                    match node.num_primitives {
+                        0 => vec![],
+                        _ => vec![(node.offset, node.num_primitives)],
+                    }
+                }
+                BvhNodes::Wide(nodes) => {
+                    let node = &nodes[current];
+                    (0..WIDTH)
+                        .filter(|&child| node.is_used(child) && node.num_primitives[child] > 0)
+                        .map(|child| (node.offsets[child], node.num_primitives[child]))
+                        .collect()
+                }
+            };
+            for (offset, num_primitives) in leaves {
+                let offset = offset as usize;
+                primitives_start = primitives_start.min(offset);
+                primitives_end = primitives_end.max(offset + num_primitives as usize);
+            }
+            to_visit.extend(self.interior_children(current));
+        }
+        (index..nodes_end, primitives_start..primitives_end)
+    }
+}
+
+impl LinearBvhNode {
+    /// Cost of tracing rays through the subtree under each of `nodes`,
+    /// relative to intersecting a primitive, by the surface area heuristic.
+    /// Each node's cost is weighted by the area of its bounds, which is
+    /// proportional to the chance of a ray hitting it.
+    fn weighted_costs(nodes: &[LinearBvhNode]) -> Vec<f64> {
+        let mut weighted_costs = vec![0.0; nodes.len()];
+        for (i, node) in nodes.iter().enumerate().rev() {
+            let area = node.bounds.surface_area();
+            weighted_costs[i] = match node.num_primitives {
+                0 => {
+                    TRAVERSAL_TO_INTERSECTION_COST_RATIO * area
+                        + weighted_costs[i + 1]
+                        + weighted_costs[node.offset as usize]
+                }
+                num_primitives => num_primitives as f64 * area,
+            };
+        }
+        weighted_costs
+    }
+}
+
+/// Divides weighted costs by the area of the whole BVH's bounds, which gives
+/// the cost that each subtree adds to tracing a ray through it. Unlike
+/// dividing by the area of each subtree's own bounds, this shows when a
+/// subtree has grown, e.g. because a primitive in it moved far away.
+fn relative_costs(weighted_costs: Vec<f64>, bounds: &Bounds) -> Vec<f64> {
+    let area = bounds.surface_area();
+    weighted_costs
+        .into_iter()
+        .map(|cost| match area > 0.0 {
+            true => cost / area,
+            false => 0.0,
+        })
+        .collect()
+}
+
+impl Bvh {
+    fn intersect_wide<'a>(
+        &'a self,
+        nodes: &[WideBvhNode],
+        ray: &mut Ray,
+        material_override: Option<&'a Material>,
+    ) -> Option<PrimitiveIntersection<'a>> {
+        let (inv_direction, direction_is_negative) = inverse_direction(ray);
+        // Nodes to visit with the distance to their bounds, so that those
+        // further than the closest hit so far can be skipped
+        let mut to_visit = [(0, 0.0); MAX_DEPTH * (WIDTH - 1) + 1];
+        let mut num_to_visit = 1;
+        let mut closest = None;
+        while num_to_visit > 0 {
+            num_to_visit -= 1;
+            let (current, distance) = to_visit[num_to_visit];
+            if distance >= ray.max_distance {
+                continue;
+            }
+            let node = &nodes[current];
+            let distances = node.intersect_children(ray, &inv_direction, &direction_is_negative);
+
+            // Visit the children in order of distance, intersecting leaves
+            // right away and pushing interior nodes so that the nearest is
+            // popped first
+            for &i in node.sort_hits(&distances).iter().rev().flatten() {
+                let offset = node.offsets[i] as usize;
+                let num_primitives = node.num_primitives[i] as usize;
+                if num_primitives == 0 {
+                    to_visit[num_to_visit] = (offset, distances[i]);
+                    num_to_visit += 1;
+                    continue;
+                }
+                if distances[i] >= ray.max_distance {
+                    continue;
+                }
+                for primitive in &self.primitives[offset..offset + num_primitives] {
+                    if let Some(intersection) =
+                        primitive.intersect_with_material(ray, material_override)
+                    {
+                        closest = Some(intersection);
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    fn intersects_wide(&self, nodes: &[WideBvhNode], ray: &Ray) -> bool {
+        let (inv_direction, direction_is_negative) = inverse_direction(ray);
+        let mut to_visit = [0; MAX_DEPTH * (WIDTH - 1) + 1];
+        let mut num_to_visit = 1;
+        while num_to_visit > 0 {
+            num_to_visit -= 1;
+            let node = &nodes[to_visit[num_to_visit]];
+            let distances = node.intersect_children(ray, &inv_direction, &direction_is_negative);
+            for (i, &distance) in distances.iter().enumerate() {
+                if distance == f64::INFINITY {
+                    continue;
+                }
+                let offset = node.offsets[i] as usize;
+                let num_primitives = node.num_primitives[i] as usize;
+                if num_primitives == 0 {
+                    to_visit[num_to_visit] = offset;
+                    num_to_visit += 1;
+                } else if self.primitives[offset..offset + num_primitives]
+                    .iter()
+                    .any(|primitive| primitive.intersects(ray))
+                {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+impl WideBvhNode {
+    /// Node whose slots are all unused
+    const EMPTY: WideBvhNode = WideBvhNode {
+        min: [[f64::INFINITY; WIDTH]; 3],
+        max: [[f64::NEG_INFINITY; WIDTH]; 3],
+        offsets: [0; WIDTH],
+        num_primitives: [0; WIDTH],
+    };
+
+    /// Builds wide nodes from the binary tree under `node`, by repeatedly
+    /// replacing the interior child with the largest surface area with its
+    /// own children until there are `WIDTH` of them. Returns the index of the
+    /// node that was added for `node`.
+    fn collapse(node: &BvhNode, nodes: &mut Vec<WideBvhNode>, depth: usize) -> usize {
+        assert!(depth < MAX_DEPTH, "BVH is too deep to be traversed");
+        let mut children = vec![node];
+        while children.len() < WIDTH {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| matches!(child, BvhNode::InteriorNode { .. }))
+                .max_by(|(_, a), (_, b)| {
+                    a.bounds()
+                        .surface_area()
+                        .total_cmp(&b.bounds().surface_area())
+                })
+                .map(|(i, _)| i);
+            match largest {
+                Some(i) => {
+                    if let BvhNode::InteriorNode { left, right, .. } = children[i] {
+                        children[i] = left;
+                        children.push(right);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let index = nodes.len();
+        nodes.push(WideBvhNode::EMPTY);
+        for (i, child) in children.into_iter().enumerate() {
+            nodes[index].set_child_bounds(i, child.bounds());
+            match child {
+                BvhNode::LeafNode {
+                    first_primitive,
+                    num_primitives,
                     ..
                 } => {
-                    if ray.direction[*split_axis] < 0.0 {
-                        q.push(left);
-                        q.push(right);
-                    } else {
-                        q.push(right);
-                        q.push(left);
-                    };
+                    nodes[index].offsets[i] =
+                        (*first_primitive).try_into().expect("Too many primitives");
+                    nodes[index].num_primitives[i] =
+                        (*num_primitives).try_into().expect("Leaf is too large");
+                }
+                BvhNode::InteriorNode { .. } => {
+                    let child_index = WideBvhNode::collapse(child, nodes, depth + 1);
+                    nodes[index].offsets[i] = child_index.try_into().expect("Too many BVH nodes");
                 }
             }
         }
+        index
+    }
 
-        false
+    /// Whether a child is in slot `i`. Unused slots have no primitives, and
+    /// point at the root, which can't be a child.
+    fn is_used(&self, i: usize) -> bool {
+        self.num_primitives[i] > 0 || self.offsets[i] > 0
+    }
+
+    /// Slots of the children that are interior nodes
+    fn interior_children(&self) -> impl Iterator<Item = usize> {
+        let (offsets, num_primitives) = (self.offsets, self.num_primitives);
+        (0..WIDTH).filter(move |&i| num_primitives[i] == 0 && offsets[i] > 0)
+    }
+
+    fn child_bounds(&self, i: usize) -> Bounds {
+        Bounds {
+            min: Point(self.min[0][i], self.min[1][i], self.min[2][i]),
+            max: Point(self.max[0][i], self.max[1][i], self.max[2][i]),
+        }
+    }
+
+    fn set_child_bounds(&mut self, i: usize, bounds: &Bounds) {
+        for axis in AXES {
+            self.min[axis as usize][i] = bounds.min[axis];
+            self.max[axis as usize][i] = bounds.max[axis];
+        }
+    }
+
+    /// Bounds of all the node's children
+    fn bounds(&self) -> Bounds {
+        (0..WIDTH)
+            .filter(|&i| self.is_used(i))
+            .map(|i| self.child_bounds(i))
+            .sum()
+    }
+
+    /// Like `LinearBvhNode::weighted_costs`, for wide nodes
+    fn weighted_costs(nodes: &[WideBvhNode]) -> Vec<f64> {
+        let mut weighted_costs = vec![0.0; nodes.len()];
+        for (i, node) in nodes.iter().enumerate().rev() {
+            let mut weighted_cost =
+                TRAVERSAL_TO_INTERSECTION_COST_RATIO * node.bounds().surface_area();
+            for child in (0..WIDTH).filter(|&child| node.is_used(child)) {
+                weighted_cost += match node.num_primitives[child] {
+                    0 => weighted_costs[node.offsets[child] as usize],
+                    num_primitives => {
+                        num_primitives as f64 * node.child_bounds(child).surface_area()
+                    }
+                };
+            }
+            weighted_costs[i] = weighted_cost;
+        }
+        weighted_costs
+    }
+
+    /// Distance along the ray to the bounds of each child, which is infinite
+    /// for those it misses. This is `Bounds::intersects_fast` for each child.
+    fn intersect_children(
+        &self,
+        ray: &Ray,
+        inv_direction: &Vector,
+        direction_is_negative: &[bool; 3],
+    ) -> [f64; WIDTH] {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx") {
+                // Safe since the CPU was just checked to support AVX
+                return unsafe {
+                    self.intersect_children_avx(ray, inv_direction, direction_is_negative)
+                };
+            }
+        }
+        self.intersect_children_scalar(ray, inv_direction, direction_is_negative)
+    }
+
+    /// `intersect_children` for CPUs without AVX
+    fn intersect_children_scalar(
+        &self,
+        ray: &Ray,
+        inv_direction: &Vector,
+        direction_is_negative: &[bool; 3],
+    ) -> [f64; WIDTH] {
+        let mut min_distance = [f64::NEG_INFINITY; WIDTH];
+        let mut max_distance = [f64::INFINITY; WIDTH];
+        for axis in AXES {
+            let a = axis as usize;
+            let (near, far) = match direction_is_negative[a] {
+                false => (&self.min[a], &self.max[a]),
+                true => (&self.max[a], &self.min[a]),
+            };
+            let origin = ray.origin[axis];
+            let inv_direction = inv_direction[axis];
+            for i in 0..WIDTH {
+                let near_distance = (near[i] - origin) * inv_direction;
+                let far_distance = (far[i] - origin) * inv_direction * FAR_DISTANCE_PADDING;
+                if near_distance > min_distance[i] {
+                    min_distance[i] = near_distance;
+                }
+                if far_distance < max_distance[i] {
+                    max_distance[i] = far_distance;
+                }
+            }
+        }
+
+        let mut distances = [f64::INFINITY; WIDTH];
+        for i in 0..WIDTH {
+            if hits_slabs(ray, min_distance[i], max_distance[i]) {
+                distances[i] = min_distance[i].max(0.0);
+            }
+        }
+        distances
+    }
+
+    /// `intersect_children` with each child's bounds in a lane of a 4 x f64
+    /// AVX vector, which gives the same results as the scalar version
+    ///
+    /// # Safety
+    ///
+    /// The CPU must support AVX
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx")]
+    unsafe fn intersect_children_avx(
+        &self,
+        ray: &Ray,
+        inv_direction: &Vector,
+        direction_is_negative: &[bool; 3],
+    ) -> [f64; WIDTH] {
+        use crate::constants::EPSILON;
+        use std::arch::x86_64::*;
+
+        let mut min_distance = _mm256_set1_pd(f64::NEG_INFINITY);
+        let mut max_distance = _mm256_set1_pd(f64::INFINITY);
+        for axis in AXES {
+            let a = axis as usize;
+            // Typed as four lanes so that this doesn't compile if WIDTH
+            // changes
+            let (near, far): (&[f64; 4], &[f64; 4]) = match direction_is_negative[a] {
+                false => (&self.min[a], &self.max[a]),
+                true => (&self.max[a], &self.min[a]),
+            };
+            let origin = _mm256_set1_pd(ray.origin[axis]);
+            let inv_direction = _mm256_set1_pd(inv_direction[axis]);
+            let near_distance = _mm256_mul_pd(
+                _mm256_sub_pd(_mm256_loadu_pd(near.as_ptr()), origin),
+                inv_direction,
+            );
+            let far_distance = _mm256_mul_pd(
+                _mm256_mul_pd(
+                    _mm256_sub_pd(_mm256_loadu_pd(far.as_ptr()), origin),
+                    inv_direction,
+                ),
+                _mm256_set1_pd(FAR_DISTANCE_PADDING),
+            );
+            // Max and min return their second operand when either is NaN, so
+            // NaNs from rays in the plane of a face leave the distances
+            // unchanged, like in `Bounds::intersects_fast`
+            min_distance = _mm256_max_pd(near_distance, min_distance);
+            max_distance = _mm256_min_pd(far_distance, max_distance);
+        }
+
+        // `hits_slabs` in each lane
+        let zero = _mm256_setzero_pd();
+        let epsilon = _mm256_set1_pd(EPSILON);
+        let ray_max_distance = _mm256_set1_pd(ray.max_distance);
+        let starts_inside = _mm256_and_pd(
+            _mm256_cmp_pd::<_CMP_LE_OQ>(min_distance, zero),
+            _mm256_cmp_pd::<_CMP_GE_OQ>(max_distance, zero),
+        );
+        let enters = _mm256_and_pd(
+            _mm256_cmp_pd::<_CMP_GT_OQ>(min_distance, epsilon),
+            _mm256_cmp_pd::<_CMP_LT_OQ>(min_distance, ray_max_distance),
+        );
+        let leaves = _mm256_and_pd(
+            _mm256_cmp_pd::<_CMP_GT_OQ>(max_distance, epsilon),
+            _mm256_cmp_pd::<_CMP_LT_OQ>(max_distance, ray_max_distance),
+        );
+        let hit = _mm256_and_pd(
+            _mm256_cmp_pd::<_CMP_LE_OQ>(min_distance, max_distance),
+            _mm256_or_pd(starts_inside, _mm256_or_pd(enters, leaves)),
+        );
+        let distances = _mm256_blendv_pd(
+            _mm256_set1_pd(f64::INFINITY),
+            _mm256_max_pd(min_distance, zero),
+            hit,
+        );
+        let mut result = [0.0; WIDTH];
+        _mm256_storeu_pd(result.as_mut_ptr(), distances);
+        result
+    }
+
+    /// Indices of the children that were hit, nearest first
+    fn sort_hits(&self, distances: &[f64; WIDTH]) -> [Option<usize>; WIDTH] {
+        let mut hits = [None; WIDTH];
+        let mut num_hits = 0;
+        for i in 0..WIDTH {
+            if distances[i] == f64::INFINITY {
+                continue;
+            }
+            // Insertion sort, which is fast for so few elements
+            let mut j = num_hits;
+            while j > 0 && distances[hits[j - 1].unwrap()] > distances[i] {
+                hits[j] = hits[j - 1];
+                j -= 1;
+            }
+            hits[j] = Some(i);
+            num_hits += 1;
+        }
+        hits
     }
 }
 
+/// Inverse of the ray's direction and its sign along each axis, which are
+/// shared by the tests against every node's bounds
+fn inverse_direction(ray: &Ray) -> (Vector, [bool; 3]) {
+    let Vector(x, y, z) = ray.direction;
+    (
+        Vector(1.0 / x, 1.0 / y, 1.0 / z),
+        [
+            x.is_sign_negative(),
+            y.is_sign_negative(),
+            z.is_sign_negative(),
+        ],
+    )
+}
+
 #[derive(Debug, Clone)]
 struct PrimitiveInfo {
     primitive: Arc<Primitive>,
+    /// Index of the primitive in the list that the BVH is built from
+    index: usize,
     bounds: Bounds,
     centroid: Point,
 }
 
+impl PrimitiveInfo {
+    fn new(primitive: Arc<Primitive>, index: usize) -> Self {
+        let bounds = primitive.bounds();
+        PrimitiveInfo {
+            primitive,
+            index,
+            bounds,
+            centroid: bounds.centroid(),
+        }
+    }
+}
+
 impl Display for PrimitiveInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!("{:?}", self.bounds))
@@ -178,31 +1193,139 @@ impl Add for &SAHBucket {
 }
 
 impl BvhNode {
-    fn leaf_node(primitive_infos: &[PrimitiveInfo], bounds: Bounds) -> BvhNode {
-        BvhNode::LeafNode {
+    /// Builds the tree for `primitive_infos`, which start at index
+    /// `first_primitive` of all the primitives, reordering them so that each
+    /// leaf's are contiguous. The root of the tree is at `depth` in the whole
+    /// BVH, which limits how deep the tree can grow.
+    fn build(
+        primitive_infos: &mut Vec<PrimitiveInfo>,
+        first_primitive: usize,
+        split_method: SplitMethod,
+        threads: BuildThreads,
+        depth: usize,
+    ) -> BvhNode {
+        match split_method {
+            SplitMethod::Median => {
+                BvhNode::from_median_splitting(primitive_infos, first_primitive, depth)
+            }
+            SplitMethod::SAH => {
+                BvhNode::from_sah_splitting(primitive_infos, first_primitive, threads, depth)
+            }
+            SplitMethod::Lbvh => {
+                BvhNode::from_morton_codes(primitive_infos, first_primitive, threads, depth)
+            }
+        }
+    }
+
+    /// Leaf for `primitive_infos`, which start at index `first_primitive` of
+    /// all the primitives. Flattened nodes count their primitives in 16 bits,
+    /// so more primitives than that, which the builders couldn't split, such
+    /// as ones with the same centroid, are split in half by index instead.
+    fn leaf_node(
+        primitive_infos: &[PrimitiveInfo],
+        bounds: Bounds,
+        first_primitive: usize,
+    ) -> BvhNode {
+        let num_primitives = primitive_infos.len();
+        if num_primitives <= u16::MAX as usize {
+            return BvhNode::LeafNode {
+                bounds,
+                first_primitive,
+                num_primitives,
+            };
+        }
+
+        let (left, right) = primitive_infos.split_at(num_primitives / 2);
+        let leaf = |infos: &[PrimitiveInfo], first_primitive| {
+            let bounds = infos.iter().map(|p| p.bounds).sum();
+            Box::new(BvhNode::leaf_node(infos, bounds, first_primitive))
+        };
+        BvhNode::InteriorNode {
             bounds,
-            primitives: primitive_infos
-                .iter()
-                .map(|pi| Arc::clone(&pi.primitive))
-                .collect(),
+            left: leaf(left, first_primitive),
+            right: leaf(right, first_primitive + left.len()),
+            split_axis: bounds.maximum_extent(),
         }
     }
 
-    fn from_median_splitting(mut primitive_infos: &mut [PrimitiveInfo]) -> BvhNode {
-        assert!(primitive_infos.len() > 0);
+    fn bounds(&self) -> &Bounds {
+        match self {
+            BvhNode::InteriorNode { bounds, .. } | BvhNode::LeafNode { bounds, .. } => bounds,
+        }
+    }
+
+    /// Appends the nodes of the tree to `nodes` in depth first order,
+    /// returning the number of nodes added
+    fn flatten(&self, nodes: &mut Vec<LinearBvhNode>, depth: usize) -> usize {
+        assert!(depth < MAX_DEPTH, "BVH is too deep to be traversed");
+        match self {
+            BvhNode::LeafNode {
+                bounds,
+                first_primitive,
+                num_primitives,
+            } => {
+                nodes.push(LinearBvhNode {
+                    bounds: *bounds,
+                    offset: (*first_primitive).try_into().expect("Too many primitives"),
+                    num_primitives: (*num_primitives).try_into().expect("Leaf is too large"),
+                    split_axis: Axis::X,
+                });
+                1
+            }
+            BvhNode::InteriorNode {
+                bounds,
+                left,
+                right,
+                split_axis,
+            } => {
+                let index = nodes.len();
+                nodes.push(LinearBvhNode {
+                    bounds: *bounds,
+                    offset: 0,
+                    num_primitives: 0,
+                    split_axis: *split_axis,
+                });
+                let num_left = left.flatten(nodes, depth + 1);
+                nodes[index].offset = (index + 1 + num_left)
+                    .try_into()
+                    .expect("Too many BVH nodes");
+                1 + num_left + right.flatten(nodes, depth + 1)
+            }
+        }
+    }
+
+    fn from_median_splitting(
+        mut primitive_infos: &mut [PrimitiveInfo],
+        first_primitive: usize,
+        depth: usize,
+    ) -> BvhNode {
+        assert!(!primitive_infos.is_empty());
         let bounds: Bounds = primitive_infos.iter().map(|p| p.bounds).sum();
 
+        // Median splits halve the primitives, so only subtrees rebuilt deep
+        // in an unbalanced tree can run out of depth
+        if depth + 1 >= MAX_DEPTH {
+            return BvhNode::leaf_node(primitive_infos, bounds, first_primitive);
+        }
         match BvhNode::split_at_median(&mut primitive_infos) {
             Some((split_axis, location)) => {
                 let (l, r) = primitive_infos.split_at_mut(location);
                 BvhNode::InteriorNode {
                     bounds,
-                    left: Box::new(BvhNode::from_median_splitting(l)),
-                    right: Box::new(BvhNode::from_median_splitting(r)),
+                    left: Box::new(BvhNode::from_median_splitting(
+                        l,
+                        first_primitive,
+                        depth + 1,
+                    )),
+                    right: Box::new(BvhNode::from_median_splitting(
+                        r,
+                        first_primitive + location,
+                        depth + 1,
+                    )),
                     split_axis,
                 }
             }
-            None => BvhNode::leaf_node(primitive_infos, bounds),
+            None => BvhNode::leaf_node(primitive_infos, bounds, first_primitive),
         }
     }
 
@@ -231,14 +1354,23 @@ impl BvhNode {
 
     // Surface Area Heuristic
 
-    fn from_sah_splitting(primitive_infos: &mut [PrimitiveInfo]) -> BvhNode {
+    /// Builds the tree for `primitive_infos`, which start at index
+    /// `first_primitive` of all the primitives, using up to `threads`
+    fn from_sah_splitting(
+        primitive_infos: &mut [PrimitiveInfo],
+        first_primitive: usize,
+        threads: BuildThreads,
+        depth: usize,
+    ) -> BvhNode {
         const NUM_BUCKETS: usize = 12;
-        const TRAVERSAL_TO_INTERSECTION_COST_RATIO: f64 = 1.0 / 8.0;
         const MAX_LEAF_PRIMITIVES: usize = 4;
 
         let bounds: Bounds = primitive_infos.iter().map(|i| i.bounds).sum();
         if primitive_infos.len() <= 1 {
-            return BvhNode::leaf_node(&primitive_infos, bounds);
+            return BvhNode::leaf_node(&primitive_infos, bounds, first_primitive);
+        }
+        if !can_split_unevenly(depth, primitive_infos.len()) {
+            return BvhNode::from_median_splitting(primitive_infos, first_primitive, depth);
         }
 
         let total_surface_area = bounds.surface_area();
@@ -253,6 +1385,10 @@ impl BvhNode {
             .map(|p| Bounds::new(p.centroid, p.centroid))
             .sum();
         let split_axis = centroid_bounds.maximum_extent();
+        // Primitives whose centroids coincide can't be put in different buckets
+        if centroid_bounds.min[split_axis] == centroid_bounds.max[split_axis] {
+            return BvhNode::leaf_node(primitive_infos, bounds, first_primitive);
+        }
 
         // We will assign primitives to buckets that their centroids lie in.
         // Some buckets will be left empty, which is represented with None.
@@ -281,28 +1417,34 @@ impl BvhNode {
                 }));
         }
 
-        // Calculate costs of splitting at each bucket (except the last, since
-        // that would assign everything to the left node)
-        let mut costs = [0.0; NUM_BUCKETS - 1];
-        for i in 0..NUM_BUCKETS - 1 {
-            let mut cost = TRAVERSAL_TO_INTERSECTION_COST_RATIO;
-            let (left, right) = buckets.split_at(i + 1);
-            for part in [left, right] {
-                let mut merged_bucket: Option<SAHBucket> = None;
-                for bucket in part {
-                    if let Some(bucket) = bucket {
-                        merged_bucket = merged_bucket
-                            .and_then(|merged_bucket| Some(&merged_bucket + &bucket))
-                            .or(Some(*bucket));
-                    }
-                }
-                if let Some(SAHBucket { bounds, count }) = merged_bucket {
-                    cost += count as f64 * bounds.surface_area() / total_surface_area;
-                }
+        // Calculate costs of splitting after each bucket (except the last,
+        // since that would assign everything to the left node), by sweeping
+        // over the buckets from each side and merging them as we go
+        let cost = |merged_bucket: &Option<SAHBucket>| match merged_bucket {
+            Some(SAHBucket { bounds, count }) => {
+                *count as f64 * bounds.surface_area() / total_surface_area
             }
+            None => 0.0,
+        };
+        let merge = |merged_bucket: Option<SAHBucket>, bucket: &Option<SAHBucket>| match (
+            merged_bucket,
+            bucket,
+        ) {
+            (Some(merged_bucket), Some(bucket)) => Some(&merged_bucket + bucket),
+            (merged_bucket, bucket) => merged_bucket.or(*bucket),
+        };
+        let mut costs = [TRAVERSAL_TO_INTERSECTION_COST_RATIO; NUM_BUCKETS - 1];
+        let mut left = None;
+        for i in 0..NUM_BUCKETS - 1 {
+            left = merge(left, &buckets[i]);
+            costs[i] += cost(&left);
+        }
+        let mut right = None;
+        for i in (0..NUM_BUCKETS - 1).rev() {
+            right = merge(right, &buckets[i + 1]);
+            costs[i] += cost(&right);
             // Guard against bad calculations / degenerate cases
-            assert!(cost.is_finite());
-            costs[i] = cost;
+            assert!(costs[i].is_finite());
         }
 
         let mut min_cost_bucket_idx = 0;
@@ -315,37 +1457,194 @@ impl BvhNode {
         // Create a leaf if it will cost less and not have too many primitives
         let leaf_cost = primitive_infos.len() as f64;
         if leaf_cost <= costs[min_cost_bucket_idx] && primitive_infos.len() <= MAX_LEAF_PRIMITIVES {
-            return BvhNode::leaf_node(primitive_infos, bounds);
+            return BvhNode::leaf_node(primitive_infos, bounds, first_primitive);
         }
 
         // Otherwise, split at the minimum cost bucket
-        let (mut left, mut right) = partition_by(primitive_infos, |primitive_info| {
+        let num_primitives = primitive_infos.len();
+        let (left, right) = partition_by(primitive_infos, |primitive_info| {
             let bucket_idx = get_bucket_idx(&primitive_info);
             bucket_idx <= min_cost_bucket_idx
         });
 
-        assert!(left.len() > 0);
-        assert!(right.len() > 0);
+        assert!(!left.is_empty());
+        assert!(!right.is_empty());
 
+        let first_right = first_primitive + left.len();
+        let (left, right) = build_subtrees(
+            num_primitives,
+            threads,
+            |threads| Self::from_sah_splitting(left, first_primitive, threads, depth + 1),
+            |threads| Self::from_sah_splitting(right, first_right, threads, depth + 1),
+        );
         BvhNode::InteriorNode {
             bounds,
-            left: Box::new(Self::from_sah_splitting(&mut left)),
-            right: Box::new(Self::from_sah_splitting(&mut right)),
+            left: Box::new(left),
+            right: Box::new(right),
             split_axis,
         }
     }
+
+    // Linear BVH
+
+    /// Sorts the primitives by the Morton codes of their centroids, which
+    /// places primitives that are close together next to each other, and
+    /// builds the tree by splitting them at the highest bit of the codes that
+    /// differs
+    ///
+    /// Source: https://pbr-book.org/3ed-2018/Primitives_and_Intersection_Acceleration/Bounding_Volume_Hierarchies#LinearBoundingVolumeHierarchies
+    fn from_morton_codes(
+        primitive_infos: &mut Vec<PrimitiveInfo>,
+        first_primitive: usize,
+        threads: BuildThreads,
+        depth: usize,
+    ) -> BvhNode {
+        let centroid_bounds: Bounds = primitive_infos
+            .iter()
+            .map(|p| Bounds::new(p.centroid, p.centroid))
+            .sum();
+        let mut coded_infos: Vec<_> = primitive_infos
+            .drain(..)
+            .map(|info| (morton_code(&centroid_bounds.offset(&info.centroid)), info))
+            .collect();
+        coded_infos.sort_unstable_by_key(|(code, _)| *code);
+        let codes: Vec<_> = coded_infos.iter().map(|(code, _)| *code).collect();
+        primitive_infos.extend(coded_infos.into_iter().map(|(_, info)| info));
+
+        BvhNode::from_sorted_morton_codes(&codes, primitive_infos, first_primitive, threads, depth)
+    }
+
+    fn from_sorted_morton_codes(
+        codes: &[u32],
+        primitive_infos: &[PrimitiveInfo],
+        first_primitive: usize,
+        threads: BuildThreads,
+        depth: usize,
+    ) -> BvhNode {
+        const MAX_LEAF_PRIMITIVES: usize = 4;
+
+        let num_primitives = primitive_infos.len();
+        if num_primitives <= MAX_LEAF_PRIMITIVES {
+            let bounds = primitive_infos.iter().map(|p| p.bounds).sum();
+            return BvhNode::leaf_node(primitive_infos, bounds, first_primitive);
+        }
+
+        // Codes are sorted, so the first and last differ in the highest bit
+        // that differs between any of them. Primitives whose centroids share
+        // a code, or that would make the tree too deep, are split in half.
+        let differing_bits = codes[0] ^ codes[num_primitives - 1];
+        let split = match differing_bits {
+            _ if !can_split_unevenly(depth, num_primitives) => num_primitives / 2,
+            0 => num_primitives / 2,
+            _ => {
+                let bit = 1 << (31 - differing_bits.leading_zeros());
+                codes.partition_point(|code| code & bit == 0)
+            }
+        };
+
+        let (left, right) = build_subtrees(
+            num_primitives,
+            threads,
+            |threads| {
+                BvhNode::from_sorted_morton_codes(
+                    &codes[..split],
+                    &primitive_infos[..split],
+                    first_primitive,
+                    threads,
+                    depth + 1,
+                )
+            },
+            |threads| {
+                BvhNode::from_sorted_morton_codes(
+                    &codes[split..],
+                    &primitive_infos[split..],
+                    first_primitive + split,
+                    threads,
+                    depth + 1,
+                )
+            },
+        );
+        let bounds = *left.bounds() + *right.bounds();
+        BvhNode::InteriorNode {
+            split_axis: bounds.maximum_extent(),
+            bounds,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+}
+
+/// Whether a node at `depth` with `num_primitives` can be split unevenly and
+/// still leave room below it for subtrees built by splitting in half, which
+/// are as deep as the log of their number of primitives. Builders that make
+/// unbalanced trees split in half once this runs out, so that the tree is
+/// never deeper than `MAX_DEPTH`.
+fn can_split_unevenly(depth: usize, num_primitives: usize) -> bool {
+    let balanced_depth = (usize::BITS - num_primitives.leading_zeros()) as usize;
+    depth + 1 + balanced_depth < MAX_DEPTH
+}
+
+/// Builds the two subtrees of a node with `num_primitives`, on separate
+/// threads when there are enough primitives and threads to go around. Each
+/// subtree is given half of the `threads` to split further.
+fn build_subtrees<L, R>(
+    num_primitives: usize,
+    threads: BuildThreads,
+    left: L,
+    right: R,
+) -> (BvhNode, BvhNode)
+where
+    L: FnOnce(BuildThreads) -> BvhNode + Send,
+    R: FnOnce(BuildThreads) -> BvhNode,
+{
+    if threads.num_threads <= 1 || num_primitives < threads.min_primitives_per_thread {
+        return (left(BuildThreads::SINGLE), right(BuildThreads::SINGLE));
+    }
+
+    let (left_threads, right_threads) = threads.halve();
+    thread::scope(|scope| {
+        let left = scope.spawn(move || left(left_threads));
+        let right = right(right_threads);
+        (left.join().unwrap(), right)
+    })
+}
+
+/// Interleaves the bits of a point's co-ordinates, each between 0 and 1, as
+/// a 30 bit Morton code with 10 bits per axis. Sorting by the code orders
+/// points along a Z-order curve.
+fn morton_code(offset: &Vector) -> u32 {
+    const SCALE: f64 = (1 << 10) as f64;
+
+    // Spaces out the lower 10 bits of x so that there are two zeros between
+    // each of them
+    fn spread_bits(x: u32) -> u32 {
+        let mut x = x & 0x3ff;
+        x = (x | (x << 16)) & 0x30000ff;
+        x = (x | (x << 8)) & 0x300f00f;
+        x = (x | (x << 4)) & 0x30c30c3;
+        x = (x | (x << 2)) & 0x9249249;
+        x
+    }
+
+    // Offsets along axes where the bounds are flat are NaN, which become 0
+    let quantize = |x: f64| ((x * SCALE) as u32).min(SCALE as u32 - 1);
+    (spread_bits(quantize(offset.z())) << 2)
+        | (spread_bits(quantize(offset.y())) << 1)
+        | spread_bits(quantize(offset.x()))
 }
 
 impl Display for BvhNode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::LeafNode { bounds, primitives } => write!(
+            Self::LeafNode {
+                bounds,
+                first_primitive,
+                num_primitives,
+            } => write!(
                 f,
-                "primitives: ({:?}) bounds: ({:?})",
-                primitives
-                    .iter()
-                    .map(|p| format!("{:?} ", p.bounds()))
-                    .collect::<String>(),
+                "primitives: {}..{}, bounds: ({:?})",
+                first_primitive,
+                first_primitive + num_primitives,
                 bounds,
             ),
             Self::InteriorNode {
@@ -4,7 +4,7 @@ use crate::{
     geometry::{normal::Normal, traits::DotProduct, vector::Vector},
     pdf::Pdf,
     sampling::{samplers::Sample2d, sampling_fns::cosine_sample_hemisphere},
-    texture::Texture,
+    texture::{Texture, TextureCoordinates},
 };
 use approx::assert_abs_diff_eq;
 use std::f64::consts::FRAC_1_PI;
@@ -47,6 +47,22 @@ pub enum BxDF {
         eta_i: f64,
         eta_t: f64,
     },
+    /// A thin dielectric slab, such as a window pane, modeled as a single
+    /// surface. Light bounces between the slab's two interfaces, and
+    /// transmitted light leaves parallel to the incident direction.
+    ///
+    /// Source: https://pbr-book.org/4ed/Reflection_Models/Dielectric_BSDF#ThinDielectricBSDF
+    ThinDielectricBxDF {
+        reflectance: Texture<Color>,
+        transmittance: Texture<Color>,
+        eta: f64,
+    },
+    /// Lambertian reflection and transmission, for thin translucent surfaces
+    /// like paper or leaves. Each side is sampled half of the time.
+    DiffuseTransmissionBxDF {
+        reflectance: Texture<Color>,
+        transmittance: Texture<Color>,
+    },
 }
 
 impl BxDF {
@@ -62,6 +78,8 @@ impl BxDF {
             BxDF::SpecularBRDF { .. } => true,
             BxDF::SpecularBTDF { .. } => false,
             BxDF::FresnelSpecularBxDF { .. } => true,
+            BxDF::ThinDielectricBxDF { .. } => true,
+            BxDF::DiffuseTransmissionBxDF { .. } => true,
         }
     }
 
@@ -73,6 +91,8 @@ impl BxDF {
             BxDF::SpecularBRDF { .. } => false,
             BxDF::SpecularBTDF { .. } => true,
             BxDF::FresnelSpecularBxDF { .. } => true,
+            BxDF::ThinDielectricBxDF { .. } => true,
+            BxDF::DiffuseTransmissionBxDF { .. } => true,
         }
     }
 
@@ -85,7 +105,7 @@ impl BxDF {
         sample: Sample2d,
         w_o: &Vector,
         normal: &Normal,
-        uv: &(f64, f64),
+        uv: &TextureCoordinates,
     ) -> Option<SurfaceSample> {
         match self {
             BxDF::LambertianBRDF { .. } => {
@@ -160,14 +180,14 @@ impl BxDF {
                 eta_i,
                 eta_t,
             } => {
-                let cos_theta_i = w_o.dot(normal).abs();
+                let cos_theta_i = w_o.dot(normal);
 
                 if let Some(w_i) = refract(&w_o, &normal, cos_theta_i, *eta_i, *eta_t) {
                     assert_abs_diff_eq!(w_i.magnitude(), 1.0, epsilon = EPSILON);
                     let fresnel = fresnel_dielectric(*eta_i, *eta_t, cos_theta_i);
                     Some(SurfaceSample {
                         w_i,
-                        f: transmittance.eval(uv) * (1.0 - fresnel) / cos_theta_i,
+                        f: transmittance.eval(uv) * (1.0 - fresnel) / w_i.dot(normal).abs(),
                         pdf: self.pdf(w_o, &w_i, normal),
                         is_specular: true,
                     })
@@ -196,7 +216,7 @@ impl BxDF {
                         Some(SurfaceSample {
                             w_i,
                             f: transmittance.eval(uv) * (1.0 - fresnel_reflectance)
-                                / cos_theta_i.abs(),
+                                / w_i.dot(normal).abs(),
                             pdf: Pdf::NonDelta(1.0 - fresnel_reflectance),
                             is_specular: true,
                         })
@@ -205,13 +225,57 @@ impl BxDF {
                     }
                 }
             }
+            BxDF::ThinDielectricBxDF {
+                reflectance,
+                transmittance,
+                eta,
+            } => {
+                let cos_theta_i = w_o.dot(normal);
+                let (fresnel_reflectance, fresnel_transmittance) =
+                    thin_dielectric_fresnel(*eta, cos_theta_i);
+
+                if sample.take().0 < fresnel_reflectance {
+                    Some(SurfaceSample {
+                        w_i: reflect(w_o, normal),
+                        f: reflectance.eval(uv) * fresnel_reflectance / cos_theta_i.abs(),
+                        pdf: Pdf::NonDelta(fresnel_reflectance),
+                        is_specular: true,
+                    })
+                } else {
+                    Some(SurfaceSample {
+                        w_i: -*w_o,
+                        f: transmittance.eval(uv) * fresnel_transmittance / cos_theta_i.abs(),
+                        pdf: Pdf::NonDelta(fresnel_transmittance),
+                        is_specular: true,
+                    })
+                }
+            }
+            BxDF::DiffuseTransmissionBxDF { .. } => {
+                // Reuse the first dimension of the sample to pick a side
+                let (u, v) = sample.take();
+                let (u, transmit) = if u < 0.5 {
+                    (u * 2.0, false)
+                } else {
+                    ((u - 0.5) * 2.0, true)
+                };
+                let mut w_i = cosine_sample_hemisphere(Sample2d::new(u, v), normal);
+                if (normal.dot(w_o) < 0.0) != transmit {
+                    w_i = -w_i;
+                }
+                Some(SurfaceSample {
+                    w_i,
+                    f: self.f(w_o, &w_i, normal, uv),
+                    pdf: self.pdf(w_o, &w_i, normal),
+                    is_specular: false,
+                })
+            }
         }
     }
 
     /// Returns the value of the BRDF given outgoing and incoming directions for
     /// light `w_o` and `w_i`
     #[allow(non_snake_case)]
-    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &(f64, f64)) -> Color {
+    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &TextureCoordinates) -> Color {
         match self {
             BxDF::LambertianBRDF { reflectance, .. } => {
                 if normal.same_hemisphere(w_o, w_i) {
@@ -261,25 +325,41 @@ impl BxDF {
             BxDF::SpecularBRDF { .. } => Color::BLACK,
             BxDF::SpecularBTDF { .. } => Color::BLACK,
             BxDF::FresnelSpecularBxDF { .. } => Color::BLACK,
+            BxDF::ThinDielectricBxDF { .. } => Color::BLACK,
+            BxDF::DiffuseTransmissionBxDF {
+                reflectance,
+                transmittance,
+            } => {
+                if normal.same_hemisphere(w_o, w_i) {
+                    reflectance.eval(uv) * FRAC_1_PI
+                } else {
+                    transmittance.eval(uv) * FRAC_1_PI
+                }
+            }
         }
     }
 
     /// Return the value of the probability density function of sampling this
     /// BRDF in the incoming direction `w_i`
-    pub fn pdf(&self, _w_o: &Vector, w_i: &Vector, normal: &Normal) -> Pdf {
+    pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal) -> Pdf {
         match self {
-            BxDF::LambertianBRDF { .. } => {
-                let cos_theta = w_i.dot(normal).abs();
-                Pdf::NonDelta(FRAC_1_PI * cos_theta)
-            }
-            BxDF::OrenNayyarBRDF { .. } => {
-                let cos_theta = w_i.dot(normal).abs();
-                Pdf::NonDelta(FRAC_1_PI * cos_theta)
+            BxDF::LambertianBRDF { .. } | BxDF::OrenNayyarBRDF { .. } => {
+                if normal.same_hemisphere(w_o, w_i) {
+                    let cos_theta = w_i.dot(normal).abs();
+                    Pdf::NonDelta(FRAC_1_PI * cos_theta)
+                } else {
+                    Pdf::NonDelta(0.0)
+                }
             }
             BxDF::FresnelConductorBRDF { .. } => Pdf::Delta,
             BxDF::SpecularBRDF { .. } => Pdf::Delta,
             BxDF::SpecularBTDF { .. } => Pdf::Delta,
             BxDF::FresnelSpecularBxDF { .. } => Pdf::Delta,
+            BxDF::ThinDielectricBxDF { .. } => Pdf::Delta,
+            BxDF::DiffuseTransmissionBxDF { .. } => {
+                let cos_theta = w_i.dot(normal).abs();
+                Pdf::NonDelta(0.5 * FRAC_1_PI * cos_theta)
+            }
         }
     }
 }
@@ -313,6 +393,20 @@ pub fn refract(
     Some(r_perpendicular + r_parallel)
 }
 
+/// Total reflectance and transmittance of a thin slab with index of refraction
+/// `eta`, summing the light that bounces any number of times between its two
+/// interfaces
+pub fn thin_dielectric_fresnel(eta: f64, cos_theta_i: f64) -> (f64, f64) {
+    let reflectance = fresnel_dielectric(1.0, eta, cos_theta_i.abs());
+    if reflectance >= 1.0 {
+        return (1.0, 0.0);
+    }
+    let transmittance = 1.0 - reflectance;
+    let reflectance = reflectance
+        + transmittance * transmittance * reflectance / (1.0 - reflectance * reflectance);
+    (reflectance, 1.0 - reflectance)
+}
+
 #[derive(Debug)]
 
 pub struct Dielectric {
@@ -335,7 +429,7 @@ pub enum Fresnel {
     Conductor(Conductor),
 }
 
-fn fresnel_dielectric(eta_i: f64, eta_t: f64, cos_theta_i: f64) -> f64 {
+pub fn fresnel_dielectric(eta_i: f64, eta_t: f64, cos_theta_i: f64) -> f64 {
     let (cos_theta_i, eta_i, eta_t) = if cos_theta_i.is_sign_negative() {
         (-cos_theta_i, eta_t, eta_i)
     } else {
@@ -0,0 +1,423 @@
+use std::{
+    cell::RefCell,
+    convert::TryInto,
+    fs::{self, File},
+    hash::Hasher,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    bvh::{Bvh, BvhLayout, SplitMethod},
+    obj::{self, NamedMaterialModels, ObjFile},
+    primitive::Primitive,
+};
+
+/// Written at the start of every cache entry. Bumping the version invalidates
+/// entries written by older versions of the format.
+const MAGIC: &[u8; 4] = b"CRYC";
+const VERSION: u32 = 1;
+
+/// Binary cache of parsed OBJ files and built BVHs, stored as files in a
+/// directory so that renders of the same scene can skip parsing and building
+/// them. Failing to read or write an entry is never an error: the mesh is
+/// parsed or the BVH built as if there were no cache.
+#[derive(Debug)]
+pub struct Cache {
+    directory: PathBuf,
+    /// Content hashes of the meshes loaded through the cache, which the BVHs
+    /// built from them are keyed by
+    mesh_hashes: RefCell<Vec<u64>>,
+}
+
+impl Cache {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Cache {
+            directory: directory.as_ref().to_path_buf(),
+            mesh_hashes: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Forgets the meshes loaded for the previous scene, so that BVHs are
+    /// keyed only by the meshes of the scene being parsed
+    pub(crate) fn start_scene(&self) {
+        self.mesh_hashes.borrow_mut().clear();
+    }
+
+    /// Loads an OBJ file from the cache if it has an entry for the same path
+    /// whose size and modification time or content hash match, and parses it
+    /// and stores an entry otherwise
+    pub fn load_obj(&self, file_name: &str) -> ObjFile {
+        let fingerprint = match Fingerprint::of(file_name) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                warn!("Cannot cache \"{}\": {}", file_name, e);
+                return obj::parse_obj(file_name);
+            }
+        };
+        let path = self.entry_path("mesh", &[file_name.as_bytes()]);
+
+        let (cached, content_hash) = match read_entry(&path, |r| read_mesh_entry(r, file_name)) {
+            Ok((entry, material_libraries, models)) if entry.length == fingerprint.length => {
+                let load = || obj::load_materials(file_name, material_libraries, models);
+                if entry.modified == fingerprint.modified {
+                    debug!("Loaded \"{}\" from {:?}", file_name, path);
+                    self.mesh_hashes.borrow_mut().push(entry.content_hash);
+                    return load();
+                }
+                // The file was modified or copied without being changed, so
+                // the entry is still valid but is written again with the new
+                // time
+                match fingerprint.content_hash(file_name) {
+                    Ok(hash) if hash == entry.content_hash => (Some(load()), hash),
+                    Ok(hash) => (None, hash),
+                    Err(e) => {
+                        warn!("Cannot cache \"{}\": {}", file_name, e);
+                        return obj::parse_obj(file_name);
+                    }
+                }
+            }
+            result => {
+                if let Err(e) = result {
+                    debug!("No usable cache entry for \"{}\": {}", file_name, e);
+                }
+                match fingerprint.content_hash(file_name) {
+                    Ok(hash) => (None, hash),
+                    Err(e) => {
+                        warn!("Cannot cache \"{}\": {}", file_name, e);
+                        return obj::parse_obj(file_name);
+                    }
+                }
+            }
+        };
+
+        let obj = cached.unwrap_or_else(|| obj::parse_obj(file_name));
+        let entry = MeshEntry {
+            modified: fingerprint.modified,
+            length: fingerprint.length,
+            content_hash,
+        };
+        if let Err(e) = write_entry(&path, |w| write_mesh_entry(w, file_name, &entry, &obj)) {
+            warn!("Error caching \"{}\": {}", file_name, e);
+        }
+        self.mesh_hashes.borrow_mut().push(content_hash);
+        obj
+    }
+
+    /// Loads the BVH for a scene from the cache, or builds and stores it.
+    /// Entries are keyed by the scene's source, the contents of the meshes
+    /// loaded through the cache so far, and the build settings.
+    pub fn load_bvh(
+        &self,
+        scene_source: &str,
+        primitives: Vec<Arc<Primitive>>,
+        split_method: SplitMethod,
+        layout: BvhLayout,
+    ) -> Bvh {
+        let mut key = Vec::new();
+        for hash in self.mesh_hashes.borrow().iter() {
+            key.extend(hash.to_le_bytes());
+        }
+        key.extend(format!("{:?} {:?}", split_method, layout).bytes());
+        let path = self.entry_path("bvh", &[scene_source.as_bytes(), &key]);
+
+        match read_entry(&path, |r| Bvh::read(r, primitives.clone())) {
+            Ok(bvh) => {
+                debug!("Loaded BVH from {:?}", path);
+                return bvh;
+            }
+            Err(e) => debug!("No usable cached BVH: {}", e),
+        }
+
+        let bvh = Bvh::with_layout(primitives, split_method, layout);
+        if let Err(e) = write_entry(&path, |w| bvh.write(w)) {
+            warn!("Error caching BVH: {}", e);
+        }
+        bvh
+    }
+
+    fn entry_path(&self, kind: &str, key: &[&[u8]]) -> PathBuf {
+        let mut hasher = CacheHasher::new();
+        for bytes in key {
+            hasher.write_u64(bytes.len() as u64);
+            hasher.write(bytes);
+        }
+        self.directory
+            .join(format!("{}-{:016x}.bin", kind, hasher.finish()))
+    }
+}
+
+/// What a mesh entry was made from, to tell whether it is still valid
+struct MeshEntry {
+    /// Modification time in nanoseconds since the epoch
+    modified: u128,
+    length: u64,
+    content_hash: u64,
+}
+
+struct Fingerprint {
+    modified: u128,
+    length: u64,
+}
+
+impl Fingerprint {
+    fn of(file_name: &str) -> io::Result<Fingerprint> {
+        let metadata = fs::metadata(file_name)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?;
+        Ok(Fingerprint {
+            modified: modified.as_nanos(),
+            length: metadata.len(),
+        })
+    }
+
+    fn content_hash(&self, file_name: &str) -> io::Result<u64> {
+        let mut hasher = CacheHasher::new();
+        hasher.write(&fs::read(file_name)?);
+        Ok(hasher.finish())
+    }
+}
+
+/// Hashes 8 bytes at a time with the SplitMix64 finalizer. Unlike the
+/// standard library's hashers, its output is the same across Rust versions,
+/// so entries stay valid.
+struct CacheHasher(u64);
+
+impl CacheHasher {
+    fn new() -> Self {
+        CacheHasher(0xcbf29ce484222325)
+    }
+
+    fn mix(&mut self, value: u64) {
+        let mut z = self.0 ^ value;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        self.0 = z ^ (z >> 31);
+    }
+}
+
+impl Hasher for CacheHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let chunks = bytes.chunks_exact(8);
+        let remainder = chunks.remainder();
+        for chunk in chunks {
+            self.mix(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let mut last = [0; 8];
+        last[..remainder.len()].copy_from_slice(remainder);
+        self.mix(u64::from_le_bytes(last) ^ ((remainder.len() as u64) << 56));
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn read_entry<T, F>(path: &Path, read: F) -> io::Result<T>
+where
+    F: FnOnce(&mut BufReader<File>) -> io::Result<T>,
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+        return Err(invalid_data("not a cache entry of this version"));
+    }
+    read(&mut reader)
+}
+
+/// Writes the entry to a temporary file that is then moved into place, so
+/// that other renders never see partially written entries
+fn write_entry<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let temporary_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let mut writer = BufWriter::new(File::create(&temporary_path)?);
+    writer.write_all(MAGIC)?;
+    write_u32(&mut writer, VERSION)?;
+    write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&temporary_path, path)
+}
+
+/// Reads a mesh entry, whose models refer to their materials by name
+fn read_mesh_entry<R: Read>(
+    reader: &mut R,
+    file_name: &str,
+) -> io::Result<(MeshEntry, Vec<PathBuf>, NamedMaterialModels)> {
+    // The path is stored in case two of them hash to the same entry
+    if read_string(reader)? != file_name {
+        return Err(invalid_data("entry is for a different file"));
+    }
+    let entry = MeshEntry {
+        modified: (read_u64(reader)? as u128) << 64 | read_u64(reader)? as u128,
+        length: read_u64(reader)?,
+        content_hash: read_u64(reader)?,
+    };
+    let material_libraries = (0..read_u64(reader)?)
+        .map(|_| read_string(reader).map(PathBuf::from))
+        .collect::<io::Result<_>>()?;
+    let models = (0..read_u64(reader)?)
+        .map(|_| {
+            let name = read_string(reader)?;
+            let material_name = match read_u8(reader)? {
+                0 => None,
+                _ => Some(read_string(reader)?),
+            };
+            let mesh = tobj::Mesh {
+                positions: read_f64s(reader)?,
+                normals: read_f64s(reader)?,
+                texcoords: read_f64s(reader)?,
+                indices: read_u32s(reader)?,
+                ..Default::default()
+            };
+            Ok((tobj::Model::new(mesh, name), material_name))
+        })
+        .collect::<io::Result<_>>()?;
+    Ok((entry, material_libraries, models))
+}
+
+fn write_mesh_entry<W: Write>(
+    writer: &mut W,
+    file_name: &str,
+    entry: &MeshEntry,
+    obj: &ObjFile,
+) -> io::Result<()> {
+    write_string(writer, file_name)?;
+    write_u64(writer, (entry.modified >> 64) as u64)?;
+    write_u64(writer, entry.modified as u64)?;
+    write_u64(writer, entry.length)?;
+    write_u64(writer, entry.content_hash)?;
+    write_u64(writer, obj.material_libraries.len() as u64)?;
+    for library in &obj.material_libraries {
+        write_string(writer, &library.to_string_lossy())?;
+    }
+    // Materials are stored by name, since the material libraries are loaded
+    // again and may have changed
+    write_u64(writer, obj.models.len() as u64)?;
+    for model in &obj.models {
+        write_string(writer, &model.name)?;
+        let material = match (&obj.materials, model.mesh.material_id) {
+            (Ok(materials), Some(id)) => Some(&materials[id].name),
+            _ => None,
+        };
+        match material {
+            None => write_u8(writer, 0)?,
+            Some(name) => {
+                write_u8(writer, 1)?;
+                write_string(writer, name)?;
+            }
+        }
+        write_f64s(writer, &model.mesh.positions)?;
+        write_f64s(writer, &model.mesh.normals)?;
+        write_f64s(writer, &model.mesh.texcoords)?;
+        write_u32s(writer, &model.mesh.indices)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Values are stored little endian, with lengths before lists and strings
+
+pub(crate) fn write_u8<W: Write>(writer: &mut W, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn write_u32s<W: Write>(writer: &mut W, values: &[u32]) -> io::Result<()> {
+    write_u64(writer, values.len() as u64)?;
+    for &value in values {
+        write_u32(writer, value)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_u32s<R: Read>(reader: &mut R) -> io::Result<Vec<u32>> {
+    let bytes = read_bytes(reader, 4)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn write_f64s<W: Write>(writer: &mut W, values: &[f64]) -> io::Result<()> {
+    write_u64(writer, values.len() as u64)?;
+    for &value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_f64s<R: Read>(reader: &mut R) -> io::Result<Vec<f64>> {
+    let bytes = read_bytes(reader, 8)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader, 1)?).map_err(|_| invalid_data("invalid string"))
+}
+
+/// Reads a length followed by that many values of `size` bytes. The bytes are
+/// read in pieces so that a corrupt length fails at the end of the file
+/// instead of allocating it all up front.
+fn read_bytes<R: Read>(reader: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let length = read_u64(reader)?
+        .checked_mul(size as u64)
+        .ok_or_else(|| invalid_data("invalid length"))?;
+    let mut bytes = Vec::new();
+    let read = reader.take(length).read_to_end(&mut bytes)?;
+    if read as u64 != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "entry is truncated",
+        ));
+    }
+    Ok(bytes)
+}
@@ -1,25 +1,156 @@
+use std::f64::consts::PI;
+
+use log::warn;
+
 use crate::{
     film::Film,
-    geometry::{point::Point, vector::Vector, O, Z},
-    ray::Ray,
-    sampling::samplers::Sample2d,
+    geometry::{point::Point, vector::Vector, O, X, Y, Z},
+    ray::{Ray, RayDifferentials},
+    sampling::{
+        samplers::{Sample1d, Sample2d},
+        sampling_fns::{sample_disk, sample_triangle},
+    },
+    texture::{Texture, TextureCoordinates},
     transformation::{Transformable, Transformation},
 };
 
+/// Number of cells along each side of the grid that aperture textures are
+/// sampled from
+const APERTURE_TEXTURE_RESOLUTION: usize = 64;
+
 #[derive(Debug)]
 pub enum CameraType {
     Perspective,
     Orthographic,
 }
 
+/// Shape of the lens aperture, which out of focus highlights (bokeh) take
+#[derive(Debug)]
+pub enum Aperture {
+    Circle,
+    /// A regular polygon inscribed in the lens, with its first corner
+    /// `rotation` degrees from the camera's x axis
+    Polygon {
+        sides: usize,
+        rotation: f64,
+    },
+    /// Lets light through in proportion to a texture stretched over the
+    /// square around the lens
+    Texture(ApertureTexture),
+}
+
+/// Piecewise constant distribution over a grid of texture values, used to
+/// pick points on a textured aperture
+#[derive(Debug)]
+pub struct ApertureTexture {
+    /// CDF of picking each row
+    rows: Vec<f64>,
+    /// CDFs of picking each cell in a row
+    cells: Vec<Vec<f64>>,
+}
+
+/// Picks an entry of `cdf` using `u`, and returns it along with where `u`
+/// falls within it
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let index = cdf.partition_point(|&c| c <= u).min(cdf.len() - 1);
+    let start = if index > 0 { cdf[index - 1] } else { 0.0 };
+    let offset = (u - start) / (cdf[index] - start);
+    (index, offset.clamp(0.0, 1.0))
+}
+
+/// Turns `weights` into a CDF, or returns None if they're all zero
+fn to_cdf(weights: &[f64]) -> Option<Vec<f64>> {
+    let mut total = 0.0;
+    let cdf: Vec<f64> = weights
+        .iter()
+        .map(|weight| {
+            total += weight.max(0.0);
+            total
+        })
+        .collect();
+    (total > 0.0).then(|| cdf.iter().map(|c| c / total).collect())
+}
+
+impl Aperture {
+    /// Creates an aperture shaped like `texture`, where u goes along the
+    /// camera's x axis and v along its y axis. Returns None if the texture is
+    /// zero everywhere.
+    pub fn texture(texture: &Texture<f64>) -> Option<Aperture> {
+        let n = APERTURE_TEXTURE_RESOLUTION;
+        let cell_values: Vec<Vec<f64>> = (0..n)
+            .map(|row| {
+                (0..n)
+                    .map(|column| {
+                        texture.eval(&TextureCoordinates::new(
+                            (column as f64 + 0.5) / n as f64,
+                            (row as f64 + 0.5) / n as f64,
+                        ))
+                    })
+                    .collect()
+            })
+            .collect();
+        let row_totals: Vec<f64> = cell_values
+            .iter()
+            .map(|row| row.iter().map(|value| value.max(0.0)).sum())
+            .collect();
+        let rows = to_cdf(&row_totals)?;
+        // Rows that are never picked get a uniform distribution, to keep
+        // every CDF valid
+        let cells = cell_values
+            .iter()
+            .map(|row| to_cdf(row).unwrap_or_else(|| to_cdf(&vec![1.0; n]).unwrap()))
+            .collect();
+        Some(Aperture::Texture(ApertureTexture { rows, cells }))
+    }
+
+    /// Picks a point on the aperture, on a lens of radius 1
+    pub fn sample(&self, sample: Sample2d) -> (f64, f64) {
+        match self {
+            Aperture::Circle => sample_disk(sample),
+            Aperture::Polygon { sides, rotation } => {
+                // Pick one of the triangles between the center and an edge,
+                // which all have the same area
+                let (u, v) = sample.take();
+                let side = ((u * *sides as f64) as usize).min(sides - 1);
+                let u = u * *sides as f64 - side as f64;
+                let corner = |i: usize| {
+                    let angle = rotation.to_radians() + 2.0 * PI * i as f64 / *sides as f64;
+                    (angle.cos(), angle.sin())
+                };
+                let (a, b) = (corner(side), corner(side + 1));
+                let (b0, b1) = sample_triangle(Sample2d::new(u, v));
+                (b0 * a.0 + b1 * b.0, b0 * a.1 + b1 * b.1)
+            }
+            Aperture::Texture(ApertureTexture { rows, cells }) => {
+                let (u, v) = sample.take();
+                let (row, v) = sample_cdf(rows, v);
+                let (column, u) = sample_cdf(&cells[row], u);
+                let n = APERTURE_TEXTURE_RESOLUTION as f64;
+                (
+                    2.0 * (column as f64 + u) / n - 1.0,
+                    2.0 * (row as f64 + v) / n - 1.0,
+                )
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Camera {
     pub film: Film,
     camera_from_raster: Transformation,
     world_from_camera: Transformation,
     lens_radius: f64,
+    /// Distance along the view direction of the plane in focus
     focal_distance: f64,
+    aperture: Aperture,
+    /// Pixel to focus through once the scene is built, see `autofocus`
+    focus_pixel: Option<(usize, usize)>,
     camera_type: CameraType,
+    /// Times that the shutter opens and closes, which rays are spread over
+    /// to blur moving objects
+    shutter_open: f64,
+    shutter_close: f64,
 }
 
 fn get_camera_from_raster_transformation(
@@ -71,7 +202,11 @@ impl Camera {
             camera_from_raster,
             lens_radius,
             focal_distance,
+            aperture: Aperture::Circle,
+            focus_pixel: None,
             camera_type,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
 
@@ -128,9 +263,65 @@ impl Camera {
         )
     }
 
+    /// Changes the resolution of the film while keeping the same field of
+    /// view, by scaling raster coordinates to the original film
+    pub fn set_resolution(&mut self, width: usize, height: usize) {
+        let raster_scale = Transformation::scale(
+            self.film.width as f64 / width as f64,
+            self.film.height as f64 / height as f64,
+            1.0,
+        );
+        self.camera_from_raster = &self.camera_from_raster * &raster_scale;
+        self.film = Film { width, height };
+    }
+
+    /// Keeps the shutter open from `open` to `close`, so that objects that
+    /// move in that time are blurred. By default, it only opens at time 0.
+    pub fn set_shutter(&mut self, open: f64, close: f64) {
+        self.shutter_open = open;
+        self.shutter_close = close;
+    }
+
+    /// Changes the shape of the aperture, which is a circle by default
+    pub fn set_aperture(&mut self, aperture: Aperture) {
+        self.aperture = aperture;
+    }
+
+    /// Moves the plane in focus to go through `point`
+    pub fn focus_on(&mut self, point: &Point) {
+        let p_camera = self.world_from_camera.inverse().transform(point);
+        self.focal_distance = p_camera.z();
+    }
+
+    /// Focuses on whatever is first seen through the center of the pixel at
+    /// `(raster_x, raster_y)`, once `autofocus` is called with the scene
+    pub fn focus_through_pixel(&mut self, raster_x: usize, raster_y: usize) {
+        self.focus_pixel = Some((raster_x, raster_y));
+    }
+
+    /// Focuses through the pixel given to `focus_through_pixel`, if any, with
+    /// `intersect` finding the first hit along a ray
+    pub fn autofocus(&mut self, intersect: impl FnOnce(&mut Ray) -> Option<Point>) {
+        let (raster_x, raster_y) = match self.focus_pixel.take() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        let p_raster = Point(raster_x as f64, raster_y as f64, 0.0);
+        let ray = &mut self
+            .generate_ray((0.0, 0.0), &p_raster)
+            .with_time(self.shutter_open);
+        match intersect(ray) {
+            Some(point) => self.focus_on(&point),
+            None => warn!(
+                "Nothing to focus on through pixel ({}, {}), keeping focal distance {}",
+                raster_x, raster_y, self.focal_distance
+            ),
+        }
+    }
+
     pub fn sample(
         &self,
-        (film_sample, lens_sample): (Sample2d, Sample2d),
+        (film_sample, lens_sample, time_sample): (Sample2d, Sample2d, Sample1d),
         raster_x: usize,
         raster_y: usize,
     ) -> Ray {
@@ -138,26 +329,44 @@ impl Camera {
         // Convert to [-1, 1)^2
         let (dx, dy) = (2.0 * dx - 1.0, 2.0 * dy - 1.0);
         let p_raster = Point(raster_x as f64 + dx, raster_y as f64 + dy, 0.0);
-        let p_camera = self.camera_from_raster.transform(&p_raster);
+        let lens_sample = self.aperture.sample(lens_sample);
 
-        let ray = self.generate_ray(lens_sample, p_camera);
-        self.world_from_camera.transform(&ray)
+        let ray = self.generate_ray(lens_sample, &p_raster);
+        // Generate rays through the neighbouring pixels, using the same point
+        // on the lens, to track the footprint of the ray
+        let rx = self.generate_ray(lens_sample, &(p_raster + X));
+        let ry = self.generate_ray(lens_sample, &(p_raster + Y));
+        Ray::with_differentials(
+            ray.origin,
+            ray.direction,
+            RayDifferentials {
+                rx_origin: rx.origin,
+                rx_direction: rx.direction,
+                ry_origin: ry.origin,
+                ry_direction: ry.direction,
+            },
+        )
+        .with_time(
+            self.shutter_open + (self.shutter_close - self.shutter_open) * time_sample.take(),
+        )
     }
 
-    fn generate_ray(&self, lens_sample: Sample2d, p_camera: Point) -> Ray {
+    /// Generates a ray through `p_raster` from a point on the aperture of a
+    /// lens with radius 1
+    fn generate_ray(&self, (lens_x, lens_y): (f64, f64), p_raster: &Point) -> Ray {
+        let p_camera = self.camera_from_raster.transform(p_raster);
         let ray = match self.camera_type {
             CameraType::Perspective => Ray::new(p_camera, (p_camera - O).normalized()),
             CameraType::Orthographic => Ray::new(p_camera, Z),
         };
 
-        if self.lens_radius == 0.0 {
+        let ray = if self.lens_radius == 0.0 {
             ray
         } else {
-            let (lens_x, lens_y) = lens_sample.take();
-            let (lens_x, lens_y) = (2.0 * lens_x - 1.0, 2.0 * lens_y - 1.0);
             let p_lens = Point(lens_x * self.lens_radius, lens_y * self.lens_radius, 0.0);
-            let p_focal_plane = ray.at(self.focal_distance / ray.direction.z());
+            let p_focal_plane = ray.at((self.focal_distance - ray.origin.z()) / ray.direction.z());
             Ray::new(p_lens, (p_focal_plane - p_lens).normalized())
-        }
+        };
+        self.world_from_camera.transform(&ray)
     }
 }
@@ -1,8 +1,11 @@
 use crate::{
     color::Color,
-    geometry::{normal::Normal, point::Point, vector::Vector},
-    material::Material,
+    geometry::{normal::Normal, point::Point, traits::DotProduct, vector::Vector, O},
+    material::{Material, Perturbation},
     primitive::Primitive,
+    ray::{Ray, RayDifferentials},
+    texture::TextureCoordinates,
+    transformation::{Transformable, Transformation},
 };
 
 #[derive(Debug)]
@@ -10,19 +13,278 @@ pub struct ShapeIntersection {
     pub location: Point,
     // TODO: Get the normal lazily when needed
     pub normal: Normal,
+    // Normal and tangent used for shading, which may differ from the geometric
+    // ones for e.g. triangles with interpolated vertex normals
+    pub shading_normal: Normal,
+    pub tangent: Vector,
     pub uv: (f64, f64),
+    // Partial derivatives of the location and normal w.r.t. uv
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    pub dndu: Normal,
+    pub dndv: Normal,
+}
+
+/// The local frame used for shading, which can differ from the geometry of the
+/// surface due to interpolated vertex normals or normal and bump mapping
+///
+/// Source: https://pbr-book.org/3ed-2018/Geometry_and_Transformations/Interactions#ShadingGeometry
+#[derive(Debug, Clone, Copy)]
+pub struct ShadingGeometry {
+    pub normal: Normal,
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    pub dndu: Normal,
+    pub dndv: Normal,
+}
+
+impl ShadingGeometry {
+    /// Builds a frame around `normal` with `dpdu` along the tangent and `dpdv`
+    /// perpendicular to it, keeping the orientation and lengths of the
+    /// surface's partial derivatives
+    fn new(
+        normal: Normal,
+        tangent: &Vector,
+        dpdu: &Vector,
+        dpdv: &Vector,
+        dndu: Normal,
+        dndv: Normal,
+    ) -> Self {
+        let n: Vector = normal.into();
+        let mut t = *tangent - n * n.dot(tangent);
+        if t.magnitude_squared() < 1e-12 {
+            t = normal.generate_tangents().0;
+        }
+        let t = t.normalized();
+        let mut b = n.cross(&t);
+        if b.dot(dpdv) < 0.0 {
+            b = -b;
+        }
+
+        let length = |v: &Vector| {
+            let length = v.magnitude();
+            if length > 0.0 {
+                length
+            } else {
+                1.0
+            }
+        };
+        ShadingGeometry {
+            normal,
+            dpdu: t * length(dpdu),
+            dpdv: b * length(dpdv),
+            dndu,
+            dndv,
+        }
+    }
+
+    /// Returns the shading geometry with the normal perturbed by a normal or
+    /// bump map
+    ///
+    /// Source: https://pbr-book.org/3ed-2018/Materials/Bump_Mapping
+    fn perturb(
+        &self,
+        perturbation: &Perturbation,
+        uv: &TextureCoordinates,
+        world_to_object: Option<&Transformation>,
+    ) -> Self {
+        let n: Vector = self.normal.into();
+        let perturbed = match perturbation {
+            Perturbation::NormalMap(texture) => {
+                let Color { r, g, b } = texture.eval(uv);
+                // The bitangent of a normal map points towards decreasing v
+                self.dpdu.normalized() * (2.0 * r - 1.0) - self.dpdv.normalized() * (2.0 * g - 1.0)
+                    + n * (2.0 * b - 1.0)
+            }
+            Perturbation::BumpMap(texture) => {
+                // Find the change in height by shifting the texture
+                // coordinates by about half the footprint of a pixel
+                let shift = |dx: f64, dy: f64| {
+                    let d = 0.5 * (dx.abs() + dy.abs());
+                    if d == 0.0 {
+                        0.0005
+                    } else {
+                        d
+                    }
+                };
+                let du = shift(uv.du_dx, uv.du_dy);
+                let dv = shift(uv.dv_dx, uv.dv_dy);
+                let to_object = |v: Vector| match world_to_object {
+                    Some(world_to_object) => world_to_object.transform(&v),
+                    None => v,
+                };
+                let uv_du = TextureCoordinates {
+                    u: uv.u + du,
+                    p: uv.p + to_object(self.dpdu * du),
+                    ..*uv
+                };
+                let uv_dv = TextureCoordinates {
+                    v: uv.v + dv,
+                    p: uv.p + to_object(self.dpdv * dv),
+                    ..*uv
+                };
+                let displace = texture.eval(uv);
+                let dpdu = self.dpdu
+                    + n * ((texture.eval(&uv_du) - displace) / du)
+                    + Vector::from(self.dndu) * displace;
+                let dpdv = self.dpdv
+                    + n * ((texture.eval(&uv_dv) - displace) / dv)
+                    + Vector::from(self.dndv) * displace;
+
+                // Keep the normal on the same side as the unperturbed one,
+                // regardless of the handedness of the uv parameterization
+                let handedness = self.dpdu.cross(&self.dpdv).dot(&n).signum();
+                dpdu.cross(&dpdv) * handedness
+            }
+        };
+
+        if perturbed.magnitude_squared() == 0.0 || !perturbed.magnitude_squared().is_finite() {
+            return *self;
+        }
+        ShadingGeometry::new(
+            perturbed.normalized().into(),
+            &self.dpdu,
+            &self.dpdu,
+            &self.dpdv,
+            self.dndu,
+            self.dndv,
+        )
+    }
 }
 
 pub struct PrimitiveIntersection<'a> {
     pub distance: f64,
     pub location: Point,
+    // The geometric normal of the surface
     pub normal: Normal,
     pub material: &'a Material,
     pub primitive: &'a Primitive,
-    pub uv: (f64, f64),
+    pub uv: TextureCoordinates,
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    pub dndu: Normal,
+    pub dndv: Normal,
+    // Estimated change in location per pixel along the film's x and y axes
+    pub dpdx: Vector,
+    pub dpdy: Vector,
+    pub shading: ShadingGeometry,
+    // Time of the ray that found the intersection, which rays leaving it
+    // keep so that the whole path sees the scene at the same moment
+    pub time: f64,
 }
 
 impl<'a> PrimitiveIntersection<'a> {
+    pub fn new(
+        shape_intersection: ShapeIntersection,
+        ray: &Ray,
+        material: &'a Material,
+        primitive: &'a Primitive,
+    ) -> Self {
+        let ShapeIntersection {
+            location,
+            normal,
+            shading_normal,
+            tangent,
+            uv: (u, v),
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        } = shape_intersection;
+
+        let mut uv = TextureCoordinates::new(u, v);
+        let mut dpdx = Vector(0.0, 0.0, 0.0);
+        let mut dpdy = Vector(0.0, 0.0, 0.0);
+        if let Some(differentials) = &ray.differentials {
+            // Source: https://pbr-book.org/3ed-2018/Texture/Sampling_and_Antialiasing#FindingtheTextureSamplingRate
+            // Intersect the offset rays with the plane tangent to the surface
+            // at the intersection to estimate the change in location
+            let d = normal.dot(&(location - O));
+            let tx = -(normal.dot(&(differentials.rx_origin - O)) - d)
+                / normal.dot(&differentials.rx_direction);
+            let ty = -(normal.dot(&(differentials.ry_origin - O)) - d)
+                / normal.dot(&differentials.ry_direction);
+            if tx.is_finite() && ty.is_finite() {
+                let px = differentials.rx_origin + differentials.rx_direction * tx;
+                let py = differentials.ry_origin + differentials.ry_direction * ty;
+                dpdx = px - location;
+                dpdy = py - location;
+
+                // Find the change in uv by solving dp = dpdu * du + dpdv * dv.
+                // This is overdetermined, so we pick the two dimensions where
+                // the normal is the smallest to avoid a degenerate system.
+                let (dim0, dim1) =
+                    if normal.x().abs() > normal.y().abs() && normal.x().abs() > normal.z().abs() {
+                        (1, 2)
+                    } else if normal.y().abs() > normal.z().abs() {
+                        (0, 2)
+                    } else {
+                        (0, 1)
+                    };
+                let component = |v: &Vector, dim: usize| [v.x(), v.y(), v.z()][dim];
+                let a = [
+                    [component(&dpdu, dim0), component(&dpdv, dim0)],
+                    [component(&dpdu, dim1), component(&dpdv, dim1)],
+                ];
+                if let Some((du_dx, dv_dx)) =
+                    solve_2x2(a, [component(&dpdx, dim0), component(&dpdx, dim1)])
+                {
+                    uv.du_dx = du_dx;
+                    uv.dv_dx = dv_dx;
+                }
+                if let Some((du_dy, dv_dy)) =
+                    solve_2x2(a, [component(&dpdy, dim0), component(&dpdy, dim1)])
+                {
+                    uv.du_dy = du_dy;
+                    uv.dv_dy = dv_dy;
+                }
+            }
+        }
+
+        // Solid textures are evaluated in object space so that they move along
+        // with the shape
+        uv.p = location;
+        uv.dpdx = dpdx;
+        uv.dpdy = dpdy;
+        let world_to_object = primitive.world_to_object();
+        if let Some(world_to_object) = world_to_object {
+            uv.p = world_to_object.transform(&uv.p);
+            uv.dpdx = world_to_object.transform(&uv.dpdx);
+            uv.dpdy = world_to_object.transform(&uv.dpdy);
+        }
+
+        let mut shading = ShadingGeometry::new(shading_normal, &tangent, &dpdu, &dpdv, dndu, dndv);
+        if let Some(perturbation) = material.perturbation() {
+            shading = shading.perturb(perturbation, &uv, world_to_object);
+        }
+
+        PrimitiveIntersection {
+            distance: ray.max_distance,
+            location,
+            normal,
+            material,
+            primitive,
+            uv,
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+            dpdx,
+            dpdy,
+            shading,
+            time: ray.time,
+        }
+    }
+
+    /// Whether the geometric and shading normals agree on whether `w_o` and
+    /// `w_i` are on the same side of the surface. When they don't, light
+    /// would leak through the surface, so such directions should be skipped.
+    pub fn normals_agree(&self, w_o: &Vector, w_i: &Vector) -> bool {
+        let geometric = w_o.dot(&self.normal) * w_i.dot(&self.normal) > 0.0;
+        let shading = w_o.dot(&self.shading.normal) * w_i.dot(&self.shading.normal) > 0.0;
+        geometric == shading
+    }
+
     /// Light emitted at the current intersection point in the given direction
     #[allow(non_snake_case)]
     pub fn Le(&self, w_o: &Vector) -> Color {
@@ -31,4 +293,93 @@ impl<'a> PrimitiveIntersection<'a> {
             None => Color::BLACK,
         }
     }
+
+    /// Spawns a ray leaving the intersection in the direction `w_i`
+    pub fn spawn_ray(&self, w_i: Vector) -> Ray {
+        Ray::new(self.location, w_i).with_time(self.time)
+    }
+
+    /// Spawns a ray leaving the intersection in the direction `w_i`, which was
+    /// sampled from a specular bsdf. If the incoming `ray` had differentials,
+    /// they are reflected or refracted along with it so that textures seen in
+    /// mirrors and through glass can still be filtered.
+    pub fn spawn_specular_ray(&self, ray: &Ray, w_o: &Vector, w_i: &Vector) -> Ray {
+        let differentials = match &ray.differentials {
+            Some(differentials) => differentials,
+            None => return self.spawn_ray(*w_i),
+        };
+
+        // Source: https://pbr-book.org/3ed-2018/Materials/Specular_Reflection_and_Transmission
+        let ShadingGeometry {
+            normal, dndu, dndv, ..
+        } = self.shading;
+        let mut normal: Vector = normal.into();
+        let mut dndx: Vector = (dndu * self.uv.du_dx + dndv * self.uv.dv_dx).into();
+        let mut dndy: Vector = (dndu * self.uv.du_dy + dndv * self.uv.dv_dy).into();
+        let dwodx = -differentials.rx_direction - *w_o;
+        let dwody = -differentials.ry_direction - *w_o;
+
+        let is_reflection = normal.dot(w_o) * normal.dot(w_i) > 0.0;
+        let (rx_direction, ry_direction) = if is_reflection {
+            let ddndx = dwodx.dot(&normal) + w_o.dot(&dndx);
+            let ddndy = dwody.dot(&normal) + w_o.dot(&dndy);
+            (
+                *w_i - dwodx + (dndx * w_o.dot(&normal) + normal * ddndx) * 2.0,
+                *w_i - dwody + (dndy * w_o.dot(&normal) + normal * ddndy) * 2.0,
+            )
+        } else {
+            if w_o.dot(&normal) < 0.0 {
+                normal = -normal;
+                dndx = -dndx;
+                dndy = -dndy;
+            }
+            // The relative index of refraction isn't known here, but it can be
+            // recovered from the two directions using Snell's law
+            let sin_theta_o = (1.0 - w_o.dot(&normal).powf(2.0)).max(0.0).sqrt();
+            let sin_theta_i = (1.0 - w_i.dot(&normal).powf(2.0)).max(0.0).sqrt();
+            let eta = if sin_theta_o > 1e-6 {
+                sin_theta_i / sin_theta_o
+            } else {
+                1.0
+            };
+
+            let cos_theta_o = w_o.dot(&normal);
+            let cos_theta_i = w_i.dot(&normal).abs();
+            let mu = eta * cos_theta_o - cos_theta_i;
+            let dmu_ddn = eta - (eta * eta * cos_theta_o) / cos_theta_i;
+            let ddndx = dwodx.dot(&normal) + w_o.dot(&dndx);
+            let ddndy = dwody.dot(&normal) + w_o.dot(&dndy);
+            (
+                *w_i - dwodx * eta + (dndx * mu + normal * (dmu_ddn * ddndx)),
+                *w_i - dwody * eta + (dndy * mu + normal * (dmu_ddn * ddndy)),
+            )
+        };
+
+        Ray::with_differentials(
+            self.location,
+            *w_i,
+            RayDifferentials {
+                rx_origin: self.location + self.dpdx,
+                rx_direction,
+                ry_origin: self.location + self.dpdy,
+                ry_direction,
+            },
+        )
+        .with_time(self.time)
+    }
+}
+
+/// Solves the 2x2 linear system `a * x = b`
+fn solve_2x2(a: [[f64; 2]; 2], b: [f64; 2]) -> Option<(f64, f64)> {
+    let determinant = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+    if determinant.abs() < 1e-10 {
+        return None;
+    }
+    let x0 = (a[1][1] * b[0] - a[0][1] * b[1]) / determinant;
+    let x1 = (a[0][0] * b[1] - a[1][0] * b[0]) / determinant;
+    if x0.is_finite() && x1.is_finite() {
+        Some((x0, x1))
+    } else {
+        None
+    }
 }
@@ -0,0 +1,454 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    f64::consts::PI,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::{
+    bxdf::{fresnel_dielectric, reflect, refract, SurfaceSample},
+    color::Color,
+    geometry::{normal::Normal, traits::DotProduct, vector::Vector},
+    material::Material,
+    pdf::Pdf,
+    sampling::{
+        samplers::{Sample1d, Sample2d},
+        sampling_fns::sample_sphere,
+    },
+    texture::{Texture, TextureCoordinates},
+};
+
+/// A smooth dielectric coat over a base material, with a homogeneous
+/// scattering medium between them. Light can bounce between the coat and the
+/// base any number of times, so the BSDF has no closed form and is instead
+/// estimated with random walks through the layer. The base is treated as
+/// opaque, so light is never transmitted through the coated material.
+///
+/// Source: https://pbr-book.org/4ed/Light_Transport_II_Volume_Rendering/Scattering_from_Layered_Materials
+#[derive(Debug)]
+pub struct Coated {
+    pub base: Arc<Material>,
+    /// Index of refraction of the coat
+    pub eta: f64,
+    /// Thickness of the medium, in units of its mean free path
+    pub thickness: Texture<f64>,
+    /// Single scattering albedo of the medium. When black, the medium only
+    /// absorbs light.
+    pub albedo: Texture<Color>,
+    pub max_depth: usize,
+    /// Number of random walks used to estimate `f` and `pdf`
+    pub num_samples: usize,
+}
+
+/// Which directions to sample at the coat's interface
+#[derive(Clone, Copy, PartialEq)]
+enum Lobes {
+    Reflection,
+    Transmission,
+    All,
+}
+
+/// A direction sampled at the coat's interface
+struct InterfaceSample {
+    w_i: Vector,
+    f: f64,
+    pdf: f64,
+    is_transmission: bool,
+}
+
+/// Isotropic phase function, for scattering within the medium
+const PHASE: f64 = 1.0 / (4.0 * PI);
+
+impl Coated {
+    /// Samples the coat's interface, seen from the direction `w_o`, picking
+    /// between the allowed lobes in proportion to the Fresnel reflectance.
+    /// Transmitted radiance is scaled by the change in index of refraction,
+    /// which should be skipped for directions towards light
+    /// (`is_radiance == false`).
+    fn sample_interface(
+        &self,
+        w_o: &Vector,
+        normal: &Normal,
+        u: f64,
+        lobes: Lobes,
+        is_radiance: bool,
+    ) -> Option<InterfaceSample> {
+        let cos_theta_o = w_o.dot(normal);
+        let reflectance = fresnel_dielectric(1.0, self.eta, cos_theta_o);
+        let transmittance = 1.0 - reflectance;
+        let reflect_pdf = if lobes == Lobes::Transmission {
+            0.0
+        } else {
+            reflectance
+        };
+        let transmit_pdf = if lobes == Lobes::Reflection {
+            0.0
+        } else {
+            transmittance
+        };
+        if reflect_pdf + transmit_pdf <= 0.0 {
+            return None;
+        }
+
+        if u < reflect_pdf / (reflect_pdf + transmit_pdf) {
+            let w_i = reflect(w_o, normal);
+            Some(InterfaceSample {
+                f: reflectance / w_i.dot(normal).abs(),
+                w_i,
+                pdf: reflect_pdf / (reflect_pdf + transmit_pdf),
+                is_transmission: false,
+            })
+        } else {
+            let w_i = refract(w_o, normal, cos_theta_o, 1.0, self.eta)?;
+            let eta_relative = if cos_theta_o > 0.0 {
+                self.eta
+            } else {
+                1.0 / self.eta
+            };
+            let mut f = transmittance / w_i.dot(normal).abs();
+            if is_radiance {
+                f /= eta_relative * eta_relative;
+            }
+            Some(InterfaceSample {
+                w_i,
+                f,
+                pdf: transmit_pdf / (reflect_pdf + transmit_pdf),
+                is_transmission: true,
+            })
+        }
+    }
+
+    /// Evaluates the BSDF, including light that scatters in the medium or
+    /// reflects off the base. Specular reflection off the coat isn't included,
+    /// since it can only be sampled.
+    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &TextureCoordinates) -> Color {
+        // The coat is on both sides of the surface
+        let normal = if w_o.dot(normal) < 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+        if w_i.dot(&normal) <= 0.0 {
+            return Color::BLACK;
+        }
+
+        let thickness = self.thickness.eval(uv).max(f64::MIN_POSITIVE);
+        let albedo = self.albedo.eval(uv);
+        let mut rng = seeded_rng(&[w_o, w_i]);
+
+        let mut f = Color::BLACK;
+        for _ in 0..self.num_samples {
+            // Sample the paths into the layer from both directions
+            let w_os =
+                match self.sample_interface(w_o, &normal, rng.gen(), Lobes::Transmission, true) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+            let w_is =
+                match self.sample_interface(w_i, &normal, rng.gen(), Lobes::Transmission, false) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+            // Light leaving the layer towards `w_i`, per unit of radiance
+            // arriving inside the layer along `-w_is.w_i`
+            let exit = w_is.f / w_is.pdf;
+
+            let mut beta = Color::WHITE * w_os.f * w_os.w_i.dot(&normal).abs() / w_os.pdf;
+            let mut z = thickness;
+            let mut w = w_os.w_i;
+            for depth in 0..self.max_depth {
+                if !russian_roulette(&mut beta, depth, &mut rng) {
+                    break;
+                }
+
+                if albedo.is_black() {
+                    z = if z == thickness { 0.0 } else { thickness };
+                    beta *= transmittance(thickness, &w, &normal);
+                } else {
+                    let z_next = z + sample_distance(&w, &normal, rng.gen());
+                    if z_next == z {
+                        continue;
+                    }
+                    if 0.0 < z_next && z_next < thickness {
+                        // Scatter in the medium, connecting to `w_i` before
+                        // continuing in a new direction
+                        f += beta
+                            * albedo
+                            * PHASE
+                            * transmittance(z_next - thickness, &w_is.w_i, &normal)
+                            * exit;
+                        w = sample_sphere(Sample2d::new(rng.gen(), rng.gen()));
+                        beta = beta * albedo;
+                        z = z_next;
+                        continue;
+                    }
+                    z = z_next.clamp(0.0, thickness);
+                }
+
+                if z == thickness {
+                    // Light can only leave through the coat via the connection
+                    // to `w_i`, so only sample reflection back into the layer
+                    let sample = match self.sample_interface(
+                        &-w,
+                        &normal,
+                        rng.gen(),
+                        Lobes::Reflection,
+                        true,
+                    ) {
+                        Some(sample) => sample,
+                        None => break,
+                    };
+                    beta = beta * sample.f * sample.w_i.dot(&normal).abs() / sample.pdf;
+                    w = sample.w_i;
+                } else {
+                    // Connect the base to `w_i`, then sample the base
+                    f += beta
+                        * self.base.f(&-w, &-w_is.w_i, &normal, uv)
+                        * w_is.w_i.dot(&normal).abs()
+                        * transmittance(thickness, &w_is.w_i, &normal)
+                        * exit;
+
+                    let sample = match self.sample_base(&-w, &normal, uv, &mut rng) {
+                        Some(sample) => sample,
+                        None => break,
+                    };
+                    beta = beta * sample.0;
+                    w = sample.1;
+                }
+            }
+        }
+        f / self.num_samples as f64
+    }
+
+    /// Samples the base material from inside the layer, returning the weight
+    /// `f * cos_theta / pdf` and the sampled direction, as long as it reflects
+    /// back into the layer
+    fn sample_base(
+        &self,
+        w_o: &Vector,
+        normal: &Normal,
+        uv: &TextureCoordinates,
+        rng: &mut impl Rng,
+    ) -> Option<(Color, Vector)> {
+        let SurfaceSample { w_i, f, pdf, .. } = self.base.sample(
+            (
+                Sample1d::new(rng.gen()),
+                Sample2d::new(rng.gen(), rng.gen()),
+            ),
+            w_o,
+            normal,
+            uv,
+        )?;
+        let pdf = match pdf {
+            Pdf::NonDelta(pdf) => pdf,
+            Pdf::Delta => 1.0,
+        };
+        if f.is_black() || pdf == 0.0 || w_i.dot(normal) <= 0.0 {
+            return None;
+        }
+        Some((f * w_i.dot(normal) / pdf, w_i))
+    }
+
+    /// Samples a random walk through the layer, starting with the direction
+    /// `w_o` and ending when the walk leaves through the coat
+    pub fn sample(
+        &self,
+        (sample_1d, sample_2d): (Sample1d, Sample2d),
+        w_o: &Vector,
+        normal: &Normal,
+        uv: &TextureCoordinates,
+    ) -> Option<SurfaceSample> {
+        let normal = if w_o.dot(normal) < 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+        let u = sample_1d.take();
+        let (u0, u1) = sample_2d.take();
+        let mut rng = seeded_rng(&[w_o, &Vector(u, u0, u1)]);
+
+        let sample = self.sample_interface(w_o, &normal, u, Lobes::All, true)?;
+        if !sample.is_transmission {
+            // Specular reflection off the coat
+            return Some(SurfaceSample {
+                w_i: sample.w_i,
+                f: Color::WHITE * sample.f,
+                pdf: Pdf::NonDelta(sample.pdf),
+                is_specular: true,
+            });
+        }
+
+        let thickness = self.thickness.eval(uv).max(f64::MIN_POSITIVE);
+        let albedo = self.albedo.eval(uv);
+        let mut w = sample.w_i;
+        let mut f = Color::WHITE * sample.f * w.dot(&normal).abs();
+        let mut pdf = sample.pdf;
+        let mut z = thickness;
+        let mut is_specular = true;
+        for depth in 0..self.max_depth {
+            // Russian roulette is based on the ratio of f and pdf, which is the
+            // path's throughput
+            let throughput = f.r.max(f.g.max(f.b)) / pdf;
+            if depth > 3 && throughput < 0.25 {
+                let q = (1.0 - throughput).max(0.0);
+                if rng.gen::<f64>() < q {
+                    return None;
+                }
+                pdf *= 1.0 - q;
+            }
+            if w.dot(&normal) == 0.0 {
+                return None;
+            }
+
+            if albedo.is_black() {
+                z = if z == thickness { 0.0 } else { thickness };
+                f *= transmittance(thickness, &w, &normal);
+            } else {
+                let z_next = z + sample_distance(&w, &normal, rng.gen());
+                if z_next == z {
+                    return None;
+                }
+                if 0.0 < z_next && z_next < thickness {
+                    w = sample_sphere(Sample2d::new(rng.gen(), rng.gen()));
+                    f = f * albedo * PHASE;
+                    pdf *= PHASE;
+                    is_specular = false;
+                    z = z_next;
+                    continue;
+                }
+                z = z_next.clamp(0.0, thickness);
+            }
+
+            if z == 0.0 {
+                let SurfaceSample {
+                    w_i,
+                    f: base_f,
+                    pdf: base_pdf,
+                    is_specular: base_is_specular,
+                } = self.base.sample(
+                    (
+                        Sample1d::new(rng.gen()),
+                        Sample2d::new(rng.gen(), rng.gen()),
+                    ),
+                    &-w,
+                    &normal,
+                    uv,
+                )?;
+                if w_i.dot(&normal) <= 0.0 {
+                    return None;
+                }
+                f = f * base_f * w_i.dot(&normal);
+                pdf *= match base_pdf {
+                    Pdf::NonDelta(pdf) => pdf,
+                    Pdf::Delta => 1.0,
+                };
+                is_specular &= base_is_specular;
+                w = w_i;
+            } else {
+                let sample = self.sample_interface(&-w, &normal, rng.gen(), Lobes::All, true)?;
+                f *= sample.f;
+                pdf *= sample.pdf;
+                w = sample.w_i;
+                if sample.is_transmission {
+                    if f.is_black() || pdf == 0.0 {
+                        return None;
+                    }
+                    if is_specular {
+                        return Some(SurfaceSample {
+                            w_i: w,
+                            f,
+                            pdf: Pdf::NonDelta(pdf),
+                            is_specular: true,
+                        });
+                    }
+                    // The random walk only gives the ratio of f and pdf, so
+                    // use the estimated pdf for MIS
+                    let estimated_pdf = match self.pdf(w_o, &w, &normal, uv) {
+                        Pdf::NonDelta(pdf) => pdf,
+                        Pdf::Delta => unreachable!("Coated materials have a non-delta pdf"),
+                    };
+                    return Some(SurfaceSample {
+                        w_i: w,
+                        f: f / pdf * estimated_pdf,
+                        pdf: Pdf::NonDelta(estimated_pdf),
+                        is_specular: false,
+                    });
+                }
+                f *= w.dot(&normal).abs();
+            }
+        }
+        None
+    }
+
+    /// Estimates the pdf of sampling `w_i`, by mixing the pdf of paths that
+    /// refract through the coat, reflect off the base and refract out again
+    /// with a uniform pdf that accounts for the rest
+    pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &TextureCoordinates) -> Pdf {
+        let normal = if w_o.dot(normal) < 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+        if w_i.dot(&normal) <= 0.0 {
+            return Pdf::NonDelta(0.0);
+        }
+
+        let mut rng = seeded_rng(&[w_i, w_o]);
+        let mut pdf_sum = 0.0;
+        for _ in 0..self.num_samples {
+            let w_os = self.sample_interface(w_o, &normal, rng.gen(), Lobes::Transmission, true);
+            let w_is = self.sample_interface(w_i, &normal, rng.gen(), Lobes::Transmission, false);
+            if let (Some(w_os), Some(w_is)) = (w_os, w_is) {
+                if let Pdf::NonDelta(pdf) = self.base.pdf(&-w_os.w_i, &-w_is.w_i, &normal, uv) {
+                    pdf_sum += pdf;
+                }
+            }
+        }
+        Pdf::NonDelta(0.1 * PHASE + 0.9 * pdf_sum / self.num_samples as f64)
+    }
+}
+
+/// Fraction of light that passes through the medium over a change in height
+/// `dz` in direction `w`
+fn transmittance(dz: f64, w: &Vector, normal: &Normal) -> f64 {
+    if dz.abs() <= f64::MIN_POSITIVE {
+        1.0
+    } else {
+        (-(dz / w.dot(normal)).abs()).exp()
+    }
+}
+
+/// Samples the change in height to the next scattering event in direction
+/// `w`, from an exponential distribution
+fn sample_distance(w: &Vector, normal: &Normal, u: f64) -> f64 {
+    let cos_theta = w.dot(normal);
+    -(1.0 - u).ln() * cos_theta
+}
+
+/// Randomly terminates paths with low throughput, scaling up the ones that
+/// survive. Returns false if the path should be terminated.
+fn russian_roulette(beta: &mut Color, depth: usize, rng: &mut impl Rng) -> bool {
+    let max_beta = beta.r.max(beta.g.max(beta.b));
+    if depth > 3 && max_beta < 0.25 {
+        let q = (1.0 - max_beta).max(0.0);
+        if rng.gen::<f64>() < q {
+            return false;
+        }
+        *beta /= 1.0 - q;
+    }
+    true
+}
+
+/// Random walks use their own random numbers, seeded by the directions so that
+/// evaluating the same directions gives the same result
+fn seeded_rng(vectors: &[&Vector]) -> SmallRng {
+    let mut hasher = DefaultHasher::new();
+    for v in vectors {
+        v.x().to_bits().hash(&mut hasher);
+        v.y().to_bits().hash(&mut hasher);
+        v.z().to_bits().hash(&mut hasher);
+    }
+    SmallRng::seed_from_u64(hasher.finish())
+}
@@ -4,24 +4,31 @@ pub mod bounds;
 pub mod bsdf;
 pub mod bvh;
 pub mod bxdf;
+pub mod cache;
 pub mod camera;
 pub mod color;
 pub mod constants;
 pub mod film;
 pub mod geometry;
 pub mod intersection;
+pub mod layered;
 pub mod light;
 pub mod material;
+pub mod measured;
+pub mod mesh;
+pub mod noise;
 pub mod obj;
 pub mod path_integrator;
 pub mod pdf;
 pub mod primitive;
 pub mod ray;
+pub mod render;
 pub mod sampling;
 pub mod scene;
 pub mod scene_parser;
 pub mod shape;
 pub mod simple_integrator;
+pub mod subsurface;
 pub mod texture;
 pub mod transformation;
 pub mod util;
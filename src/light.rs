@@ -19,9 +19,7 @@ use crate::{
     shape::Shape,
 };
 
-// TODO: Avoid this PartialEq, currently used by path_integrator to map an area
-// light to index in the lights array
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum Light {
     Point {
         origin: Point,
@@ -67,7 +65,7 @@ impl Light {
                 let dist_squared = op.magnitude_squared();
                 let dist = dist_squared.sqrt();
                 let w_i = op / dist;
-                let mut shadow_ray = Ray::new(intersection.location, w_i);
+                let mut shadow_ray = intersection.spawn_ray(w_i);
                 shadow_ray.update_max_distance(dist);
 
                 LightSample {
@@ -84,7 +82,7 @@ impl Light {
                 assert_abs_diff_eq!(direction.magnitude(), 1.0, epsilon = EPSILON);
 
                 // Leave the max distance to infinity, since the light is at qz
-                let shadow_ray = Ray::new(intersection.location, *direction);
+                let shadow_ray = intersection.spawn_ray(*direction);
                 let w_i = *direction;
 
                 LightSample {
@@ -102,7 +100,7 @@ impl Light {
                 };
 
                 let w_i = sample_hemisphere(sample_2d, &normal);
-                let shadow_ray = Ray::new(intersection.location, w_i);
+                let shadow_ray = intersection.spawn_ray(w_i);
 
                 LightSample {
                     Li: *intensity,
@@ -120,7 +118,7 @@ impl Light {
                 // where it is used.
                 let (shape_point, w_i, pdf) = shape.sample_from(sample_2d, intersection);
                 let distance = (shape_point - intersection.location).magnitude();
-                let mut shadow_ray = Ray::new(intersection.location, w_i);
+                let mut shadow_ray = intersection.spawn_ray(w_i);
                 shadow_ray.update_max_distance(distance - EPSILON);
                 return LightSample {
                     Li: *emittance,
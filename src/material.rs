@@ -1,22 +1,103 @@
-use std::vec;
+use std::{sync::Arc, vec};
 
 use crate::{
     bsdf::BSDF,
     bxdf::{BxDF, Dielectric, Fresnel, SurfaceSample},
     color::Color,
     geometry::{normal::Normal, vector::Vector},
+    layered::Coated,
+    measured::MeasuredBRDF,
     pdf::Pdf,
     sampling::samplers::{Sample1d, Sample2d},
-    texture::Texture,
+    subsurface::Subsurface,
+    texture::{Texture, TextureCoordinates},
 };
 
 #[derive(Debug)]
 pub enum Material {
     BxDF(BxDF),
     BSDF(BSDF),
+    /// A material whose shading normal is perturbed to add surface detail
+    Perturbed {
+        material: Box<Material>,
+        perturbation: Perturbation,
+    },
+    /// Blends two materials, using `b` where `amount` is 1
+    Mix {
+        a: Arc<Material>,
+        b: Arc<Material>,
+        amount: Texture<f64>,
+    },
+    Coated(Coated),
+    Subsurface(Subsurface),
+    Measured(MeasuredBRDF),
+}
+
+/// Ways of perturbing the shading normal of a surface
+#[derive(Debug)]
+pub enum Perturbation {
+    /// A tangent space normal map, where the red, green and blue channels are
+    /// the tangent, bitangent and normal components mapped to [0, 1]. The
+    /// bitangent points up in the image, i.e. towards decreasing v.
+    NormalMap(Texture<Color>),
+    /// A height field that displaces the surface along its normal
+    BumpMap(Texture<f64>),
 }
 
 impl Material {
+    pub fn new_perturbed(material: Material, perturbation: Perturbation) -> Material {
+        Material::Perturbed {
+            material: Box::new(material),
+            perturbation,
+        }
+    }
+
+    pub fn perturbation(&self) -> Option<&Perturbation> {
+        match self {
+            Material::Perturbed { perturbation, .. } => Some(perturbation),
+            _ => None,
+        }
+    }
+
+    pub fn new_mix(a: Arc<Material>, b: Arc<Material>, amount: Texture<f64>) -> Material {
+        Material::Mix { a, b, amount }
+    }
+
+    pub fn new_coated(
+        base: Arc<Material>,
+        eta: f64,
+        thickness: Texture<f64>,
+        albedo: Texture<Color>,
+        max_depth: usize,
+        num_samples: usize,
+    ) -> Material {
+        Material::Coated(Coated {
+            base,
+            eta,
+            thickness,
+            albedo,
+            max_depth,
+            num_samples,
+        })
+    }
+
+    pub fn new_subsurface(
+        albedo: Texture<Color>,
+        mean_free_path: Texture<Color>,
+        eta: f64,
+    ) -> Material {
+        Material::Subsurface(Subsurface::new(albedo, mean_free_path, eta))
+    }
+
+    /// The subsurface material light can be transmitted into, if any
+    pub fn subsurface(&self) -> Option<&Subsurface> {
+        match self {
+            Material::Subsurface(subsurface) => Some(subsurface),
+            Material::Perturbed { material, .. } => material.subsurface(),
+            _ => None,
+        }
+    }
+
     pub fn new_matte(reflectance: Texture<Color>, sigma: Texture<f64>) -> Material {
         if sigma.is_zero() {
             Material::BxDF(BxDF::LambertianBRDF { reflectance })
@@ -36,6 +117,25 @@ impl Material {
             eta_t: eta,
         })
     }
+    /// Glass that is thin enough to be modeled as a single surface, which
+    /// doesn't bend transmitted light
+    pub fn new_thin_glass(
+        reflectance: Texture<Color>,
+        transmittance: Texture<Color>,
+        eta: f64,
+    ) -> Material {
+        Material::BxDF(BxDF::ThinDielectricBxDF {
+            reflectance,
+            transmittance,
+            eta,
+        })
+    }
+    pub fn new_translucent(reflectance: Texture<Color>, transmittance: Texture<Color>) -> Material {
+        Material::BxDF(BxDF::DiffuseTransmissionBxDF {
+            reflectance,
+            transmittance,
+        })
+    }
     pub fn new_plastic(
         diffuse: Texture<Color>,
         specular: Texture<Color>,
@@ -74,23 +174,77 @@ impl Material {
         (sample_1d, sample_2d): (Sample1d, Sample2d),
         w_o: &Vector,
         normal: &Normal,
-        uv: &(f64, f64),
+        uv: &TextureCoordinates,
     ) -> Option<SurfaceSample> {
         match self {
             Material::BxDF(bxdf) => bxdf.sample(sample_2d, w_o, normal, uv),
             Material::BSDF(bsdf) => bsdf.sample((sample_1d, sample_2d), w_o, normal, uv),
+            Material::Perturbed { material, .. } => {
+                material.sample((sample_1d, sample_2d), w_o, normal, uv)
+            }
+            Material::Mix { a, b, amount } => {
+                // Pick one of the materials to sample, and reuse the sample to
+                // sample it
+                let t = amount.eval(uv).clamp(0.0, 1.0);
+                let u = sample_1d.take();
+                let (material, u) = if u < t {
+                    (b, u / t)
+                } else {
+                    (a, (u - t) / (1.0 - t))
+                };
+                let sample =
+                    material.sample((Sample1d::new(u.min(1.0)), sample_2d), w_o, normal, uv)?;
+                if sample.is_specular {
+                    // The material's weight cancels out with the probability
+                    // of picking it
+                    return Some(sample);
+                }
+                Some(SurfaceSample {
+                    f: self.f(w_o, &sample.w_i, normal, uv),
+                    pdf: self.pdf(w_o, &sample.w_i, normal, uv),
+                    ..sample
+                })
+            }
+            Material::Coated(coated) => coated.sample((sample_1d, sample_2d), w_o, normal, uv),
+            Material::Subsurface(subsurface) => {
+                subsurface.sample((sample_1d, sample_2d), w_o, normal, uv)
+            }
+            Material::Measured(brdf) => brdf.sample((sample_1d, sample_2d), w_o, normal),
         }
     }
-    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &(f64, f64)) -> Color {
+    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &TextureCoordinates) -> Color {
         match self {
             Material::BxDF(bxdf) => bxdf.f(w_o, w_i, normal, uv),
             Material::BSDF(bsdf) => bsdf.f(w_o, w_i, normal, uv),
+            Material::Perturbed { material, .. } => material.f(w_o, w_i, normal, uv),
+            Material::Mix { a, b, amount } => {
+                let t = amount.eval(uv).clamp(0.0, 1.0);
+                a.f(w_o, w_i, normal, uv) * (1.0 - t) + b.f(w_o, w_i, normal, uv) * t
+            }
+            Material::Coated(coated) => coated.f(w_o, w_i, normal, uv),
+            Material::Subsurface(subsurface) => subsurface.f(w_o, w_i, normal, uv),
+            Material::Measured(brdf) => brdf.f(w_o, w_i, normal),
         }
     }
-    pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal) -> Pdf {
+    pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal, uv: &TextureCoordinates) -> Pdf {
         match self {
             Material::BxDF(bxdf) => bxdf.pdf(w_o, w_i, normal),
             Material::BSDF(bsdf) => bsdf.pdf(w_o, w_i, normal),
+            Material::Perturbed { material, .. } => material.pdf(w_o, w_i, normal, uv),
+            Material::Mix { a, b, amount } => {
+                let t = amount.eval(uv).clamp(0.0, 1.0);
+                match (a.pdf(w_o, w_i, normal, uv), b.pdf(w_o, w_i, normal, uv)) {
+                    (Pdf::Delta, Pdf::Delta) => Pdf::Delta,
+                    (Pdf::NonDelta(pdf_a), Pdf::Delta) => Pdf::NonDelta(pdf_a * (1.0 - t)),
+                    (Pdf::Delta, Pdf::NonDelta(pdf_b)) => Pdf::NonDelta(pdf_b * t),
+                    (Pdf::NonDelta(pdf_a), Pdf::NonDelta(pdf_b)) => {
+                        Pdf::NonDelta(pdf_a * (1.0 - t) + pdf_b * t)
+                    }
+                }
+            }
+            Material::Coated(coated) => coated.pdf(w_o, w_i, normal, uv),
+            Material::Subsurface(subsurface) => subsurface.pdf(w_o, w_i, normal),
+            Material::Measured(brdf) => brdf.pdf(w_o, w_i, normal),
         }
     }
 }
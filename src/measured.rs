@@ -0,0 +1,300 @@
+use std::{
+    convert::TryInto,
+    f64::consts::{FRAC_1_PI, FRAC_PI_2, PI},
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+};
+
+use crate::{
+    bxdf::{reflect, SurfaceSample},
+    color::Color,
+    geometry::{normal::Normal, traits::DotProduct, vector::Vector},
+    pdf::Pdf,
+    sampling::{
+        samplers::{Sample1d, Sample2d},
+        sampling_fns::cosine_sample_hemisphere,
+    },
+};
+
+const THETA_HALF_RESOLUTION: usize = 90;
+const THETA_DIFF_RESOLUTION: usize = 90;
+const PHI_DIFF_RESOLUTION: usize = 180;
+const TABLE_SIZE: usize = THETA_HALF_RESOLUTION * THETA_DIFF_RESOLUTION * PHI_DIFF_RESOLUTION;
+
+/// Factors converting the stored values of each channel to reflectance
+const CHANNEL_SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+/// An isotropic BRDF measured from a real material, tabulated in the MERL
+/// format over Rusinkiewicz's half and difference angles. Directions are
+/// sampled half of the time from the cosine distribution, and otherwise by
+/// sampling a half vector in proportion to the BRDF's retroreflection.
+///
+/// Source: https://www.merl.com/brdf/
+pub struct MeasuredBRDF {
+    values: Vec<Color>,
+    /// Cumulative distribution for sampling the theta half bins
+    theta_half_cdf: Vec<f64>,
+}
+
+impl fmt::Debug for MeasuredBRDF {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeasuredBRDF")
+            .field("values", &self.values.len())
+            .finish()
+    }
+}
+
+impl MeasuredBRDF {
+    pub fn load(file_name: &str) -> Result<MeasuredBRDF, String> {
+        let file = File::open(file_name).map_err(|e| e.to_string())?;
+        MeasuredBRDF::from_reader(BufReader::new(file))
+    }
+
+    /// Reads a MERL `.binary` file: three little endian `i32` dimensions,
+    /// followed by the red, green and blue tables as `f64`s
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<MeasuredBRDF, String> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let dimensions: Vec<i32> = header
+            .chunks_exact(4)
+            .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        if dimensions.iter().product::<i32>() != TABLE_SIZE as i32 {
+            return Err(format!(
+                "Expected {} values, but the dimensions are {:?}",
+                TABLE_SIZE, dimensions
+            ));
+        }
+
+        let mut bytes = vec![0; TABLE_SIZE * 3 * 8];
+        reader.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+        let channel = |c: usize, i: usize| {
+            let offset = (c * TABLE_SIZE + i) * 8;
+            let value = f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+            // Missing measurements are stored as negative values
+            value.max(0.0) * CHANNEL_SCALE[c]
+        };
+        let values = (0..TABLE_SIZE)
+            .map(|i| Color {
+                r: channel(0, i),
+                g: channel(1, i),
+                b: channel(2, i),
+            })
+            .collect();
+        Ok(MeasuredBRDF::new(values))
+    }
+
+    /// Creates a BRDF from values indexed by `phi_diff + theta_diff * 180 +
+    /// theta_half * 180 * 90`
+    pub fn new(values: Vec<Color>) -> MeasuredBRDF {
+        assert_eq!(values.len(), TABLE_SIZE);
+
+        // Weigh each theta half bin by the retroreflection at its center,
+        // which is where the BRDF's peak is, and by its projected solid angle
+        // since half vectors far from the normal often reflect below the
+        // surface
+        let mut brdf = MeasuredBRDF {
+            values,
+            theta_half_cdf: vec![],
+        };
+        let weights: Vec<f64> = (0..THETA_HALF_RESOLUTION)
+            .map(|i| {
+                let (theta_min, theta_max) = theta_half_bin(i);
+                let value = brdf.values[index(0.5 * (theta_min + theta_max), 0.0, 0.0)];
+                let solid_angle = 2.0 * PI * (theta_min.cos() - theta_max.cos());
+                let cos_theta = (0.5 * (theta_min + theta_max)).cos();
+                (value.r + value.g + value.b) / 3.0 * solid_angle * cos_theta
+            })
+            .collect();
+        let total: f64 = weights.iter().sum();
+        let mut cumulative = 0.0;
+        brdf.theta_half_cdf = (0..THETA_HALF_RESOLUTION)
+            .map(|i| {
+                // Fall back to sampling by solid angle for black materials
+                cumulative += if total > 0.0 {
+                    weights[i] / total
+                } else {
+                    let (theta_min, theta_max) = theta_half_bin(i);
+                    theta_min.cos() - theta_max.cos()
+                };
+                cumulative
+            })
+            .collect();
+        brdf
+    }
+
+    pub fn f(&self, w_o: &Vector, w_i: &Vector, normal: &Normal) -> Color {
+        if !normal.same_hemisphere(w_o, w_i) {
+            return Color::BLACK;
+        }
+        let frame = Frame::new(normal, w_o);
+        let (theta_half, theta_diff, phi_diff) =
+            half_diff_angles(&frame.to_local(w_i), &frame.to_local(w_o));
+        self.values[index(theta_half, theta_diff, phi_diff)]
+    }
+
+    pub fn sample(
+        &self,
+        (sample_1d, sample_2d): (Sample1d, Sample2d),
+        w_o: &Vector,
+        normal: &Normal,
+    ) -> Option<SurfaceSample> {
+        let frame = Frame::new(normal, w_o);
+        let w_i = if sample_1d.take() < 0.5 {
+            cosine_sample_hemisphere(sample_2d, &frame.normal)
+        } else {
+            let (u, v) = sample_2d.take();
+            let bin = self
+                .theta_half_cdf
+                .binary_search_by(|probe| probe.total_cmp(&u))
+                .unwrap_or_else(|bin| bin)
+                .min(THETA_HALF_RESOLUTION - 1);
+            let bin_min = if bin > 0 {
+                self.theta_half_cdf[bin - 1]
+            } else {
+                0.0
+            };
+            let u = ((u - bin_min) / (self.theta_half_cdf[bin] - bin_min)).clamp(0.0, 1.0);
+
+            // Uniformly sample the solid angle covered by the bin
+            let (theta_min, theta_max) = theta_half_bin(bin);
+            let cos_theta = theta_min.cos() * (1.0 - u) + theta_max.cos() * u;
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * v;
+            let half = frame.to_world(&Vector(
+                sin_theta * phi.cos(),
+                sin_theta * phi.sin(),
+                cos_theta,
+            ));
+            let w_i = reflect(w_o, &half.into());
+            if w_i.dot(&frame.normal) <= 0.0 {
+                return None;
+            }
+            w_i
+        };
+        Some(SurfaceSample {
+            w_i,
+            f: self.f(w_o, &w_i, normal),
+            pdf: self.pdf(w_o, &w_i, normal),
+            is_specular: false,
+        })
+    }
+
+    pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal) -> Pdf {
+        if !normal.same_hemisphere(w_o, w_i) {
+            return Pdf::NonDelta(0.0);
+        }
+        let frame = Frame::new(normal, w_o);
+        let cosine_pdf = w_i.dot(&frame.normal).abs() * FRAC_1_PI;
+
+        let half = (*w_o + *w_i).normalized();
+        let theta_half = half.dot(&frame.normal).clamp(-1.0, 1.0).acos();
+        let bin = theta_half_index(theta_half);
+        let bin_probability = self.theta_half_cdf[bin]
+            - if bin > 0 {
+                self.theta_half_cdf[bin - 1]
+            } else {
+                0.0
+            };
+        let (theta_min, theta_max) = theta_half_bin(bin);
+        let half_pdf = bin_probability / (2.0 * PI * (theta_min.cos() - theta_max.cos()));
+        // Change of variables from the half vector to the reflected direction
+        let reflected_pdf = half_pdf / (4.0 * w_o.dot(&half).abs());
+
+        Pdf::NonDelta(0.5 * cosine_pdf + 0.5 * reflected_pdf)
+    }
+}
+
+/// Shading frame with the normal flipped to the side of `w_o`
+struct Frame {
+    normal: Normal,
+    tangent: Vector,
+    bitangent: Vector,
+}
+
+impl Frame {
+    fn new(normal: &Normal, w_o: &Vector) -> Frame {
+        let normal = if normal.dot(w_o) < 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+        let (tangent, bitangent) = normal.generate_tangents();
+        Frame {
+            normal,
+            tangent,
+            bitangent,
+        }
+    }
+
+    fn to_local(&self, v: &Vector) -> Vector {
+        Vector(
+            v.dot(&self.tangent),
+            v.dot(&self.bitangent),
+            v.dot(&self.normal),
+        )
+    }
+
+    fn to_world(&self, v: &Vector) -> Vector {
+        let normal: Vector = self.normal.into();
+        self.tangent * v.x() + self.bitangent * v.y() + normal * v.z()
+    }
+}
+
+/// Converts a pair of directions in a local frame to the theta half, theta
+/// difference and phi difference angles
+///
+/// Source: https://www.merl.com/brdf/
+fn half_diff_angles(w_i: &Vector, w_o: &Vector) -> (f64, f64, f64) {
+    let half = (*w_i + *w_o).normalized();
+    let theta_half = half.z().clamp(-1.0, 1.0).acos();
+    let phi_half = half.y().atan2(half.x());
+
+    // Rotate w_i so that the half vector lies along the z axis
+    let (cos_phi, sin_phi) = (phi_half.cos(), phi_half.sin());
+    let (x, y, z) = (
+        w_i.x() * cos_phi + w_i.y() * sin_phi,
+        w_i.y() * cos_phi - w_i.x() * sin_phi,
+        w_i.z(),
+    );
+    let (cos_theta, sin_theta) = (theta_half.cos(), theta_half.sin());
+    let diff = Vector(
+        x * cos_theta - z * sin_theta,
+        y,
+        x * sin_theta + z * cos_theta,
+    );
+
+    let theta_diff = diff.z().clamp(-1.0, 1.0).acos();
+    let phi_diff = diff.y().atan2(diff.x());
+    (theta_half, theta_diff, phi_diff)
+}
+
+/// Theta half is sampled more densely near 0, where specular peaks are
+fn theta_half_index(theta_half: f64) -> usize {
+    let index = (theta_half.max(0.0) / FRAC_PI_2).sqrt() * THETA_HALF_RESOLUTION as f64;
+    (index as usize).min(THETA_HALF_RESOLUTION - 1)
+}
+
+/// The range of theta half angles covered by a bin
+fn theta_half_bin(index: usize) -> (f64, f64) {
+    let bound = |i: usize| (i as f64 / THETA_HALF_RESOLUTION as f64).powi(2) * FRAC_PI_2;
+    (bound(index), bound(index + 1))
+}
+
+fn index(theta_half: f64, theta_diff: f64, phi_diff: f64) -> usize {
+    let theta_diff_index = ((theta_diff.max(0.0) / FRAC_PI_2 * THETA_DIFF_RESOLUTION as f64)
+        as usize)
+        .min(THETA_DIFF_RESOLUTION - 1);
+    // The BRDF is reciprocal, so phi diff is only stored in [0, pi)
+    let phi_diff = if phi_diff < 0.0 {
+        phi_diff + PI
+    } else {
+        phi_diff
+    };
+    let phi_diff_index =
+        ((phi_diff / PI * PHI_DIFF_RESOLUTION as f64) as usize).min(PHI_DIFF_RESOLUTION - 1);
+    phi_diff_index
+        + theta_diff_index * PHI_DIFF_RESOLUTION
+        + theta_half_index(theta_half) * PHI_DIFF_RESOLUTION * THETA_DIFF_RESOLUTION
+}
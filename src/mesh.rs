@@ -0,0 +1,410 @@
+use crate::{
+    bounds::Bounds,
+    constants::EPSILON,
+    geometry::{normal::Normal, point::Point, traits::DotProduct, vector::Vector},
+    intersection::ShapeIntersection,
+    ray::Ray,
+    sampling::{samplers::Sample2d, sampling_fns::sample_triangle},
+    transformation::{Transformable, Transformation},
+};
+
+/// Vertex data shared by the triangles of a mesh. Triangles refer to their
+/// vertices by index, so each vertex is stored once no matter how many
+/// triangles use it.
+#[derive(Debug, PartialEq)]
+pub struct TriangleMesh {
+    /// Indices of the vertices of each triangle, in clockwise order
+    pub indices: Vec<[u32; 3]>,
+    pub vertices: Vec<Point>,
+    /// Per vertex normals, which are empty for flat shaded meshes
+    pub normals: Vec<Vector>,
+    /// Per vertex texture coordinates. Without them, every triangle is mapped
+    /// to (0, 0), (1, 0) and (1, 1).
+    pub uvs: Vec<(f64, f64)>,
+    /// Per vertex tangents for normal mapping, which are empty or zero if they
+    /// aren't known
+    pub tangents: Vec<Vector>,
+    /// Where the vertices move to during the shutter interval, for meshes
+    /// that deform
+    pub motion: Option<VertexMotion>,
+}
+
+/// Vertex positions of a deforming mesh at a series of times, starting with
+/// the mesh's own `vertices` and `normals`. Triangles are interpolated
+/// linearly between the steps, and stay at the first and last ones outside of
+/// them.
+#[derive(Debug, PartialEq)]
+pub struct VertexMotion {
+    /// Increasing time of each step, including the first
+    pub times: Vec<f64>,
+    /// Vertex positions at each step after the first
+    pub vertices: Vec<Vec<Point>>,
+    /// Vertex normals at each step after the first, which are empty if the
+    /// mesh has none
+    pub normals: Vec<Vec<Vector>>,
+}
+
+impl VertexMotion {
+    /// The steps before and after `time`, and how far between them it is
+    fn steps(&self, time: f64) -> (usize, usize, f64) {
+        let next = self.times.partition_point(|&step_time| step_time <= time);
+        if next == 0 {
+            return (0, 0, 0.0);
+        }
+        if next == self.times.len() {
+            return (next - 1, next - 1, 0.0);
+        }
+        let t = (time - self.times[next - 1]) / (self.times[next] - self.times[next - 1]);
+        (next - 1, next, t)
+    }
+}
+
+/// The vertex attributes of a single triangle, relative to its first vertex
+struct Triangle {
+    e1: Vector,
+    e2: Vector,
+    n0: Vector,
+    n01: Vector,
+    n02: Vector,
+    uv0: (f64, f64),
+    uv01: (f64, f64),
+    uv02: (f64, f64),
+    t0: Vector,
+    t01: Vector,
+    t02: Vector,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        indices: Vec<[u32; 3]>,
+        vertices: Vec<Point>,
+        normals: Vec<Vector>,
+        uvs: Vec<(f64, f64)>,
+        tangents: Vec<Vector>,
+    ) -> Self {
+        for attribute in [normals.len(), uvs.len(), tangents.len()] {
+            assert!(
+                attribute == 0 || attribute == vertices.len(),
+                "Vertex attributes must be empty or have one value per vertex"
+            );
+        }
+        assert!(
+            indices
+                .iter()
+                .flatten()
+                .all(|&i| (i as usize) < vertices.len()),
+            "Triangle indices must refer to vertices in the mesh"
+        );
+        Self {
+            indices,
+            vertices,
+            normals,
+            uvs,
+            tangents,
+            motion: None,
+        }
+    }
+
+    /// Makes the mesh deform over time, moving its vertices through `motion`
+    pub fn with_motion(self, motion: VertexMotion) -> Self {
+        assert!(
+            motion.times.len() == motion.vertices.len() + 1,
+            "Vertex motion needs a time for each step"
+        );
+        assert!(
+            motion.times.windows(2).all(|times| times[0] < times[1]),
+            "Vertex motion steps must be in increasing order of time"
+        );
+        assert!(
+            motion
+                .vertices
+                .iter()
+                .all(|vertices| vertices.len() == self.vertices.len()),
+            "Every vertex motion step must have a position for each vertex"
+        );
+        assert!(
+            motion.normals.len() == motion.vertices.len() || self.normals.is_empty(),
+            "Every vertex motion step must have normals if the mesh has them"
+        );
+        assert!(
+            motion
+                .normals
+                .iter()
+                .all(|normals| normals.len() == self.normals.len()),
+            "Every vertex motion step must have a normal for each vertex"
+        );
+        Self {
+            motion: Some(motion),
+            ..self
+        }
+    }
+
+    /// Positions of the vertices at step `step` of the motion
+    fn step_vertices(&self, step: usize) -> &[Point] {
+        match (step, &self.motion) {
+            (0, _) | (_, None) => &self.vertices,
+            (_, Some(motion)) => &motion.vertices[step - 1],
+        }
+    }
+
+    fn step_normals(&self, step: usize) -> &[Vector] {
+        match (step, &self.motion) {
+            (0, _) | (_, None) => &self.normals,
+            (_, Some(motion)) => &motion.normals[step - 1],
+        }
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn triangle_vertices(&self, index: usize) -> [Point; 3] {
+        let [i, j, k] = self.indices[index];
+        [
+            self.vertices[i as usize],
+            self.vertices[j as usize],
+            self.vertices[k as usize],
+        ]
+    }
+
+    /// Vertices of a triangle where the mesh is at `time`
+    pub fn triangle_vertices_at(&self, index: usize, time: f64) -> [Point; 3] {
+        let motion = match &self.motion {
+            None => return self.triangle_vertices(index),
+            Some(motion) => motion,
+        };
+        let (previous, next, t) = motion.steps(time);
+        let (previous, next) = (self.step_vertices(previous), self.step_vertices(next));
+        self.indices[index].map(|i| {
+            let (a, b) = (previous[i as usize], next[i as usize]);
+            a + (b - a) * t
+        })
+    }
+
+    /// Whether the triangle has no area, or a vertex normal of zero length,
+    /// in which case it can't be rendered
+    pub fn is_degenerate(&self, index: usize) -> bool {
+        let [v0, v1, v2] = self.triangle_vertices(index);
+        (v2 - v0).cross(&(v1 - v0)).magnitude_squared() == 0.0
+            || (!self.normals.is_empty()
+                && self.indices[index]
+                    .iter()
+                    .any(|&i| self.normals[i as usize].magnitude_squared() == 0.0))
+    }
+
+    fn triangle(&self, index: usize, time: f64) -> Triangle {
+        let [i, j, k] = self.indices[index];
+        let (i, j, k) = (i as usize, j as usize, k as usize);
+        let [v0, v1, v2] = self.triangle_vertices_at(index, time);
+        let e1 = v1 - v0;
+        let e2 = v2 - v0;
+
+        // Flat shaded triangles use the face normal, assuming that the
+        // vertices are in clockwise order in a left handed co-ordinate system
+        let (n0, n1, n2) = if self.normals.is_empty() {
+            let n = e2.cross(&e1).normalized();
+            (n, n, n)
+        } else {
+            match &self.motion {
+                None => (self.normals[i], self.normals[j], self.normals[k]),
+                Some(motion) => {
+                    let (previous, next, t) = motion.steps(time);
+                    let (previous, next) = (self.step_normals(previous), self.step_normals(next));
+                    let normal = |i: usize| previous[i] * (1.0 - t) + next[i] * t;
+                    (normal(i), normal(j), normal(k))
+                }
+            }
+        };
+        let (uv0, uv1, uv2) = if self.uvs.is_empty() {
+            ((0.0, 0.0), (1.0, 0.0), (1.0, 1.0))
+        } else {
+            (self.uvs[i], self.uvs[j], self.uvs[k])
+        };
+        let (t0, t1, t2) = if self.tangents.is_empty() {
+            let zero = Vector(0.0, 0.0, 0.0);
+            (zero, zero, zero)
+        } else {
+            (self.tangents[i], self.tangents[j], self.tangents[k])
+        };
+
+        Triangle {
+            e1,
+            e2,
+            n0,
+            n01: n1 - n0,
+            n02: n2 - n0,
+            uv0,
+            uv01: (uv1.0 - uv0.0, uv1.1 - uv0.1),
+            uv02: (uv2.0 - uv0.0, uv2.1 - uv0.1),
+            t0,
+            t01: t1 - t0,
+            t02: t2 - t0,
+        }
+    }
+
+    /// Finds the barycentric coordinates and distance of the ray's hit with a
+    /// triangle
+    ///
+    /// Source: http://www.graphics.cornell.edu/pubs/1997/MT97.pdf
+    #[allow(non_snake_case)]
+    fn hit(&self, index: usize, ray: &Ray) -> Option<(f64, f64, f64)> {
+        let [v0, v1, v2] = self.triangle_vertices_at(index, ray.time);
+        let (e1, e2) = (v1 - v0, v2 - v0);
+        let P = ray.direction.cross(&e2);
+
+        let denominator = P.dot(&e1);
+        if denominator > -EPSILON && denominator < EPSILON {
+            return None;
+        }
+
+        let T = ray.origin - v0;
+        let u = P.dot(&T) / denominator;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let Q = T.cross(&e1);
+        let v = Q.dot(&ray.direction) / denominator;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let distance = Q.dot(&e2) / denominator;
+        Some((u, v, distance))
+    }
+
+    /// Should update the ray's max_distance if an intersection is found
+    pub fn intersect(&self, index: usize, ray: &mut Ray) -> Option<ShapeIntersection> {
+        let (u, v, distance) = self.hit(index, ray)?;
+        if !ray.update_max_distance(distance) {
+            return None;
+        }
+        let location = ray.at(distance);
+        let Triangle {
+            e1,
+            e2,
+            n0,
+            n01,
+            n02,
+            uv0,
+            uv01,
+            uv02,
+            t0,
+            t01,
+            t02,
+            ..
+        } = self.triangle(index, ray.time);
+
+        let shading_normal: Normal = (n0 + n01 * u + n02 * v).normalized().into();
+        // Flip the geometric normal to be on the same side as the vertex
+        // normals, which define the intended orientation
+        let mut normal: Normal = e2.cross(&e1).normalized().into();
+        if normal.dot(&shading_normal) < 0.0 {
+            normal = -normal;
+        }
+
+        // Solve for the partial derivatives of position and normal w.r.t. uv
+        // using the differences along the edges
+        let determinant = uv01.0 * uv02.1 - uv01.1 * uv02.0;
+        let (dpdu, dpdv, dndu, dndv) = if determinant.abs() < EPSILON {
+            let (dpdu, dpdv) = shading_normal.generate_tangents();
+            (dpdu, dpdv, Normal(0.0, 0.0, 0.0), Normal(0.0, 0.0, 0.0))
+        } else {
+            let inv_determinant = 1.0 / determinant;
+            (
+                (e1 * uv02.1 - e2 * uv01.1) * inv_determinant,
+                (e2 * uv01.0 - e1 * uv02.0) * inv_determinant,
+                ((n01 * uv02.1 - n02 * uv01.1) * inv_determinant).into(),
+                ((n02 * uv01.0 - n01 * uv02.0) * inv_determinant).into(),
+            )
+        };
+
+        let mut tangent = t0 + t01 * u + t02 * v;
+        if tangent.magnitude_squared() == 0.0 {
+            tangent = dpdu;
+        }
+
+        Some(ShapeIntersection {
+            location,
+            normal,
+            shading_normal,
+            tangent,
+            uv: (
+                uv0.0 + uv01.0 * u + uv02.0 * v,
+                uv0.1 + uv01.1 * u + uv02.1 * v,
+            ),
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        })
+    }
+
+    pub fn intersects(&self, index: usize, ray: &Ray) -> bool {
+        match self.hit(index, ray) {
+            Some((_, _, distance)) => ray.contains_distance(distance),
+            None => false,
+        }
+    }
+
+    /// Bounds of the triangle at every step of its motion, which also
+    /// enclose it between the steps
+    pub fn bounds(&self, index: usize) -> Bounds {
+        let [v0, v1, v2] = self.triangle_vertices(index);
+        let bounds = Bounds::new(v0, v1) + v2;
+        match &self.motion {
+            None => bounds,
+            Some(motion) => motion.vertices.iter().fold(bounds, |bounds, vertices| {
+                self.indices[index]
+                    .iter()
+                    .fold(bounds, |bounds, &i| bounds + vertices[i as usize])
+            }),
+        }
+    }
+
+    /// Samples a point uniformly on a triangle
+    pub fn sample(&self, index: usize, point_sample: Sample2d) -> Point {
+        let [v0, v1, v2] = self.triangle_vertices(index);
+        let (b1, b2) = sample_triangle(point_sample);
+        v0 + (v1 - v0) * b1 + (v2 - v0) * b2
+    }
+
+    pub fn area(&self, index: usize) -> f64 {
+        let [v0, v1, v2] = self.triangle_vertices(index);
+        (v1 - v0).cross(&(v2 - v0)).magnitude() / 2.0
+    }
+}
+
+impl Transformable<TriangleMesh> for Transformation {
+    fn transform(&self, mesh: &TriangleMesh) -> TriangleMesh {
+        TriangleMesh {
+            indices: mesh.indices.clone(),
+            vertices: mesh.vertices.iter().map(|v| self.transform(v)).collect(),
+            normals: mesh
+                .normals
+                .iter()
+                .map(|n| Vector::from(self.transform(&Normal::from(n))))
+                .collect(),
+            uvs: mesh.uvs.clone(),
+            tangents: mesh.tangents.iter().map(|t| self.transform(t)).collect(),
+            motion: mesh.motion.as_ref().map(|motion| VertexMotion {
+                times: motion.times.clone(),
+                vertices: motion
+                    .vertices
+                    .iter()
+                    .map(|vertices| vertices.iter().map(|v| self.transform(v)).collect())
+                    .collect(),
+                normals: motion
+                    .normals
+                    .iter()
+                    .map(|normals| {
+                        normals
+                            .iter()
+                            .map(|n| Vector::from(self.transform(&Normal::from(n))))
+                            .collect()
+                    })
+                    .collect(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::geometry::{point::Point, vector::Vector};
+
+/// Ken Perlin's reference permutation table, used to hash lattice points
+const PERMUTATION: [usize; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69,
+    142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219,
+    203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76,
+    132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173,
+    186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206,
+    59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163,
+    70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232,
+    178, 185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162,
+    241, 81, 51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204,
+    176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141,
+    128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+fn permute(i: i64) -> usize {
+    PERMUTATION[(i & 255) as usize]
+}
+
+/// Hashes an integer lattice point to a value in [0, 256)
+fn hash(x: i64, y: i64, z: i64) -> usize {
+    permute(permute(permute(x) as i64 + y) as i64 + z)
+}
+
+/// Dot product of the offset (dx, dy, dz) with one of 12 gradient directions
+/// chosen by hashing the lattice point
+fn gradient(x: i64, y: i64, z: i64, dx: f64, dy: f64, dz: f64) -> f64 {
+    let h = hash(x, y, z) & 15;
+    let u = if h < 8 || h == 12 || h == 13 { dx } else { dy };
+    let v = if h < 4 || h == 12 || h == 13 { dy } else { dz };
+    (if h & 1 == 1 { -u } else { u }) + (if h & 2 == 2 { -v } else { v })
+}
+
+/// Quintic smoothing curve, which has zero first and second derivatives at 0
+/// and 1 so that the noise is smooth across lattice cells
+fn noise_weight(t: f64) -> f64 {
+    let t3 = t * t * t;
+    6.0 * t3 * t * t - 15.0 * t3 * t + 10.0 * t3
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    (1.0 - t) * a + t * b
+}
+
+fn smooth_step(a: f64, b: f64, x: f64) -> f64 {
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn scale_point(p: &Point, scale: f64) -> Point {
+    Point(p.x() * scale, p.y() * scale, p.z() * scale)
+}
+
+/// Perlin gradient noise in roughly [-1, 1], which is zero at integer lattice
+/// points
+///
+/// Source: https://pbr-book.org/3ed-2018/Texture/Noise#PerlinNoise
+pub fn noise(p: &Point) -> f64 {
+    let (x, y, z) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (dx, dy, dz) = (p.x() - x, p.y() - y, p.z() - z);
+    let (x, y, z) = (x as i64, y as i64, z as i64);
+
+    let w000 = gradient(x, y, z, dx, dy, dz);
+    let w100 = gradient(x + 1, y, z, dx - 1.0, dy, dz);
+    let w010 = gradient(x, y + 1, z, dx, dy - 1.0, dz);
+    let w110 = gradient(x + 1, y + 1, z, dx - 1.0, dy - 1.0, dz);
+    let w001 = gradient(x, y, z + 1, dx, dy, dz - 1.0);
+    let w101 = gradient(x + 1, y, z + 1, dx - 1.0, dy, dz - 1.0);
+    let w011 = gradient(x, y + 1, z + 1, dx, dy - 1.0, dz - 1.0);
+    let w111 = gradient(x + 1, y + 1, z + 1, dx - 1.0, dy - 1.0, dz - 1.0);
+
+    let (wx, wy, wz) = (noise_weight(dx), noise_weight(dy), noise_weight(dz));
+    let x00 = lerp(wx, w000, w100);
+    let x10 = lerp(wx, w010, w110);
+    let x01 = lerp(wx, w001, w101);
+    let x11 = lerp(wx, w011, w111);
+    let y0 = lerp(wy, x00, x10);
+    let y1 = lerp(wy, x01, x11);
+    lerp(wz, y0, y1)
+}
+
+/// Number of octaves to sum so that the highest frequency is still below the
+/// sampling rate given by the change in `p` per pixel
+fn num_octaves(dpdx: &Vector, dpdy: &Vector, max_octaves: usize) -> f64 {
+    let length_squared = dpdx.magnitude_squared().max(dpdy.magnitude_squared());
+    (-1.0 - 0.5 * length_squared.log2()).clamp(0.0, max_octaves as f64)
+}
+
+/// Fractional Brownian motion: a sum of noise octaves with increasing frequency
+/// and amplitude scaled by `roughness` each octave
+///
+/// Source: https://pbr-book.org/3ed-2018/Texture/Noise
+pub fn fbm(p: &Point, dpdx: &Vector, dpdy: &Vector, roughness: f64, max_octaves: usize) -> f64 {
+    let n = num_octaves(dpdx, dpdy, max_octaves);
+    let n_int = n.floor() as usize;
+    let mut sum = 0.0;
+    let mut lambda = 1.0;
+    let mut o = 1.0;
+    for _ in 0..n_int {
+        sum += o * noise(&scale_point(p, lambda));
+        lambda *= 1.99;
+        o *= roughness;
+    }
+    // Fade in the last octave to avoid discontinuities
+    sum + o * smooth_step(0.3, 0.7, n - n_int as f64) * noise(&scale_point(p, lambda))
+}
+
+/// Like `fbm`, but sums the absolute value of each octave, which gives sharp
+/// creases where the noise crosses zero
+pub fn turbulence(
+    p: &Point,
+    dpdx: &Vector,
+    dpdy: &Vector,
+    roughness: f64,
+    max_octaves: usize,
+) -> f64 {
+    let n = num_octaves(dpdx, dpdy, max_octaves);
+    let n_int = n.floor() as usize;
+    let mut sum = 0.0;
+    let mut lambda = 1.0;
+    let mut o = 1.0;
+    for _ in 0..n_int {
+        sum += o * noise(&scale_point(p, lambda)).abs();
+        lambda *= 1.99;
+        o *= roughness;
+    }
+
+    // Fade the last octave towards the average value of |noise| instead of
+    // zero, and use that average for the octaves that are too high frequency
+    let n_partial = n - n_int as f64;
+    sum += o * lerp(
+        smooth_step(0.3, 0.7, n_partial),
+        0.2,
+        noise(&scale_point(p, lambda)).abs(),
+    );
+    for _ in n_int..max_octaves {
+        sum += o * 0.2;
+        o *= roughness;
+    }
+    sum
+}
+
+/// Cellular noise: the distance from `p` to the nearest of a set of feature
+/// points scattered with one point per lattice cell
+pub fn worley(p: &Point) -> f64 {
+    let (x, y, z) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (ix, iy, iz) = (x as i64, y as i64, z as i64);
+    let mut min_distance_squared = f64::INFINITY;
+    for cz in iz - 1..=iz + 1 {
+        for cy in iy - 1..=iy + 1 {
+            for cx in ix - 1..=ix + 1 {
+                let h = hash(cx, cy, cz) as i64;
+                let jitter = |offset: i64| (permute(h + offset) as f64 + 0.5) / 256.0;
+                let feature = Point(
+                    cx as f64 + jitter(0),
+                    cy as f64 + jitter(1),
+                    cz as f64 + jitter(2),
+                );
+                min_distance_squared = min_distance_squared.min((feature - *p).magnitude_squared());
+            }
+        }
+    }
+    min_distance_squared.sqrt()
+}
@@ -1,16 +1,27 @@
-use std::{collections::HashMap, f64::consts::E, path::Path, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    f64::consts::E,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use image::DynamicImage;
+use image::{DynamicImage, ImageBuffer, Luma};
 use log::{debug, warn};
 
 use crate::{
+    cache::Cache,
     color::Color,
-    geometry::{point::Point, vector::Vector},
+    geometry::{normal::Normal, point::Point, vector::Vector},
     light::Light,
-    material::Material,
-    primitive::Primitive,
+    material::{Material, Perturbation},
+    mesh::{TriangleMesh, VertexMotion},
+    primitive::{AlphaMask, AlphaMode, Mesh, Primitive},
     shape::Shape,
-    texture::Texture,
+    texture::{ColorSpace, FilterMethod, Texture, WrapMode},
+    transformation::{Transformable, Transformation},
 };
 
 fn load_texture(file_name: &str, texture_file_name: &str) -> DynamicImage {
@@ -23,10 +34,206 @@ fn load_texture(file_name: &str, texture_file_name: &str) -> DynamicImage {
         &format!("Could not find texture file \"{}\"", texture_file_name)).decode().unwrap()
 }
 
-pub fn load_obj(file_name: &str, fallback_material: Arc<Material>) -> Vec<Arc<Primitive>> {
+/// Normal maps store directions rather than colors, so they are never sRGB
+/// encoded
+fn load_normal_map(image: DynamicImage) -> Texture<Color> {
+    Texture::image_with_options(
+        image,
+        ColorSpace::Linear,
+        FilterMethod::Trilinear,
+        WrapMode::Repeat,
+    )
+}
+
+/// Alpha textures are either grayscale or use the alpha channel of an RGBA
+/// image. Either way, they're used as a cutout mask.
+fn load_alpha_mask(image: DynamicImage) -> AlphaMask {
+    let image = if image.color().has_alpha() {
+        let rgba = image.to_rgba16();
+        DynamicImage::ImageLuma16(ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+            Luma([rgba.get_pixel(x, y)[3]])
+        }))
+    } else {
+        image
+    };
+    let alpha = Texture::image_with_options(
+        image,
+        ColorSpace::Linear,
+        FilterMethod::Trilinear,
+        WrapMode::Repeat,
+    );
+    AlphaMask::new(alpha, AlphaMode::Threshold(0.5))
+}
+
+/// Splits a bump texture statement like "-bm 0.5 bump.png" into the file name
+/// and the bump multiplier
+fn parse_bump_texture(texture: &str) -> (&str, f64) {
+    let tokens: Vec<&str> = texture.split_whitespace().collect();
+    let bump_multiplier = tokens
+        .iter()
+        .position(|&token| token == "-bm")
+        .and_then(|i| tokens.get(i + 1))
+        .and_then(|multiplier| multiplier.parse().ok())
+        .unwrap_or(1.0);
+    (tokens.last().copied().unwrap_or(texture), bump_multiplier)
+}
+
+/// The models in an OBJ file and the materials they use
+#[derive(Debug)]
+pub struct ObjFile {
+    pub models: Vec<tobj::Model>,
+    pub materials: Result<Vec<tobj::Material>, tobj::LoadError>,
+    /// Paths of the material libraries that the OBJ file refers to, relative
+    /// to it
+    pub material_libraries: Vec<PathBuf>,
+}
+
+/// Parses an OBJ file and the material libraries it refers to
+pub fn parse_obj(file_name: &str) -> ObjFile {
+    debug!("Parsing \"{}\"", file_name);
+    let material_libraries = RefCell::new(Vec::new());
+    let mut reader = BufReader::new(File::open(file_name).expect("Error opening OBJ file"));
+    let (models, materials) = tobj::load_obj_buf(&mut reader, &tobj::GPU_LOAD_OPTIONS, |library| {
+        material_libraries.borrow_mut().push(library.to_path_buf());
+        tobj::load_mtl(material_library_path(file_name, library))
+    })
+    .unwrap();
+    ObjFile {
+        models,
+        materials,
+        material_libraries: material_libraries.into_inner(),
+    }
+}
+
+/// Models along with the names of their materials
+pub type NamedMaterialModels = Vec<(tobj::Model, Option<String>)>;
+
+/// Loads the material libraries for models that were parsed before, e.g. by
+/// `Cache`, and points each model at the material with its name. Materials
+/// from later libraries are numbered after the earlier ones, like
+/// `tobj::load_obj` does.
+pub fn load_materials(
+    file_name: &str,
+    material_libraries: Vec<PathBuf>,
+    models: NamedMaterialModels,
+) -> ObjFile {
+    let mut materials = Ok((Vec::new(), HashMap::new()));
+    for library in &material_libraries {
+        let loaded = tobj::load_mtl(material_library_path(file_name, library));
+        materials = match (materials, loaded) {
+            (Ok((mut materials, mut ids)), Ok((library_materials, library_ids))) => {
+                let offset = materials.len();
+                materials.extend(library_materials);
+                ids.extend(
+                    library_ids
+                        .into_iter()
+                        .map(|(name, id)| (name, id + offset)),
+                );
+                Ok((materials, ids))
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+    }
+    let models = models
+        .into_iter()
+        .map(|(mut model, material_name)| {
+            model.mesh.material_id = match (&materials, material_name) {
+                (Ok((_, ids)), Some(name)) => ids.get(&name).copied(),
+                _ => None,
+            };
+            model
+        })
+        .collect();
+    ObjFile {
+        models,
+        materials: materials.map(|(materials, _)| materials),
+        material_libraries,
+    }
+}
+
+fn material_library_path(file_name: &str, library: &Path) -> PathBuf {
+    Path::new(file_name)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(library)
+}
+
+/// Loads the triangles in an OBJ file as primitives, placing them in the
+/// scene with `object_to_world`
+pub fn load_obj(
+    file_name: &str,
+    fallback_material: Arc<Material>,
+    object_to_world: &Transformation,
+) -> Vec<Arc<Primitive>> {
+    load_obj_with_cache(file_name, fallback_material, object_to_world, None)
+}
+
+/// Like `load_obj`, but reads the parsed OBJ file from `cache` if given
+pub fn load_obj_with_cache(
+    file_name: &str,
+    fallback_material: Arc<Material>,
+    object_to_world: &Transformation,
+    cache: Option<&Cache>,
+) -> Vec<Arc<Primitive>> {
+    load_deforming_obj(
+        file_name,
+        0.0,
+        &[],
+        fallback_material,
+        object_to_world,
+        cache,
+    )
+    .expect("Meshes without motion steps always load")
+}
+
+/// Loads a mesh that deforms over time. The mesh in `file_name` is at `time`,
+/// and each of `motion_steps` is a later time and an OBJ file with the same
+/// triangles, whose vertices are where the mesh's are at that time. Returns
+/// an error if the files don't match.
+pub fn load_deforming_obj(
+    file_name: &str,
+    time: f64,
+    motion_steps: &[(f64, String)],
+    fallback_material: Arc<Material>,
+    object_to_world: &Transformation,
+    cache: Option<&Cache>,
+) -> Result<Vec<Arc<Primitive>>, String> {
     debug!("Loading mesh from \"{}\"", file_name);
 
-    let (models, input_materials) = tobj::load_obj(file_name, &tobj::GPU_LOAD_OPTIONS).unwrap();
+    let load = |file_name: &str| match cache {
+        Some(cache) => cache.load_obj(file_name),
+        None => parse_obj(file_name),
+    };
+    let ObjFile {
+        models,
+        materials: input_materials,
+        ..
+    } = load(file_name);
+
+    let mut times = vec![time];
+    let mut step_models = Vec::new();
+    for (step_time, step_file_name) in motion_steps {
+        if *step_time <= *times.last().unwrap() {
+            return Err(format!(
+                "Motion step times must increase after the mesh's time {}, found {}",
+                time, step_time
+            ));
+        }
+        let step = load(step_file_name).models;
+        let same_topology = step.len() == models.len()
+            && step.iter().zip(&models).all(|(step, model)| {
+                step.mesh.indices == model.mesh.indices
+                    && step.mesh.positions.len() == model.mesh.positions.len()
+            });
+        if !same_topology {
+            return Err(format!(
+                "Motion step \"{}\" has different triangles from \"{}\"",
+                step_file_name, file_name
+            ));
+        }
+        times.push(*step_time);
+        step_models.push(step);
+    }
     let input_materials = match input_materials {
         Ok(m) => m,
         Err(e) => {
@@ -59,6 +266,7 @@ pub fn load_obj(file_name: &str, fallback_material: Arc<Material>) -> Vec<Arc<Pr
     }
 
     let mut materials = Vec::new();
+    let mut alpha_masks = Vec::new();
     let mut emittances = HashMap::new();
     for (id, m) in input_materials.iter().enumerate() {
         debug!("Creating material \"{}\":", m.name);
@@ -84,24 +292,63 @@ pub fn load_obj(file_name: &str, fallback_material: Arc<Material>) -> Vec<Arc<Pr
         let roughness = Texture::constant(180.0 * (1.0 - E.powf(-shininess / 100.0)));
 
         let dissolve: f64 = m.dissolve.unwrap_or(1.0);
+        let alpha_mask = m.dissolve_texture.as_ref().map(|texture_file_name| {
+            Arc::new(load_alpha_mask(load_texture(file_name, texture_file_name)))
+        });
+
+        // "bump" is commonly used for both height maps and tangent space
+        // normal maps, so single channel images are treated as bump maps and
+        // everything else as normal maps
+        let perturbation = match (m.unknown_param.get("norm"), &m.normal_texture) {
+            (Some(texture_file_name), _) => Some(Perturbation::NormalMap(load_normal_map(
+                load_texture(file_name, texture_file_name),
+            ))),
+            (None, Some(texture)) => {
+                let (texture_file_name, bump_multiplier) = parse_bump_texture(texture);
+                let image = load_texture(file_name, texture_file_name);
+                if image.color().channel_count() <= 2 {
+                    Some(Perturbation::BumpMap(Texture::scale(
+                        Texture::image(image),
+                        Texture::constant(bump_multiplier),
+                    )))
+                } else {
+                    Some(Perturbation::NormalMap(load_normal_map(image)))
+                }
+            }
+            (None, None) => None,
+        };
 
         let material = if !emittance.is_black() {
             emittances.insert(id, emittance);
             Arc::clone(&fallback_material)
-        } else if dissolve < 1.0 {
-            // TODO: Use "dissolve"?
-            let eta = m.optical_density.unwrap_or(1.0);
-            Arc::new(Material::new_glass(diffuse.clone(), diffuse.clone(), eta))
         } else {
-            // This is a hacky way to support reflective surfaces. We should
-            // likely switch to glTF or something
-            match m.illumination_model {
-                Some(3 | 4 | 5 | 6 | 7 | 8 | 9) => Arc::new(Material::new_metal(diffuse, specular)),
-                _ => Arc::new(Material::new_plastic(diffuse, specular, roughness)),
-            }
+            // Cutouts are modeled with the alpha mask, not transparency
+            let material = if dissolve < 1.0 && alpha_mask.is_none() {
+                // TODO: Use "dissolve"?
+                let eta = m.optical_density.unwrap_or(1.0);
+                // Illumination model 4 is glass without refraction, which is
+                // what single sided window panes need
+                if m.illumination_model == Some(4) {
+                    Material::new_thin_glass(diffuse.clone(), diffuse.clone(), eta)
+                } else {
+                    Material::new_glass(diffuse.clone(), diffuse.clone(), eta)
+                }
+            } else {
+                // This is a hacky way to support reflective surfaces. We should
+                // likely switch to glTF or something
+                match m.illumination_model {
+                    Some(3 | 4 | 5 | 6 | 7 | 8 | 9) => Material::new_metal(diffuse, specular),
+                    _ => Material::new_plastic(diffuse, specular, roughness),
+                }
+            };
+            Arc::new(match perturbation {
+                Some(perturbation) => Material::new_perturbed(material, perturbation),
+                None => material,
+            })
         };
         debug!("\t{:?}", material);
         materials.push(material);
+        alpha_masks.push(alpha_mask);
     }
 
     let mut primitives: Vec<Arc<Primitive>> = Vec::new();
@@ -110,10 +357,14 @@ pub fn load_obj(file_name: &str, fallback_material: Arc<Material>) -> Vec<Arc<Pr
 
         let mesh = &model.mesh;
 
-        let (material, emittance) = if let Some(material_id) = mesh.material_id {
-            (&materials[material_id], emittances.get(&material_id))
+        let (material, emittance, alpha_mask) = if let Some(material_id) = mesh.material_id {
+            (
+                &materials[material_id],
+                emittances.get(&material_id),
+                &alpha_masks[material_id],
+            )
         } else {
-            (&fallback_material, None)
+            (&fallback_material, None, &None)
         };
 
         assert!(
@@ -123,79 +374,128 @@ pub fn load_obj(file_name: &str, fallback_material: Arc<Material>) -> Vec<Arc<Pr
             model.name
         );
 
-        let vertices: Vec<Point> = mesh
-            .positions
-            .chunks_exact(3)
-            .map(|p| {
-                // Convert from right-handed to left-handed coordinate system
-                Point(p[0], p[1], -p[2])
-            })
-            .collect();
-
-        let normals: Vec<Vector> = mesh
-            .normals
-            .chunks_exact(3)
-            .map(|n| {
-                // Convert from right-handed to left-handed coordinate system
-                Vector(n[0], n[1], -n[2])
-            })
-            .collect();
+        let to_vertices = |positions: &[f64]| -> Vec<Point> {
+            positions
+                .chunks_exact(3)
+                .map(|p| {
+                    // Convert from right-handed to left-handed coordinate system
+                    object_to_world.transform(&Point(p[0], p[1], -p[2]))
+                })
+                .collect()
+        };
+        let to_normals = |normals: &[f64]| -> Vec<Vector> {
+            normals
+                .chunks_exact(3)
+                .map(|n| {
+                    // Convert from right-handed to left-handed coordinate system
+                    Vector::from(object_to_world.transform(&Normal(n[0], n[1], -n[2])))
+                })
+                .collect()
+        };
+        let vertices = to_vertices(&mesh.positions);
+        let normals = to_normals(&mesh.normals);
 
         let texture_coordinates: Vec<(f64, f64)> = mesh
             .texcoords
             .chunks_exact(2)
-            .map(|tc| 
+            .map(|tc|
                 // Convert from right-handed to left-handed coordinate system
-                (tc[0], 1.0 - tc[1])
-            )
+                (tc[0], 1.0 - tc[1]))
             .collect();
 
-        for chunk in mesh.indices.chunks(3) {
-            if let &[i, j, k] = chunk {
-                let vi = vertices[i as usize];
-                let vj = vertices[j as usize];
-                let vk = vertices[k as usize];
-
-                let normal = (vk - vi).cross(&(vj - vi)).normalized();
-                let mut ni = normal;
-                let mut nj = normal;
-                let mut nk = normal;
-                if normals.len() > 0 {
-                    ni = normals[i as usize];
-                    nj = normals[j as usize];
-                    nk = normals[k as usize];
+        // Per vertex tangents for normal mapping, found by summing the
+        // direction of increasing u over the faces around each vertex
+        let mut tangents = Vec::new();
+        if !texture_coordinates.is_empty() {
+            tangents = vec![Vector(0.0, 0.0, 0.0); vertices.len()];
+            for chunk in mesh.indices.chunks_exact(3) {
+                let (i, j, k) = (chunk[0] as usize, chunk[1] as usize, chunk[2] as usize);
+                let e1 = vertices[j] - vertices[i];
+                let e2 = vertices[k] - vertices[i];
+                let uv0 = texture_coordinates[i];
+                let uv1 = texture_coordinates[j];
+                let uv2 = texture_coordinates[k];
+                let uv01 = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+                let uv02 = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+                let determinant = uv01.0 * uv02.1 - uv01.1 * uv02.0;
+                let dpdu = (e1 * uv02.1 - e2 * uv01.1) / determinant;
+                if dpdu.magnitude_squared() > 0.0 && dpdu.magnitude_squared().is_finite() {
+                    let dpdu = dpdu.normalized();
+                    for index in [i, j, k] {
+                        tangents[index] += dpdu;
+                    }
                 }
+            }
+        }
 
-                let mut uv0 = (0.0, 0.0);
-                let mut uv1 = (1.0, 0.0);
-                let mut uv2 = (1.0, 1.0);
-                if texture_coordinates.len() > 0 {
-                    uv0 = texture_coordinates[i as usize];
-                    uv1 = texture_coordinates[j as usize];
-                    uv2 = texture_coordinates[k as usize];
-                }
+        let indices: Vec<[u32; 3]> = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|chunk| [chunk[0], chunk[1], chunk[2]])
+            .collect();
+        let mut triangles =
+            TriangleMesh::new(indices, vertices, normals, texture_coordinates, tangents);
+        if !step_models.is_empty() {
+            if emittance.is_some() {
+                // Lights are sampled where the triangles are, which doesn't
+                // depend on time
+                warn!("\tEmissive triangles don't deform, skipping motion steps");
+            } else {
+                let step_meshes = step_models.iter().map(|step| &step[i].mesh);
+                // Steps without the mesh's vertex normals keep its normals
+                let step_normals =
+                    |step_mesh: &tobj::Mesh| match step_mesh.normals.len() == mesh.normals.len() {
+                        true => to_normals(&step_mesh.normals),
+                        false => triangles.normals.clone(),
+                    };
+                let motion = VertexMotion {
+                    times: times.clone(),
+                    vertices: step_meshes
+                        .clone()
+                        .map(|step_mesh| to_vertices(&step_mesh.positions))
+                        .collect(),
+                    normals: match triangles.normals.is_empty() {
+                        true => Vec::new(),
+                        false => step_meshes.map(step_normals).collect(),
+                    },
+                };
+                triangles = triangles.with_motion(motion);
+            }
+        }
+        let triangles = Arc::new(triangles);
 
-                let triangle = Shape::new_triangle_with_normals_and_texture_coordinates(
-                    vi, vj, vk, ni, nj, nk, uv0, uv1, uv2,
-                );
+        // Triangles share the attributes of the mesh, except for emissive
+        // ones, which each need their own light
+        let shared = Arc::new(Mesh {
+            triangles: Arc::clone(&triangles),
+            material: Arc::clone(material),
+            alpha_mask: alpha_mask.clone(),
+        });
 
-                if let Some(triangle) = triangle {
-                    let triangle = Arc::new(triangle);
-                    let primitive = match emittance {
-                        None => Primitive::new(Arc::clone(&triangle), Arc::clone(&material)),
-                        Some(&emittance) => Primitive::new_area_light(
-                            Arc::clone(&triangle),
-                            Arc::new(Light::Area {
-                                shape: Arc::clone(&triangle),
-                                emittance,
-                            }),
-                        ),
-                    };
-                    primitives.push(Arc::new(primitive));
-                } else {
-                    debug!("\tSkipping degenerate triangle with vertices {i}, {j}, {k}");
-                }
+        for index in 0..triangles.num_triangles() {
+            if triangles.is_degenerate(index) {
+                let [i, j, k] = triangles.indices[index];
+                debug!("\tSkipping degenerate triangle with vertices {i}, {j}, {k}");
+                continue;
             }
+
+            let primitive = match emittance {
+                None => Primitive::new_mesh_triangle(Arc::clone(&shared), index),
+                Some(&emittance) => {
+                    let triangle = Arc::new(Shape::Triangle {
+                        mesh: Arc::clone(&triangles),
+                        index,
+                    });
+                    Primitive::new_area_light(
+                        Arc::clone(&triangle),
+                        Arc::new(Light::Area {
+                            shape: triangle,
+                            emittance,
+                        }),
+                    )
+                }
+            };
+            primitives.push(Arc::new(primitive));
         }
 
         debug!(
@@ -216,5 +516,5 @@ pub fn load_obj(file_name: &str, fallback_material: Arc<Material>) -> Vec<Arc<Pr
         file_name
     );
 
-    primitives
+    Ok(primitives)
 }
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use crate::{
     bxdf::SurfaceSample,
     color::Color,
@@ -21,6 +23,7 @@ struct PathSegmentSamples {
     light_index: Sample1d,
     light: (Sample1d, Sample2d),
     russian_roulette: Sample1d,
+    subsurface: Sample1d,
 }
 impl PathSegmentSamples {
     fn from<S>(sampler: &mut S) -> PathSegmentSamples
@@ -32,6 +35,7 @@ impl PathSegmentSamples {
             light_index: sampler.sample_1d(),
             light: (sampler.sample_1d(), sampler.sample_2d()),
             russian_roulette: sampler.sample_1d(),
+            subsurface: sampler.sample_1d(),
         }
     }
 }
@@ -50,12 +54,15 @@ where
     let mut is_specular_bounce = true;
     let mut prev_bsdf_pdf = 0.0;
     let mut prev_intersection: Option<PrimitiveIntersection> = None;
+    // Paths transmitted into a subsurface material continue from where they
+    // leave the object, rather than from the next intersection along the ray
+    let mut subsurface_exit: Option<PrimitiveIntersection> = None;
 
-    while bounces < scene.max_depth && !beta.is_black() {
+    while !beta.is_black() {
         // Both `w_o` and `w_i` should be coming out of the surface
         let w_o = -ray.direction;
 
-        let intersection = match scene.intersect(&mut ray) {
+        let intersection = match subsurface_exit.take().or_else(|| scene.intersect(&mut ray)) {
             Some(intersection) => intersection,
             None => {
                 // If the path escapes the scene, account for infinite lights
@@ -72,7 +79,7 @@ where
                     for (light_idx, light) in scene.lights.iter().enumerate() {
                         let Le = light.Le(&w_o);
                         if !Le.is_black() {
-                            let light_pdf = match light.pdf_Li(&prev_intersection, &w_o) {
+                            let light_pdf = match light.pdf_Li(&prev_intersection, &ray.direction) {
                                 Pdf::NonDelta(pdf) => pdf,
                                 Pdf::Delta => {
                                     unreachable!(
@@ -81,7 +88,7 @@ where
                                     )
                                 }
                             } * scene.light_sampler.pdf(light_idx);
-                            let weight = power_heuristic(1, light_pdf, 1, prev_bsdf_pdf);
+                            let weight = power_heuristic(1, prev_bsdf_pdf, 1, light_pdf);
                             L += beta * Le * weight;
                         }
                     }
@@ -89,13 +96,9 @@ where
                 break;
             }
         };
-        let PrimitiveIntersection {
-            normal,
-            location,
-            material,
-            uv,
-            ..
-        } = intersection;
+        let PrimitiveIntersection { material, uv, .. } = intersection;
+        // Materials are evaluated using the (possibly perturbed) shading normal
+        let normal = intersection.shading.normal;
 
         let path_samples = PathSegmentSamples::from(sampler);
 
@@ -113,18 +116,33 @@ where
                     .get_area_light()
                     .expect("Expected area light for emissive interaction");
                 // TODO: Avoid this linear search
-                let light_idx = scene.lights.iter().position(|l| l == light).unwrap();
-                let light_pdf = match light.pdf_Li(&intersection, &w_o) {
+                let light_idx = scene
+                    .lights
+                    .iter()
+                    .position(|l| Arc::ptr_eq(l, light))
+                    .unwrap();
+                // The light would have been sampled from the previous vertex,
+                // in the direction the path arrived from
+                let prev_intersection = prev_intersection
+                    .as_ref()
+                    .expect("Expected a previous vertex for a non specular bounce");
+                let light_pdf = match light.pdf_Li(prev_intersection, &ray.direction) {
                     Pdf::NonDelta(pdf) => pdf,
                     Pdf::Delta => {
                         unreachable!("Emissive light {:?} should not be a delta light", light)
                     }
                 } * scene.light_sampler.pdf(light_idx);
-                let weight = power_heuristic(1, light_pdf, 1, prev_bsdf_pdf);
+                let weight = power_heuristic(1, prev_bsdf_pdf, 1, light_pdf);
                 L += beta * Le * weight;
             }
         }
 
+        // The path ends here rather than at the top of the loop, so that light
+        // reached by sampling the BSDF at the previous vertex is included
+        if bounces == scene.max_depth {
+            break;
+        }
+
         // Sample a light and add contribution
         {
             let (light_index, light_sampler_pdf) =
@@ -138,7 +156,7 @@ where
                 shadow_ray,
             } = light.sample_Li(path_samples.light, &intersection);
 
-            if !scene.intersects(&shadow_ray) {
+            if intersection.normals_agree(&w_o, &w_i) && !scene.intersects(&shadow_ray) {
                 let f = material.f(&w_o, &w_i, &normal, &uv);
                 let cos_theta = w_i.dot(&normal).abs();
                 match light_pdf {
@@ -147,7 +165,7 @@ where
                             // If it's a non delta light, we can do MIS with the
                             // bsdf's pdf for the sampled direction
                             let light_pdf = light_pdf * light_sampler_pdf;
-                            let bsdf_pdf = match material.pdf(&w_o, &w_i, &normal) {
+                            let bsdf_pdf = match material.pdf(&w_o, &w_i, &normal, &uv) {
                                 Pdf::NonDelta(pdf) => pdf,
                                 Pdf::Delta => 0.0,
                             };
@@ -174,7 +192,9 @@ where
                 Some(surface_sample) => surface_sample,
                 None => break,
             };
-            if f.is_black() {
+            // A direction on the wrong side of the geometric surface would
+            // leak light through it
+            if f.is_black() || !intersection.normals_agree(&w_o, &w_i) {
                 break;
             }
             let cos_theta = w_i.dot(&normal).abs();
@@ -188,7 +208,25 @@ where
 
             beta = beta * f * cos_theta / bsdf_pdf;
 
-            ray = Ray::new(location, w_i);
+            ray = if is_specular {
+                intersection.spawn_specular_ray(&ray, &w_o, &w_i)
+            } else {
+                intersection.spawn_ray(w_i)
+            };
+            if let Some(subsurface) = material.subsurface() {
+                if !normal.same_hemisphere(&w_o, &w_i) {
+                    let exit =
+                        match subsurface.random_walk(scene, &ray, &uv, path_samples.subsurface) {
+                            Some(exit) => exit,
+                            None => break,
+                        };
+                    beta = beta * exit.throughput;
+                    // Set up the ray so that `w_o` is the direction the walk
+                    // left in
+                    ray = exit.intersection.spawn_ray(-exit.w_o);
+                    subsurface_exit = Some(exit.intersection);
+                }
+            }
             is_specular_bounce = is_specular;
             prev_bsdf_pdf = bsdf_pdf;
             prev_intersection = Some(intersection);
@@ -2,31 +2,142 @@ use std::sync::Arc;
 
 use crate::{
     bounds::Bounds,
+    bvh::Bvh,
     color::Color,
+    geometry::{point::Point, vector::Vector},
     intersection::{PrimitiveIntersection, ShapeIntersection},
     light::Light,
     material::Material,
+    mesh::TriangleMesh,
     ray::Ray,
     shape::Shape,
-    texture::Texture,
+    texture::{Texture, TextureCoordinates},
+    transformation::{AnimatedTransformation, Transformable, Transformation},
 };
 
+/// How the alpha value of a mask decides whether a surface is hit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+    /// Parts of the surface with alpha below the threshold are cut out
+    Threshold(f64),
+    /// Each hit is kept with probability alpha, which makes the surface
+    /// partially transparent on average
+    Stochastic,
+}
+
+/// Cuts out parts of a primitive's surface, e.g. to model leaves or fences
+/// with simple geometry
+#[derive(Debug)]
+pub struct AlphaMask {
+    pub alpha: Texture<f64>,
+    pub mode: AlphaMode,
+}
+
+impl AlphaMask {
+    pub fn new(alpha: Texture<f64>, mode: AlphaMode) -> Self {
+        Self { alpha, mode }
+    }
+
+    /// Whether the surface is present at the given hit
+    fn is_opaque(
+        &self,
+        world_to_object: Option<&Transformation>,
+        intersection: &ShapeIntersection,
+        ray: &Ray,
+    ) -> bool {
+        let (u, v) = intersection.uv;
+        let mut uv = TextureCoordinates::new(u, v);
+        uv.p = match world_to_object {
+            Some(world_to_object) => world_to_object.transform(&intersection.location),
+            None => intersection.location,
+        };
+        let alpha = self.alpha.eval(&uv);
+        match self.mode {
+            AlphaMode::Threshold(threshold) => alpha >= threshold,
+            AlphaMode::Stochastic => {
+                alpha >= 1.0
+                    || (alpha > 0.0 && hash_float(&intersection.location, &ray.direction) < alpha)
+            }
+        }
+    }
+}
+
+/// Hashes a hit to a float in [0, 1). The stochastic alpha test must give
+/// the same answer every time the same hit is tested, e.g. by both
+/// `intersect` and `intersects`, so it can't use the sampler.
+fn hash_float(location: &Point, direction: &Vector) -> f64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for value in [
+        location.x(),
+        location.y(),
+        location.z(),
+        direction.x(),
+        direction.y(),
+        direction.z(),
+    ] {
+        // SplitMix64 finalizer
+        let mut z = hash ^ value.to_bits();
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash = z ^ (z >> 31);
+    }
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The triangles of a mesh, with the attributes they share
+#[derive(Debug)]
+pub struct Mesh {
+    pub triangles: Arc<TriangleMesh>,
+    pub material: Arc<Material>,
+    pub alpha_mask: Option<Arc<AlphaMask>>,
+}
+
 #[derive(Debug)]
 pub enum Primitive {
     ShapePrimitive {
         shape: Arc<Shape>,
         material: Arc<Material>,
+        alpha_mask: Option<Arc<AlphaMask>>,
     },
     AreaLightPrimitive {
         shape: Arc<Shape>,
         material: Arc<Material>,
         area_light: Arc<Light>,
     },
+    /// Triangle `index` of a mesh, which only refers to the data it shares
+    /// with the rest of the mesh
+    MeshTriangle { mesh: Arc<Mesh>, index: usize },
+    /// A copy of an object placed in the scene with its own transformation,
+    /// which may move it during the shutter interval. All instances of the
+    /// object share its BVH, which rays are moved into the space of at their
+    /// time to intersect it.
+    Instance {
+        object: Arc<Bvh>,
+        object_to_world: Arc<AnimatedTransformation>,
+        /// Replaces the materials of all the object's primitives
+        material: Option<Arc<Material>>,
+    },
 }
 
 impl Primitive {
     pub fn new(shape: Arc<Shape>, material: Arc<Material>) -> Self {
-        Self::ShapePrimitive { shape, material }
+        Self::ShapePrimitive {
+            shape,
+            material,
+            alpha_mask: None,
+        }
+    }
+
+    pub fn new_alpha_masked(
+        shape: Arc<Shape>,
+        material: Arc<Material>,
+        alpha_mask: Arc<AlphaMask>,
+    ) -> Self {
+        Self::ShapePrimitive {
+            shape,
+            material,
+            alpha_mask: Some(alpha_mask),
+        }
     }
 
     pub fn new_area_light(shape: Arc<Shape>, area_light: Arc<Light>) -> Self {
@@ -47,51 +158,158 @@ impl Primitive {
         }
     }
 
+    pub fn new_mesh_triangle(mesh: Arc<Mesh>, index: usize) -> Self {
+        assert!(
+            index < mesh.triangles.num_triangles(),
+            "Triangle index out of range for mesh"
+        );
+        Self::MeshTriangle { mesh, index }
+    }
+
+    pub fn new_instance(
+        object: Arc<Bvh>,
+        object_to_world: AnimatedTransformation,
+        material: Option<Arc<Material>>,
+    ) -> Self {
+        Self::Instance {
+            object,
+            object_to_world: Arc::new(object_to_world),
+            material,
+        }
+    }
+
     pub fn intersect(&self, ray: &mut Ray) -> Option<PrimitiveIntersection> {
-        let (shape, material) = match self {
-            Primitive::ShapePrimitive {
-                shape, material, ..
-            } => (shape, material),
-            Primitive::AreaLightPrimitive {
-                shape, material, ..
-            } => (shape, material),
+        self.intersect_with_material(ray, None)
+    }
+
+    /// Like `intersect`, but with `material_override` replacing the
+    /// primitive's own material, which is how instances override the
+    /// materials of their objects
+    pub(crate) fn intersect_with_material<'a>(
+        &'a self,
+        ray: &mut Ray,
+        material_override: Option<&'a Material>,
+    ) -> Option<PrimitiveIntersection<'a>> {
+        let material = match self {
+            Primitive::ShapePrimitive { material, .. } => material,
+            Primitive::AreaLightPrimitive { material, .. } => material,
+            Primitive::MeshTriangle { mesh, .. } => &mesh.material,
+            Primitive::Instance {
+                object,
+                object_to_world,
+                material,
+            } => {
+                // Overrides from enclosing instances take precedence
+                let material_override = material_override.or(material.as_deref());
+                let object_to_world = object_to_world.at(ray.time);
+                let mut object_ray = object_to_world.inverse().transform(ray);
+                let intersection =
+                    object.intersect_with_material(&mut object_ray, material_override)?;
+                ray.max_distance = object_ray.max_distance;
+                return Some(object_to_world.transform(&intersection));
+            }
         };
+        let material = material_override.unwrap_or(material);
 
-        let ShapeIntersection {
-            location,
-            normal,
-            uv,
-        } = shape.intersect(ray)?;
-        Some(PrimitiveIntersection {
-            distance: ray.max_distance,
-            normal,
-            location,
-            uv,
+        let shape_intersection = self.intersect_shape(ray)?;
+        Some(PrimitiveIntersection::new(
+            shape_intersection,
+            ray,
             material,
-            primitive: self,
-        })
+            self,
+        ))
     }
 
     pub fn intersects(&self, ray: &Ray) -> bool {
-        let shape = match self {
-            Primitive::ShapePrimitive { shape, .. } => shape,
-            Primitive::AreaLightPrimitive { shape, .. } => shape,
+        if self.alpha_mask().is_none() {
+            return match self {
+                Primitive::ShapePrimitive { shape, .. }
+                | Primitive::AreaLightPrimitive { shape, .. } => shape.intersects(ray),
+                Primitive::MeshTriangle { mesh, index } => mesh.triangles.intersects(*index, ray),
+                Primitive::Instance {
+                    object,
+                    object_to_world,
+                    ..
+                } => object.intersects(&object_to_world.at(ray.time).inverse().transform(ray)),
+            };
+        }
+        self.intersect_shape(&mut Ray {
+            max_distance: ray.max_distance,
+            ..Ray::new(ray.origin, ray.direction).with_time(ray.time)
+        })
+        .is_some()
+    }
+
+    /// Finds the closest hit with the shape that isn't cut out by the alpha
+    /// mask, updating the ray's max distance
+    fn intersect_shape(&self, ray: &mut Ray) -> Option<ShapeIntersection> {
+        let intersect = |ray: &mut Ray| match self {
+            Primitive::ShapePrimitive { shape, .. }
+            | Primitive::AreaLightPrimitive { shape, .. } => shape.intersect(ray),
+            Primitive::MeshTriangle { mesh, index } => mesh.triangles.intersect(*index, ray),
+            Primitive::Instance { .. } => {
+                unreachable!("Instances are intersected through their object's BVH")
+            }
+        };
+        let alpha_mask = match self.alpha_mask() {
+            Some(alpha_mask) => alpha_mask,
+            None => return intersect(ray),
         };
 
-        shape.intersects(ray)
+        // A shape can be hit more than once along the ray, so keep searching
+        // past hits that are cut out
+        let mut offset = 0.0;
+        loop {
+            let mut remaining = Ray {
+                max_distance: ray.max_distance - offset,
+                ..Ray::new(ray.at(offset), ray.direction).with_time(ray.time)
+            };
+            let shape_intersection = intersect(&mut remaining)?;
+            offset += remaining.max_distance;
+            if alpha_mask.is_opaque(self.world_to_object(), &shape_intersection, ray) {
+                ray.max_distance = offset;
+                return Some(shape_intersection);
+            }
+        }
     }
 
     pub fn bounds(&self) -> Bounds {
         match self {
-            Primitive::AreaLightPrimitive { shape, .. } => shape,
-            Primitive::ShapePrimitive { shape, .. } => shape,
+            Primitive::ShapePrimitive { shape, .. }
+            | Primitive::AreaLightPrimitive { shape, .. } => shape.bounds(),
+            Primitive::MeshTriangle { mesh, index } => mesh.triangles.bounds(*index),
+            Primitive::Instance {
+                object,
+                object_to_world,
+                ..
+            } => object_to_world.motion_bounds(&object.bounds),
+        }
+    }
+
+    /// Transformation to the space that textures are evaluated in, if the
+    /// primitive's shape has one
+    pub fn world_to_object(&self) -> Option<&Transformation> {
+        match self {
+            Primitive::ShapePrimitive { shape, .. }
+            | Primitive::AreaLightPrimitive { shape, .. } => shape.world_to_object(),
+            Primitive::MeshTriangle { .. } | Primitive::Instance { .. } => None,
+        }
+    }
+
+    fn alpha_mask(&self) -> Option<&AlphaMask> {
+        match self {
+            Primitive::ShapePrimitive { alpha_mask, .. } => alpha_mask.as_deref(),
+            Primitive::AreaLightPrimitive { .. } => None,
+            Primitive::MeshTriangle { mesh, .. } => mesh.alpha_mask.as_deref(),
+            Primitive::Instance { .. } => None,
         }
-        .bounds()
     }
 
     pub fn get_area_light(&self) -> Option<&Arc<Light>> {
         match self {
-            Primitive::ShapePrimitive { .. } => None,
+            Primitive::ShapePrimitive { .. }
+            | Primitive::MeshTriangle { .. }
+            | Primitive::Instance { .. } => None,
             Primitive::AreaLightPrimitive { area_light, .. } => Some(area_light),
         }
     }
@@ -3,11 +3,26 @@ use crate::{
     geometry::{point::Point, vector::Vector},
 };
 
+/// Rays offset by one pixel in the x and y directions on the film from the
+/// main ray. These are used to estimate the footprint of the ray on surfaces it
+/// hits, for filtering textures.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RayDifferentials {
+    pub rx_origin: Point,
+    pub rx_direction: Vector,
+    pub ry_origin: Point,
+    pub ry_direction: Vector,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Ray {
     pub origin: Point,
     pub direction: Vector,
     pub max_distance: f64,
+    pub differentials: Option<RayDifferentials>,
+    /// When the ray is traced during the camera's shutter interval, which
+    /// decides where moving objects are
+    pub time: f64,
 }
 
 impl Ray {
@@ -16,6 +31,25 @@ impl Ray {
             origin,
             direction,
             max_distance: f64::INFINITY,
+            differentials: None,
+            time: 0.0,
+        }
+    }
+
+    /// Returns the ray at the given time, for rays continuing a path started
+    /// by a camera ray
+    pub fn with_time(self, time: f64) -> Ray {
+        Ray { time, ..self }
+    }
+
+    pub fn with_differentials(
+        origin: Point,
+        direction: Vector,
+        differentials: RayDifferentials,
+    ) -> Ray {
+        Ray {
+            differentials: Some(differentials),
+            ..Ray::new(origin, direction)
         }
     }
 
@@ -35,4 +69,17 @@ impl Ray {
             false
         }
     }
+
+    /// Scales the offset between the differential rays and this ray. Camera
+    /// rays are generated with differentials for a one pixel offset, so with
+    /// multiple samples per pixel, this should be scaled down to reflect the
+    /// smaller spacing between samples.
+    pub fn scale_differentials(&mut self, scale: f64) {
+        if let Some(d) = &mut self.differentials {
+            d.rx_origin = self.origin + (d.rx_origin - self.origin) * scale;
+            d.ry_origin = self.origin + (d.ry_origin - self.origin) * scale;
+            d.rx_direction = self.direction + (d.rx_direction - self.direction) * scale;
+            d.ry_direction = self.direction + (d.ry_direction - self.direction) * scale;
+        }
+    }
 }
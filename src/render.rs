@@ -0,0 +1,53 @@
+use crate::{color::Color, path_integrator, sampling::samplers::Sampler, scene::Scene};
+
+/// Estimates the radiance arriving at a pixel along a single camera ray
+#[allow(non_snake_case)]
+#[inline]
+pub fn render_pixel<S>(
+    sampler: &mut S,
+    x: usize,
+    y: usize,
+    sample_index: usize,
+    scene: &Scene,
+) -> Color
+where
+    S: Sampler,
+{
+    sampler.start_pixel(x, y, sample_index);
+    let film_sample = sampler.sample_2d();
+    let lens_sample = sampler.sample_2d();
+    let time_sample = sampler.sample_1d();
+
+    let mut ray = scene
+        .camera
+        .sample((film_sample, lens_sample, time_sample), x, y);
+    ray.scale_differentials(1.0 / (sampler.num_samples() as f64).sqrt());
+
+    // TODO: Allow picking integrator from command line
+    let L = path_integrator::estimate_Li(sampler, ray, scene);
+    // let L = simple_integrator::estimate_Li(sampler, ray, &scene);
+    L
+}
+
+/// Renders the whole film on the calling thread, returning the average of all
+/// samples for each pixel as RGB values in row major order. Since samplers are
+/// seeded per pixel, this produces the same image as rendering in tiles.
+pub fn render_image<S>(scene: &Scene, mut sampler: S) -> Vec<f32>
+where
+    S: Sampler,
+{
+    let (width, height) = scene.film_bounds();
+    let num_samples = sampler.num_samples();
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let mut color = Color::BLACK;
+            for sample_index in 0..num_samples {
+                color += render_pixel(&mut sampler, x, y, sample_index, scene);
+            }
+            let (r, g, b) = (color / num_samples as f64).into();
+            pixels.extend([r, g, b]);
+        }
+    }
+    pixels
+}
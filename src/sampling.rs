@@ -78,6 +78,11 @@ pub mod samplers {
     // can only be used via `take` which consumes `self`.
     pub struct Sample1d(f64);
     impl Sample1d {
+        /// For samples that don't come from a sampler, e.g. when a material
+        /// needs to sample another material with its own random numbers
+        pub fn new(value: f64) -> Self {
+            Self(value)
+        }
         pub fn take(self) -> f64 {
             self.0
         }
@@ -85,6 +90,9 @@ pub mod samplers {
 
     pub struct Sample2d(f64, f64);
     impl Sample2d {
+        pub fn new(x: f64, y: f64) -> Self {
+            Self(x, y)
+        }
         pub fn take(self) -> (f64, f64) {
             (self.0, self.1)
         }
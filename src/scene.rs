@@ -1,9 +1,9 @@
-use std::{sync::Arc, time::Instant};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
 use log::debug;
 
 use crate::{
-    bvh::{Bvh, SplitMethod},
+    bvh::{Bvh, BvhLayout, SplitMethod},
     camera::Camera,
     intersection::PrimitiveIntersection,
     light::{Light, LightSampler},
@@ -18,6 +18,9 @@ pub struct Scene {
     pub camera: Camera,
     pub lights: Vec<Arc<Light>>,
     pub light_sampler: LightSampler,
+    /// BVHs of the named objects placed by instances, which the objects of
+    /// the next frame of an animation are refit from
+    pub objects: HashMap<String, Arc<Bvh>>,
     bvh: Bvh,
 }
 
@@ -28,17 +31,62 @@ impl Scene {
         camera: Camera,
         lights: Vec<Arc<Light>>,
         primitives: Vec<Arc<Primitive>>,
+        split_method: SplitMethod,
+        bvh_layout: BvhLayout,
     ) -> Self {
-        // TODO: Maybe allow picking split method in scene files
         let start = Instant::now();
         debug!(
             "Scene with {} lights and {} primitives",
             lights.len(),
             primitives.len()
         );
-        let bvh = Bvh::new(primitives, SplitMethod::SAH);
+        let bvh = Bvh::with_layout(primitives, split_method, bvh_layout);
         debug!("BVH constructed in {:?}", start.elapsed());
 
+        Scene::with_bvh(max_depth, num_samples, camera, lights, bvh)
+    }
+
+    /// Builds the scene for the next frame of an animation, reusing this
+    /// frame's BVH. If the frame has as many primitives, they are assumed to
+    /// be the same ones moved to new places, and the BVH is refit to them.
+    pub fn next_frame(
+        self,
+        max_depth: usize,
+        num_samples: usize,
+        camera: Camera,
+        lights: Vec<Arc<Light>>,
+        primitives: Vec<Arc<Primitive>>,
+    ) -> Self {
+        let start = Instant::now();
+        let mut bvh = self.bvh;
+        if primitives.len() == bvh.num_primitives() {
+            let num_rebuilt = bvh.update(&primitives);
+            debug!(
+                "BVH refit in {:?}, rebuilding {} subtrees",
+                start.elapsed(),
+                num_rebuilt
+            );
+        } else {
+            bvh = Bvh::with_layout(primitives, bvh.split_method(), bvh.layout());
+            debug!(
+                "Number of primitives changed, BVH constructed in {:?}",
+                start.elapsed()
+            );
+        }
+
+        Scene::with_bvh(max_depth, num_samples, camera, lights, bvh)
+    }
+
+    /// Builds a scene around an already built BVH, such as one read from a
+    /// cache. Cameras that focus through a pixel are focused here.
+    pub fn with_bvh(
+        max_depth: usize,
+        num_samples: usize,
+        mut camera: Camera,
+        lights: Vec<Arc<Light>>,
+        bvh: Bvh,
+    ) -> Self {
+        camera.autofocus(|ray| bvh.intersect(ray).map(|intersection| intersection.location));
         let world_radius = bvh.bounds.diagonal().magnitude() * 0.5;
         let light_sampler = LightSampler::new(&lights, world_radius);
 
@@ -48,6 +96,7 @@ impl Scene {
             camera,
             lights,
             light_sampler,
+            objects: HashMap::new(),
             bvh,
         }
     }
@@ -563,6 +563,17 @@ pub mod parser {
             self.map.get_or(key, default)
         }
 
+        /// Returns the raw value for `key`, for values that can't be converted
+        /// with TryFrom
+        pub fn get_raw(&mut self, key: &str) -> Result<&mut RawValue, ParserError> {
+            self.used_keys.insert(key.to_string());
+            let location = &self.map.location;
+            self.map
+                .map
+                .get_mut(key)
+                .ok_or_else(|| ParserError::new(&format!("{} not found in map", key), location))
+        }
+
         pub fn location(&self) -> &Location {
             &self.map.location
         }
@@ -576,7 +587,7 @@ pub mod parser {
                 .keys()
                 .filter(|key| !self.used_keys.contains(key.as_str()))
                 .collect();
-            if unused_keys.len() > 0 {
+            if !unused_keys.is_empty() {
                 warn!(
                     "Found unused key(s) {:?} in {} at {}",
                     unused_keys, self.name, self.map.location
@@ -721,6 +732,19 @@ pub mod parser {
         }
     }
 
+    impl<'a: 'b, 'b> TryFrom<&'a mut RawValue> for &'b mut RawValueMap {
+        type Error = ParserError;
+        fn try_from(value: &'a mut RawValue) -> Result<Self, Self::Error> {
+            match value {
+                RawValue::Map(value) => Ok(value),
+                _ => Err(ParserError::without_location(&format!(
+                    "Cannot get RawValueMap, found {:?}",
+                    value
+                ))),
+            }
+        }
+    }
+
     /// Converts a RawValueMap into a hashmap with values of given type.
     /// Useful for objects where the keys are names of things of the same type.
     /// For e.g. {materials: {foo: Emissive {...}, bar: Plastic {...}}}
@@ -779,69 +803,160 @@ pub mod scene_parser {
         Location,
     };
     use crate::{
-        camera::Camera,
+        bvh::{Bvh, BvhLayout, SplitMethod},
+        cache::Cache,
+        camera::{Aperture, Camera},
         color::Color,
         film::Film,
-        geometry::{point::Point, vector::Vector},
+        geometry::{point::Point, vector::Vector, O},
         light::Light,
-        material::Material,
-        obj::load_obj,
-        primitive::Primitive,
+        material::{Material, Perturbation},
+        measured::MeasuredBRDF,
+        obj::load_deforming_obj,
+        primitive::{AlphaMask, AlphaMode, Primitive},
         scene::Scene,
         shape::Shape,
-        texture::{FromPixel, Texture},
+        texture::{
+            ColorSpace, FilterMethod, Pattern, Texel, Texture, TextureMapping, UvTransform,
+            WrapMode,
+        },
+        transformation::{AnimatedTransformation, Matrix, Transformable, Transformation},
+    };
+    use log::debug;
+    use std::{
+        collections::HashMap,
+        convert::{TryFrom, TryInto},
+        sync::Arc,
+        time::Instant,
     };
-    use std::{collections::HashMap, convert::TryFrom, sync::Arc};
 
     const DEFAULT_MAX_DEPTH: usize = 8;
     const DEFAULT_NUM_SAMPLES: usize = 4;
+    const DEFAULT_SPLIT_METHOD: SplitMethod = SplitMethod::SAH;
+    const DEFAULT_BVH_LAYOUT: BvhLayout = BvhLayout::Binary;
     const DEFAULT_FOCAL_DISTANCE: f64 = 1e6;
 
     /// RawValue -> Camera
-    impl TryFrom<&mut RawValue> for Camera {
-        type Error = ParserError;
-        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
-            let typed_map = match value {
-                RawValue::TypedMap(typed_map) => Ok(typed_map),
-                _ => Err(ParserError::without_location(&format!(
-                    "Cannot get Camera, found {:?}",
-                    value
-                ))),
-            }?;
-            let film: Film = typed_map.get("film")?;
-            let origin: Point = typed_map.get("origin")?;
-            let target: Point = typed_map.get("target")?;
-            let up: Vector = typed_map.get("up")?;
-            let lens_radius: f64 = typed_map.get_or("lens_radius", 0.0)?;
-            let focal_distance: f64 = typed_map.get_or("focal_distance", DEFAULT_FOCAL_DISTANCE)?;
-            match typed_map.name.as_str() {
-                "Perspective" => {
-                    let fov: f64 = typed_map.get("fov")?;
-
-                    Ok(Camera::perspective(
-                        film,
-                        origin,
-                        target,
-                        up,
-                        fov,
-                        lens_radius,
-                        focal_distance,
-                    ))
-                }
-                "Orthographic" => Ok(Camera::orthographic(
+    ///
+    /// Like primitives, this can't use the TryFrom pattern since textured
+    /// apertures rely on the named textures.
+    fn create_camera(
+        typed_map: &mut TypedRawValueMap,
+        textures: &mut NamedTextures,
+    ) -> Result<Camera, ParserError> {
+        let location = typed_map.location().clone();
+        let film: Film = typed_map.get("film")?;
+        let origin: Point = typed_map.get("origin")?;
+        let target: Point = typed_map.get("target")?;
+        let up: Vector = typed_map.get("up")?;
+        // The aperture can be given either as a radius, or as an f-number
+        // which divides the focal length into the aperture's diameter
+        let lens_radius: f64 = if typed_map.has("f_stop") {
+            if typed_map.has("lens_radius") {
+                return Err(ParserError::new(
+                    "Camera cannot have both lens_radius and f_stop",
+                    &location,
+                ));
+            }
+            let f_stop: f64 = typed_map.get("f_stop")?;
+            let focal_length: f64 = typed_map.get("focal_length")?;
+            if f_stop <= 0.0 || focal_length < 0.0 {
+                return Err(ParserError::new(
+                    "Camera needs a positive f_stop and focal_length",
+                    &location,
+                ));
+            }
+            focal_length / (2.0 * f_stop)
+        } else {
+            typed_map.get_or("lens_radius", 0.0)?
+        };
+        let focal_distance: f64 = typed_map.get_or("focal_distance", DEFAULT_FOCAL_DISTANCE)?;
+        if typed_map.has("focus") && typed_map.has("focal_distance") {
+            return Err(ParserError::new(
+                "Camera cannot have both focus and focal_distance",
+                &location,
+            ));
+        }
+        let shutter_open: f64 = typed_map.get_or("shutter_open", 0.0)?;
+        let shutter_close: f64 = typed_map.get_or("shutter_close", shutter_open)?;
+        if shutter_close < shutter_open {
+            return Err(ParserError::new(
+                "Shutter must close after it opens",
+                &location,
+            ));
+        }
+        let mut camera = match typed_map.name.as_str() {
+            "Perspective" => {
+                let fov: f64 = typed_map.get("fov")?;
+
+                Ok(Camera::perspective(
                     film,
                     origin,
                     target,
                     up,
+                    fov,
                     lens_radius,
                     focal_distance,
-                )),
-                _ => Err(ParserError::without_location(&format!(
-                    "Unknown camera type: {}",
-                    typed_map.name
-                ))),
+                ))
             }
+            "Orthographic" => Ok(Camera::orthographic(
+                film,
+                origin,
+                target,
+                up,
+                lens_radius,
+                focal_distance,
+            )),
+            _ => Err(ParserError::without_location(&format!(
+                "Unknown camera type: {}",
+                typed_map.name
+            ))),
+        }?;
+        camera.set_shutter(shutter_open, shutter_close);
+
+        if typed_map.has("focus") {
+            // Focus on a point, the target, or whatever is seen through a
+            // pixel
+            match typed_map.get_raw("focus")? {
+                RawValue::String(name) if name == "target" => camera.focus_on(&target),
+                RawValue::TypedMap(pixel) if pixel.name == "Pixel" => {
+                    camera.focus_through_pixel(pixel.get("x")?, pixel.get("y")?)
+                }
+                value => camera.focus_on(&value.try_into().map_err(|_| {
+                    ParserError::new(
+                        "Camera focus must be a Point, 'target' or a Pixel",
+                        &location,
+                    )
+                })?),
+            }
+        }
+
+        if typed_map.has("aperture") {
+            let aperture = match typed_map.get_raw("aperture")? {
+                RawValue::TypedMap(polygon) if polygon.name == "Polygon" => {
+                    let sides: usize = polygon.get("sides")?;
+                    if sides < 3 {
+                        return Err(ParserError::new(
+                            "Aperture polygon needs at least 3 sides",
+                            polygon.location(),
+                        ));
+                    }
+                    Aperture::Polygon {
+                        sides,
+                        rotation: polygon.get_or("rotation", 0.0)?,
+                    }
+                }
+                RawValue::String(name) if name == "circle" => Aperture::Circle,
+                _ => {
+                    let texture: Texture<f64> = get_texture(typed_map, "aperture", textures)?;
+                    Aperture::texture(&texture).ok_or_else(|| {
+                        ParserError::new("Aperture texture is zero everywhere", &location)
+                    })?
+                }
+            };
+            camera.set_aperture(aperture);
         }
+        Ok(camera)
     }
 
     /// RawValue -> Film
@@ -902,76 +1017,626 @@ pub mod scene_parser {
         }
     }
 
+    /// Textures declared in the `textures` block, which can be referenced by
+    /// name wherever a texture is expected. Since the same definition can be
+    /// used as either a Texture<Color> or a Texture<f64>, each one is parsed
+    /// when it is first referenced as a particular type.
+    #[derive(Default)]
+    struct NamedTextures {
+        definitions: HashMap<String, RawValue>,
+        colors: HashMap<String, Texture<Color>>,
+        floats: HashMap<String, Texture<f64>>,
+    }
+
+    /// Texel types that named textures can be parsed as
+    trait NamedTexel: Texel + for<'a> TryFrom<&'a mut RawValue, Error = ParserError> {
+        fn cache(textures: &mut NamedTextures) -> &mut HashMap<String, Texture<Self>>;
+    }
+
+    impl NamedTexel for Color {
+        fn cache(textures: &mut NamedTextures) -> &mut HashMap<String, Texture<Self>> {
+            &mut textures.colors
+        }
+    }
+
+    impl NamedTexel for f64 {
+        fn cache(textures: &mut NamedTextures) -> &mut HashMap<String, Texture<Self>> {
+            &mut textures.floats
+        }
+    }
+
+    impl NamedTextures {
+        fn new(definitions: Option<RawValue>) -> Result<Self, ParserError> {
+            let definitions = match definitions {
+                None => HashMap::new(),
+                Some(RawValue::Map(map)) => map.map,
+                Some(value) => {
+                    return Err(ParserError::without_location(&format!(
+                        "Cannot get textures Map, found {:?}",
+                        value
+                    )))
+                }
+            };
+            Ok(NamedTextures {
+                definitions,
+                ..NamedTextures::default()
+            })
+        }
+
+        fn get<T: NamedTexel>(&mut self, name: &str) -> Result<Texture<T>, ParserError> {
+            if let Some(texture) = T::cache(self).get(name) {
+                return Ok(texture.clone());
+            }
+
+            // Take the definition out of the map while parsing it so that
+            // textures it references can be looked up. This also means that a
+            // texture referencing itself won't be found.
+            let mut definition = self.definitions.remove(name).ok_or_else(|| {
+                ParserError::without_location(&format!(
+                    "Cannot find texture named '{}' (textures cannot reference themselves)",
+                    name
+                ))
+            })?;
+            let texture = create_texture(&mut definition, self);
+            self.definitions.insert(name.to_string(), definition);
+            let texture = texture.map_err(|e| ParserError {
+                message: format!("Error in texture '{}': {}", name, e.message),
+                ..e
+            })?;
+
+            T::cache(self).insert(name.to_string(), texture.clone());
+            Ok(texture)
+        }
+    }
+
     /// RawValue -> Texture
     ///
     /// Textures support a shorthand where a value of type T will work as a
-    /// constant texture instead of writing out a  Texture<T>.
-    impl<T> TryFrom<&mut RawValue> for Texture<T>
-    where
-        T: for<'a> TryFrom<&'a mut RawValue, Error = ParserError> + Copy + FromPixel,
-    {
+    /// constant texture instead of writing out a  Texture<T>, and a string
+    /// refers to a texture in the `textures` block.
+    ///
+    /// Like primitives, these can't use the TryFrom pattern since they rely on
+    /// the named textures.
+    fn create_texture<T: NamedTexel>(
+        value: &mut RawValue,
+        textures: &mut NamedTextures,
+    ) -> Result<Texture<T>, ParserError> {
+        // If a value of type T can be directly constructed from this raw
+        // value, create a constant texture using it
+        if let Ok(value) = T::try_from(value) {
+            return Ok(Texture::Constant(value));
+        }
+
+        let typed_map = match value {
+            RawValue::String(name) => return textures.get(name),
+            RawValue::TypedMap(typed_map) => typed_map,
+            _ => {
+                return Err(ParserError::without_location(&format!(
+                    "Cannot get Texture, found {:?}",
+                    value
+                )))
+            }
+        };
+        match typed_map.name.as_str() {
+            "Checkerboard" => Ok(Texture::checkerboard(
+                typed_map.get("a")?,
+                typed_map.get("b")?,
+                typed_map.get_or("scale", 1.0)?,
+            )),
+            "Image" => {
+                let file_name: String = typed_map.get("file_name")?;
+                let image = image::io::Reader::open(&file_name)
+                    .map_err(|e| e.to_string())
+                    .and_then(|reader| reader.decode().map_err(|e| e.to_string()))
+                    .map_err(|e| {
+                        ParserError::new(
+                            &format!("Error loading image '{}': {}", file_name, e),
+                            typed_map.location(),
+                        )
+                    })?;
+                let color_space =
+                    typed_map.get_or("color_space", ColorSpace::default_for(&image))?;
+                Ok(Texture::image_with_options(
+                    image,
+                    color_space,
+                    typed_map.get_or("filter", FilterMethod::Trilinear)?,
+                    typed_map.get_or("wrap", WrapMode::Repeat)?,
+                ))
+            }
+            "Noise" | "Fbm" | "Turbulence" | "Marble" | "Wood" | "Worley" => {
+                let pattern = match typed_map.name.as_str() {
+                    "Noise" => Pattern::Noise,
+                    "Fbm" => Pattern::Fbm {
+                        octaves: typed_map.get_or("octaves", 8)?,
+                        roughness: typed_map.get_or("roughness", 0.5)?,
+                    },
+                    "Turbulence" => Pattern::Turbulence {
+                        octaves: typed_map.get_or("octaves", 8)?,
+                        roughness: typed_map.get_or("roughness", 0.5)?,
+                    },
+                    "Marble" => Pattern::Marble {
+                        octaves: typed_map.get_or("octaves", 8)?,
+                        roughness: typed_map.get_or("roughness", 0.5)?,
+                        variation: typed_map.get_or("variation", 0.2)?,
+                    },
+                    "Wood" => Pattern::Wood {
+                        variation: typed_map.get_or("variation", 0.1)?,
+                    },
+                    _ => Pattern::Worley,
+                };
+                Ok(Texture::procedural(
+                    typed_map.get("a")?,
+                    typed_map.get("b")?,
+                    pattern,
+                    typed_map.get_or("mapping", TextureMapping::Object)?,
+                    typed_map.get_or("scale", 1.0)?,
+                ))
+            }
+            "Scale" => Ok(Texture::scale(
+                get_texture(typed_map, "texture", textures)?,
+                get_texture(typed_map, "scale", textures)?,
+            )),
+            "Multiply" => Ok(Texture::multiply(
+                get_texture(typed_map, "a", textures)?,
+                get_texture(typed_map, "b", textures)?,
+            )),
+            "Mix" => Ok(Texture::mix(
+                get_texture(typed_map, "a", textures)?,
+                get_texture(typed_map, "b", textures)?,
+                get_texture(typed_map, "amount", textures)?,
+            )),
+            "Ramp" => {
+                let factor = get_texture(typed_map, "factor", textures)?;
+                let positions: Vec<f64> = typed_map.get("positions")?;
+                let values: Vec<T> = typed_map.get("values")?;
+                if positions.len() != values.len() {
+                    return Err(ParserError::new(
+                        "Ramp needs the same number of positions and values",
+                        typed_map.location(),
+                    ));
+                }
+                Texture::ramp(factor, positions.into_iter().zip(values).collect()).ok_or_else(
+                    || ParserError::new("Ramp needs at least one stop", typed_map.location()),
+                )
+            }
+            "Invert" => Ok(Texture::invert(get_texture(
+                typed_map, "texture", textures,
+            )?)),
+            "UvTransform" => Ok(Texture::uv_transform(
+                get_texture(typed_map, "texture", textures)?,
+                UvTransform::new(
+                    (
+                        typed_map.get_or("offset_u", 0.0)?,
+                        typed_map.get_or("offset_v", 0.0)?,
+                    ),
+                    (
+                        typed_map.get_or("scale_u", 1.0)?,
+                        typed_map.get_or("scale_v", 1.0)?,
+                    ),
+                    typed_map.get_or("rotate", 0.0)?,
+                ),
+            )),
+            _ => Err(ParserError::new(
+                &format!("Unknown texture type: {}", typed_map.name),
+                typed_map.location(),
+            )),
+        }
+    }
+
+    /// Creates the texture for `key` in `typed_map`
+    fn get_texture<T: NamedTexel>(
+        typed_map: &mut TypedRawValueMap,
+        key: &str,
+        textures: &mut NamedTextures,
+    ) -> Result<Texture<T>, ParserError> {
+        let location = typed_map.location().clone();
+        create_texture(typed_map.get_raw(key)?, textures).map_err(|e| {
+            ParserError::new(
+                &format!(
+                    "Error converting map value for '{}' to expected type: {}",
+                    key, e.message
+                ),
+                &e.location.unwrap_or(location),
+            )
+        })
+    }
+
+    /// Like `get_texture`, but with a constant default if `key` is missing
+    fn get_texture_or<T: NamedTexel>(
+        typed_map: &mut TypedRawValueMap,
+        key: &str,
+        default: T,
+        textures: &mut NamedTextures,
+    ) -> Result<Texture<T>, ParserError> {
+        if typed_map.has(key) {
+            get_texture(typed_map, key, textures)
+        } else {
+            Ok(Texture::constant(default))
+        }
+    }
+
+    /// RawValue -> TextureMapping
+    impl TryFrom<&mut RawValue> for TextureMapping {
         type Error = ParserError;
         fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
-            // If a value of type T can be directly constructed from this raw
-            // value, create a constant texture using it
-            T::try_from(value)
-                .map(|value| Texture::Constant(value))
-                // Otherwise expect a typed map describing the texture
-                .or_else(|_| match value {
-                    RawValue::TypedMap(typed_map) => match typed_map.name.as_str() {
-                        "Checkerboard" => Ok(Texture::checkerboard(
-                            typed_map.get("a")?,
-                            typed_map.get("b")?,
-                            typed_map.get_or("scale", 1.0)?,
-                        )),
-                        _ => Err(ParserError::new(
-                            &format!("Unknown material type: {}", typed_map.name),
-                            &typed_map.location(),
-                        )),
-                    },
-                    _ => Err(ParserError::without_location(&format!(
-                        "Cannot get Color, found {:?}",
-                        value
-                    ))),
-                })
+            let name: String = value.try_into()?;
+            match name.as_str() {
+                "uv" => Ok(TextureMapping::Uv),
+                "object" => Ok(TextureMapping::Object),
+                _ => Err(ParserError::without_location(&format!(
+                    "Unknown texture mapping: {}",
+                    name
+                ))),
+            }
         }
     }
 
-    /// RawValue -> Material
-    impl TryFrom<&mut RawValue> for Arc<Material> {
+    /// RawValue -> ColorSpace
+    impl TryFrom<&mut RawValue> for ColorSpace {
         type Error = ParserError;
         fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
-            let typed_map = match value {
-                RawValue::TypedMap(typed_map) => Ok(typed_map),
+            let name: String = value.try_into()?;
+            match name.as_str() {
+                "linear" => Ok(ColorSpace::Linear),
+                "srgb" => Ok(ColorSpace::Srgb),
                 _ => Err(ParserError::without_location(&format!(
-                    "Cannot get Material, found {:?}",
-                    value
+                    "Unknown color space: {}",
+                    name
                 ))),
-            }?;
-            match typed_map.name.as_str() {
-                "Matte" => Ok(Arc::new(Material::new_matte(
-                    typed_map.get("reflectance")?,
-                    typed_map.get("sigma")?,
+            }
+        }
+    }
+
+    /// RawValue -> SplitMethod
+    impl TryFrom<&mut RawValue> for SplitMethod {
+        type Error = ParserError;
+        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
+            let name: String = value.try_into()?;
+            match name.as_str() {
+                "median" => Ok(SplitMethod::Median),
+                "sah" => Ok(SplitMethod::SAH),
+                "lbvh" => Ok(SplitMethod::Lbvh),
+                _ => Err(ParserError::without_location(&format!(
+                    "Unknown BVH split method: {}",
+                    name
                 ))),
-                "Glass" => Ok(Arc::new(Material::new_glass(
-                    typed_map.get("reflectance")?,
-                    typed_map.get("transmittance")?,
-                    typed_map.get("eta")?,
+            }
+        }
+    }
+
+    /// RawValue -> BvhLayout
+    impl TryFrom<&mut RawValue> for BvhLayout {
+        type Error = ParserError;
+        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
+            let name: String = value.try_into()?;
+            match name.as_str() {
+                "binary" => Ok(BvhLayout::Binary),
+                "wide" => Ok(BvhLayout::Wide),
+                _ => Err(ParserError::without_location(&format!(
+                    "Unknown BVH layout: {}",
+                    name
                 ))),
-                "Plastic" => Ok(Arc::new(Material::new_plastic(
-                    typed_map.get("diffuse")?,
-                    typed_map.get("specular")?,
-                    typed_map.get("roughness")?,
+            }
+        }
+    }
+
+    /// RawValue -> FilterMethod
+    impl TryFrom<&mut RawValue> for FilterMethod {
+        type Error = ParserError;
+        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
+            let name: String = value.try_into()?;
+            match name.as_str() {
+                "bilinear" => Ok(FilterMethod::Bilinear),
+                "trilinear" => Ok(FilterMethod::Trilinear),
+                "ewa" => Ok(FilterMethod::Ewa),
+                _ => Err(ParserError::without_location(&format!(
+                    "Unknown filter method: {}",
+                    name
                 ))),
-                "Metal" => Ok(Arc::new(Material::new_metal(
-                    typed_map.get("eta")?,
-                    typed_map.get("k")?,
+            }
+        }
+    }
+
+    /// RawValue -> WrapMode
+    impl TryFrom<&mut RawValue> for WrapMode {
+        type Error = ParserError;
+        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
+            let name: String = value.try_into()?;
+            match name.as_str() {
+                "repeat" => Ok(WrapMode::Repeat),
+                "clamp" => Ok(WrapMode::Clamp),
+                "mirror" => Ok(WrapMode::Mirror),
+                _ => Err(ParserError::without_location(&format!(
+                    "Unknown wrap mode: {}",
+                    name
                 ))),
-                _ => Err(ParserError::new(
+            }
+        }
+    }
+
+    /// Materials declared in the `materials` block. Mix and Coated materials
+    /// can refer to other materials by name, so they are parsed on demand.
+    struct NamedMaterials {
+        definitions: HashMap<String, RawValue>,
+        materials: HashMap<String, Arc<Material>>,
+    }
+
+    impl NamedMaterials {
+        fn new(definitions: HashMap<String, RawValue>) -> Self {
+            NamedMaterials {
+                definitions,
+                materials: HashMap::new(),
+            }
+        }
+
+        fn get(
+            &mut self,
+            name: &str,
+            textures: &mut NamedTextures,
+        ) -> Result<Arc<Material>, ParserError> {
+            if let Some(material) = self.materials.get(name) {
+                return Ok(Arc::clone(material));
+            }
+
+            // Like named textures, the definition is taken out of the map
+            // while parsing it so that a material can't reference itself
+            let mut definition = self.definitions.remove(name).ok_or_else(|| {
+                ParserError::without_location(&format!(
+                    "Cannot find material named '{}' (materials cannot reference themselves)",
+                    name
+                ))
+            })?;
+            let material = create_material(&mut definition, self, textures);
+            self.definitions.insert(name.to_string(), definition);
+            let material = material.map_err(|e| ParserError {
+                message: format!("Error in material '{}': {}", name, e.message),
+                ..e
+            })?;
+
+            self.materials
+                .insert(name.to_string(), Arc::clone(&material));
+            Ok(material)
+        }
+    }
+
+    /// A material given either by name or inline
+    fn get_material(
+        typed_map: &mut TypedRawValueMap,
+        key: &str,
+        materials: &mut NamedMaterials,
+        textures: &mut NamedTextures,
+    ) -> Result<Arc<Material>, ParserError> {
+        let location = typed_map.location().clone();
+        match typed_map.get_raw(key)? {
+            RawValue::String(name) => materials.get(name, textures),
+            value => create_material(value, materials, textures),
+        }
+        .map_err(|e| {
+            ParserError::new(
+                &format!("Error in '{}': {}", key, e.message),
+                &e.location.unwrap_or(location),
+            )
+        })
+    }
+
+    /// RawValue -> Material
+    fn create_material(
+        value: &mut RawValue,
+        materials: &mut NamedMaterials,
+        textures: &mut NamedTextures,
+    ) -> Result<Arc<Material>, ParserError> {
+        let typed_map = match value {
+            RawValue::TypedMap(typed_map) => Ok(typed_map),
+            _ => Err(ParserError::without_location(&format!(
+                "Cannot get Material, found {:?}",
+                value
+            ))),
+        }?;
+        let material = match typed_map.name.as_str() {
+            "Matte" => Material::new_matte(
+                get_texture(typed_map, "reflectance", textures)?,
+                get_texture(typed_map, "sigma", textures)?,
+            ),
+            "Glass" => Material::new_glass(
+                get_texture(typed_map, "reflectance", textures)?,
+                get_texture(typed_map, "transmittance", textures)?,
+                typed_map.get("eta")?,
+            ),
+            "ThinGlass" => Material::new_thin_glass(
+                get_texture(typed_map, "reflectance", textures)?,
+                get_texture(typed_map, "transmittance", textures)?,
+                typed_map.get("eta")?,
+            ),
+            "Translucent" => Material::new_translucent(
+                get_texture(typed_map, "reflectance", textures)?,
+                get_texture(typed_map, "transmittance", textures)?,
+            ),
+            "Plastic" => Material::new_plastic(
+                get_texture(typed_map, "diffuse", textures)?,
+                get_texture(typed_map, "specular", textures)?,
+                get_texture(typed_map, "roughness", textures)?,
+            ),
+            "Metal" => Material::new_metal(
+                get_texture(typed_map, "eta", textures)?,
+                get_texture(typed_map, "k", textures)?,
+            ),
+            "Mix" => Material::new_mix(
+                get_material(typed_map, "a", materials, textures)?,
+                get_material(typed_map, "b", materials, textures)?,
+                get_texture(typed_map, "amount", textures)?,
+            ),
+            "Coated" => Material::new_coated(
+                get_material(typed_map, "base", materials, textures)?,
+                typed_map.get_or("eta", 1.5)?,
+                get_texture_or(typed_map, "thickness", 0.01, textures)?,
+                get_texture_or(typed_map, "albedo", Color::BLACK, textures)?,
+                typed_map.get_or("max_depth", 10)?,
+                typed_map.get_or("num_samples", 1)?,
+            ),
+            "Measured" => {
+                let file_name: String = typed_map.get("file_name")?;
+                Material::Measured(MeasuredBRDF::load(&file_name).map_err(|e| {
+                    ParserError::new(
+                        &format!("Error loading measured BRDF '{}': {}", file_name, e),
+                        typed_map.location(),
+                    )
+                })?)
+            }
+            "Subsurface" => Material::new_subsurface(
+                get_texture(typed_map, "albedo", textures)?,
+                get_texture(typed_map, "mean_free_path", textures)?,
+                typed_map.get_or("eta", 1.33)?,
+            ),
+            _ => {
+                return Err(ParserError::new(
                     &format!("Unknown material type: {}", typed_map.name),
                     &typed_map.location(),
-                )),
+                ))
+            }
+        };
+
+        // Any material can have its shading normal perturbed. Normal map
+        // images store vectors rather than colors, so they should be loaded
+        // with `color_space: 'linear'`.
+        let perturbation = match (typed_map.has("normal_map"), typed_map.has("bump_map")) {
+            (false, false) => None,
+            (true, false) => Some(Perturbation::NormalMap(get_texture(
+                typed_map,
+                "normal_map",
+                textures,
+            )?)),
+            (false, true) => Some(Perturbation::BumpMap(get_texture(
+                typed_map, "bump_map", textures,
+            )?)),
+            (true, true) => {
+                return Err(ParserError::new(
+                    "A material cannot have both a normal_map and a bump_map",
+                    &typed_map.location(),
+                ))
             }
+        };
+        Ok(Arc::new(match perturbation {
+            Some(perturbation) => Material::new_perturbed(material, perturbation),
+            None => material,
+        }))
+    }
+
+    /// RawValue -> Transformation
+    ///
+    /// A transform is either one of `Translate { x, y, z }`, `Scale { x, y, z }`,
+    /// `Rotate { axis, angle }` (in degrees), `Matrix { m }` (16 numbers in row
+    /// major order) and `LookAt { origin, target, up }`, or a list of them. The
+    /// transforms in a list are applied in order, so
+    /// `[Scale { x: 2 }, Translate { y: 1 }]` stretches an object before moving
+    /// it.
+    impl TryFrom<&mut RawValue> for Transformation {
+        type Error = ParserError;
+        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
+            let typed_map = match value {
+                RawValue::Array(array) => {
+                    return array.array.iter_mut().try_fold(
+                        Transformation::I,
+                        |transformation, value| {
+                            Ok(Transformation::try_from(value)? * transformation)
+                        },
+                    );
+                }
+                RawValue::TypedMap(typed_map) => Ok(typed_map),
+                _ => Err(ParserError::without_location(&format!(
+                    "Cannot get Transformation, found {:?}",
+                    value
+                ))),
+            }?;
+            let transformation = match typed_map.name.as_str() {
+                "Translate" => Transformation::translate(
+                    typed_map.get_or("x", 0.0)?,
+                    typed_map.get_or("y", 0.0)?,
+                    typed_map.get_or("z", 0.0)?,
+                ),
+                "Scale" => Transformation::scale(
+                    typed_map.get_or("x", 1.0)?,
+                    typed_map.get_or("y", 1.0)?,
+                    typed_map.get_or("z", 1.0)?,
+                ),
+                "Rotate" => {
+                    let axis: Vector = typed_map.get("axis")?;
+                    if axis.magnitude_squared() == 0.0 {
+                        return Err(ParserError::new(
+                            "Rotation axis must not be zero",
+                            typed_map.location(),
+                        ));
+                    }
+                    let angle: f64 = typed_map.get("angle")?;
+                    Transformation::rotate(angle.to_radians(), &axis)
+                }
+                "Matrix" => {
+                    let values: Vec<f64> = typed_map.get("m")?;
+                    if values.len() != 16 {
+                        return Err(ParserError::new(
+                            &format!("Matrix needs 16 values, found {}", values.len()),
+                            typed_map.location(),
+                        ));
+                    }
+                    let mut matrix = Matrix::I;
+                    for (i, value) in values.into_iter().enumerate() {
+                        matrix.m[i / 4][i % 4] = value;
+                    }
+                    let inverse = matrix.inverse().ok_or_else(|| {
+                        ParserError::new("Matrix transform is not invertible", typed_map.location())
+                    })?;
+                    Transformation { matrix, inverse }
+                }
+                "LookAt" => Transformation::look_at(
+                    typed_map.get("origin")?,
+                    typed_map.get("target")?,
+                    typed_map.get("up")?,
+                ),
+                _ => {
+                    return Err(ParserError::new(
+                        &format!("Unknown transform type: {}", typed_map.name),
+                        typed_map.location(),
+                    ))
+                }
+            };
+            if !transformation.is_valid() {
+                return Err(ParserError::new(
+                    &format!("{} transform is not invertible", typed_map.name),
+                    typed_map.location(),
+                ));
+            }
+            Ok(transformation)
+        }
+    }
+
+    /// A transform that moves during the shutter interval is given as
+    /// `Animated { keyframes: [Keyframe { time, transform }, ...] }`, and is
+    /// interpolated between the keyframes. Any other transform stays still.
+    impl TryFrom<&mut RawValue> for AnimatedTransformation {
+        type Error = ParserError;
+        fn try_from(value: &mut RawValue) -> Result<Self, Self::Error> {
+            let typed_map = match value {
+                RawValue::TypedMap(typed_map) if typed_map.name == "Animated" => typed_map,
+                _ => return Ok(Transformation::try_from(value)?.into()),
+            };
+            let location = typed_map.location().clone();
+            let keyframe_defs: Vec<&mut TypedRawValueMap> = typed_map.get("keyframes")?;
+            if keyframe_defs.is_empty() {
+                return Err(ParserError::new(
+                    "Animated transform needs at least one keyframe",
+                    &location,
+                ));
+            }
+            let mut keyframes = Vec::new();
+            for keyframe_def in keyframe_defs {
+                if keyframe_def.name != "Keyframe" {
+                    return Err(ParserError::new(
+                        &format!("Expected Keyframe, found {}", keyframe_def.name),
+                        keyframe_def.location(),
+                    ));
+                }
+                keyframes.push((keyframe_def.get("time")?, keyframe_def.get("transform")?));
+            }
+            Ok(AnimatedTransformation::new(keyframes))
         }
     }
 
@@ -986,11 +1651,10 @@ pub mod scene_parser {
                     value
                 ))),
             }?;
-            match typed_map.name.as_str() {
-                "Sphere" => Ok(Arc::new(Shape::new_sphere(
-                    typed_map.get("origin")?,
-                    typed_map.get("radius")?,
-                ))),
+            let mut shape = match typed_map.name.as_str() {
+                "Sphere" => {
+                    Shape::new_sphere(typed_map.get_or("origin", O)?, typed_map.get("radius")?)
+                }
                 "Triangle" => Shape::new_triangle(
                     typed_map.get("v0")?,
                     typed_map.get("v1")?,
@@ -1001,20 +1665,205 @@ pub mod scene_parser {
                         &format!("Degenerate triangle: {}", typed_map.name),
                         &typed_map.location(),
                     )
-                })
-                .map(|shape| Arc::new(shape)),
-                "Disk" => Ok(Arc::new(Shape::new_disk(
-                    typed_map.get("origin")?,
+                })?,
+                "Disk" => Shape::new_disk(
+                    typed_map.get_or("origin", O)?,
                     typed_map.get_or("rotate_x", 0.0)?,
                     typed_map.get_or("rotate_y", 0.0)?,
                     typed_map.get("radius")?,
                     typed_map.get_or("inner_radius", 0.0)?,
-                ))),
-                _ => Err(ParserError::without_location(&format!(
-                    "Unknown shape type: {}",
-                    typed_map.name
-                ))),
+                ),
+                "Cylinder" => Shape::new_cylinder(
+                    typed_map.get_or("origin", O)?,
+                    typed_map.get_or("rotate_x", 0.0)?,
+                    typed_map.get_or("rotate_y", 0.0)?,
+                    typed_map.get("radius")?,
+                    typed_map.get("z_min")?,
+                    typed_map.get("z_max")?,
+                    typed_map.get_or("phi_max", 360.0)?,
+                ),
+                "Cone" => Shape::new_cone(
+                    typed_map.get_or("origin", O)?,
+                    typed_map.get_or("rotate_x", 0.0)?,
+                    typed_map.get_or("rotate_y", 0.0)?,
+                    typed_map.get("radius")?,
+                    typed_map.get("height")?,
+                    typed_map.get_or("phi_max", 360.0)?,
+                ),
+                "Paraboloid" => Shape::new_paraboloid(
+                    typed_map.get_or("origin", O)?,
+                    typed_map.get_or("rotate_x", 0.0)?,
+                    typed_map.get_or("rotate_y", 0.0)?,
+                    typed_map.get("radius")?,
+                    typed_map.get_or("z_min", 0.0)?,
+                    typed_map.get("z_max")?,
+                    typed_map.get_or("phi_max", 360.0)?,
+                ),
+                "Hyperboloid" => Shape::new_hyperboloid(
+                    typed_map.get_or("origin", O)?,
+                    typed_map.get_or("rotate_x", 0.0)?,
+                    typed_map.get_or("rotate_y", 0.0)?,
+                    typed_map.get("p1")?,
+                    typed_map.get("p2")?,
+                    typed_map.get_or("phi_max", 360.0)?,
+                )
+                .ok_or_else(|| {
+                    ParserError::new(
+                        "Degenerate hyperboloid: p1 and p2 must be at different heights",
+                        typed_map.location(),
+                    )
+                })?,
+                _ => {
+                    return Err(ParserError::without_location(&format!(
+                        "Unknown shape type: {}",
+                        typed_map.name
+                    )))
+                }
+            };
+
+            // Shapes can be placed more freely with a general transform,
+            // which is applied after their own parameters
+            if typed_map.has("transform") {
+                let object_to_world: Transformation = typed_map.get("transform")?;
+                shape = object_to_world.transform(&shape);
+            }
+            Ok(Arc::new(shape))
+        }
+    }
+
+    /// Alpha masks are described by an `alpha` texture and an `alpha_mode`,
+    /// which is either 'threshold' (the default) with an `alpha_threshold`, or
+    /// 'stochastic'
+    fn create_alpha_mask(
+        primitive_def: &mut TypedRawValueMap,
+        textures: &mut NamedTextures,
+    ) -> Result<AlphaMask, ParserError> {
+        let alpha = get_texture(primitive_def, "alpha", textures)?;
+        let mode_name: String = primitive_def.get_or("alpha_mode", "threshold".to_string())?;
+        let mode = match mode_name.as_str() {
+            "threshold" => AlphaMode::Threshold(primitive_def.get_or("alpha_threshold", 0.5)?),
+            "stochastic" => AlphaMode::Stochastic,
+            _ => {
+                return Err(ParserError::new(
+                    &format!("Unknown alpha mode: {}", mode_name),
+                    primitive_def.location(),
+                ))
+            }
+        };
+        Ok(AlphaMask::new(alpha, mode))
+    }
+
+    /// Objects that can be placed in the scene many times with `Instance`
+    /// primitives. Each is defined like a primitive, and its BVH is built the
+    /// first time it is instanced, or refit from the previous frame's.
+    struct NamedObjects<'a> {
+        definitions: HashMap<String, RawValue>,
+        objects: HashMap<String, Arc<Bvh>>,
+        /// Objects of the previous frame of an animation
+        previous: HashMap<String, Arc<Bvh>>,
+        split_method: SplitMethod,
+        bvh_layout: BvhLayout,
+        /// Where meshes and BVHs are cached, with the scene source that
+        /// object BVHs are keyed by
+        cache: Option<(&'a Cache, &'a str)>,
+    }
+
+    impl<'a> NamedObjects<'a> {
+        fn new(
+            definitions: Option<RawValue>,
+            previous: HashMap<String, Arc<Bvh>>,
+            split_method: SplitMethod,
+            bvh_layout: BvhLayout,
+            cache: Option<(&'a Cache, &'a str)>,
+        ) -> Result<Self, ParserError> {
+            let definitions = match definitions {
+                None => HashMap::new(),
+                Some(RawValue::Map(map)) => map.map,
+                Some(value) => {
+                    return Err(ParserError::without_location(&format!(
+                        "Cannot get objects Map, found {:?}",
+                        value
+                    )))
+                }
+            };
+            Ok(NamedObjects {
+                definitions,
+                objects: HashMap::new(),
+                previous,
+                split_method,
+                bvh_layout,
+                cache,
+            })
+        }
+
+        fn get(
+            &mut self,
+            name: &str,
+            materials: &HashMap<String, Arc<Material>>,
+            shapes: &HashMap<String, Arc<Shape>>,
+            textures: &mut NamedTextures,
+        ) -> Result<Arc<Bvh>, ParserError> {
+            if let Some(object) = self.objects.get(name) {
+                return Ok(Arc::clone(object));
             }
+
+            // Like named materials, the definition is taken out of the map
+            // while parsing it so that an object can't instance itself
+            let mut definition = self.definitions.remove(name).ok_or_else(|| {
+                ParserError::without_location(&format!(
+                    "Cannot find object named '{}' (objects cannot instance themselves)",
+                    name
+                ))
+            })?;
+            let primitives = (&mut definition).try_into().and_then(|object_def| {
+                create_primitives(object_def, materials, shapes, textures, self)
+            });
+            self.definitions.insert(name.to_string(), definition);
+            let primitives = primitives.map_err(|e| ParserError {
+                message: format!("Error in object '{}': {}", name, e.message),
+                ..e
+            })?;
+
+            if primitives.is_empty() {
+                return Err(ParserError::without_location(&format!(
+                    "Object '{}' has no primitives",
+                    name
+                )));
+            }
+            // Area lights are sampled in world space, so they have to be
+            // placed in the scene directly
+            if primitives.iter().any(|p| p.get_area_light().is_some()) {
+                return Err(ParserError::without_location(&format!(
+                    "Object '{}' has emissive primitives, which cannot be instanced",
+                    name
+                )));
+            }
+
+            // Like the scene's BVH, an object with as many primitives as in
+            // the previous frame is assumed to have the same ones moved. The
+            // previous frame's instances still use its BVH, so it is copied.
+            let previous = self.previous.remove(name).filter(|previous| {
+                previous.num_primitives() == primitives.len()
+                    && previous.split_method() == self.split_method
+                    && previous.layout() == self.bvh_layout
+            });
+            let object = Arc::new(match (previous, self.cache) {
+                (Some(previous), _) => {
+                    let mut object =
+                        Arc::try_unwrap(previous).unwrap_or_else(|previous| (*previous).clone());
+                    object.update(&primitives);
+                    object
+                }
+                (None, Some((cache, source))) => cache.load_bvh(
+                    &format!("object {}\n{}", name, source),
+                    primitives,
+                    self.split_method,
+                    self.bvh_layout,
+                ),
+                (None, None) => Bvh::with_layout(primitives, self.split_method, self.bvh_layout),
+            });
+            self.objects.insert(name.to_string(), Arc::clone(&object));
+            Ok(object)
         }
     }
 
@@ -1026,6 +1875,8 @@ pub mod scene_parser {
         primitive_def: &mut TypedRawValueMap,
         materials: &HashMap<String, Arc<Material>>,
         shapes: &HashMap<String, Arc<Shape>>,
+        textures: &mut NamedTextures,
+        objects: &mut NamedObjects,
     ) -> Result<Vec<Arc<Primitive>>, ParserError> {
         match primitive_def.name.as_str() {
             "Shape" => {
@@ -1034,6 +1885,25 @@ pub mod scene_parser {
                     &format!("Cannot find shape named '{}'", shape_name),
                     &primitive_def.location(),
                 ))?;
+                // Transforming the primitive places a copy of the shape, so
+                // that named shapes can be reused. Moving shapes are placed
+                // in an instance instead, which is transformed at each ray's
+                // time.
+                let mut motion = None;
+                let shape = match primitive_def.has("transform") {
+                    false => Arc::clone(shape),
+                    true => {
+                        let object_to_world: AnimatedTransformation =
+                            primitive_def.get("transform")?;
+                        match object_to_world.is_animated() {
+                            false => Arc::new(object_to_world.at(0.0).transform(&**shape)),
+                            true => {
+                                motion = Some(object_to_world);
+                                Arc::clone(shape)
+                            }
+                        }
+                    }
+                };
 
                 let primitive = match primitive_def.has("emittance") {
                     false => {
@@ -1042,18 +1912,33 @@ pub mod scene_parser {
                             &format!("Cannot find material named '{}'", material_name),
                             &primitive_def.location(),
                         ))?;
-                        Primitive::new(Arc::clone(shape), Arc::clone(material))
+                        match primitive_def.has("alpha") {
+                            false => Primitive::new(Arc::clone(&shape), Arc::clone(material)),
+                            true => Primitive::new_alpha_masked(
+                                Arc::clone(&shape),
+                                Arc::clone(material),
+                                Arc::new(create_alpha_mask(primitive_def, textures)?),
+                            ),
+                        }
                     }
                     true => {
                         let area_light = Arc::new(Light::Area {
-                            shape: Arc::clone(shape),
+                            shape: Arc::clone(&shape),
                             emittance: primitive_def.get("emittance")?,
                         });
-                        Primitive::new_area_light(Arc::clone(shape), Arc::clone(&area_light))
+                        Primitive::new_area_light(Arc::clone(&shape), Arc::clone(&area_light))
                     }
                 };
 
-                Ok(vec![Arc::new(primitive)])
+                match motion {
+                    None => Ok(vec![Arc::new(primitive)]),
+                    Some(object_to_world) => animate(
+                        vec![Arc::new(primitive)],
+                        object_to_world,
+                        objects,
+                        primitive_def.location(),
+                    ),
+                }
             }
             "Mesh" => {
                 let file_name: String = primitive_def.get("file_name")?;
@@ -1064,10 +1949,88 @@ pub mod scene_parser {
                     &primitive_def.location(),
                 ))?;
 
-                let primitives = load_obj(&file_name, Arc::clone(fallback_material));
+                let object_to_world: AnimatedTransformation =
+                    primitive_def.get_or("transform", Transformation::I.into())?;
+                let static_transform = match object_to_world.is_animated() {
+                    false => object_to_world.at(0.0).into_owned(),
+                    true => Transformation::I,
+                };
 
+                // Deforming meshes are given as `MotionStep { time, file_name }`
+                // for each time after `time`, in OBJ files with the same
+                // triangles whose vertices are where the mesh's are then
+                let time = primitive_def.get_or("time", 0.0)?;
+                let mut motion_steps = Vec::new();
+                if primitive_def.has("motion_steps") {
+                    let step_defs: Vec<&mut TypedRawValueMap> =
+                        primitive_def.get("motion_steps")?;
+                    for step_def in step_defs {
+                        if step_def.name != "MotionStep" {
+                            return Err(ParserError::new(
+                                &format!("Expected MotionStep, found {}", step_def.name),
+                                step_def.location(),
+                            ));
+                        }
+                        motion_steps.push((step_def.get("time")?, step_def.get("file_name")?));
+                    }
+                }
+                let primitives = load_deforming_obj(
+                    &file_name,
+                    time,
+                    &motion_steps,
+                    Arc::clone(fallback_material),
+                    &static_transform,
+                    objects.cache.map(|(cache, _)| cache),
+                )
+                .map_err(|message| ParserError::new(&message, primitive_def.location()))?;
+
+                match object_to_world.is_animated() {
+                    false => Ok(primitives),
+                    true => animate(
+                        primitives,
+                        object_to_world,
+                        objects,
+                        primitive_def.location(),
+                    ),
+                }
+            }
+            "Group" => {
+                let primitive_defs: Vec<&mut TypedRawValueMap> = primitive_def.get("primitives")?;
+                let mut primitives = Vec::new();
+                for primitive_def in primitive_defs {
+                    primitives.extend(create_primitives(
+                        primitive_def,
+                        materials,
+                        shapes,
+                        textures,
+                        objects,
+                    )?);
+                }
                 Ok(primitives)
             }
+            "Instance" => {
+                let object_name: String = primitive_def.get("object")?;
+                let object = objects.get(&object_name, materials, shapes, textures)?;
+                let object_to_world: AnimatedTransformation =
+                    primitive_def.get_or("transform", Transformation::I.into())?;
+                let material = match primitive_def.has("material") {
+                    false => None,
+                    true => {
+                        let material_name: String = primitive_def.get("material")?;
+                        let material = materials.get(&material_name).ok_or(ParserError::new(
+                            &format!("Cannot find material named '{}'", material_name),
+                            primitive_def.location(),
+                        ))?;
+                        Some(Arc::clone(material))
+                    }
+                };
+
+                Ok(vec![Arc::new(Primitive::new_instance(
+                    object,
+                    object_to_world,
+                    material,
+                ))])
+            }
             _ => Err(ParserError::new(
                 &format!("Unknown primitive type: {}", primitive_def.name),
                 &primitive_def.location(),
@@ -1075,7 +2038,53 @@ pub mod scene_parser {
         }
     }
 
+    /// Places primitives that move during the shutter interval in an instance
+    /// with their motion
+    fn animate(
+        primitives: Vec<Arc<Primitive>>,
+        object_to_world: AnimatedTransformation,
+        objects: &NamedObjects,
+        location: &Location,
+    ) -> Result<Vec<Arc<Primitive>>, ParserError> {
+        // Like instanced objects, area lights can't be moved since they are
+        // sampled in world space
+        if primitives.iter().any(|p| p.get_area_light().is_some()) {
+            return Err(ParserError::new(
+                "Emissive primitives cannot be animated",
+                location,
+            ));
+        }
+        if primitives.is_empty() {
+            return Ok(primitives);
+        }
+        let object = Bvh::with_layout(primitives, objects.split_method, objects.bvh_layout);
+        Ok(vec![Arc::new(Primitive::new_instance(
+            Arc::new(object),
+            object_to_world,
+            None,
+        ))])
+    }
+
     pub fn parse_scene(input: &str) -> Result<Scene, ParserError> {
+        parse_scene_with_options(input, None, None)
+    }
+
+    /// Parses a frame of an animation, whose BVH is refit from the previous
+    /// frame's scene rather than built from scratch
+    pub fn parse_scene_frame(input: &str, previous: Scene) -> Result<Scene, ParserError> {
+        parse_scene_with_options(input, Some(previous), None)
+    }
+
+    /// Parses a scene, or a frame of an animation if `previous` is given,
+    /// loading meshes and BVHs from `cache` if given
+    pub fn parse_scene_with_options(
+        input: &str,
+        previous: Option<Scene>,
+        cache: Option<&Cache>,
+    ) -> Result<Scene, ParserError> {
+        if let Some(cache) = cache {
+            cache.start_scene();
+        }
         let tokens = tokenize(input)?;
 
         let mut tokens = tokens.iter().peekable();
@@ -1083,16 +2092,45 @@ pub mod scene_parser {
 
         let max_depth: usize = scene_map.get_or("max_depth", DEFAULT_MAX_DEPTH)?;
         let num_samples: usize = scene_map.get_or("num_samples", DEFAULT_NUM_SAMPLES)?;
-        let camera: Camera = scene_map.get("camera")?;
+        let split_method: SplitMethod =
+            scene_map.get_or("bvh_split_method", DEFAULT_SPLIT_METHOD)?;
+        let bvh_layout: BvhLayout = scene_map.get_or("bvh_layout", DEFAULT_BVH_LAYOUT)?;
 
         let mut lights: Vec<Arc<Light>> = scene_map.get("lights")?;
-        let materials: HashMap<String, Arc<Material>> = scene_map.get("materials")?;
+
+        let mut textures = NamedTextures::new(scene_map.map.remove("textures"))?;
+        let camera = create_camera(scene_map.get("camera")?, &mut textures)?;
+        let material_defs: &mut RawValueMap = scene_map.get("materials")?;
+        let mut named_materials = NamedMaterials::new(std::mem::take(&mut material_defs.map));
+        let material_names: Vec<String> = named_materials.definitions.keys().cloned().collect();
+        for name in material_names {
+            named_materials.get(&name, &mut textures)?;
+        }
+        let materials = named_materials.materials;
+
         let shapes: HashMap<String, Arc<Shape>> = scene_map.get("shapes")?;
+        let (previous_objects, previous) = match previous {
+            Some(mut previous) => (std::mem::take(&mut previous.objects), Some(previous)),
+            None => (HashMap::new(), None),
+        };
+        let mut objects = NamedObjects::new(
+            scene_map.map.remove("objects"),
+            previous_objects,
+            split_method,
+            bvh_layout,
+            cache.map(|cache| (cache, input)),
+        )?;
         let primitive_defs: Vec<&mut TypedRawValueMap> = scene_map.get("primitives")?;
 
         let mut primitives: Vec<Arc<Primitive>> = Vec::new();
         for primitive_def in primitive_defs {
-            for primitive in create_primitives(primitive_def, &materials, &shapes)? {
+            for primitive in create_primitives(
+                primitive_def,
+                &materials,
+                &shapes,
+                &mut textures,
+                &mut objects,
+            )? {
                 if let Some(area_light) = primitive.get_area_light() {
                     lights.push(Arc::clone(area_light));
                 }
@@ -1100,19 +2138,36 @@ pub mod scene_parser {
             }
         }
 
-        if lights.len() == 0 {
+        if lights.is_empty() {
             return Err(ParserError::new(
                 "No lights in the scene.",
                 &Location { line: 0, column: 0 },
             ));
         }
 
-        Ok(Scene::new(
-            max_depth,
-            num_samples,
-            camera,
-            lights,
-            primitives,
-        ))
+        let mut scene = match previous {
+            Some(previous) => {
+                previous.next_frame(max_depth, num_samples, camera, lights, primitives)
+            }
+            None => match cache {
+                Some(cache) => {
+                    let start = Instant::now();
+                    let bvh = cache.load_bvh(input, primitives, split_method, bvh_layout);
+                    debug!("BVH loaded in {:?}", start.elapsed());
+                    Scene::with_bvh(max_depth, num_samples, camera, lights, bvh)
+                }
+                None => Scene::new(
+                    max_depth,
+                    num_samples,
+                    camera,
+                    lights,
+                    primitives,
+                    split_method,
+                    bvh_layout,
+                ),
+            },
+        };
+        scene.objects = objects.objects;
+        Ok(scene)
     }
 }
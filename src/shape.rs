@@ -5,21 +5,18 @@ use std::{
 
 use crate::{
     bounds::Bounds,
-    constants::EPSILON,
     geometry::{normal::Normal, point::Point, traits::DotProduct, vector::Vector, O},
     intersection::{PrimitiveIntersection, ShapeIntersection},
+    mesh::TriangleMesh,
     pdf::Pdf,
     ray::Ray,
     sampling::{
         samplers::Sample2d,
-        sampling_fns::{sample_disk, sample_sphere, sample_triangle},
+        sampling_fns::{sample_disk, sample_sphere},
     },
     transformation::{Transformable, Transformation},
-    v,
 };
 
-// TODO: Avoid this PartialEq, currently used by path_integrator to map an area
-// light to index in the lights array
 #[derive(Debug, PartialEq)]
 pub enum Shape {
     Sphere {
@@ -27,16 +24,11 @@ pub enum Shape {
         world_to_object: Arc<Transformation>,
         radius: f64,
     },
+    /// Triangle `index` of a mesh, whose vertex data is shared with the other
+    /// triangles in it
     Triangle {
-        v0: Point,
-        e1: Vector,
-        e2: Vector,
-        n0: Vector,
-        n01: Vector,
-        n02: Vector,
-        uv0: (f64, f64),
-        uv01: (f64, f64),
-        uv02: (f64, f64),
+        mesh: Arc<TriangleMesh>,
+        index: usize,
     },
     Disk {
         object_to_world: Arc<Transformation>,
@@ -44,6 +36,173 @@ pub enum Shape {
         radius: f64,
         inner_radius: f64,
     },
+    /// Cylinder around the z axis, from `z_min` to `z_max`
+    Cylinder {
+        object_to_world: Arc<Transformation>,
+        world_to_object: Arc<Transformation>,
+        radius: f64,
+        z_min: f64,
+        z_max: f64,
+        phi_max: f64,
+    },
+    /// Cone around the z axis, with its base at z = 0 and apex at z = height
+    Cone {
+        object_to_world: Arc<Transformation>,
+        world_to_object: Arc<Transformation>,
+        radius: f64,
+        height: f64,
+        phi_max: f64,
+    },
+    /// Paraboloid around the z axis, with its apex at the origin and a radius
+    /// of `radius` at `z_max`
+    Paraboloid {
+        object_to_world: Arc<Transformation>,
+        world_to_object: Arc<Transformation>,
+        radius: f64,
+        z_min: f64,
+        z_max: f64,
+        phi_max: f64,
+    },
+    /// Hyperboloid swept by rotating the line from `p1` to `p2` around the z
+    /// axis, which satisfies x² + y² = a z² + b z + c
+    Hyperboloid {
+        object_to_world: Arc<Transformation>,
+        world_to_object: Arc<Transformation>,
+        p1: Point,
+        p2: Point,
+        z_min: f64,
+        z_max: f64,
+        r_max: f64,
+        phi_max: f64,
+        a: f64,
+        b: f64,
+        c: f64,
+    },
+}
+
+/// Creates the intersection for a `location` on a sphere of `radius` centered at
+/// the origin, in object space
+fn sphere_intersection(location: Point, radius: f64) -> ShapeIntersection {
+    let mut phi = location.y().atan2(location.x());
+    if phi < 0.0 {
+        phi += PI * 2.0;
+    }
+    let u = phi / (PI * 2.0);
+    let theta = (location.z() / radius).clamp(-1.0, 1.0).acos();
+    let v = theta * FRAC_1_PI;
+
+    let dpdu = Vector(-location.y(), location.x(), 0.0) * (PI * 2.0);
+    let dpdv = Vector(
+        location.z() * phi.cos(),
+        location.z() * phi.sin(),
+        -radius * theta.sin(),
+    ) * PI;
+    let normal = Normal(location.x(), location.y(), location.z()) / radius;
+    ShapeIntersection {
+        location,
+        normal,
+        shading_normal: normal,
+        tangent: dpdu,
+        uv: (u, v),
+        dpdu,
+        dpdv,
+        dndu: Normal::from(dpdu / radius),
+        dndv: Normal::from(dpdv / radius),
+    }
+}
+
+/// Creates the intersection for a `location` on a quadric with the given
+/// outward `normal`, from the first and second partial derivatives of the
+/// location w.r.t. uv. The normal's derivatives follow from the Weingarten
+/// equations.
+///
+/// Source: https://www.pbr-book.org/3ed-2018/Shapes/Spheres#PartialDerivativesofNormalVectors
+#[allow(non_snake_case)]
+fn quadric_intersection(
+    location: Point,
+    normal: Normal,
+    uv: (f64, f64),
+    (dpdu, dpdv): (Vector, Vector),
+    (d2pduu, d2pduv, d2pdvv): (Vector, Vector, Vector),
+) -> ShapeIntersection {
+    let normal = normal.normalized();
+
+    // Coefficients of the first and second fundamental forms
+    let E = dpdu.dot(&dpdu);
+    let F = dpdu.dot(&dpdv);
+    let G = dpdv.dot(&dpdv);
+    let e = normal.dot(&d2pduu);
+    let f = normal.dot(&d2pduv);
+    let g = normal.dot(&d2pdvv);
+
+    let inv_EGF2 = 1.0 / (E * G - F * F);
+    let (dndu, dndv) = if inv_EGF2.is_finite() {
+        (
+            Normal::from(dpdu * ((f * F - e * G) * inv_EGF2) + dpdv * ((e * F - f * E) * inv_EGF2)),
+            Normal::from(dpdu * ((g * F - f * G) * inv_EGF2) + dpdv * ((f * F - g * E) * inv_EGF2)),
+        )
+    } else {
+        (Normal(0.0, 0.0, 0.0), Normal(0.0, 0.0, 0.0))
+    };
+
+    ShapeIntersection {
+        location,
+        normal,
+        shading_normal: normal,
+        tangent: dpdu,
+        uv,
+        dpdu,
+        dpdv,
+        dndu,
+        dndv,
+    }
+}
+
+/// Returns the solutions of a t² + b t + c = 0 in increasing order
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        // Rays parallel to a line on a cone only cross it once
+        if b == 0.0 {
+            return None;
+        }
+        return Some((-c / b, -c / b));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // Avoid cancellation when b and the square root are close
+    let q = if b < 0.0 {
+        -0.5 * (b - discriminant.sqrt())
+    } else {
+        -0.5 * (b + discriminant.sqrt())
+    };
+    let (t0, t1) = (q / a, c / q);
+    Some(if t0 < t1 { (t0, t1) } else { (t1, t0) })
+}
+
+/// Angle of a point around the z axis, in [0, 2π)
+fn phi(x: f64, y: f64) -> f64 {
+    let phi = y.atan2(x);
+    if phi < 0.0 {
+        phi + PI * 2.0
+    } else {
+        phi
+    }
+}
+
+fn object_transformations(
+    origin: Point,
+    rotate_x: f64,
+    rotate_y: f64,
+) -> (Arc<Transformation>, Arc<Transformation>) {
+    let object_to_world = Arc::new(
+        Transformation::translate(origin.x(), origin.y(), origin.z())
+            * Transformation::rotate_x(rotate_x.to_radians())
+            * Transformation::rotate_y(rotate_y.to_radians()),
+    );
+    let world_to_object = Arc::new(object_to_world.inverse());
+    (object_to_world, world_to_object)
 }
 
 pub struct ShapeSample {
@@ -68,66 +227,23 @@ impl Shape {
         }
     }
     pub fn new_triangle(v0: Point, v1: Point, v2: Point) -> Option<Shape> {
-        let e1 = v1 - v0;
-        let e2 = v2 - v0;
-
-        // Assuming that vertices are in clockwise order, calculate the normal
-        // in a left handed co-ordinate system:
-        let mut n0 = e2.cross(&e1);
-        let magnitude = n0.magnitude();
-        if magnitude == 0.0 {
-            // Degenerate triangle
-            return None;
-        }
-        n0 = n0 / magnitude;
-
-        Some(Shape::Triangle {
-            v0,
-            e1,
-            e2,
-            n0,
-            n01: v!(0, 0, 0),
-            n02: v!(0, 0, 0),
-            uv0: (0.0, 0.0),
-            uv01: (1.0, 0.0),
-            uv02: (1.0, 1.0),
-        })
+        Shape::new_mesh_triangle(TriangleMesh::new(
+            vec![[0, 1, 2]],
+            vec![v0, v1, v2],
+            vec![],
+            vec![],
+            vec![],
+        ))
     }
-    pub fn new_triangle_with_normals_and_texture_coordinates(
-        v0: Point,
-        v1: Point,
-        v2: Point,
-        n0: Vector,
-        n1: Vector,
-        n2: Vector,
-        uv0: (f64, f64),
-        uv1: (f64, f64),
-        uv2: (f64, f64),
-    ) -> Option<Shape> {
-        let e1 = v1 - v0;
-        let e2 = v2 - v0;
-
-        if e2.cross(&e1).magnitude_squared() == 0.0
-            || n0.magnitude_squared() == 0.0
-            || n1.magnitude_squared() == 0.0
-            || n2.magnitude_squared() == 0.0
-        {
+    /// Wraps a mesh holding a single triangle, unless it is degenerate. This
+    /// is how triangles with vertex normals, uvs or tangents are made.
+    pub fn new_mesh_triangle(mesh: TriangleMesh) -> Option<Shape> {
+        if mesh.is_degenerate(0) {
             return None;
         }
-
-        let uv01 = (uv1.0 - uv0.0, uv1.1 - uv0.1);
-        let uv02 = (uv2.0 - uv0.0, uv2.1 - uv0.1);
-
         Some(Shape::Triangle {
-            v0,
-            e1,
-            e2,
-            n0,
-            n01: n1 - n0,
-            n02: n2 - n0,
-            uv0,
-            uv01,
-            uv02,
+            mesh: Arc::new(mesh),
+            index: 0,
         })
     }
     pub fn new_disk(
@@ -137,12 +253,7 @@ impl Shape {
         radius: f64,
         inner_radius: f64,
     ) -> Shape {
-        let object_to_world = Arc::new(
-            Transformation::translate(origin.x(), origin.y(), origin.z())
-                * Transformation::rotate_x(rotate_x.to_radians())
-                * Transformation::rotate_y(rotate_y.to_radians()),
-        );
-        let world_to_object = Arc::new(object_to_world.inverse());
+        let (object_to_world, world_to_object) = object_transformations(origin, rotate_x, rotate_y);
 
         Shape::Disk {
             radius,
@@ -152,6 +263,296 @@ impl Shape {
         }
     }
 
+    // The quadrics below are positioned like disks, by rotating them around
+    // the x and then y axes before translating them to `origin`. Angles are in
+    // degrees, and `phi_max` limits the sweep around the z axis.
+
+    pub fn new_cylinder(
+        origin: Point,
+        rotate_x: f64,
+        rotate_y: f64,
+        radius: f64,
+        z_min: f64,
+        z_max: f64,
+        phi_max: f64,
+    ) -> Shape {
+        let (object_to_world, world_to_object) = object_transformations(origin, rotate_x, rotate_y);
+        Shape::Cylinder {
+            object_to_world,
+            world_to_object,
+            radius,
+            z_min: z_min.min(z_max),
+            z_max: z_min.max(z_max),
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+        }
+    }
+
+    pub fn new_cone(
+        origin: Point,
+        rotate_x: f64,
+        rotate_y: f64,
+        radius: f64,
+        height: f64,
+        phi_max: f64,
+    ) -> Shape {
+        let (object_to_world, world_to_object) = object_transformations(origin, rotate_x, rotate_y);
+        Shape::Cone {
+            object_to_world,
+            world_to_object,
+            radius,
+            height,
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+        }
+    }
+
+    pub fn new_paraboloid(
+        origin: Point,
+        rotate_x: f64,
+        rotate_y: f64,
+        radius: f64,
+        z_min: f64,
+        z_max: f64,
+        phi_max: f64,
+    ) -> Shape {
+        let (object_to_world, world_to_object) = object_transformations(origin, rotate_x, rotate_y);
+        Shape::Paraboloid {
+            object_to_world,
+            world_to_object,
+            radius,
+            z_min: z_min.min(z_max),
+            z_max: z_min.max(z_max),
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+        }
+    }
+
+    /// Returns `None` if `p1` and `p2` are at the same height, in which case
+    /// the surface would be a disk. Lines that cross the axis produce cones.
+    pub fn new_hyperboloid(
+        origin: Point,
+        rotate_x: f64,
+        rotate_y: f64,
+        p1: Point,
+        p2: Point,
+        phi_max: f64,
+    ) -> Option<Shape> {
+        if p1.z() == p2.z() {
+            return None;
+        }
+        let (object_to_world, world_to_object) = object_transformations(origin, rotate_x, rotate_y);
+
+        // The squared distance from the axis of the point on the line at
+        // height z is a quadratic in z. Unlike pbrt, which fits a hyperboloid
+        // centered at z = 0 through p1 and p2, this keeps the whole line on
+        // the surface.
+        let d = p2 - p1;
+        let (dx, dy) = (d.x() / d.z(), d.y() / d.z());
+        let (x0, y0) = (p1.x() - dx * p1.z(), p1.y() - dy * p1.z());
+
+        Some(Shape::Hyperboloid {
+            object_to_world,
+            world_to_object,
+            p1,
+            p2,
+            z_min: p1.z().min(p2.z()),
+            z_max: p1.z().max(p2.z()),
+            r_max: (p1.x() * p1.x() + p1.y() * p1.y())
+                .max(p2.x() * p2.x() + p2.y() * p2.y())
+                .sqrt(),
+            phi_max: phi_max.clamp(0.0, 360.0).to_radians(),
+            a: dx * dx + dy * dy,
+            b: 2.0 * (x0 * dx + y0 * dy),
+            c: x0 * x0 + y0 * y0,
+        })
+    }
+
+    /// Finds the closest intersection of a ray in object space with a quadric
+    /// other than a sphere, returning its distance, location and angle around
+    /// the z axis
+    ///
+    /// Source: https://www.pbr-book.org/3ed-2018/Shapes/Other_Quadrics
+    fn quadric_hit(&self, ray: &Ray) -> Option<(f64, Point, f64)> {
+        let (o, d) = (ray.origin, ray.direction);
+        let (a, b, c) = match self {
+            Shape::Cylinder { radius, .. } => (
+                d.x() * d.x() + d.y() * d.y(),
+                2.0 * (d.x() * o.x() + d.y() * o.y()),
+                o.x() * o.x() + o.y() * o.y() - radius * radius,
+            ),
+            Shape::Cone { radius, height, .. } => {
+                let k = (radius / height).powi(2);
+                (
+                    d.x() * d.x() + d.y() * d.y() - k * d.z() * d.z(),
+                    2.0 * (d.x() * o.x() + d.y() * o.y() - k * d.z() * (o.z() - height)),
+                    o.x() * o.x() + o.y() * o.y() - k * (o.z() - height).powi(2),
+                )
+            }
+            Shape::Paraboloid { radius, z_max, .. } => {
+                let k = z_max / (radius * radius);
+                (
+                    k * (d.x() * d.x() + d.y() * d.y()),
+                    2.0 * k * (d.x() * o.x() + d.y() * o.y()) - d.z(),
+                    k * (o.x() * o.x() + o.y() * o.y()) - o.z(),
+                )
+            }
+            Shape::Hyperboloid { a, b, c, .. } => (
+                d.x() * d.x() + d.y() * d.y() - a * d.z() * d.z(),
+                2.0 * (d.x() * o.x() + d.y() * o.y() - a * d.z() * o.z()) - b * d.z(),
+                o.x() * o.x() + o.y() * o.y() - a * o.z() * o.z() - b * o.z() - c,
+            ),
+            _ => unreachable!("{:?} is not a quadric", self),
+        };
+        let (t0, t1) = solve_quadratic(a, b, c)?;
+
+        // Use the closest hit that lies within the shape's bounds
+        [t0, t1].iter().copied().find_map(|t| {
+            if !ray.contains_distance(t) {
+                return None;
+            }
+            let location = ray.at(t);
+            let (z_min, z_max, phi_max, phi) = match self {
+                Shape::Cylinder {
+                    z_min,
+                    z_max,
+                    phi_max,
+                    ..
+                }
+                | Shape::Paraboloid {
+                    z_min,
+                    z_max,
+                    phi_max,
+                    ..
+                } => (*z_min, *z_max, *phi_max, phi(location.x(), location.y())),
+                Shape::Cone {
+                    height, phi_max, ..
+                } => (0.0, *height, *phi_max, phi(location.x(), location.y())),
+                Shape::Hyperboloid {
+                    p1,
+                    p2,
+                    z_min,
+                    z_max,
+                    phi_max,
+                    ..
+                } => {
+                    // Measure the angle from the point on the line at the
+                    // same height, which isn't at phi = 0
+                    let v = (location.z() - p1.z()) / (p2.z() - p1.z());
+                    let pr = *p1 + (*p2 - *p1) * v;
+                    let phi = phi(
+                        pr.x() * location.x() + pr.y() * location.y(),
+                        pr.x() * location.y() - location.x() * pr.y(),
+                    );
+                    (*z_min, *z_max, *phi_max, phi)
+                }
+                _ => unreachable!(),
+            };
+            if location.z() < z_min || location.z() > z_max || phi > phi_max {
+                return None;
+            }
+            Some((t, location, phi))
+        })
+    }
+
+    /// Creates the intersection for a point in object space found by
+    /// `quadric_hit`
+    fn quadric_intersection(&self, location: Point, phi: f64) -> ShapeIntersection {
+        let (x, y, z) = (location.x(), location.y(), location.z());
+        match self {
+            Shape::Cylinder {
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => quadric_intersection(
+                location,
+                Normal(x, y, 0.0),
+                (phi / phi_max, (z - z_min) / (z_max - z_min)),
+                (
+                    Vector(-phi_max * y, phi_max * x, 0.0),
+                    Vector(0.0, 0.0, z_max - z_min),
+                ),
+                (
+                    Vector(x, y, 0.0) * -(phi_max * phi_max),
+                    Vector(0.0, 0.0, 0.0),
+                    Vector(0.0, 0.0, 0.0),
+                ),
+            ),
+            Shape::Cone {
+                radius,
+                height,
+                phi_max,
+                ..
+            } => {
+                let v = z / height;
+                quadric_intersection(
+                    location,
+                    Normal(x, y, (radius / height).powi(2) * (height - z)),
+                    (phi / phi_max, v),
+                    (
+                        Vector(-phi_max * y, phi_max * x, 0.0),
+                        Vector(-x / (1.0 - v), -y / (1.0 - v), *height),
+                    ),
+                    (
+                        Vector(x, y, 0.0) * -(phi_max * phi_max),
+                        Vector(y, -x, 0.0) * (phi_max / (1.0 - v)),
+                        Vector(0.0, 0.0, 0.0),
+                    ),
+                )
+            }
+            Shape::Paraboloid {
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => {
+                let k = z_max / (radius * radius);
+                let dz = z_max - z_min;
+                quadric_intersection(
+                    location,
+                    Normal(2.0 * k * x, 2.0 * k * y, -1.0),
+                    (phi / phi_max, (z - z_min) / dz),
+                    (
+                        Vector(-phi_max * y, phi_max * x, 0.0),
+                        Vector(x / (2.0 * z), y / (2.0 * z), 1.0) * dz,
+                    ),
+                    (
+                        Vector(x, y, 0.0) * -(phi_max * phi_max),
+                        Vector(-y / (2.0 * z), x / (2.0 * z), 0.0) * (dz * phi_max),
+                        Vector(x / (4.0 * z * z), y / (4.0 * z * z), 0.0) * -(dz * dz),
+                    ),
+                )
+            }
+            Shape::Hyperboloid {
+                p1,
+                p2,
+                phi_max,
+                a,
+                b,
+                ..
+            } => {
+                let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+                let d = *p2 - *p1;
+                let dpdv = Vector(
+                    d.x() * cos_phi - d.y() * sin_phi,
+                    d.x() * sin_phi + d.y() * cos_phi,
+                    d.z(),
+                );
+                quadric_intersection(
+                    location,
+                    Normal(x, y, -(a * z + b / 2.0)),
+                    (phi / phi_max, (z - p1.z()) / d.z()),
+                    (Vector(-phi_max * y, phi_max * x, 0.0), dpdv),
+                    (
+                        Vector(x, y, 0.0) * -(phi_max * phi_max),
+                        Vector(-dpdv.y(), dpdv.x(), 0.0) * *phi_max,
+                        Vector(0.0, 0.0, 0.0),
+                    ),
+                )
+            }
+            _ => unreachable!("{:?} is not a quadric", self),
+        }
+    }
+
     #[allow(non_snake_case)]
     // Should update the ray's max_distance if an intersection is found
     pub fn intersect(&self, ray: &mut Ray) -> Option<ShapeIntersection> {
@@ -179,87 +580,23 @@ impl Shape {
                 if obj_ray.update_max_distance(distance) {
                     let location = obj_ray.at(distance);
                     ray.update_max_distance(distance);
-                    let mut phi = location.y().atan2(location.x());
-                    if phi < 0.0 {
-                        phi += PI * 2.0;
-                    }
-                    let u = phi / (PI * 2.0);
-                    let theta = (location.z() / radius).acos();
-                    let v = theta * FRAC_1_PI;
-                    return Some(object_to_world.transform(&ShapeIntersection {
-                        location,
-                        normal: Normal(location.x(), location.y(), location.z()) / *radius,
-                        uv: (u, v),
-                    }));
+                    return Some(
+                        object_to_world.transform(&sphere_intersection(location, *radius)),
+                    );
                 }
 
                 distance = (-b + discriminant_sqrt) * inv_2_a;
                 if obj_ray.update_max_distance(distance) {
                     let location = obj_ray.at(distance);
                     ray.update_max_distance(distance);
-                    let mut phi = location.y().atan2(location.x());
-                    if phi < 0.0 {
-                        phi += PI * 2.0;
-                    }
-                    let u = phi / (PI * 2.0);
-                    let theta = (location.z() / radius).acos();
-                    let v = theta * FRAC_1_PI;
-                    return Some(object_to_world.transform(&ShapeIntersection {
-                        location,
-                        normal: Normal(location.x(), location.y(), location.z()) / *radius,
-                        uv: (u, v),
-                    }));
+                    return Some(
+                        object_to_world.transform(&sphere_intersection(location, *radius)),
+                    );
                 }
 
                 None
             }
-            Shape::Triangle {
-                v0,
-                e1,
-                e2,
-                n0,
-                n01,
-                n02,
-                uv0,
-                uv01,
-                uv02,
-            } => {
-                // Source: http://www.graphics.cornell.edu/pubs/1997/MT97.pdf
-                let P = ray.direction.cross(e2);
-
-                let denominator = P.dot(e1);
-                if denominator > -EPSILON && denominator < EPSILON {
-                    return None;
-                }
-
-                let T = ray.origin - *v0;
-                let u = P.dot(&T) / denominator;
-                if u < 0.0 || u > 1.0 {
-                    return None;
-                }
-
-                let Q = T.cross(e1);
-                let v = Q.dot(&ray.direction) / denominator;
-                if v < 0.0 || u + v > 1.0 {
-                    return None;
-                }
-
-                let distance = T.cross(e1).dot(e2) / denominator;
-                if ray.update_max_distance(distance) {
-                    let location = ray.at(distance);
-
-                    Some(ShapeIntersection {
-                        location,
-                        normal: (*n0 + *n01 * u + *n02 * v).normalized().into(),
-                        uv: (
-                            uv0.0 + uv01.0 * u + uv02.0 * v,
-                            uv0.1 + uv01.1 * u + uv02.1 * v,
-                        ),
-                    })
-                } else {
-                    None
-                }
-            }
+            Shape::Triangle { mesh, index } => mesh.intersect(*index, ray),
             Shape::Disk {
                 object_to_world,
                 world_to_object,
@@ -295,18 +632,57 @@ impl Shape {
                     theta += PI * 2.0;
                 }
                 let u = theta / (PI * 2.0);
-                let v = distance_squared.sqrt() / radius;
+                let distance = distance_squared.sqrt();
+                let v = distance / radius;
+
+                let dpdv = if distance > 0.0 {
+                    Vector(location.x(), location.y(), 0.0) * (radius / distance)
+                } else {
+                    Vector(*radius, 0.0, 0.0)
+                };
 
                 if ray.update_max_distance(t) {
+                    let dpdu = Vector(-location.y(), location.x(), 0.0) * (PI * 2.0);
                     Some(object_to_world.transform(&ShapeIntersection {
                         location,
                         normal: Normal::Z,
+                        shading_normal: Normal::Z,
+                        tangent: dpdu,
                         uv: (u, v),
+                        dpdu,
+                        dpdv,
+                        dndu: Normal(0.0, 0.0, 0.0),
+                        dndv: Normal(0.0, 0.0, 0.0),
                     }))
                 } else {
                     None
                 }
             }
+            Shape::Cylinder {
+                object_to_world,
+                world_to_object,
+                ..
+            }
+            | Shape::Cone {
+                object_to_world,
+                world_to_object,
+                ..
+            }
+            | Shape::Paraboloid {
+                object_to_world,
+                world_to_object,
+                ..
+            }
+            | Shape::Hyperboloid {
+                object_to_world,
+                world_to_object,
+                ..
+            } => {
+                let obj_ray = world_to_object.transform(ray);
+                let (t, location, phi) = self.quadric_hit(&obj_ray)?;
+                ray.update_max_distance(t);
+                Some(object_to_world.transform(&self.quadric_intersection(location, phi)))
+            }
         }
     }
 
@@ -340,30 +716,7 @@ impl Shape {
                 distance = (-b + discriminant_sqrt) * inv_2_a;
                 obj_ray.contains_distance(distance)
             }
-            Shape::Triangle { v0, e1, e2, .. } => {
-                // Source: http://www.graphics.cornell.edu/pubs/1997/MT97.pdf
-                let P = ray.direction.cross(e2);
-
-                let denominator = P.dot(e1);
-                if denominator > -EPSILON && denominator < EPSILON {
-                    return false;
-                }
-
-                let T = ray.origin - *v0;
-                let u = P.dot(&T) / denominator;
-                if u < 0.0 || u > 1.0 {
-                    return false;
-                }
-
-                let Q = T.cross(e1);
-                let v = Q.dot(&ray.direction) / denominator;
-                if v < 0.0 || u + v > 1.0 {
-                    return false;
-                }
-
-                let distance = T.cross(e1).dot(e2) / denominator;
-                ray.contains_distance(distance)
-            }
+            Shape::Triangle { mesh, index } => mesh.intersects(*index, ray),
             Shape::Disk {
                 world_to_object,
                 radius,
@@ -396,6 +749,18 @@ impl Shape {
 
                 ray.contains_distance(t)
             }
+            Shape::Cylinder {
+                world_to_object, ..
+            }
+            | Shape::Cone {
+                world_to_object, ..
+            }
+            | Shape::Paraboloid {
+                world_to_object, ..
+            }
+            | Shape::Hyperboloid {
+                world_to_object, ..
+            } => self.quadric_hit(&world_to_object.transform(ray)).is_some(),
         }
     }
 
@@ -409,23 +774,7 @@ impl Shape {
                 Point(-radius, -radius, -radius),
                 Point(*radius, *radius, *radius),
             )),
-            Shape::Triangle { v0, e1, e2, .. } => {
-                let v1 = *v0 + *e1;
-                let v2 = *v0 + *e2;
-
-                Bounds::new(
-                    Point(
-                        v1.x().min(v2.x().min(v0.x())),
-                        v1.y().min(v2.y().min(v0.y())),
-                        v1.z().min(v2.z().min(v0.z())),
-                    ),
-                    Point(
-                        v1.x().max(v2.x().max(v0.x())),
-                        v1.y().max(v2.y().max(v0.y())),
-                        v1.z().max(v2.z().max(v0.z())),
-                    ),
-                )
-            }
+            Shape::Triangle { mesh, index } => mesh.bounds(*index),
             Shape::Disk {
                 radius,
                 object_to_world,
@@ -434,6 +783,109 @@ impl Shape {
                 Point(-*radius, -*radius, 0.0),
                 Point(*radius, *radius, 0.0),
             )),
+            Shape::Cylinder {
+                object_to_world,
+                radius,
+                z_min,
+                z_max,
+                ..
+            }
+            | Shape::Paraboloid {
+                object_to_world,
+                radius,
+                z_min,
+                z_max,
+                ..
+            } => object_to_world.transform(&Bounds::new(
+                Point(-*radius, -*radius, *z_min),
+                Point(*radius, *radius, *z_max),
+            )),
+            Shape::Cone {
+                object_to_world,
+                radius,
+                height,
+                ..
+            } => object_to_world.transform(&Bounds::new(
+                Point(-*radius, -*radius, 0.0),
+                Point(*radius, *radius, *height),
+            )),
+            Shape::Hyperboloid {
+                object_to_world,
+                z_min,
+                z_max,
+                r_max,
+                ..
+            } => object_to_world.transform(&Bounds::new(
+                Point(-*r_max, -*r_max, *z_min),
+                Point(*r_max, *r_max, *z_max),
+            )),
+        }
+    }
+
+    /// Transformation to the space the shape was defined in, if it differs from
+    /// world space
+    pub fn world_to_object(&self) -> Option<&Transformation> {
+        match self {
+            Shape::Sphere {
+                world_to_object, ..
+            } => Some(world_to_object),
+            Shape::Triangle { .. } => None,
+            Shape::Disk {
+                world_to_object, ..
+            }
+            | Shape::Cylinder {
+                world_to_object, ..
+            }
+            | Shape::Cone {
+                world_to_object, ..
+            }
+            | Shape::Paraboloid {
+                world_to_object, ..
+            }
+            | Shape::Hyperboloid {
+                world_to_object, ..
+            } => Some(world_to_object),
+        }
+    }
+
+    fn object_to_world(&self) -> Option<&Transformation> {
+        match self {
+            Shape::Sphere {
+                object_to_world, ..
+            }
+            | Shape::Disk {
+                object_to_world, ..
+            }
+            | Shape::Cylinder {
+                object_to_world, ..
+            }
+            | Shape::Cone {
+                object_to_world, ..
+            }
+            | Shape::Paraboloid {
+                object_to_world, ..
+            }
+            | Shape::Hyperboloid {
+                object_to_world, ..
+            } => Some(object_to_world),
+            Shape::Triangle { .. } => None,
+        }
+    }
+
+    /// Factor by which the transformation to world space stretches areas on
+    /// the surface around a point with the given normal in world space. This
+    /// is 1 everywhere for rigid transformations, but varies over the surface
+    /// for non-uniform scales.
+    fn area_scale(&self, normal: &Normal) -> f64 {
+        match (self.object_to_world(), self.world_to_object()) {
+            (Some(object_to_world), Some(world_to_object)) => {
+                let (u, v) = world_to_object.transform(normal).generate_tangents();
+                object_to_world
+                    .transform(&u)
+                    .cross(&object_to_world.transform(&v))
+                    .magnitude()
+            }
+            _ => 1.0,
         }
     }
 
@@ -441,7 +893,10 @@ impl Shape {
     /// https://www.pbr-book.org/3ed-2018/Light_Transport_I_Surface_Reflection/Sampling_Light_Sources#SamplingShapes
     /// So far, these are only used for area lights.
 
-    /// Samples a point uniformly on the surface of the shape
+    /// Samples a point uniformly on the surface of the shape in object space,
+    /// except for hyperboloids, which are sampled uniformly in uv. Points are
+    /// only uniform in world space if the shape wasn't stretched by its
+    /// transformation.
     pub fn sample(&self, point_sample: Sample2d) -> Point {
         match &self {
             Shape::Sphere {
@@ -452,10 +907,7 @@ impl Shape {
                 let point = O + sample_sphere(point_sample) * *radius;
                 object_to_world.transform(&point)
             }
-            Shape::Triangle { v0, e1, e2, .. } => {
-                let (b1, b2) = sample_triangle(point_sample);
-                *v0 + *e1 * b1 + *e2 * b2
-            }
+            Shape::Triangle { mesh, index } => mesh.sample(*index, point_sample),
             Shape::Disk {
                 object_to_world,
                 radius,
@@ -466,6 +918,70 @@ impl Shape {
                 let point = Point(x * radius, y * radius, 0.0);
                 object_to_world.transform(&point)
             }
+            Shape::Cylinder {
+                object_to_world,
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => {
+                let (u, v) = point_sample.take();
+                let phi = u * phi_max;
+                let z = z_min + (z_max - z_min) * v;
+                object_to_world.transform(&Point(radius * phi.cos(), radius * phi.sin(), z))
+            }
+            Shape::Cone {
+                object_to_world,
+                radius,
+                height,
+                phi_max,
+                ..
+            } => {
+                // The circumference shrinks linearly towards the apex
+                let (u, v) = point_sample.take();
+                let phi = u * phi_max;
+                let v = 1.0 - (1.0 - v).sqrt();
+                let r = radius * (1.0 - v);
+                object_to_world.transform(&Point(r * phi.cos(), r * phi.sin(), height * v))
+            }
+            Shape::Paraboloid {
+                object_to_world,
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => {
+                // The area up to a height z is proportional to (4 k z + 1)^1.5
+                let (u, v) = point_sample.take();
+                let phi = u * phi_max;
+                let k = z_max / (radius * radius);
+                let (a_min, a_max) = (
+                    (4.0 * k * z_min + 1.0).powf(1.5),
+                    (4.0 * k * z_max + 1.0).powf(1.5),
+                );
+                let z = ((a_min + (a_max - a_min) * v).powf(2.0 / 3.0) - 1.0) / (4.0 * k);
+                let r = (z / k).max(0.0).sqrt();
+                object_to_world.transform(&Point(r * phi.cos(), r * phi.sin(), z))
+            }
+            Shape::Hyperboloid {
+                object_to_world,
+                p1,
+                p2,
+                phi_max,
+                ..
+            } => {
+                let (u, v) = point_sample.take();
+                let phi = u * phi_max;
+                let pr = *p1 + (*p2 - *p1) * v;
+                let (cos_phi, sin_phi) = (phi.cos(), phi.sin());
+                object_to_world.transform(&Point(
+                    pr.x() * cos_phi - pr.y() * sin_phi,
+                    pr.x() * sin_phi + pr.y() * cos_phi,
+                    pr.z(),
+                ))
+            }
         }
     }
 
@@ -490,8 +1006,21 @@ impl Shape {
             Some(shape_intersection) => {
                 let distance_squared =
                     (shape_intersection.location - intersection.location).magnitude_squared();
-                let cos_theta = w_i.dot(&intersection.normal).abs();
-                let pdf = distance_squared / (cos_theta * self.area());
+                // Convert from area to solid angle using the cosine at the
+                // sampled point on the shape
+                let cos_theta = w_i.dot(&shape_intersection.normal).abs();
+                let area_pdf = match self {
+                    // Sampled uniformly in uv, so the density is inversely
+                    // proportional to the area covered at that point
+                    Shape::Hyperboloid { .. } => {
+                        1.0 / shape_intersection
+                            .dpdu
+                            .cross(&shape_intersection.dpdv)
+                            .magnitude()
+                    }
+                    _ => 1.0 / (self.object_area() * self.area_scale(&shape_intersection.normal)),
+                };
+                let pdf = distance_squared * area_pdf / cos_theta;
                 Pdf::NonDelta(pdf)
             }
             // We should ideally never sample a direction that does not hit this
@@ -501,15 +1030,243 @@ impl Shape {
         }
     }
 
+    /// Surface area in world space
     pub fn area(&self) -> f64 {
+        let world_to_object = match self.world_to_object() {
+            Some(world_to_object) => world_to_object,
+            None => return self.object_area(),
+        };
+
+        // Non-uniform scales stretch some parts of the surface more than
+        // others, so average how much they stretch small patches around a
+        // grid of samples. These are spread uniformly over the surface in
+        // object space, except on hyperboloids, where patches are weighted by
+        // their area instead.
+        let n = 32;
+        let h = 0.25 / n as f64;
+        let (mut stretch, mut total_weight) = (0.0, 0.0);
+        for i in 0..n {
+            for j in 0..n {
+                let (u, v) = ((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64);
+                let points = [(u - h, v), (u + h, v), (u, v - h), (u, v + h)]
+                    .iter()
+                    .map(|&(u, v)| self.sample(Sample2d::new(u, v)))
+                    .collect::<Vec<_>>();
+                let world_area = (points[1] - points[0])
+                    .cross(&(points[3] - points[2]))
+                    .magnitude();
+                let points = points
+                    .iter()
+                    .map(|point| world_to_object.transform(point))
+                    .collect::<Vec<_>>();
+                let object_area = (points[1] - points[0])
+                    .cross(&(points[3] - points[2]))
+                    .magnitude();
+                if object_area == 0.0 {
+                    continue;
+                }
+                let weight = match self {
+                    Shape::Hyperboloid { .. } => object_area,
+                    _ => 1.0,
+                };
+                stretch += weight * world_area / object_area;
+                total_weight += weight;
+            }
+        }
+        self.object_area() * stretch / total_weight
+    }
+
+    /// Surface area before the shape's transformation is applied
+    fn object_area(&self) -> f64 {
         match &self {
-            Shape::Sphere { radius, .. } => PI * radius.powf(2.0),
-            Shape::Triangle { e1, e2, .. } => e1.cross(e2).magnitude() / 2.0,
+            Shape::Sphere { radius, .. } => 4.0 * PI * radius.powf(2.0),
+            Shape::Triangle { mesh, index } => mesh.area(*index),
             Shape::Disk {
                 radius,
                 inner_radius,
                 ..
             } => PI * (radius.powf(2.0) - inner_radius.powf(2.0)),
+            Shape::Cylinder {
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => (z_max - z_min) * radius * phi_max,
+            Shape::Cone {
+                radius,
+                height,
+                phi_max,
+                ..
+            } => radius * (height * height + radius * radius).sqrt() * phi_max / 2.0,
+            Shape::Paraboloid {
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => {
+                let k = z_max / (radius * radius);
+                phi_max / (12.0 * k * k)
+                    * ((4.0 * k * z_max + 1.0).powf(1.5) - (4.0 * k * z_min + 1.0).powf(1.5))
+            }
+            Shape::Hyperboloid {
+                p1, p2, phi_max, ..
+            } => {
+                // The area swept by each point on the line depends on its
+                // distance from the axis, and on the angle between the line
+                // and the direction it is swept in. There is no simple closed
+                // form, so integrate it numerically using Simpson's rule.
+                let d = *p2 - *p1;
+                let k = p1.x() * d.y() - p1.y() * d.x();
+                let swept_length = |v: f64| {
+                    let pr = *p1 + d * v;
+                    ((pr.x() * pr.x() + pr.y() * pr.y()) * d.magnitude_squared() - k * k)
+                        .max(0.0)
+                        .sqrt()
+                };
+                let n = 64;
+                let h = 1.0 / n as f64;
+                let sum: f64 = (0..=n)
+                    .map(|i| {
+                        let weight = if i == 0 || i == n {
+                            1.0
+                        } else if i % 2 == 1 {
+                            4.0
+                        } else {
+                            2.0
+                        };
+                        weight * swept_length(i as f64 * h)
+                    })
+                    .sum();
+                phi_max * sum * h / 3.0
+            }
+        }
+    }
+}
+
+/// Applies a transformation on top of the one a shape already has. Triangles
+/// are defined in world space, so the vertices of their mesh are transformed
+/// instead.
+impl Transformable<Shape> for Transformation {
+    fn transform(&self, shape: &Shape) -> Shape {
+        let transformations = |object_to_world: &Transformation| {
+            let object_to_world = self * object_to_world;
+            let world_to_object = object_to_world.inverse();
+            (Arc::new(object_to_world), Arc::new(world_to_object))
+        };
+
+        match shape {
+            Shape::Sphere {
+                object_to_world,
+                radius,
+                ..
+            } => {
+                let (object_to_world, world_to_object) = transformations(object_to_world);
+                Shape::Sphere {
+                    object_to_world,
+                    world_to_object,
+                    radius: *radius,
+                }
+            }
+            Shape::Triangle { mesh, index } => Shape::Triangle {
+                mesh: Arc::new(self.transform(&**mesh)),
+                index: *index,
+            },
+            Shape::Disk {
+                object_to_world,
+                radius,
+                inner_radius,
+                ..
+            } => {
+                let (object_to_world, world_to_object) = transformations(object_to_world);
+                Shape::Disk {
+                    object_to_world,
+                    world_to_object,
+                    radius: *radius,
+                    inner_radius: *inner_radius,
+                }
+            }
+            Shape::Cylinder {
+                object_to_world,
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => {
+                let (object_to_world, world_to_object) = transformations(object_to_world);
+                Shape::Cylinder {
+                    object_to_world,
+                    world_to_object,
+                    radius: *radius,
+                    z_min: *z_min,
+                    z_max: *z_max,
+                    phi_max: *phi_max,
+                }
+            }
+            Shape::Cone {
+                object_to_world,
+                radius,
+                height,
+                phi_max,
+                ..
+            } => {
+                let (object_to_world, world_to_object) = transformations(object_to_world);
+                Shape::Cone {
+                    object_to_world,
+                    world_to_object,
+                    radius: *radius,
+                    height: *height,
+                    phi_max: *phi_max,
+                }
+            }
+            Shape::Paraboloid {
+                object_to_world,
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+                ..
+            } => {
+                let (object_to_world, world_to_object) = transformations(object_to_world);
+                Shape::Paraboloid {
+                    object_to_world,
+                    world_to_object,
+                    radius: *radius,
+                    z_min: *z_min,
+                    z_max: *z_max,
+                    phi_max: *phi_max,
+                }
+            }
+            Shape::Hyperboloid {
+                object_to_world,
+                p1,
+                p2,
+                z_min,
+                z_max,
+                r_max,
+                phi_max,
+                a,
+                b,
+                c,
+                ..
+            } => {
+                let (object_to_world, world_to_object) = transformations(object_to_world);
+                Shape::Hyperboloid {
+                    object_to_world,
+                    world_to_object,
+                    p1: *p1,
+                    p2: *p2,
+                    z_min: *z_min,
+                    z_max: *z_max,
+                    r_max: *r_max,
+                    phi_max: *phi_max,
+                    a: *a,
+                    b: *b,
+                    c: *c,
+                }
+            }
         }
     }
 }
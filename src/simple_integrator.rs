@@ -62,13 +62,9 @@ where
                 break;
             }
         };
-        let PrimitiveIntersection {
-            normal,
-            location,
-            material,
-            uv,
-            ..
-        } = intersection;
+        let PrimitiveIntersection { material, uv, .. } = intersection;
+        // Materials are evaluated using the (possibly perturbed) shading normal
+        let normal = intersection.shading.normal;
 
         let path_samples = PathSegmentSamples::from(sampler);
 
@@ -97,7 +93,10 @@ where
                 Pdf::Delta => 1.0,
             };
 
-            if light_pdf > 0.0 && !scene.intersects(&shadow_ray) {
+            if light_pdf > 0.0
+                && intersection.normals_agree(&w_o, &w_i)
+                && !scene.intersects(&shadow_ray)
+            {
                 let f = material.f(&w_o, &w_i, &normal, &uv);
                 let cos_theta = w_i.dot(&normal).abs();
                 L += beta * Li * f * cos_theta / light_sampler_pdf / light_pdf;
@@ -115,7 +114,9 @@ where
                 Some(surface_sample) => surface_sample,
                 None => break,
             };
-            if f.is_black() {
+            // A direction on the wrong side of the geometric surface would
+            // leak light through it
+            if f.is_black() || !intersection.normals_agree(&w_o, &w_i) {
                 break;
             }
             let cos_theta = w_i.dot(&normal).abs();
@@ -129,7 +130,11 @@ where
 
             beta = beta * f * cos_theta / bsdf_pdf;
 
-            ray = Ray::new(location, w_i);
+            ray = if is_specular {
+                intersection.spawn_specular_ray(&ray, &w_o, &w_i)
+            } else {
+                intersection.spawn_ray(w_i)
+            };
             is_specular_bounce = is_specular;
         }
 
@@ -0,0 +1,226 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    f64::consts::FRAC_1_PI,
+    hash::{Hash, Hasher},
+};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::{
+    bxdf::{fresnel_dielectric, reflect, SurfaceSample},
+    color::Color,
+    geometry::{normal::Normal, point::Point, traits::DotProduct, vector::Vector},
+    intersection::PrimitiveIntersection,
+    material::Material,
+    pdf::Pdf,
+    ray::Ray,
+    sampling::{
+        samplers::{Sample1d, Sample2d},
+        sampling_fns::{cosine_sample_hemisphere, sample_sphere},
+    },
+    scene::Scene,
+    texture::{Texture, TextureCoordinates},
+};
+
+/// Walks longer than this are assumed to never leave the object
+const MAX_WALK_LENGTH: usize = 256;
+
+/// A translucent material, where light enters the surface, scatters inside the
+/// object and leaves it somewhere else, as in skin, wax, marble or milk. The
+/// surface is a smooth dielectric boundary, which reflects light specularly
+/// and transmits the rest diffusely into the object.
+///
+/// `path_integrator` follows transmitted light with a random walk through a
+/// homogeneous medium filling the object, which must be closed, and shades the
+/// point where the walk leaves it as a diffuse surface. Other integrators see
+/// a surface that diffusely transmits light.
+///
+/// Source: https://graphics.pixar.com/library/PathTracedSubsurface/paper.pdf
+#[derive(Debug)]
+pub struct Subsurface {
+    /// Color of the surface once light has scattered through the object, from
+    /// which the single scattering albedo of the medium is derived
+    pub albedo: Texture<Color>,
+    /// Average distance light travels between scattering events, per channel
+    pub mean_free_path: Texture<Color>,
+    /// Index of refraction of the surface
+    pub eta: f64,
+    /// Material used to shade the points where walks leave the object
+    exit_material: Box<Material>,
+}
+
+/// Where a random walk leaves the object, and how much light is carried along
+/// it
+pub struct SubsurfaceExit<'a> {
+    /// The exit point, shaded with a white diffuse material
+    pub intersection: PrimitiveIntersection<'a>,
+    /// Direction in which the walk left the object, which is on the outside
+    /// of the surface at the exit point
+    pub w_o: Vector,
+    pub throughput: Color,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Texture<Color>, mean_free_path: Texture<Color>, eta: f64) -> Subsurface {
+        Subsurface {
+            albedo,
+            mean_free_path,
+            eta,
+            exit_material: Box::new(Material::new_matte(
+                Texture::constant(Color::WHITE),
+                Texture::constant(0.0),
+            )),
+        }
+    }
+
+    pub fn sample(
+        &self,
+        (sample_1d, sample_2d): (Sample1d, Sample2d),
+        w_o: &Vector,
+        normal: &Normal,
+        uv: &TextureCoordinates,
+    ) -> Option<SurfaceSample> {
+        let cos_theta_o = w_o.dot(normal);
+        let reflectance = fresnel_dielectric(1.0, self.eta, cos_theta_o);
+        if sample_1d.take() < reflectance {
+            return Some(SurfaceSample {
+                w_i: reflect(w_o, normal),
+                f: Color::WHITE * reflectance / cos_theta_o.abs(),
+                pdf: Pdf::NonDelta(reflectance),
+                is_specular: true,
+            });
+        }
+
+        // Transmit into the other side of the surface
+        let mut w_i = cosine_sample_hemisphere(sample_2d, normal);
+        if cos_theta_o > 0.0 {
+            w_i = -w_i;
+        }
+        Some(SurfaceSample {
+            w_i,
+            f: self.f(w_o, &w_i, normal, uv),
+            pdf: self.pdf(w_o, &w_i, normal),
+            is_specular: false,
+        })
+    }
+
+    pub fn f(
+        &self,
+        w_o: &Vector,
+        w_i: &Vector,
+        normal: &Normal,
+        _uv: &TextureCoordinates,
+    ) -> Color {
+        if normal.same_hemisphere(w_o, w_i) {
+            return Color::BLACK;
+        }
+        let transmittance = 1.0 - fresnel_dielectric(1.0, self.eta, w_o.dot(normal));
+        Color::WHITE * transmittance * FRAC_1_PI
+    }
+
+    pub fn pdf(&self, w_o: &Vector, w_i: &Vector, normal: &Normal) -> Pdf {
+        if normal.same_hemisphere(w_o, w_i) {
+            return Pdf::NonDelta(0.0);
+        }
+        let transmittance = 1.0 - fresnel_dielectric(1.0, self.eta, w_o.dot(normal));
+        Pdf::NonDelta(transmittance * w_i.dot(normal).abs() * FRAC_1_PI)
+    }
+
+    /// Follows light transmitted into the object along `ray` until it leaves
+    /// the object again, scattering isotropically within it. Distances are
+    /// sampled using the extinction of a randomly picked channel, and weighted
+    /// by the average pdf of all channels. Returns `None` if the walk escapes
+    /// the scene or is absorbed.
+    pub fn random_walk<'a>(
+        &'a self,
+        scene: &'a Scene,
+        ray: &Ray,
+        uv: &TextureCoordinates,
+        seed: Sample1d,
+    ) -> Option<SubsurfaceExit<'a>> {
+        let albedo = single_scattering_albedo(self.albedo.eval(uv));
+        let mean_free_path = channels(self.mean_free_path.eval(uv));
+        let sigma_t = Color::from(mean_free_path.map(|d| 1.0 / d.max(1e-9)));
+
+        let mut rng = seeded_rng(&ray.origin, seed);
+        let mut ray = Ray::new(ray.origin, ray.direction).with_time(ray.time);
+        let mut throughput = Color::WHITE;
+        for _ in 0..MAX_WALK_LENGTH {
+            let channel = rng.gen_range(0..3);
+            let distance = -(1.0 - rng.gen::<f64>()).ln() / channels(sigma_t)[channel];
+
+            ray.max_distance = distance;
+            if !scene.intersects(&ray) {
+                // Scatter within the medium
+                let transmittance = exp(sigma_t * -distance);
+                let pdf = average(sigma_t * transmittance);
+                throughput = throughput * transmittance * sigma_t * albedo / pdf;
+                ray = Ray::new(
+                    ray.at(distance),
+                    sample_sphere(Sample2d::new(rng.gen(), rng.gen())),
+                )
+                .with_time(ray.time);
+            } else {
+                // Leave the object through the closest surface
+                let intersection = scene.intersect(&mut ray)?;
+                let transmittance = exp(sigma_t * -intersection.distance);
+                throughput = throughput * transmittance / average(transmittance);
+                return Some(SubsurfaceExit {
+                    intersection: PrimitiveIntersection {
+                        material: &self.exit_material,
+                        ..intersection
+                    },
+                    w_o: ray.direction,
+                    throughput,
+                });
+            }
+
+            // Russian roulette, since long walks through bright media carry
+            // little light each
+            let max_component = throughput.r.max(throughput.g.max(throughput.b));
+            if max_component < 0.25 {
+                let q = 1.0 - max_component;
+                if rng.gen::<f64>() < q {
+                    return None;
+                }
+                throughput /= 1.0 - q;
+            }
+        }
+        None
+    }
+}
+
+/// Inverts the multiple scattering albedo of a semi-infinite medium to find
+/// its single scattering albedo
+///
+/// Source: https://graphics.pixar.com/library/PathTracedSubsurface/paper.pdf
+fn single_scattering_albedo(albedo: Color) -> Color {
+    Color::from(channels(albedo).map(|a| {
+        let a = a.clamp(0.0, 1.0);
+        let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+        (1.0 - s * s).clamp(0.0, 1.0)
+    }))
+}
+
+fn channels(color: Color) -> [f64; 3] {
+    [color.r, color.g, color.b]
+}
+
+fn exp(color: Color) -> Color {
+    Color::from(channels(color).map(f64::exp))
+}
+
+fn average(color: Color) -> f64 {
+    (color.r + color.g + color.b) / 3.0
+}
+
+/// Walks use their own random numbers, since their length varies and they
+/// would otherwise desynchronise the sampler's dimensions
+fn seeded_rng(origin: &Point, seed: Sample1d) -> SmallRng {
+    let mut hasher = DefaultHasher::new();
+    origin.x().to_bits().hash(&mut hasher);
+    origin.y().to_bits().hash(&mut hasher);
+    origin.z().to_bits().hash(&mut hasher);
+    seed.take().to_bits().hash(&mut hasher);
+    SmallRng::seed_from_u64(hasher.finish())
+}
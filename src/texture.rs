@@ -1,48 +1,161 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    ops::{Add, Mul},
+    sync::Arc,
+};
 
-use image::{DynamicImage, Pixel, Rgb, RgbImage};
+use image::{ColorType, DynamicImage};
 
-use crate::color::Color;
+use crate::{
+    color::Color,
+    geometry::{point::Point, vector::Vector, O},
+    noise::{fbm, noise, turbulence, worley},
+};
+
+/// Coordinates at which a texture is evaluated, along with their rate of
+/// change per pixel on the film, which is used to filter textures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureCoordinates {
+    pub u: f64,
+    pub v: f64,
+    pub du_dx: f64,
+    pub dv_dx: f64,
+    pub du_dy: f64,
+    pub dv_dy: f64,
+    // Location in the object space of the shape, for solid textures
+    pub p: Point,
+    pub dpdx: Vector,
+    pub dpdy: Vector,
+}
+
+impl TextureCoordinates {
+    pub fn new(u: f64, v: f64) -> Self {
+        TextureCoordinates {
+            u,
+            v,
+            du_dx: 0.0,
+            dv_dx: 0.0,
+            du_dy: 0.0,
+            dv_dy: 0.0,
+            p: O,
+            dpdx: Vector(0.0, 0.0, 0.0),
+            dpdy: Vector(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+impl From<(f64, f64)> for TextureCoordinates {
+    fn from((u, v): (f64, f64)) -> Self {
+        TextureCoordinates::new(u, v)
+    }
+}
 
 #[derive(Clone, PartialEq)]
 pub enum Texture<T> {
     Constant(T),
-    Checkerboard { a: T, b: T, scale: f64 },
-    Image(RgbImage),
+    Checkerboard {
+        a: T,
+        b: T,
+        scale: f64,
+    },
+    Image(Arc<MipMap<T>>),
+    /// Blends between `a` and `b` using a procedural pattern in [0, 1]
+    Procedural {
+        a: T,
+        b: T,
+        pattern: Pattern,
+        mapping: TextureMapping,
+        scale: f64,
+    },
+    /// `texture` multiplied by a scalar texture
+    Scale {
+        texture: Arc<Texture<T>>,
+        scale: Arc<Texture<f64>>,
+    },
+    /// Component-wise product of two textures
+    Multiply(Arc<Texture<T>>, Arc<Texture<T>>),
+    /// Linear interpolation from `a` to `b` by `amount`
+    Mix {
+        a: Arc<Texture<T>>,
+        b: Arc<Texture<T>>,
+        amount: Arc<Texture<f64>>,
+    },
+    /// Maps a scalar texture through a piecewise linear ramp. The stops are
+    /// sorted by position.
+    Ramp {
+        factor: Arc<Texture<f64>>,
+        stops: Vec<(f64, T)>,
+    },
+    /// One minus the texture
+    Invert(Arc<Texture<T>>),
+    /// Evaluates `texture` with transformed uv coordinates
+    UvTransform {
+        texture: Arc<Texture<T>>,
+        transform: UvTransform,
+    },
 }
 
+/// Conversion from linear texel values read from an image
 pub trait FromPixel {
-    fn from_pixel(pixel: &Rgb<u8>) -> Self;
+    fn from_rgb(rgb: [f64; 3]) -> Self;
+    fn from_gray(value: f64) -> Self;
+}
+
+/// Values that can be stored in and filtered from an image texture
+pub trait Texel:
+    Copy + FromPixel + Add<Output = Self> + Mul<Output = Self> + Mul<f64, Output = Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
 }
 
-impl<T: Copy + FromPixel> Texture<T> {
-    pub fn eval(&self, &(u, v): &(f64, f64)) -> T {
+impl<T: Texel> Texture<T> {
+    pub fn eval(&self, uv: &TextureCoordinates) -> T {
         match self {
             Self::Constant(t) => *t,
             Self::Checkerboard { a, b, scale } => {
-                let u = (u * scale * 2.0) as usize;
-                let v = (v * scale * 2.0) as usize;
+                let u = (uv.u * scale * 2.0) as usize;
+                let v = (uv.v * scale * 2.0) as usize;
                 if (u & 1) ^ (v & 1) == 0 {
                     *a
                 } else {
                     *b
                 }
             }
-            Self::Image(image) => {
-                let mut u = u.fract();
-                if u < 0.0 {
-                    u += 1.0;
-                }
-
-                let mut v = v.fract();
-                if v < 0.0 {
-                    v += 1.0;
+            Self::Image(mipmap) => mipmap.lookup(uv),
+            Self::Procedural {
+                a,
+                b,
+                pattern,
+                mapping,
+                scale,
+            } => {
+                let (p, dpdx, dpdy) = mapping.map(uv, *scale);
+                let t = pattern.eval(&p, &dpdx, &dpdy);
+                *a * (1.0 - t) + *b * t
+            }
+            Self::Scale { texture, scale } => texture.eval(uv) * scale.eval(uv),
+            Self::Multiply(a, b) => a.eval(uv) * b.eval(uv),
+            Self::Mix { a, b, amount } => {
+                let t = amount.eval(uv);
+                a.eval(uv) * (1.0 - t) + b.eval(uv) * t
+            }
+            Self::Ramp { factor, stops } => {
+                let t = factor.eval(uv);
+                let i = stops.partition_point(|(position, _)| *position <= t);
+                if i == 0 {
+                    stops[0].1
+                } else if i == stops.len() {
+                    stops[i - 1].1
+                } else {
+                    let (p0, v0) = stops[i - 1];
+                    let (p1, v1) = stops[i];
+                    let delta = (t - p0) / (p1 - p0);
+                    v0 * (1.0 - delta) + v1 * delta
                 }
-
-                let x = ((image.width() - 1) as f64 * u) as u32;
-                let y = ((image.height() - 1) as f64 * v) as u32;
-                T::from_pixel(image.get_pixel(x, y))
             }
+            Self::Invert(texture) => T::ONE + texture.eval(uv) * -1.0,
+            Self::UvTransform { texture, transform } => texture.eval(&transform.apply(uv)),
         }
     }
 
@@ -54,8 +167,80 @@ impl<T: Copy + FromPixel> Texture<T> {
         Self::Checkerboard { a, b, scale }
     }
 
+    pub fn procedural(a: T, b: T, pattern: Pattern, mapping: TextureMapping, scale: f64) -> Self {
+        Self::Procedural {
+            a,
+            b,
+            pattern,
+            mapping,
+            scale,
+        }
+    }
+
+    pub fn scale(texture: Texture<T>, scale: Texture<f64>) -> Self {
+        Self::Scale {
+            texture: Arc::new(texture),
+            scale: Arc::new(scale),
+        }
+    }
+
+    pub fn multiply(a: Texture<T>, b: Texture<T>) -> Self {
+        Self::Multiply(Arc::new(a), Arc::new(b))
+    }
+
+    pub fn mix(a: Texture<T>, b: Texture<T>, amount: Texture<f64>) -> Self {
+        Self::Mix {
+            a: Arc::new(a),
+            b: Arc::new(b),
+            amount: Arc::new(amount),
+        }
+    }
+
+    /// Returns None if there are no stops
+    pub fn ramp(factor: Texture<f64>, mut stops: Vec<(f64, T)>) -> Option<Self> {
+        if stops.is_empty() {
+            return None;
+        }
+        stops.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Some(Self::Ramp {
+            factor: Arc::new(factor),
+            stops,
+        })
+    }
+
+    pub fn invert(texture: Texture<T>) -> Self {
+        Self::Invert(Arc::new(texture))
+    }
+
+    pub fn uv_transform(texture: Texture<T>, transform: UvTransform) -> Self {
+        Self::UvTransform {
+            texture: Arc::new(texture),
+            transform,
+        }
+    }
+
     pub fn image(image: DynamicImage) -> Self {
-        Self::Image(image.to_rgb8())
+        let color_space = ColorSpace::default_for(&image);
+        Self::image_with_options(
+            image,
+            color_space,
+            FilterMethod::Trilinear,
+            WrapMode::Repeat,
+        )
+    }
+
+    pub fn image_with_options(
+        image: DynamicImage,
+        color_space: ColorSpace,
+        filter_method: FilterMethod,
+        wrap_mode: WrapMode,
+    ) -> Self {
+        Self::Image(Arc::new(MipMap::new(
+            &image,
+            color_space,
+            filter_method,
+            wrap_mode,
+        )))
     }
 }
 
@@ -71,10 +256,49 @@ impl<T: Debug> Debug for Texture<T> {
                 .field("b", b)
                 .field("scale", scale)
                 .finish(),
-            Self::Image(image) => f
+            Self::Image(mipmap) => f
                 .debug_tuple("Image")
-                .field(&image.width())
-                .field(&image.height())
+                .field(&mipmap.width())
+                .field(&mipmap.height())
+                .field(&mipmap.filter_method)
+                .field(&mipmap.wrap_mode)
+                .finish(),
+            Self::Procedural {
+                a,
+                b,
+                pattern,
+                mapping,
+                scale,
+            } => f
+                .debug_struct("Procedural")
+                .field("a", a)
+                .field("b", b)
+                .field("pattern", pattern)
+                .field("mapping", mapping)
+                .field("scale", scale)
+                .finish(),
+            Self::Scale { texture, scale } => f
+                .debug_struct("Scale")
+                .field("texture", texture)
+                .field("scale", scale)
+                .finish(),
+            Self::Multiply(a, b) => f.debug_tuple("Multiply").field(a).field(b).finish(),
+            Self::Mix { a, b, amount } => f
+                .debug_struct("Mix")
+                .field("a", a)
+                .field("b", b)
+                .field("amount", amount)
+                .finish(),
+            Self::Ramp { factor, stops } => f
+                .debug_struct("Ramp")
+                .field("factor", factor)
+                .field("stops", stops)
+                .finish(),
+            Self::Invert(texture) => f.debug_tuple("Invert").field(texture).finish(),
+            Self::UvTransform { texture, transform } => f
+                .debug_struct("UvTransform")
+                .field("texture", texture)
+                .field("transform", transform)
                 .finish(),
         }
     }
@@ -86,6 +310,13 @@ impl Texture<Color> {
             Texture::Constant(c) => c.is_black(),
             Texture::Checkerboard { a, b, .. } => a.is_black() && b.is_black(),
             Texture::Image(_) => false,
+            Texture::Procedural { a, b, .. } => a.is_black() && b.is_black(),
+            Texture::Scale { texture, scale } => texture.is_black() || scale.is_zero(),
+            Texture::Multiply(a, b) => a.is_black() || b.is_black(),
+            Texture::Mix { a, b, .. } => a.is_black() && b.is_black(),
+            Texture::Ramp { stops, .. } => stops.iter().all(|(_, c)| c.is_black()),
+            Texture::Invert(_) => false,
+            Texture::UvTransform { texture, .. } => texture.is_black(),
         }
     }
 }
@@ -96,18 +327,508 @@ impl Texture<f64> {
             &Texture::Constant(c) => c == 0.0,
             &Texture::Checkerboard { a, b, .. } => a == 0.0 && b == 0.0,
             &Texture::Image(_) => false,
+            &Texture::Procedural { a, b, .. } => a == 0.0 && b == 0.0,
+            Texture::Scale { texture, scale } => texture.is_zero() || scale.is_zero(),
+            Texture::Multiply(a, b) => a.is_zero() || b.is_zero(),
+            Texture::Mix { a, b, .. } => a.is_zero() && b.is_zero(),
+            Texture::Ramp { stops, .. } => stops.iter().all(|(_, t)| *t == 0.0),
+            Texture::Invert(_) => false,
+            Texture::UvTransform { texture, .. } => texture.is_zero(),
         }
     }
 }
 
 impl FromPixel for f64 {
-    fn from_pixel(pixel: &Rgb<u8>) -> f64 {
-        pixel.to_luma()[0] as f64 / 255.0
+    fn from_rgb([r, g, b]: [f64; 3]) -> f64 {
+        // Luminance using the same Rec. 709 weights as `image`'s luma conversion
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
+    fn from_gray(value: f64) -> f64 {
+        value
     }
 }
 
 impl FromPixel for Color {
-    fn from_pixel(value: &Rgb<u8>) -> Self {
-        Color::from_rgb(value[0], value[1], value[2])
+    fn from_rgb(rgb: [f64; 3]) -> Self {
+        rgb.into()
+    }
+
+    fn from_gray(value: f64) -> Self {
+        [value; 3].into()
     }
 }
+
+impl Texel for f64 {
+    const ZERO: f64 = 0.0;
+    const ONE: f64 = 1.0;
+}
+
+impl Texel for Color {
+    const ZERO: Color = Color::BLACK;
+    const ONE: Color = Color::WHITE;
+}
+
+/// Affine transformation of texture coordinates, which scales, then rotates
+/// counter-clockwise, then offsets them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvTransform {
+    // Linear part as a row-major 2x2 matrix
+    m: [[f64; 2]; 2],
+    offset: (f64, f64),
+}
+
+impl UvTransform {
+    pub fn new(offset: (f64, f64), scale: (f64, f64), rotate_degrees: f64) -> Self {
+        let (sin, cos) = rotate_degrees.to_radians().sin_cos();
+        UvTransform {
+            m: [
+                [cos * scale.0, -sin * scale.1],
+                [sin * scale.0, cos * scale.1],
+            ],
+            offset,
+        }
+    }
+
+    pub fn apply(&self, uv: &TextureCoordinates) -> TextureCoordinates {
+        let [[a, b], [c, d]] = self.m;
+        TextureCoordinates {
+            u: a * uv.u + b * uv.v + self.offset.0,
+            v: c * uv.u + d * uv.v + self.offset.1,
+            du_dx: a * uv.du_dx + b * uv.dv_dx,
+            dv_dx: c * uv.du_dx + d * uv.dv_dx,
+            du_dy: a * uv.du_dy + b * uv.dv_dy,
+            dv_dy: c * uv.du_dy + d * uv.dv_dy,
+            ..*uv
+        }
+    }
+}
+
+/// The space in which procedural textures are evaluated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureMapping {
+    /// (u, v, 0)
+    Uv,
+    /// The location in the object space of the shape that was hit, so that the
+    /// pattern moves with the object
+    Object,
+}
+
+impl TextureMapping {
+    /// Returns the point at which to evaluate a texture along with its change
+    /// per pixel, multiplied by `scale`
+    fn map(self, uv: &TextureCoordinates, scale: f64) -> (Point, Vector, Vector) {
+        let (p, dpdx, dpdy) = match self {
+            TextureMapping::Uv => (
+                Vector(uv.u, uv.v, 0.0),
+                Vector(uv.du_dx, uv.dv_dx, 0.0),
+                Vector(uv.du_dy, uv.dv_dy, 0.0),
+            ),
+            TextureMapping::Object => (uv.p - O, uv.dpdx, uv.dpdy),
+        };
+        (O + p * scale, dpdx * scale, dpdy * scale)
+    }
+}
+
+/// Procedural patterns, which all produce values in [0, 1]
+///
+/// Source: https://pbr-book.org/3ed-2018/Texture/Noise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pattern {
+    /// Perlin noise
+    Noise,
+    /// Fractional Brownian motion, a sum of `octaves` layers of noise where
+    /// each layer has twice the frequency and `roughness` times the amplitude
+    /// of the previous one
+    Fbm { octaves: usize, roughness: f64 },
+    /// Like `Fbm`, but sums the absolute value of the noise
+    Turbulence { octaves: usize, roughness: f64 },
+    /// Bands along the y axis perturbed by fBm
+    Marble {
+        octaves: usize,
+        roughness: f64,
+        variation: f64,
+    },
+    /// Rings around the y axis perturbed by noise
+    Wood { variation: f64 },
+    /// Distance to the nearest of a set of randomly scattered points, which
+    /// gives a cellular pattern
+    Worley,
+}
+
+impl Pattern {
+    pub fn eval(&self, p: &Point, dpdx: &Vector, dpdy: &Vector) -> f64 {
+        let t = match *self {
+            Pattern::Noise => 0.5 + 0.5 * noise(p),
+            Pattern::Fbm { octaves, roughness } => {
+                0.5 + 0.5 * fbm(p, dpdx, dpdy, roughness, octaves)
+            }
+            Pattern::Turbulence { octaves, roughness } => {
+                turbulence(p, dpdx, dpdy, roughness, octaves)
+            }
+            Pattern::Marble {
+                octaves,
+                roughness,
+                variation,
+            } => {
+                let marble = p.y() + variation * fbm(p, dpdx, dpdy, roughness, octaves);
+                0.5 + 0.5 * marble.sin()
+            }
+            Pattern::Wood { variation } => {
+                let r = (p.x() * p.x() + p.z() * p.z()).sqrt() + variation * noise(p);
+                r - r.floor()
+            }
+            Pattern::Worley => worley(p),
+        };
+        t.clamp(0.0, 1.0)
+    }
+}
+
+/// How the values stored in an image map to linear values used for rendering
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// Values are already linear, as in most HDR formats or data textures like
+    /// roughness maps
+    Linear,
+    /// Values are encoded with the sRGB transfer function, as in most 8-bit
+    /// color images
+    Srgb,
+}
+
+impl ColorSpace {
+    /// Floating point and single-channel images are assumed to be linear,
+    /// everything else is assumed to be sRGB encoded
+    pub fn default_for(image: &DynamicImage) -> Self {
+        match image.color() {
+            ColorType::Rgb32F | ColorType::Rgba32F => ColorSpace::Linear,
+            color_type if color_type.channel_count() <= 2 => ColorSpace::Linear,
+            _ => ColorSpace::Srgb,
+        }
+    }
+
+    /// Converts a value in [0, 1] (or larger for HDR images) to linear
+    ///
+    /// Source: https://pbr-book.org/3ed-2018/Texture/Image_Texture
+    pub fn to_linear(self, value: f64) -> f64 {
+        match self {
+            ColorSpace::Linear => value,
+            ColorSpace::Srgb => {
+                if value <= 0.04045 {
+                    value / 12.92
+                } else {
+                    ((value + 0.055) / 1.055).powf(2.4)
+                }
+            }
+        }
+    }
+}
+
+/// How texture coordinates outside [0, 1] are mapped back onto the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMethod {
+    /// Bilinear interpolation on the full resolution image
+    Bilinear,
+    /// Linear interpolation between bilinear lookups in the two MIP levels
+    /// closest to the filter width
+    Trilinear,
+    /// Elliptically weighted average over the (possibly anisotropic) footprint
+    /// of the pixel on the texture
+    Ewa,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct MipLevel<T> {
+    width: usize,
+    height: usize,
+    texels: Vec<T>,
+}
+
+/// An image pyramid where each level is half the resolution of the previous
+/// one, rounded up, so that lookups with a wide footprint can be answered by looking at a
+/// few texels in a coarser level.
+///
+/// Source: https://pbr-book.org/3ed-2018/Texture/Image_Texture#MIPMaps
+#[derive(Debug, Clone, PartialEq)]
+pub struct MipMap<T> {
+    // Ordered from the full resolution image to a single texel
+    levels: Vec<MipLevel<T>>,
+    filter_method: FilterMethod,
+    wrap_mode: WrapMode,
+}
+
+/// Indices and weights of the texels in a row (or column) of `size` texels
+/// that each texel of the next MIP level covers. The next level is half the
+/// size, rounded up so that every texel reaches the coarser levels. Odd sizes
+/// don't divide evenly, so the texels on the boundaries are shared.
+fn downsample_weights(size: usize) -> Vec<Vec<(usize, f64)>> {
+    let next_size = size.div_ceil(2);
+    let scale = size as f64 / next_size as f64;
+    (0..next_size)
+        .map(|i| {
+            let (start, end) = (i as f64 * scale, (i + 1) as f64 * scale);
+            (start.floor() as usize..(end.ceil() as usize).min(size))
+                .map(|j| {
+                    let overlap = end.min((j + 1) as f64) - start.max(j as f64);
+                    (j, overlap / scale)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+const EWA_LUT_SIZE: usize = 128;
+const MAX_ANISOTROPY: f64 = 8.0;
+
+impl<T> MipMap<T> {
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+}
+
+impl<T: Texel> MipMap<T> {
+    pub fn new(
+        image: &DynamicImage,
+        color_space: ColorSpace,
+        filter_method: FilterMethod,
+        wrap_mode: WrapMode,
+    ) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        assert!(width > 0 && height > 0, "Empty image texture");
+
+        // Read the image as floats so that HDR and 16-bit images keep their
+        // range and precision. Single-channel images are read as they are
+        // instead of going through an rgb conversion.
+        let to_linear = |value: f32| color_space.to_linear(value as f64);
+        let texels = if image.color().channel_count() <= 2 {
+            image
+                .to_luma32f()
+                .pixels()
+                .map(|pixel| T::from_gray(to_linear(pixel[0])))
+                .collect()
+        } else {
+            image
+                .to_rgb32f()
+                .pixels()
+                .map(|pixel| T::from_rgb(pixel.0.map(to_linear)))
+                .collect()
+        };
+
+        let mut levels = vec![MipLevel {
+            width,
+            height,
+            texels,
+        }];
+        loop {
+            let previous = levels.last().unwrap();
+            if previous.width == 1 && previous.height == 1 {
+                break;
+            }
+            // Box filter the texels of the previous level that each texel
+            // covers, which are 2x2 unless the previous level has an odd size
+            let x_weights = downsample_weights(previous.width);
+            let y_weights = downsample_weights(previous.height);
+            let (width, height) = (x_weights.len(), y_weights.len());
+            let mut texels = Vec::with_capacity(width * height);
+            for y_texels in &y_weights {
+                for x_texels in &x_weights {
+                    let mut texel = T::ZERO;
+                    for &(y, y_weight) in y_texels {
+                        for &(x, x_weight) in x_texels {
+                            texel = texel
+                                + previous.texels[x + y * previous.width] * (x_weight * y_weight);
+                        }
+                    }
+                    texels.push(texel);
+                }
+            }
+            levels.push(MipLevel {
+                width,
+                height,
+                texels,
+            });
+        }
+
+        MipMap {
+            levels,
+            filter_method,
+            wrap_mode,
+        }
+    }
+
+    /// Returns the texel at integer co-ordinates in the given level, after
+    /// applying the wrap mode
+    pub fn texel(&self, level: usize, x: i64, y: i64) -> T {
+        let level = &self.levels[level];
+        let wrap = |i: i64, size: usize| -> usize {
+            let size = size as i64;
+            match self.wrap_mode {
+                WrapMode::Repeat => i.rem_euclid(size) as usize,
+                WrapMode::Clamp => i.clamp(0, size - 1) as usize,
+                WrapMode::Mirror => {
+                    let i = i.rem_euclid(2 * size);
+                    (if i < size { i } else { 2 * size - 1 - i }) as usize
+                }
+            }
+        };
+        level.texels[wrap(x, level.width) + wrap(y, level.height) * level.width]
+    }
+
+    pub fn lookup(&self, uv: &TextureCoordinates) -> T {
+        match self.filter_method {
+            FilterMethod::Bilinear => self.bilinear(0, uv.u, uv.v),
+            FilterMethod::Trilinear => {
+                let width = 2.0
+                    * uv.du_dx
+                        .abs()
+                        .max(uv.dv_dx.abs())
+                        .max(uv.du_dy.abs())
+                        .max(uv.dv_dy.abs());
+                self.trilinear(uv.u, uv.v, width)
+            }
+            FilterMethod::Ewa => self.ewa(
+                uv.u,
+                uv.v,
+                (uv.du_dx * 2.0, uv.dv_dx * 2.0),
+                (uv.du_dy * 2.0, uv.dv_dy * 2.0),
+            ),
+        }
+    }
+
+    fn bilinear(&self, level: usize, u: f64, v: f64) -> T {
+        let MipLevel { width, height, .. } = self.levels[level];
+        let s = u * width as f64 - 0.5;
+        let t = v * height as f64 - 0.5;
+        let (s0, t0) = (s.floor(), t.floor());
+        let (ds, dt) = (s - s0, t - t0);
+        let (s0, t0) = (s0 as i64, t0 as i64);
+        self.texel(level, s0, t0) * ((1.0 - ds) * (1.0 - dt))
+            + self.texel(level, s0 + 1, t0) * (ds * (1.0 - dt))
+            + self.texel(level, s0, t0 + 1) * ((1.0 - ds) * dt)
+            + self.texel(level, s0 + 1, t0 + 1) * (ds * dt)
+    }
+
+    /// Picks the level where the filter `width` spans about one texel
+    fn level_for_width(&self, width: f64) -> f64 {
+        (self.num_levels() - 1) as f64 + width.max(1e-8).log2()
+    }
+
+    fn trilinear(&self, u: f64, v: f64, width: f64) -> T {
+        let level = self.level_for_width(width);
+        if level <= 0.0 {
+            self.bilinear(0, u, v)
+        } else if level >= (self.num_levels() - 1) as f64 {
+            self.texel(self.num_levels() - 1, 0, 0)
+        } else {
+            let level_floor = level.floor();
+            let delta = level - level_floor;
+            let level_floor = level_floor as usize;
+            self.bilinear(level_floor, u, v) * (1.0 - delta)
+                + self.bilinear(level_floor + 1, u, v) * delta
+        }
+    }
+
+    fn ewa(&self, u: f64, v: f64, mut major: (f64, f64), mut minor: (f64, f64)) -> T {
+        let length_squared = |(x, y): (f64, f64)| x * x + y * y;
+        if length_squared(major) < length_squared(minor) {
+            std::mem::swap(&mut major, &mut minor);
+        }
+        let major_length = length_squared(major).sqrt();
+        let mut minor_length = length_squared(minor).sqrt();
+
+        // Clamp the eccentricity of the ellipse, since very long ellipses can
+        // cover a huge number of texels
+        if minor_length * MAX_ANISOTROPY < major_length && minor_length > 0.0 {
+            let scale = major_length / (minor_length * MAX_ANISOTROPY);
+            minor = (minor.0 * scale, minor.1 * scale);
+            minor_length *= scale;
+        }
+        if minor_length == 0.0 {
+            return self.bilinear(0, u, v);
+        }
+
+        let level = self.level_for_width(minor_length).max(0.0);
+        let level_floor = level.floor();
+        let delta = level - level_floor;
+        let level_floor = level_floor as usize;
+        self.ewa_level(level_floor, u, v, major, minor) * (1.0 - delta)
+            + self.ewa_level(level_floor + 1, u, v, major, minor) * delta
+    }
+
+    fn ewa_level(&self, level: usize, u: f64, v: f64, d0: (f64, f64), d1: (f64, f64)) -> T {
+        if level >= self.num_levels() {
+            return self.texel(self.num_levels() - 1, 0, 0);
+        }
+
+        // Convert to texel co-ordinates in this level
+        let MipLevel { width, height, .. } = self.levels[level];
+        let (width, height) = (width as f64, height as f64);
+        let s = u * width - 0.5;
+        let t = v * height - 0.5;
+        let d0 = (d0.0 * width, d0.1 * height);
+        let d1 = (d1.0 * width, d1.1 * height);
+
+        // Coefficients of the implicit ellipse equation, normalized so that
+        // points inside the ellipse have a value < 1
+        let mut a = d0.1 * d0.1 + d1.1 * d1.1 + 1.0;
+        let mut b = -2.0 * (d0.0 * d0.1 + d1.0 * d1.1);
+        let mut c = d0.0 * d0.0 + d1.0 * d1.0 + 1.0;
+        let inv_f = 1.0 / (a * c - b * b * 0.25);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+
+        // Bounding box of the ellipse in texel space
+        let determinant = -b * b + 4.0 * a * c;
+        let inv_determinant = 1.0 / determinant;
+        let u_sqrt = (determinant * c).sqrt();
+        let v_sqrt = (a * determinant).sqrt();
+        let s0 = (s - 2.0 * inv_determinant * u_sqrt).ceil() as i64;
+        let s1 = (s + 2.0 * inv_determinant * u_sqrt).floor() as i64;
+        let t0 = (t - 2.0 * inv_determinant * v_sqrt).ceil() as i64;
+        let t1 = (t + 2.0 * inv_determinant * v_sqrt).floor() as i64;
+
+        let mut sum = T::ZERO;
+        let mut sum_weights = 0.0;
+        for it in t0..=t1 {
+            let tt = it as f64 - t;
+            for is in s0..=s1 {
+                let ss = is as f64 - s;
+                let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+                if r2 < 1.0 {
+                    let weight = ewa_weight(r2);
+                    sum = sum + self.texel(level, is, it) * weight;
+                    sum_weights += weight;
+                }
+            }
+        }
+        if sum_weights > 0.0 {
+            sum * (1.0 / sum_weights)
+        } else {
+            self.bilinear(level, u, v)
+        }
+    }
+}
+
+/// Gaussian filter weight for a squared radius in [0, 1), quantized the same
+/// way as pbrt's lookup table
+fn ewa_weight(r2: f64) -> f64 {
+    const ALPHA: f64 = 2.0;
+    let index = ((r2 * EWA_LUT_SIZE as f64) as usize).min(EWA_LUT_SIZE - 1);
+    let r2 = index as f64 / (EWA_LUT_SIZE - 1) as f64;
+    (-ALPHA * r2).exp() - (-ALPHA).exp()
+}
@@ -1,12 +1,12 @@
 use approx::{assert_abs_diff_eq, AbsDiffEq};
-use std::{fmt::Display, ops::Mul};
+use std::{borrow::Cow, fmt::Display, ops::Mul};
 
 use crate::{
     bounds::Bounds,
     constants::EPSILON,
     geometry::{normal::Normal, point::Point, traits::DotProduct, vector::Vector},
-    intersection::ShapeIntersection,
-    ray::Ray,
+    intersection::{PrimitiveIntersection, ShadingGeometry, ShapeIntersection},
+    ray::{Ray, RayDifferentials},
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -255,6 +255,11 @@ pub struct Transformation {
 }
 
 impl Transformation {
+    pub const I: Transformation = Transformation {
+        matrix: Matrix::I,
+        inverse: Matrix::I,
+    };
+
     pub fn is_valid(&self) -> bool {
         self.matrix.is_valid() && self.inverse.is_valid()
     }
@@ -353,6 +358,40 @@ impl Transformation {
         Transformation { matrix, inverse }
     }
 
+    /// Rotates around an arbitrary `axis` through the origin
+    ///
+    /// Source: https://www.pbr-book.org/3ed-2018/Geometry_and_Transformations/Transformations#RotationaroundanArbitraryAxis
+    pub fn rotate(radians: f64, axis: &Vector) -> Self {
+        let a = axis.normalized();
+        let sin = radians.sin();
+        let cos = radians.cos();
+        let matrix = Matrix {
+            m: [
+                [
+                    a.x() * a.x() + (1.0 - a.x() * a.x()) * cos,
+                    a.x() * a.y() * (1.0 - cos) - a.z() * sin,
+                    a.x() * a.z() * (1.0 - cos) + a.y() * sin,
+                    0.0,
+                ],
+                [
+                    a.x() * a.y() * (1.0 - cos) + a.z() * sin,
+                    a.y() * a.y() + (1.0 - a.y() * a.y()) * cos,
+                    a.y() * a.z() * (1.0 - cos) - a.x() * sin,
+                    0.0,
+                ],
+                [
+                    a.x() * a.z() * (1.0 - cos) - a.y() * sin,
+                    a.y() * a.z() * (1.0 - cos) + a.x() * sin,
+                    a.z() * a.z() + (1.0 - a.z() * a.z()) * cos,
+                    0.0,
+                ],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        };
+        let inverse = matrix.transpose();
+        Transformation { matrix, inverse }
+    }
+
     pub fn look_at(origin: Point, target: Point, up: Vector) -> Self {
         let z = (target - origin).normalized();
         let x = up.normalized().cross(&z).normalized();
@@ -458,7 +497,13 @@ impl Transformable<Ray> for Transformation {
         let mut transformed_ray =
             Ray::new(self.transform(&ray.origin), self.transform(&ray.direction));
         transformed_ray.update_max_distance(ray.max_distance);
-        transformed_ray
+        transformed_ray.differentials = ray.differentials.map(|d| RayDifferentials {
+            rx_origin: self.transform(&d.rx_origin),
+            rx_direction: self.transform(&d.rx_direction),
+            ry_origin: self.transform(&d.ry_origin),
+            ry_direction: self.transform(&d.ry_direction),
+        });
+        transformed_ray.with_time(ray.time)
     }
 }
 
@@ -483,11 +528,356 @@ impl Transformable<Bounds> for Transformation {
 
 impl Transformable<ShapeIntersection> for Transformation {
     fn transform(&self, intersection: &ShapeIntersection) -> ShapeIntersection {
+        // Normals only keep their length under rigid transformations
         ShapeIntersection {
             location: self.transform(&intersection.location),
-            normal: self.transform(&intersection.normal),
+            normal: self.transform(&intersection.normal).normalized(),
+            shading_normal: self.transform(&intersection.shading_normal).normalized(),
+            tangent: self.transform(&intersection.tangent),
             uv: intersection.uv,
+            dpdu: self.transform(&intersection.dpdu),
+            dpdv: self.transform(&intersection.dpdv),
+            dndu: self.transform(&intersection.dndu),
+            dndv: self.transform(&intersection.dndv),
+        }
+    }
+}
+
+/// Texture coordinates are left in the space the intersection was found in,
+/// so that solid textures move along with instanced objects
+impl<'a> Transformable<PrimitiveIntersection<'a>> for Transformation {
+    fn transform(&self, intersection: &PrimitiveIntersection<'a>) -> PrimitiveIntersection<'a> {
+        let shading = &intersection.shading;
+        PrimitiveIntersection {
+            distance: intersection.distance,
+            location: self.transform(&intersection.location),
+            normal: self.transform(&intersection.normal).normalized(),
+            material: intersection.material,
+            primitive: intersection.primitive,
+            uv: intersection.uv,
+            dpdu: self.transform(&intersection.dpdu),
+            dpdv: self.transform(&intersection.dpdv),
+            dndu: self.transform(&intersection.dndu),
+            dndv: self.transform(&intersection.dndv),
+            dpdx: self.transform(&intersection.dpdx),
+            dpdy: self.transform(&intersection.dpdy),
+            shading: ShadingGeometry {
+                normal: self.transform(&shading.normal).normalized(),
+                dpdu: self.transform(&shading.dpdu),
+                dpdv: self.transform(&shading.dpdv),
+                dndu: self.transform(&shading.dndu),
+                dndv: self.transform(&shading.dndv),
+            },
+            time: intersection.time,
+        }
+    }
+}
+
+/// How many times between each pair of keyframes the bounds of a moving
+/// object are found at
+const MOTION_BOUNDS_STEPS: usize = 32;
+
+/// A rotation stored as a unit quaternion `[x, y, z, w]`, which unlike a
+/// matrix can be interpolated without distorting the shape it rotates
+///
+/// Source: https://pbr-book.org/3ed-2018/Geometry_and_Transformations/Quaternions
+#[derive(Debug, Clone, Copy)]
+struct Quaternion([f64; 4]);
+
+impl Quaternion {
+    /// Converts the rotation in the upper 3x3 part of `matrix`
+    fn from_matrix(matrix: &Matrix) -> Self {
+        let m = &matrix.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt();
+            let w = s * 0.5;
+            let s = 0.5 / s;
+            return Quaternion([
+                (m[2][1] - m[1][2]) * s,
+                (m[0][2] - m[2][0]) * s,
+                (m[1][0] - m[0][1]) * s,
+                w,
+            ]);
+        }
+        // Compute the largest of x, y and z first, for numerical stability
+        let i = if m[1][1] > m[0][0] { 1 } else { 0 };
+        let i = if m[2][2] > m[i][i] { 2 } else { i };
+        let j = (i + 1) % 3;
+        let k = (j + 1) % 3;
+        let s = (m[i][i] - (m[j][j] + m[k][k]) + 1.0).sqrt();
+        let mut q = [0.0; 4];
+        q[i] = s * 0.5;
+        let s = if s != 0.0 { 0.5 / s } else { s };
+        q[j] = (m[j][i] + m[i][j]) * s;
+        q[k] = (m[k][i] + m[i][k]) * s;
+        q[3] = (m[k][j] - m[j][k]) * s;
+        Quaternion(q)
+    }
+
+    fn to_transformation(self) -> Transformation {
+        let [x, y, z, w] = self.0;
+        let matrix = Matrix {
+            m: [
+                [
+                    1.0 - 2.0 * (y * y + z * z),
+                    2.0 * (x * y - z * w),
+                    2.0 * (x * z + y * w),
+                    0.0,
+                ],
+                [
+                    2.0 * (x * y + z * w),
+                    1.0 - 2.0 * (x * x + z * z),
+                    2.0 * (y * z - x * w),
+                    0.0,
+                ],
+                [
+                    2.0 * (x * z - y * w),
+                    2.0 * (y * z + x * w),
+                    1.0 - 2.0 * (x * x + y * y),
+                    0.0,
+                ],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        };
+        let inverse = matrix.transpose();
+        Transformation { matrix, inverse }
+    }
+
+    fn dot(&self, other: &Quaternion) -> f64 {
+        (0..4).map(|i| self.0[i] * other.0[i]).sum()
+    }
+
+    fn normalized(self) -> Quaternion {
+        let length = self.dot(&self).sqrt();
+        Quaternion(self.0.map(|c| c / length))
+    }
+
+    /// Spherical linear interpolation, which rotates at a constant speed
+    fn slerp(t: f64, a: &Quaternion, b: &Quaternion) -> Quaternion {
+        let cos_theta = a.dot(b);
+        if cos_theta > 0.9995 {
+            // Nearly the same rotation, where linear interpolation is as good
+            // and avoids dividing by a tiny sine
+            let mut q = [0.0; 4];
+            for (i, c) in q.iter_mut().enumerate() {
+                *c = a.0[i] * (1.0 - t) + b.0[i] * t;
+            }
+            return Quaternion(q).normalized();
+        }
+        let theta = cos_theta.clamp(-1.0, 1.0).acos();
+        let mut perpendicular = [0.0; 4];
+        for (i, c) in perpendicular.iter_mut().enumerate() {
+            *c = b.0[i] - a.0[i] * cos_theta;
         }
+        let perpendicular = Quaternion(perpendicular).normalized();
+        let (sin, cos) = (theta * t).sin_cos();
+        let mut q = [0.0; 4];
+        for (i, c) in q.iter_mut().enumerate() {
+            *c = a.0[i] * cos + perpendicular.0[i] * sin;
+        }
+        Quaternion(q)
+    }
+}
+
+/// A keyframe split into parts that can each be interpolated
+#[derive(Debug, Clone)]
+struct Keyframe {
+    time: f64,
+    transformation: Transformation,
+    translation: Vector,
+    rotation: Quaternion,
+    /// Scale and shear left over after taking out the rotation
+    scale: Matrix,
+}
+
+impl Keyframe {
+    /// Splits `transformation` into a translation, a rotation and a scale,
+    /// which are applied in the reverse order. Projective transformations
+    /// can't be split like this, so the bottom row of the matrix is ignored.
+    ///
+    /// Source: https://pbr-book.org/3ed-2018/Geometry_and_Transformations/Animating_Transformations
+    fn new(time: f64, transformation: Transformation) -> Self {
+        let m = &transformation.matrix.m;
+        let translation = Vector(m[0][3], m[1][3], m[2][3]);
+        let mut linear = Matrix::I;
+        for (row, m_row) in linear.m.iter_mut().zip(m).take(3) {
+            row[..3].copy_from_slice(&m_row[..3]);
+        }
+
+        // The polar decomposition finds the rotation closest to the matrix,
+        // by averaging it with its inverse transpose until it converges
+        let mut rotation = linear.clone();
+        for _ in 0..100 {
+            let inverse_transpose = match rotation.transpose().inverse() {
+                Some(inverse_transpose) => inverse_transpose,
+                None => break,
+            };
+            let mut next = Matrix::I;
+            let mut change: f64 = 0.0;
+            for i in 0..3 {
+                let mut row_change = 0.0;
+                for j in 0..3 {
+                    next.m[i][j] = 0.5 * (rotation.m[i][j] + inverse_transpose.m[i][j]);
+                    row_change += (next.m[i][j] - rotation.m[i][j]).abs();
+                }
+                change = change.max(row_change);
+            }
+            rotation = next;
+            if change < 1e-12 {
+                break;
+            }
+        }
+        // A mirroring isn't a rotation, so it's left in the scale instead
+        let r = &rotation.m;
+        let determinant = r[0][0] * (r[1][1] * r[2][2] - r[1][2] * r[2][1])
+            - r[0][1] * (r[1][0] * r[2][2] - r[1][2] * r[2][0])
+            + r[0][2] * (r[1][0] * r[2][1] - r[1][1] * r[2][0]);
+        if determinant < 0.0 {
+            for row in rotation.m.iter_mut().take(3) {
+                for value in row.iter_mut().take(3) {
+                    *value = -*value;
+                }
+            }
+        }
+        let scale = &rotation.transpose() * &linear;
+
+        Keyframe {
+            time,
+            translation,
+            rotation: Quaternion::from_matrix(&rotation),
+            scale,
+            transformation,
+        }
+    }
+
+    /// The transformation a fraction `t` of the way from `self` to `next`
+    fn interpolate(&self, next: &Keyframe, t: f64) -> Transformation {
+        let translation = self.translation * (1.0 - t) + next.translation * t;
+        let rotation = Quaternion::slerp(t, &self.rotation, &next.rotation);
+        let mut scale = Matrix::I;
+        for i in 0..3 {
+            for j in 0..3 {
+                scale.m[i][j] = self.scale.m[i][j] * (1.0 - t) + next.scale.m[i][j] * t;
+            }
+        }
+        // Interpolating between a mirrored and an unmirrored keyframe passes
+        // through a flat scale, which can't be inverted
+        let scale = match scale.inverse() {
+            Some(inverse) => Transformation {
+                matrix: scale,
+                inverse,
+            },
+            None if t < 0.5 => return self.transformation.clone(),
+            None => return next.transformation.clone(),
+        };
+        Transformation::translate(translation.x(), translation.y(), translation.z())
+            * rotation.to_transformation()
+            * scale
+    }
+}
+
+/// A transformation that changes over time, interpolating between keyframes
+/// so that rotations stay rigid. Before the first and after the last
+/// keyframe, it stays at them.
+#[derive(Debug, Clone)]
+pub struct AnimatedTransformation {
+    keyframes: Vec<Keyframe>,
+}
+
+impl AnimatedTransformation {
+    /// Creates the transformation from `(time, transformation)` keyframes in
+    /// any order
+    pub fn new(mut keyframes: Vec<(f64, Transformation)>) -> Self {
+        assert!(
+            !keyframes.is_empty(),
+            "Animated transformation needs at least one keyframe"
+        );
+        keyframes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut result: Vec<Keyframe> = Vec::with_capacity(keyframes.len());
+        for (time, transformation) in keyframes {
+            let mut keyframe = Keyframe::new(time, transformation);
+            // Negating a quaternion gives the same rotation, so pick the one
+            // that takes the shorter way around from the previous keyframe
+            if let Some(previous) = result.last() {
+                if previous.rotation.dot(&keyframe.rotation) < 0.0 {
+                    keyframe.rotation = Quaternion(keyframe.rotation.0.map(|c| -c));
+                }
+            }
+            result.push(keyframe);
+        }
+        AnimatedTransformation { keyframes: result }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.keyframes.len() > 1
+    }
+
+    /// The transformation at `time`, which is only computed if it falls
+    /// between two keyframes
+    pub fn at(&self, time: f64) -> Cow<'_, Transformation> {
+        let next = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.time <= time);
+        if next == 0 {
+            return Cow::Borrowed(&self.keyframes[0].transformation);
+        }
+        if next == self.keyframes.len() {
+            return Cow::Borrowed(&self.keyframes[next - 1].transformation);
+        }
+        let (previous, next) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (time - previous.time) / (next.time - previous.time);
+        Cow::Owned(previous.interpolate(next, t))
+    }
+
+    /// Bounds of `bounds` over the whole animation. The corners of the bounds
+    /// are moved to a number of times between each pair of keyframes, and
+    /// since they can't get further from where they were found than they
+    /// move in a step, the bounds at each time are padded by that distance.
+    pub fn motion_bounds(&self, bounds: &Bounds) -> Bounds {
+        if !self.is_animated() {
+            return self.keyframes[0].transformation.transform(bounds);
+        }
+        let corners = [
+            Point(bounds.min.0, bounds.min.1, bounds.min.2),
+            Point(bounds.min.0, bounds.min.1, bounds.max.2),
+            Point(bounds.min.0, bounds.max.1, bounds.min.2),
+            Point(bounds.min.0, bounds.max.1, bounds.max.2),
+            Point(bounds.max.0, bounds.min.1, bounds.min.2),
+            Point(bounds.max.0, bounds.min.1, bounds.max.2),
+            Point(bounds.max.0, bounds.max.1, bounds.min.2),
+            Point(bounds.max.0, bounds.max.1, bounds.max.2),
+        ];
+        let mut motion_bounds: Option<Bounds> = None;
+        for pair in self.keyframes.windows(2) {
+            let mut previous: Option<[Point; 8]> = None;
+            for step in 0..=MOTION_BOUNDS_STEPS {
+                let transformation =
+                    pair[0].interpolate(&pair[1], step as f64 / MOTION_BOUNDS_STEPS as f64);
+                let moved = corners.map(|corner| transformation.transform(&corner));
+                let padding = match previous {
+                    Some(previous) => (0..8)
+                        .map(|i| (moved[i] - previous[i]).magnitude())
+                        .fold(0.0, f64::max),
+                    None => 0.0,
+                };
+                let padding = Vector(padding, padding, padding);
+                let step_bounds: Bounds = moved.iter().map(|&p| Bounds::new(p, p)).sum();
+                let step_bounds = Bounds::new(step_bounds.min - padding, step_bounds.max + padding);
+                motion_bounds = Some(match motion_bounds {
+                    Some(motion_bounds) => motion_bounds + step_bounds,
+                    None => step_bounds,
+                });
+                previous = Some(moved);
+            }
+        }
+        motion_bounds.unwrap()
+    }
+}
+
+impl From<Transformation> for AnimatedTransformation {
+    fn from(transformation: Transformation) -> Self {
+        AnimatedTransformation::new(vec![(0.0, transformation)])
     }
 }
 
@@ -2,7 +2,7 @@ use rand::{thread_rng, Rng};
 
 use craytracer::{
     bounds::Bounds,
-    geometry::{point::Point, O, X, Y, Z},
+    geometry::{point::Point, vector::Vector, O, X, Y, Z},
     p,
     ray::Ray,
 };
@@ -63,6 +63,75 @@ fn test_intersect_miss() {
     assert!(!b.intersects(&Ray::new(Point::new(-2, 0, 0), -Y)));
 }
 
+fn intersects_fast(b: &Bounds, ray: &Ray) -> bool {
+    let Vector(x, y, z) = ray.direction;
+    b.intersects_fast(
+        ray,
+        &Vector(1.0 / x, 1.0 / y, 1.0 / z),
+        &[
+            x.is_sign_negative(),
+            y.is_sign_negative(),
+            z.is_sign_negative(),
+        ],
+    )
+}
+
+#[test]
+fn test_intersects_fast() {
+    let b = Bounds {
+        min: O,
+        max: Point::new(1, 1, 1),
+    };
+
+    assert!(intersects_fast(&b, &Ray::new(Point(0.5, 0.5, -1.0), Z)));
+    assert!(intersects_fast(&b, &Ray::new(Point(0.5, 0.5, 0.5), -X)));
+    assert!(!intersects_fast(&b, &Ray::new(Point::new(0, 2, 0), X)));
+    assert!(!intersects_fast(&b, &Ray::new(Point::new(2, 0, 0), Y)));
+    // Boxes behind the ray or past its max distance are missed
+    assert!(!intersects_fast(&b, &Ray::new(Point(0.5, 0.5, 2.0), Z)));
+    let mut ray = Ray::new(Point(0.5, 0.5, -2.0), Z);
+    ray.max_distance = 1.5;
+    assert!(!intersects_fast(&b, &ray));
+    // Rays that start inside the box are hits
+    let mut ray = Ray::new(Point(0.5, 0.5, 0.5), Z);
+    ray.max_distance = 0.1;
+    assert!(intersects_fast(&b, &ray));
+    // Rays in the plane of a face, which give NaN distances
+    assert!(intersects_fast(&b, &Ray::new(Point(-1.0, 0.0, 0.5), X)));
+    assert!(intersects_fast(&b, &Ray::new(Point(-1.0, 1.0, 0.5), X)));
+}
+
+#[test]
+fn test_intersects_fast_random() {
+    // Hits the same boxes as the slower tests
+    let b = Bounds {
+        min: O,
+        max: Point::new(1, 1, 1),
+    };
+    let mut rng = thread_rng();
+    for _ in 0..10000 {
+        let origin = Point(
+            rng.gen_range(-1.0..2.0),
+            rng.gen_range(-1.0..2.0),
+            rng.gen_range(-1.0..2.0),
+        );
+        let direction = Vector(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        )
+        .normalized();
+        let mut ray = Ray::new(origin, direction);
+        ray.max_distance = rng.gen_range(0.0..3.0);
+        assert_eq!(
+            intersects_fast(&b, &ray),
+            b.intersects(&ray) || b.contains(&ray.origin),
+            "{:?}",
+            ray
+        );
+    }
+}
+
 #[test]
 fn test_sum() {
     assert_eq!(
@@ -1,17 +1,19 @@
 use pretty_assertions::assert_eq;
-use std::sync::Arc;
+use std::{iter, sync::Arc};
 
 use craytracer::{
-    bvh::{Bvh, SplitMethod},
+    bvh::{BuildThreads, Bvh, BvhLayout, SplitMethod},
     color::Color,
-    geometry::X,
+    geometry::{X, Y},
     material::Material,
     p,
-    primitive::Primitive,
+    primitive::{AlphaMask, AlphaMode, Primitive},
     ray::Ray,
     shape::Shape,
     texture::Texture,
+    v,
 };
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 #[test]
 fn bvh_node() {
@@ -65,3 +67,476 @@ fn bvh_node() {
             .location
     );
 }
+
+fn alpha_masked_sphere(alpha: Texture<f64>, mode: AlphaMode) -> Arc<Primitive> {
+    Arc::new(Primitive::new_alpha_masked(
+        Arc::new(Shape::new_sphere(p!(0, 0, 0), 1.0)),
+        Arc::new(Material::new_matte(
+            Texture::constant(Color::WHITE),
+            Texture::constant(0.0),
+        )),
+        Arc::new(AlphaMask::new(alpha, mode)),
+    ))
+}
+
+#[test]
+fn alpha_mask() {
+    let behind = Arc::new(Primitive::new(
+        Arc::new(Shape::new_sphere(p!(3, 0, 0), 1.0)),
+        Arc::new(Material::new_matte(
+            Texture::constant(Color::WHITE),
+            Texture::constant(0.0),
+        )),
+    ));
+
+    // Fully cut out spheres are skipped by both camera and shadow rays
+    let node = Bvh::new(
+        vec![
+            alpha_masked_sphere(Texture::constant(0.2), AlphaMode::Threshold(0.5)),
+            Arc::clone(&behind),
+        ],
+        SplitMethod::Median,
+    );
+    assert_eq!(
+        p!(2, 0, 0),
+        node.intersect(&mut Ray::new(p!(-3, 0, 0), X))
+            .unwrap()
+            .location
+    );
+    let mut shadow_ray = Ray::new(p!(-3, 0, 0), X);
+    shadow_ray.max_distance = 4.0;
+    assert!(!node.intersects(&shadow_ray));
+
+    let node = Bvh::new(
+        vec![
+            alpha_masked_sphere(Texture::constant(0.8), AlphaMode::Threshold(0.5)),
+            Arc::clone(&behind),
+        ],
+        SplitMethod::Median,
+    );
+    assert_eq!(
+        p!(-1, 0, 0),
+        node.intersect(&mut Ray::new(p!(-3, 0, 0), X))
+            .unwrap()
+            .location
+    );
+    assert!(node.intersects(&shadow_ray));
+}
+
+#[test]
+fn alpha_mask_far_side() {
+    // The checkerboard cuts out the side of the sphere facing -y but not the
+    // side facing +y, so the ray should pass through to the far side
+    let sphere = alpha_masked_sphere(
+        Texture::checkerboard(1.0, 0.0, 1.0),
+        AlphaMode::Threshold(0.5),
+    );
+    let ray = &mut Ray::new(p!(0.1, -5, 0.1), Y);
+    let intersection = sphere.intersect(ray).unwrap();
+    assert!(intersection.location.y() > 0.0);
+    assert_eq!(ray.max_distance, intersection.distance);
+    assert!(ray.max_distance > 5.0);
+}
+
+#[test]
+fn alpha_mask_stochastic() {
+    let sphere = alpha_masked_sphere(Texture::constant(0.5), AlphaMode::Stochastic);
+
+    // Each of the two surfaces along a ray through the sphere is kept with
+    // probability 0.5, so 3/4 of the rays should hit it
+    let n = 10000;
+    let mut hits = 0;
+    for i in 0..n {
+        let offset = (i as f64 / n as f64 - 0.5) * 0.5;
+        let ray = &mut Ray::new(p!(-3, offset, 0.1), X);
+        let hit = sphere.intersect(ray).is_some();
+        assert_eq!(hit, sphere.intersects(&Ray::new(p!(-3, offset, 0.1), X)));
+        if hit {
+            hits += 1;
+        }
+    }
+    let fraction = hits as f64 / n as f64;
+    assert!((fraction - 0.75).abs() < 0.02, "{}", fraction);
+}
+
+#[test]
+fn matches_brute_force() {
+    // Enough spheres for a tree that is several levels deep
+    let mut rng = StdRng::seed_from_u64(0);
+    let material = Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE),
+        Texture::constant(0.0),
+    ));
+    let primitives: Vec<_> = (0..500)
+        .map(|_| {
+            let center = p!(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0)
+            );
+            Arc::new(Primitive::new(
+                Arc::new(Shape::new_sphere(center, rng.gen_range(0.1..1.0))),
+                Arc::clone(&material),
+            ))
+        })
+        .collect();
+
+    // Splitting even small subtrees between threads builds the same tree
+    let many_threads = BuildThreads {
+        num_threads: 8,
+        min_primitives_per_thread: 2,
+    };
+    for (split_method, layout, threads) in [
+        (
+            SplitMethod::Median,
+            BvhLayout::Binary,
+            BuildThreads::default(),
+        ),
+        (
+            SplitMethod::Median,
+            BvhLayout::Wide,
+            BuildThreads::default(),
+        ),
+        (SplitMethod::SAH, BvhLayout::Binary, BuildThreads::default()),
+        (SplitMethod::SAH, BvhLayout::Wide, BuildThreads::default()),
+        (SplitMethod::SAH, BvhLayout::Binary, many_threads),
+        (
+            SplitMethod::Lbvh,
+            BvhLayout::Binary,
+            BuildThreads::default(),
+        ),
+        (SplitMethod::Lbvh, BvhLayout::Wide, BuildThreads::default()),
+        (SplitMethod::Lbvh, BvhLayout::Wide, many_threads),
+    ] {
+        let bvh = Bvh::with_threads(primitives.clone(), split_method, layout, threads);
+        let single_threaded = Bvh::with_threads(
+            primitives.clone(),
+            split_method,
+            layout,
+            BuildThreads::SINGLE,
+        );
+        let (mut bytes, mut single_threaded_bytes) = (Vec::new(), Vec::new());
+        bvh.write(&mut bytes).unwrap();
+        single_threaded.write(&mut single_threaded_bytes).unwrap();
+        assert!(bytes == single_threaded_bytes);
+
+        for _ in 0..1000 {
+            let origin = p!(
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0)
+            );
+            let direction = v!(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0)
+            )
+            .normalized();
+
+            let expected = primitives
+                .iter()
+                .filter_map(|p| p.intersect(&mut Ray::new(origin, direction)))
+                .map(|intersection| intersection.distance)
+                .min_by(|a, b| a.total_cmp(b));
+            let intersection = bvh.intersect(&mut Ray::new(origin, direction));
+            assert_eq!(
+                intersection.map(|intersection| intersection.distance),
+                expected
+            );
+            assert_eq!(
+                bvh.intersects(&Ray::new(origin, direction)),
+                expected.is_some()
+            );
+        }
+    }
+}
+
+#[test]
+fn axis_aligned_rays() {
+    // Rays along an axis have infinite inverse directions in the others, and
+    // rays with negative zero components must still find the boxes
+    for layout in [BvhLayout::Binary, BvhLayout::Wide] {
+        let bvh = Bvh::with_layout(
+            (0..8)
+                .map(|i| {
+                    Arc::new(Primitive::new(
+                        Arc::new(Shape::new_sphere(p!(i * 3, 0, 0), 1.0)),
+                        Arc::new(Material::new_matte(
+                            Texture::constant(Color::WHITE),
+                            Texture::constant(0.0),
+                        )),
+                    ))
+                })
+                .collect(),
+            SplitMethod::SAH,
+            layout,
+        );
+        for (origin, direction, location) in [
+            (p!(-5, 0, 0), X, p!(-1, 0, 0)),
+            (p!(30, 0, 0), -X, p!(22, 0, 0)),
+            (p!(9, -5, 0), Y, p!(9, -1, 0)),
+            (p!(9, 5, 0), -Y, p!(9, 1, 0)),
+            (p!(9, 0, 5), v!(-0.0, -0.0, -1), p!(9, 0, 1)),
+        ] {
+            let ray = &mut Ray::new(origin, direction);
+            assert_eq!(
+                bvh.intersect(ray).map(|intersection| intersection.location),
+                Some(location)
+            );
+            assert!(bvh.intersects(&Ray::new(origin, direction)));
+        }
+    }
+}
+
+#[test]
+fn update_matches_brute_force() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let material = Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE),
+        Texture::constant(0.0),
+    ));
+    let sphere = |center, radius| {
+        Arc::new(Primitive::new(
+            Arc::new(Shape::new_sphere(center, radius)),
+            Arc::clone(&material),
+        ))
+    };
+    let spheres: Vec<_> = (0..500)
+        .map(|_| {
+            (
+                p!(
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0)
+                ),
+                rng.gen_range(0.1..1.0),
+            )
+        })
+        .collect();
+    let primitives: Vec<_> = spheres
+        .iter()
+        .map(|&(center, radius)| sphere(center, radius))
+        .collect();
+
+    for (split_method, layout) in [
+        (SplitMethod::Median, BvhLayout::Binary),
+        (SplitMethod::SAH, BvhLayout::Binary),
+        (SplitMethod::SAH, BvhLayout::Wide),
+        (SplitMethod::Lbvh, BvhLayout::Wide),
+    ] {
+        let mut bvh = Bvh::with_layout(primitives.clone(), split_method, layout);
+
+        // Moving every primitive by the same amount keeps the tree as good as
+        // it was, so nothing is rebuilt
+        let moved: Vec<_> = spheres
+            .iter()
+            .map(|&(center, radius)| sphere(center + v!(5, 0, 0), radius))
+            .collect();
+        assert_eq!(bvh.update(&moved), 0);
+        assert_eq!(bvh.bounds, Bvh::new(moved, split_method).bounds);
+
+        // Moving a couple of them far away makes the subtrees they are in
+        // expensive to trace through, which are rebuilt
+        let moved: Vec<_> = spheres
+            .iter()
+            .enumerate()
+            .map(|(i, &(center, radius))| match i % 50 {
+                0 if i < 100 => sphere(p!(-center.x(), -center.y(), -center.z()), radius),
+                _ => sphere(center + v!(0, rng.gen_range(-0.5..0.5), 0), radius),
+            })
+            .collect();
+        assert!(bvh.update(&moved) > 0);
+
+        for _ in 0..1000 {
+            let origin = p!(
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0)
+            );
+            let direction = v!(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0)
+            )
+            .normalized();
+
+            let expected = moved
+                .iter()
+                .filter_map(|p| p.intersect(&mut Ray::new(origin, direction)))
+                .map(|intersection| intersection.distance)
+                .min_by(|a, b| a.total_cmp(b));
+            let intersection = bvh.intersect(&mut Ray::new(origin, direction));
+            assert_eq!(
+                intersection.map(|intersection| intersection.distance),
+                expected
+            );
+            assert_eq!(
+                bvh.intersects(&Ray::new(origin, direction)),
+                expected.is_some()
+            );
+        }
+    }
+}
+
+#[test]
+fn exponentially_spaced() {
+    // Splitting off one sphere at a time is cheapest for spheres that get
+    // further and further apart, which would make a tree too deep to
+    // traverse if the builders didn't fall back to splitting in half
+    let material = Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE),
+        Texture::constant(0.0),
+    ));
+    let spheres = |spacing: f64| -> (Vec<_>, Vec<f64>) {
+        let positions: Vec<f64> = iter::successors(Some(1.0), |x| Some(x * spacing))
+            .take(300)
+            .collect();
+        let spheres = positions
+            .iter()
+            .map(|&x| {
+                Arc::new(Primitive::new(
+                    Arc::new(Shape::new_sphere(p!(x, 0, 0), 1.0)),
+                    Arc::clone(&material),
+                ))
+            })
+            .collect();
+        (spheres, positions)
+    };
+    let (exponential, positions) = spheres(3.0);
+    let check = |bvh: &Bvh| {
+        for i in [0, 1, 10, 100, 299] {
+            let x = positions[i];
+            let origin = p!(x, 5, 0);
+            let ray = &mut Ray::new(origin, -Y);
+            assert_eq!(
+                bvh.intersect(ray).map(|intersection| intersection.location),
+                Some(p!(x, 1, 0))
+            );
+            assert!(bvh.intersects(&Ray::new(origin, -Y)));
+            assert!(!bvh.intersects(&Ray::new(p!(x, 5, 1.5), -Y)));
+        }
+    };
+
+    for split_method in [SplitMethod::Median, SplitMethod::SAH, SplitMethod::Lbvh] {
+        for layout in [BvhLayout::Binary, BvhLayout::Wide] {
+            check(&Bvh::with_layout(exponential.clone(), split_method, layout));
+
+            // Subtrees rebuilt when spheres move apart are limited too
+            let mut bvh = Bvh::with_layout(spheres(1.01).0, split_method, layout);
+            bvh.update(&exponential);
+            check(&bvh);
+        }
+    }
+}
+
+#[test]
+fn write_and_read() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let material = Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE),
+        Texture::constant(0.0),
+    ));
+    let primitives: Vec<_> = (0..200)
+        .map(|_| {
+            let center = p!(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0)
+            );
+            Arc::new(Primitive::new(
+                Arc::new(Shape::new_sphere(center, rng.gen_range(0.1..1.0))),
+                Arc::clone(&material),
+            ))
+        })
+        .collect();
+
+    for (split_method, layout) in [
+        (SplitMethod::SAH, BvhLayout::Binary),
+        (SplitMethod::Lbvh, BvhLayout::Wide),
+    ] {
+        let bvh = Bvh::with_layout(primitives.clone(), split_method, layout);
+        let mut bytes = Vec::new();
+        bvh.write(&mut bytes).unwrap();
+
+        let read = Bvh::read(&mut bytes.as_slice(), primitives.clone()).unwrap();
+        assert_eq!(read.split_method(), split_method);
+        assert_eq!(read.layout(), layout);
+        assert_eq!(read.bounds, bvh.bounds);
+        for _ in 0..200 {
+            let origin = p!(
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0)
+            );
+            let direction = v!(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0)
+            )
+            .normalized();
+            assert_eq!(
+                read.intersect(&mut Ray::new(origin, direction))
+                    .map(|intersection| intersection.distance),
+                bvh.intersect(&mut Ray::new(origin, direction))
+                    .map(|intersection| intersection.distance)
+            );
+        }
+
+        // Trees for other primitives and truncated trees are rejected
+        assert!(Bvh::read(&mut bytes.as_slice(), primitives[..100].to_vec()).is_err());
+        assert!(Bvh::read(&mut &bytes[..bytes.len() - 1], primitives.clone()).is_err());
+        // Pointing the root's second child at its first would traverse that
+        // subtree twice and never reach the rest
+        if layout == BvhLayout::Binary {
+            let root_offset = 1 + 8 + 4 * primitives.len() + 1 + 4;
+            let mut shared_child = bytes.clone();
+            shared_child[root_offset..root_offset + 4].copy_from_slice(&1u32.to_le_bytes());
+            let error = Bvh::read(&mut shared_child.as_slice(), primitives.clone()).unwrap_err();
+            assert!(
+                error.to_string().contains("more than one parent"),
+                "{}",
+                error
+            );
+        }
+    }
+}
+
+#[test]
+fn coincident_centroids() {
+    // More primitives than a leaf can hold, which can't be split by position
+    let material = Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE),
+        Texture::constant(0.0),
+    ));
+    let primitives: Vec<_> = (0..70_000)
+        .map(|i| {
+            Arc::new(Primitive::new(
+                Arc::new(Shape::new_sphere(p!(0, 0, 0), 1.0 + i as f64 * 1e-5)),
+                Arc::clone(&material),
+            ))
+        })
+        .collect();
+
+    for (split_method, layout) in [
+        (SplitMethod::Median, BvhLayout::Binary),
+        (SplitMethod::Median, BvhLayout::Wide),
+        (SplitMethod::SAH, BvhLayout::Binary),
+        (SplitMethod::SAH, BvhLayout::Wide),
+        (SplitMethod::Lbvh, BvhLayout::Binary),
+        (SplitMethod::Lbvh, BvhLayout::Wide),
+    ] {
+        let bvh = Bvh::with_layout(primitives.clone(), split_method, layout);
+        for origin in [p!(-10, 0, 0), p!(0, 0.5, 0)] {
+            let expected = primitives
+                .iter()
+                .filter_map(|p| p.intersect(&mut Ray::new(origin, X)))
+                .map(|intersection| intersection.distance)
+                .min_by(|a, b| a.total_cmp(b));
+            let intersection = bvh.intersect(&mut Ray::new(origin, X));
+            assert_eq!(
+                intersection.map(|intersection| intersection.distance),
+                expected
+            );
+        }
+    }
+}
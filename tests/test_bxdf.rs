@@ -23,3 +23,630 @@ fn refract_test() {
         Vector::new(1, -1, 0).normalized()
     );
 }
+
+mod thin_dielectric {
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        bxdf::{thin_dielectric_fresnel, BxDF},
+        color::Color,
+        geometry::traits::DotProduct,
+        n,
+        pdf::Pdf,
+        sampling::samplers::Sample2d,
+        texture::{Texture, TextureCoordinates},
+        v,
+    };
+
+    fn thin_glass() -> BxDF {
+        BxDF::ThinDielectricBxDF {
+            reflectance: Texture::constant(Color::WHITE),
+            transmittance: Texture::constant(Color::WHITE),
+            eta: 1.5,
+        }
+    }
+
+    #[test]
+    fn fresnel() {
+        // At normal incidence, a slab reflects 2R / (1 + R) where R is the
+        // reflectance of a single interface
+        let (reflectance, transmittance) = thin_dielectric_fresnel(1.5, 1.0);
+        assert_abs_diff_eq!(reflectance, 0.08 / 1.04, epsilon = 1e-9);
+        assert_abs_diff_eq!(reflectance + transmittance, 1.0, epsilon = 1e-9);
+        // Both sides of the slab are the same
+        assert_eq!(
+            thin_dielectric_fresnel(1.5, 0.3),
+            thin_dielectric_fresnel(1.5, -0.3)
+        );
+    }
+
+    #[test]
+    fn transmission_is_not_bent() {
+        let bxdf = thin_glass();
+        assert!(bxdf.has_reflection());
+        assert!(bxdf.has_transmission());
+
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, 1).normalized();
+        let (reflectance, transmittance) = thin_dielectric_fresnel(1.5, w_o.dot(&normal));
+
+        let sample = bxdf
+            .sample(Sample2d::new(0.99, 0.5), &w_o, &normal, &uv)
+            .unwrap();
+        assert_abs_diff_eq!(sample.w_i, -w_o);
+        assert!(sample.is_specular);
+        assert!(matches!(sample.pdf, Pdf::NonDelta(pdf) if (pdf - transmittance).abs() < 1e-9));
+
+        let sample = bxdf
+            .sample(Sample2d::new(0.0, 0.5), &w_o, &normal, &uv)
+            .unwrap();
+        assert_abs_diff_eq!(sample.w_i, v!(-1, 0, 1).normalized());
+        assert!(matches!(sample.pdf, Pdf::NonDelta(pdf) if (pdf - reflectance).abs() < 1e-9));
+    }
+}
+
+mod diffuse_transmission {
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        bxdf::BxDF,
+        color::Color,
+        geometry::traits::DotProduct,
+        n,
+        pdf::Pdf,
+        sampling::samplers::Sample2d,
+        texture::{Texture, TextureCoordinates},
+        v,
+    };
+    use std::f64::consts::FRAC_1_PI;
+
+    fn translucent() -> BxDF {
+        BxDF::DiffuseTransmissionBxDF {
+            reflectance: Texture::constant(Color::WHITE * 0.3),
+            transmittance: Texture::constant(Color::WHITE * 0.5),
+        }
+    }
+
+    #[test]
+    fn f() {
+        let bxdf = translucent();
+        assert!(bxdf.has_reflection());
+        assert!(bxdf.has_transmission());
+
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, 1).normalized();
+        let reflected = bxdf.f(&w_o, &v!(0, 1, 1).normalized(), &normal, &uv);
+        let transmitted = bxdf.f(&w_o, &v!(0, 1, -1).normalized(), &normal, &uv);
+        assert_abs_diff_eq!(reflected.r, 0.3 * FRAC_1_PI, epsilon = 1e-9);
+        assert_abs_diff_eq!(transmitted.r, 0.5 * FRAC_1_PI, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn samples_both_sides() {
+        let bxdf = translucent();
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, -1).normalized();
+        let n = 100;
+        let mut total = Color::BLACK;
+        let mut transmitted = 0;
+        for i in 0..n {
+            for j in 0..n {
+                let u = Sample2d::new((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64);
+                let sample = bxdf.sample(u, &w_o, &normal, &uv).unwrap();
+                if sample.w_i.dot(&normal) > 0.0 {
+                    transmitted += 1;
+                }
+                let pdf = match sample.pdf {
+                    Pdf::NonDelta(pdf) => pdf,
+                    Pdf::Delta => panic!("Expected a non-delta pdf"),
+                };
+                total += sample.f * sample.w_i.dot(&normal).abs() / pdf;
+            }
+        }
+        assert_eq!(transmitted, n * n / 2);
+        assert_abs_diff_eq!((total / (n * n) as f64).r, 0.8, epsilon = 1e-3);
+    }
+}
+
+/// Statistical checks that every material's `sample`, `f` and `pdf` agree with
+/// each other, and that materials don't create energy
+///
+/// Source: https://github.com/mitsuba-renderer/mitsuba/blob/master/src/utils/chisquare.cpp
+mod statistical {
+    use std::{collections::HashSet, f64::consts::PI, sync::Arc};
+
+    use craytracer::{
+        bxdf::BxDF,
+        color::Color,
+        geometry::{normal::Normal, traits::DotProduct, vector::Vector},
+        material::{Material, Perturbation},
+        measured::MeasuredBRDF,
+        n,
+        pdf::Pdf,
+        sampling::samplers::{Sample1d, Sample2d},
+        texture::{Texture, TextureCoordinates},
+        v,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const COS_THETA_BINS: usize = 10;
+    const PHI_BINS: usize = 20;
+    /// Number of points per axis used to integrate the pdf over each bin
+    const QUADRATURE_POINTS: usize = 16;
+    const CHI_SQUARE_SAMPLES: usize = 100000;
+    const FURNACE_SAMPLES: usize = 10000;
+    /// Probability of a correct material failing the chi-square test, before
+    /// correcting for the number of tests
+    const SIGNIFICANCE_LEVEL: f64 = 0.01;
+
+    struct TestMaterial {
+        name: &'static str,
+        material: Material,
+        /// Materials whose `f` and `pdf` are themselves Monte Carlo estimates
+        /// only get the furnace test
+        is_stochastic: bool,
+    }
+
+    fn color(value: f64) -> Texture<Color> {
+        Texture::constant(Color::WHITE * value)
+    }
+
+    fn test_material(name: &'static str, material: Material) -> TestMaterial {
+        TestMaterial {
+            name,
+            material,
+            is_stochastic: false,
+        }
+    }
+
+    /// Every kind of material, with parameters that shouldn't reflect more
+    /// light than they receive
+    fn test_materials() -> Vec<TestMaterial> {
+        let matte = || Material::new_matte(color(0.9), Texture::constant(0.0));
+        let glossy_table = (0..90 * 90 * 180)
+            .map(|i| Color::WHITE * if i / (90 * 180) < 20 { 2.0 } else { 0.05 })
+            .collect();
+        vec![
+            test_material("lambertian", matte()),
+            test_material(
+                "oren_nayar",
+                Material::new_matte(color(0.8), Texture::constant(30.0)),
+            ),
+            test_material(
+                "conductor",
+                Material::BxDF(BxDF::FresnelConductorBRDF {
+                    eta: Texture::constant(Color::from([0.2, 0.9, 1.1])),
+                    k: Texture::constant(Color::from([3.9, 2.4, 2.2])),
+                }),
+            ),
+            test_material(
+                "specular_btdf",
+                Material::BxDF(BxDF::SpecularBTDF {
+                    transmittance: color(1.0),
+                    eta_i: 1.0,
+                    eta_t: 1.5,
+                }),
+            ),
+            test_material("glass", Material::new_glass(color(1.0), color(1.0), 1.5)),
+            test_material(
+                "thin_glass",
+                Material::new_thin_glass(color(1.0), color(1.0), 1.5),
+            ),
+            test_material(
+                "translucent",
+                Material::new_translucent(color(0.4), color(0.6)),
+            ),
+            test_material(
+                "plastic",
+                Material::new_plastic(color(0.5), color(1.0), Texture::constant(0.0)),
+            ),
+            test_material(
+                "rough_plastic",
+                Material::new_plastic(color(0.5), color(1.0), Texture::constant(20.0)),
+            ),
+            test_material(
+                "metal",
+                Material::new_metal(
+                    Texture::constant(Color::from([0.2, 0.9, 1.1])),
+                    Texture::constant(Color::from([3.9, 2.4, 2.2])),
+                ),
+            ),
+            test_material(
+                "perturbed",
+                Material::new_perturbed(matte(), Perturbation::BumpMap(Texture::constant(0.0))),
+            ),
+            test_material(
+                "mix",
+                Material::new_mix(
+                    Arc::new(matte()),
+                    Arc::new(Material::new_glass(color(1.0), color(1.0), 1.5)),
+                    Texture::constant(0.3),
+                ),
+            ),
+            TestMaterial {
+                name: "coated",
+                material: Material::new_coated(
+                    Arc::new(matte()),
+                    1.5,
+                    Texture::constant(0.1),
+                    color(0.5),
+                    10,
+                    1,
+                ),
+                is_stochastic: true,
+            },
+            test_material(
+                "subsurface",
+                Material::new_subsurface(color(0.8), color(0.1), 1.33),
+            ),
+            test_material(
+                "measured",
+                Material::Measured(MeasuredBRDF::new(glossy_table)),
+            ),
+        ]
+    }
+
+    /// Names of the variants a material is built from. The exhaustive matches
+    /// fail to compile when a variant is added, as a reminder to add it to
+    /// `test_materials`.
+    fn variants(material: &Material) -> Vec<&'static str> {
+        fn bxdf_variant(bxdf: &BxDF) -> &'static str {
+            match bxdf {
+                BxDF::LambertianBRDF { .. } => "LambertianBRDF",
+                BxDF::OrenNayyarBRDF { .. } => "OrenNayyarBRDF",
+                BxDF::FresnelConductorBRDF { .. } => "FresnelConductorBRDF",
+                BxDF::SpecularBRDF { .. } => "SpecularBRDF",
+                BxDF::SpecularBTDF { .. } => "SpecularBTDF",
+                BxDF::FresnelSpecularBxDF { .. } => "FresnelSpecularBxDF",
+                BxDF::ThinDielectricBxDF { .. } => "ThinDielectricBxDF",
+                BxDF::DiffuseTransmissionBxDF { .. } => "DiffuseTransmissionBxDF",
+            }
+        }
+        match material {
+            Material::BxDF(bxdf) => vec!["BxDF", bxdf_variant(bxdf)],
+            Material::BSDF(bsdf) => {
+                let mut variants = vec!["BSDF"];
+                variants.extend(bsdf.bxdfs.iter().map(bxdf_variant));
+                variants
+            }
+            Material::Perturbed { .. } => vec!["Perturbed"],
+            Material::Mix { .. } => vec!["Mix"],
+            Material::Coated(_) => vec!["Coated"],
+            Material::Subsurface(_) => vec!["Subsurface"],
+            Material::Measured(_) => vec!["Measured"],
+        }
+    }
+
+    const ALL_VARIANTS: [&str; 15] = [
+        "BxDF",
+        "BSDF",
+        "Perturbed",
+        "Mix",
+        "Coated",
+        "Subsurface",
+        "Measured",
+        "LambertianBRDF",
+        "OrenNayyarBRDF",
+        "FresnelConductorBRDF",
+        "SpecularBRDF",
+        "SpecularBTDF",
+        "FresnelSpecularBxDF",
+        "ThinDielectricBxDF",
+        "DiffuseTransmissionBxDF",
+    ];
+
+    fn outgoing_directions() -> [Vector; 3] {
+        [
+            v!(0.3, 0, 1).normalized(),
+            v!(1, 0.5, 0.3).normalized(),
+            v!(-0.5, 1, -1).normalized(),
+        ]
+    }
+
+    fn pdf_value(pdf: Pdf) -> f64 {
+        match pdf {
+            Pdf::NonDelta(pdf) => pdf,
+            Pdf::Delta => 0.0,
+        }
+    }
+
+    fn random_samples(rng: &mut StdRng) -> (Sample1d, Sample2d) {
+        (
+            Sample1d::new(rng.gen()),
+            Sample2d::new(rng.gen(), rng.gen()),
+        )
+    }
+
+    /// Direction at the given cos theta and phi, relative to the z axis
+    fn direction(cos_theta: f64, phi: f64) -> Vector {
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        Vector(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    fn bin(w: &Vector) -> usize {
+        let cos_theta_bin = ((w.z() + 1.0) * 0.5 * COS_THETA_BINS as f64) as usize;
+        let phi = w.y().atan2(w.x()).rem_euclid(2.0 * PI);
+        let phi_bin = (phi / (2.0 * PI) * PHI_BINS as f64) as usize;
+        cos_theta_bin.min(COS_THETA_BINS - 1) * PHI_BINS + phi_bin.min(PHI_BINS - 1)
+    }
+
+    /// Natural logarithm of the gamma function, using the Lanczos
+    /// approximation
+    fn ln_gamma(x: f64) -> f64 {
+        const COEFFICIENTS: [f64; 6] = [
+            76.18009172947146,
+            -86.50532032941677,
+            24.01409824083091,
+            -1.231739572450155,
+            0.1208650973866179e-2,
+            -0.5395239384953e-5,
+        ];
+        let tmp = x + 5.5;
+        let tmp = tmp - (x + 0.5) * tmp.ln();
+        let series: f64 = COEFFICIENTS
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c / (x + 1.0 + i as f64))
+            .sum();
+        -tmp + (2.5066282746310005 * (1.000000000190015 + series) / x).ln()
+    }
+
+    /// Regularized upper incomplete gamma function Q(a, x)
+    ///
+    /// Source: Numerical Recipes, section 6.2
+    fn gamma_q(a: f64, x: f64) -> f64 {
+        let ln_prefactor = -x + a * x.ln() - ln_gamma(a);
+        if x < a + 1.0 {
+            // Series representation of P(a, x)
+            let mut term = 1.0 / a;
+            let mut sum = term;
+            for n in 1..1000 {
+                term *= x / (a + n as f64);
+                sum += term;
+                if term.abs() < sum.abs() * 1e-15 {
+                    break;
+                }
+            }
+            1.0 - sum * ln_prefactor.exp()
+        } else {
+            // Continued fraction representation of Q(a, x)
+            let tiny = 1e-300;
+            let mut b = x + 1.0 - a;
+            let mut c = 1.0 / tiny;
+            let mut d = 1.0 / b;
+            let mut h = d;
+            for i in 1..1000 {
+                let an = -(i as f64) * (i as f64 - a);
+                b += 2.0;
+                d = an * d + b;
+                if d.abs() < tiny {
+                    d = tiny;
+                }
+                c = b + an / c;
+                if c.abs() < tiny {
+                    c = tiny;
+                }
+                d = 1.0 / d;
+                let delta = d * c;
+                h *= delta;
+                if (delta - 1.0).abs() < 1e-15 {
+                    break;
+                }
+            }
+            ln_prefactor.exp() * h
+        }
+    }
+
+    /// Pearson's chi-square test of the observed bin counts against the
+    /// expected ones, returning the p-value. Bins with small expected counts
+    /// are pooled, since the test is inaccurate for them.
+    fn chi_square_test(observed: &[f64], expected: &[f64]) -> f64 {
+        let mut bins: Vec<(f64, f64)> = observed
+            .iter()
+            .copied()
+            .zip(expected.iter().copied())
+            .collect();
+        bins.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let mut chi_square = 0.0;
+        let mut degrees_of_freedom = 0;
+        let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+        for (observed, expected) in bins {
+            if expected == 0.0 {
+                assert_eq!(observed, 0.0, "Sampled a direction with a pdf of 0");
+            } else if expected < 5.0 || pooled_expected > 0.0 && pooled_expected < 5.0 {
+                pooled_observed += observed;
+                pooled_expected += expected;
+            } else {
+                chi_square += (observed - expected).powi(2) / expected;
+                degrees_of_freedom += 1;
+            }
+        }
+        if pooled_expected > 0.0 {
+            chi_square += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+            degrees_of_freedom += 1;
+        }
+        if degrees_of_freedom <= 1 {
+            // All samples are specular, so there is nothing to compare
+            return 1.0;
+        }
+        gamma_q((degrees_of_freedom - 1) as f64 / 2.0, chi_square / 2.0)
+    }
+
+    /// Compares the histogram of non-specular sampled directions with the
+    /// integral of `pdf` over each bin
+    fn check_sampling(name: &str, material: &Material, w_o: &Vector, significance_level: f64) {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut observed = vec![0.0; COS_THETA_BINS * PHI_BINS];
+        for _ in 0..CHI_SQUARE_SAMPLES {
+            if let Some(sample) = material.sample(random_samples(&mut rng), w_o, &normal, &uv) {
+                if !sample.is_specular {
+                    observed[bin(&sample.w_i)] += 1.0;
+                }
+            }
+        }
+
+        let cos_theta_step = 2.0 / COS_THETA_BINS as f64;
+        let phi_step = 2.0 * PI / PHI_BINS as f64;
+        let mut expected = vec![0.0; COS_THETA_BINS * PHI_BINS];
+        for i in 0..COS_THETA_BINS {
+            for j in 0..PHI_BINS {
+                let mut integral = 0.0;
+                for k in 0..QUADRATURE_POINTS {
+                    for l in 0..QUADRATURE_POINTS {
+                        let cos_theta = -1.0
+                            + cos_theta_step
+                                * (i as f64 + (k as f64 + 0.5) / QUADRATURE_POINTS as f64);
+                        let phi =
+                            phi_step * (j as f64 + (l as f64 + 0.5) / QUADRATURE_POINTS as f64);
+                        let w_i = direction(cos_theta, phi);
+                        integral += pdf_value(material.pdf(w_o, &w_i, &normal, &uv));
+                    }
+                }
+                expected[i * PHI_BINS + j] = integral
+                    * (cos_theta_step * phi_step / (QUADRATURE_POINTS * QUADRATURE_POINTS) as f64)
+                    * CHI_SQUARE_SAMPLES as f64;
+            }
+        }
+
+        let p_value = chi_square_test(&observed, &expected);
+        assert!(
+            p_value > significance_level,
+            "Sampling '{}' with w_o {:?} doesn't match its pdf (p-value {})",
+            name,
+            w_o,
+            p_value
+        );
+    }
+
+    /// Estimates the fraction of light scattered from `w_o`, which should be
+    /// at most 1
+    fn check_energy_conservation(name: &str, material: &Material, w_o: &Vector) {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut total = Color::BLACK;
+        for _ in 0..FURNACE_SAMPLES {
+            if let Some(sample) = material.sample(random_samples(&mut rng), w_o, &normal, &uv) {
+                let pdf = match sample.pdf {
+                    Pdf::NonDelta(pdf) => pdf,
+                    Pdf::Delta => 1.0,
+                };
+                if pdf > 0.0 {
+                    total += sample.f * sample.w_i.dot(&normal).abs() / pdf;
+                }
+            }
+        }
+        let albedo = total / FURNACE_SAMPLES as f64;
+        assert!(albedo.is_finite(), "'{}' has albedo {}", name, albedo);
+        for value in [albedo.r, albedo.g, albedo.b] {
+            assert!(
+                value <= 1.02,
+                "'{}' reflects more light than it receives from {:?}: {}",
+                name,
+                w_o,
+                albedo
+            );
+        }
+    }
+
+    /// Light scattered between two directions on the same side of the surface
+    /// should be the same in both directions
+    fn check_reciprocity(name: &str, material: &Material) {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..1000 {
+            let normal: Normal =
+                direction(rng.gen_range(-1.0..1.0), rng.gen_range(0.0..2.0 * PI)).into();
+            let mut random_direction = || {
+                let w = direction(rng.gen_range(0.0..1.0), rng.gen_range(0.0..2.0 * PI));
+                let (tangent, bitangent) = normal.generate_tangents();
+                let normal: Vector = normal.into();
+                tangent * w.x() + bitangent * w.y() + normal * w.z()
+            };
+            let (w_o, w_i) = (random_direction(), random_direction());
+            let f = material.f(&w_o, &w_i, &normal, &uv);
+            let f_reversed = material.f(&w_i, &w_o, &normal, &uv);
+            for (a, b) in [
+                (f.r, f_reversed.r),
+                (f.g, f_reversed.g),
+                (f.b, f_reversed.b),
+            ] {
+                assert!(
+                    (a - b).abs() <= 1e-9 * a.abs().max(1.0),
+                    "'{}' isn't reciprocal for {:?} and {:?}: {} != {}",
+                    name,
+                    w_o,
+                    w_i,
+                    f,
+                    f_reversed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn all_variants_are_tested() {
+        let tested: HashSet<&str> = test_materials()
+            .iter()
+            .flat_map(|test| variants(&test.material))
+            .collect();
+        for variant in ALL_VARIANTS {
+            assert!(tested.contains(variant), "No test material for {}", variant);
+        }
+    }
+
+    #[test]
+    fn energy_conservation() {
+        for test in test_materials() {
+            for w_o in outgoing_directions() {
+                check_energy_conservation(test.name, &test.material, &w_o);
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_matches_pdf() {
+        let tests: Vec<TestMaterial> = test_materials()
+            .into_iter()
+            .filter(|test| !test.is_stochastic)
+            .collect();
+        // Šidák correction, so that the probability of any correct material
+        // failing stays at the significance level
+        let num_tests = tests.len() * outgoing_directions().len();
+        let significance_level = 1.0 - (1.0 - SIGNIFICANCE_LEVEL).powf(1.0 / num_tests as f64);
+        for test in tests {
+            for w_o in outgoing_directions() {
+                check_sampling(test.name, &test.material, &w_o, significance_level);
+            }
+        }
+    }
+
+    #[test]
+    fn reciprocity() {
+        for test in test_materials() {
+            if !test.is_stochastic {
+                check_reciprocity(test.name, &test.material);
+            }
+        }
+    }
+
+    #[test]
+    fn chi_square_detects_mismatches() {
+        // Sanity check of the test itself: cosine sampling doesn't match a
+        // uniform pdf
+        let expected = vec![1000.0; 10];
+        let mut observed: Vec<f64> = (0..10).map(|i| 500.0 + 100.0 * i as f64).collect();
+        assert!(chi_square_test(&observed, &expected) < 1e-6);
+        observed = vec![
+            1010.0, 990.0, 1005.0, 995.0, 1000.0, 1020.0, 980.0, 1000.0, 1000.0, 1000.0,
+        ];
+        assert!(chi_square_test(&observed, &expected) > 0.5);
+        // Q(1, x) = e^-x
+        assert!((gamma_q(1.0, 2.0) - (-2.0f64).exp()).abs() < 1e-9);
+    }
+}
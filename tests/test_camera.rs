@@ -0,0 +1,158 @@
+use approx::assert_abs_diff_eq;
+use std::f64::consts::PI;
+
+use craytracer::{
+    camera::{Aperture, Camera},
+    film::Film,
+    geometry::{point::Point, vector::Vector, O, Y},
+    p,
+    ray::Ray,
+    sampling::samplers::{Sample1d, Sample2d},
+    texture::{Texture, TextureCoordinates},
+};
+
+const N: usize = 32;
+
+/// Checks whether a point is inside an aperture
+type Inside<'a> = Box<dyn Fn((f64, f64)) -> bool + 'a>;
+
+/// Samples on an N x N grid
+fn grid() -> impl Iterator<Item = (f64, f64)> {
+    (0..N * N).map(|i| {
+        (
+            ((i % N) as f64 + 0.5) / N as f64,
+            ((i / N) as f64 + 0.5) / N as f64,
+        )
+    })
+}
+
+fn camera(lens_radius: f64, focal_distance: f64) -> Camera {
+    Camera::perspective(
+        Film {
+            width: 4,
+            height: 4,
+        },
+        O,
+        p!(0, 0, 1),
+        Y,
+        60.0,
+        lens_radius,
+        focal_distance,
+    )
+}
+
+/// Rays through the same point on the film, from all over the lens
+fn rays(camera: &Camera, raster_x: usize, raster_y: usize) -> Vec<Ray> {
+    grid()
+        .map(|(u, v)| {
+            camera.sample(
+                (
+                    Sample2d::new(0.5, 0.5),
+                    Sample2d::new(u, v),
+                    Sample1d::new(0.0),
+                ),
+                raster_x,
+                raster_y,
+            )
+        })
+        .collect()
+}
+
+/// Where `ray` crosses the plane at `z`
+fn at_z(ray: &Ray, z: f64) -> Point {
+    ray.at((z - ray.origin.z()) / ray.direction.z())
+}
+
+#[test]
+fn apertures() {
+    let inside_polygon = |sides: usize, rotation: f64, (x, y): (f64, f64)| {
+        (0..sides).all(|i| {
+            let corner = |i: usize| {
+                let angle = rotation.to_radians() + 2.0 * PI * i as f64 / sides as f64;
+                (angle.cos(), angle.sin())
+            };
+            let (a, b) = (corner(i), corner(i + 1));
+            (b.0 - a.0) * (y - a.1) - (b.1 - a.1) * (x - a.0) >= -1e-9
+        })
+    };
+    let checks = Texture::checkerboard(0.0, 1.0, 1.0);
+    let checks_aperture = Aperture::texture(&checks).unwrap();
+    let apertures: Vec<(Aperture, Inside)> = vec![
+        (
+            Aperture::Circle,
+            Box::new(|(x, y): (f64, f64)| x * x + y * y <= 1.0 + 1e-9),
+        ),
+        (
+            Aperture::Polygon {
+                sides: 6,
+                rotation: 0.0,
+            },
+            Box::new(|p| inside_polygon(6, 0.0, p)),
+        ),
+        (
+            Aperture::Polygon {
+                sides: 3,
+                rotation: 90.0,
+            },
+            Box::new(|p| inside_polygon(3, 90.0, p)),
+        ),
+        (
+            checks_aperture,
+            Box::new(|(x, y): (f64, f64)| {
+                checks.eval(&TextureCoordinates::new((x + 1.0) / 2.0, (y + 1.0) / 2.0)) == 1.0
+            }),
+        ),
+    ];
+    for (aperture, inside) in apertures {
+        let points: Vec<(f64, f64)> = grid()
+            .map(|(u, v)| aperture.sample(Sample2d::new(u, v)))
+            .collect();
+        for &point in &points {
+            assert!(inside(point), "{:?} outside {:?}", point, aperture);
+        }
+        // The points reach the edges of the aperture
+        let max_radius = points
+            .iter()
+            .map(|(x, y)| (x * x + y * y).sqrt())
+            .fold(0.0, f64::max);
+        assert!(max_radius > 0.9, "{:?} {}", aperture, max_radius);
+    }
+
+    assert!(Aperture::texture(&Texture::constant(0.0)).is_none());
+}
+
+#[test]
+fn focus() {
+    let mut camera = camera(0.5, 10.0);
+    let in_focus = |camera: &Camera, z: f64| {
+        let rays = rays(camera, 1, 3);
+        let focus = at_z(&rays[0], z);
+        for ray in &rays {
+            assert_abs_diff_eq!(at_z(ray, z), focus, epsilon = 1e-9);
+        }
+        // Rays leave from all over the lens
+        let spread = rays
+            .iter()
+            .map(|ray| (ray.origin - O).magnitude())
+            .fold(0.0, f64::max);
+        assert!(spread > 0.45 && spread <= 0.5, "{}", spread);
+    };
+    in_focus(&camera, 10.0);
+
+    camera.focus_on(&p!(3, -2, 20));
+    in_focus(&camera, 20.0);
+
+    // Focusing through the center of the film finds what's straight ahead
+    camera.focus_through_pixel(2, 2);
+    camera.autofocus(|ray| {
+        assert_abs_diff_eq!(ray.direction, Vector(0.0, 0.0, 1.0), epsilon = 1e-9);
+        Some(p!(0, 0, 7))
+    });
+    in_focus(&camera, 7.0);
+
+    // Only once, and nothing hit keeps the focus
+    camera.autofocus(|_| panic!("Autofocused twice"));
+    camera.focus_through_pixel(0, 0);
+    camera.autofocus(|_| None);
+    in_focus(&camera, 7.0);
+}
@@ -0,0 +1,224 @@
+mod convergence {
+    use std::f64::consts::PI;
+
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        color::Color,
+        geometry::{point::Point, vector::Vector},
+        path_integrator,
+        ray::Ray,
+        sampling::samplers::{Sampler, SobolSampler},
+        scene::Scene,
+        scene_parser::scene_parser::parse_scene,
+        simple_integrator,
+    };
+
+    type Integrator = fn(&mut SobolSampler, Ray, &Scene) -> Color;
+
+    const INTEGRATORS: [(&str, Integrator); 2] = [
+        ("path_integrator", path_integrator::estimate_Li),
+        ("simple_integrator", simple_integrator::estimate_Li),
+    ];
+
+    /// Sample counts at which estimates are checked, which should get closer
+    /// to the expected radiance as they grow
+    const NUM_SAMPLES: [usize; 3] = [64, 1024, 16384];
+
+    fn scene(max_depth: usize, lights: &str, shapes: &str, primitives: &str) -> Scene {
+        parse_scene(&format!(
+            "{{
+                max_depth: {},
+                camera: Perspective {{
+                    origin: Point(0, 0, -10),
+                    target: Point(0, 0, 0),
+                    up: Vector(0, 1, 0),
+                    fov: 30,
+                    film: {{ width: 1, height: 1 }},
+                }},
+                lights: [{}],
+                materials: {{
+                    white: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }},
+                    grey: Matte {{ reflectance: Color(0.5, 0.5, 0.5), sigma: 0 }},
+                }},
+                shapes: {{ {} }},
+                primitives: [{}],
+            }}",
+            max_depth, lights, shapes, primitives
+        ))
+        .unwrap()
+    }
+
+    /// Returns the mean of the red channel of `num_samples` estimates of the
+    /// radiance along `ray`, and its standard error
+    #[allow(non_snake_case)]
+    fn estimate(
+        integrator: Integrator,
+        scene: &Scene,
+        ray: &Ray,
+        num_samples: usize,
+    ) -> (f64, f64) {
+        let mut sampler = SobolSampler::new(0, num_samples);
+        let (mut sum, mut sum_squared) = (0.0, 0.0);
+        for sample_index in 0..num_samples {
+            sampler.start_pixel(0, 0, sample_index);
+            let L = integrator(&mut sampler, Ray::new(ray.origin, ray.direction), scene);
+            assert_eq!(L.r, L.g);
+            assert_eq!(L.r, L.b);
+            sum += L.r;
+            sum_squared += L.r * L.r;
+        }
+        let mean = sum / num_samples as f64;
+        let variance = (sum_squared / num_samples as f64 - mean * mean).max(0.0);
+        (mean, (variance / num_samples as f64).sqrt())
+    }
+
+    /// Checks that estimates of the radiance along `ray` are consistent with
+    /// `expected` at every sample count, and within 1% of it at the highest
+    fn assert_converges(scene: &Scene, ray: Ray, expected: f64) {
+        for (name, integrator) in INTEGRATORS {
+            for num_samples in NUM_SAMPLES {
+                let (mean, standard_error) = estimate(integrator, scene, &ray, num_samples);
+                assert!(
+                    (mean - expected).abs() <= 4.0 * standard_error + 1e-3 * expected,
+                    "{} estimated {} ± {} with {} samples, expected {}",
+                    name,
+                    mean,
+                    standard_error,
+                    num_samples,
+                    expected
+                );
+            }
+            let (mean, _) = estimate(integrator, scene, &ray, NUM_SAMPLES[2]);
+            assert_abs_diff_eq!(mean, expected, epsilon = 0.01 * expected);
+        }
+    }
+
+    #[test]
+    fn emitter() {
+        // Light arriving directly from an emitter is its emittance
+        let scene = scene(
+            5,
+            "",
+            "light: Disk { origin: Point(0, 0, 0), radius: 1, rotate_x: 0 }",
+            "Shape { shape: 'light', emittance: Color(2, 2, 2) }",
+        );
+        assert_converges(
+            &scene,
+            Ray::new(Point(0.0, 0.0, -10.0), Vector(0.0, 0.0, 1.0)),
+            2.0,
+        );
+        // Rays that miss it see nothing
+        assert_converges(
+            &scene,
+            Ray::new(Point(0.0, 0.0, -10.0), Vector(0.0, 1.0, 0.0)),
+            0.0,
+        );
+    }
+
+    #[test]
+    fn infinite_light() {
+        // Scenes need at least one primitive, which is placed behind the ray
+        let scene = scene(
+            5,
+            "Infinite { intensity: Color(0.7, 0.7, 0.7) }",
+            "sphere: Sphere { origin: Point(0, 0, -5), radius: 1 }",
+            "Shape { shape: 'sphere', material: 'white' }",
+        );
+        assert_converges(
+            &scene,
+            Ray::new(Point(0.0, 0.0, 0.0), Vector(0.0, 0.0, 1.0)),
+            0.7,
+        );
+    }
+
+    #[test]
+    fn white_furnace() {
+        // Every direction above a convex object reaches the environment, so a
+        // Lambertian surface reflects its albedo times the environment's
+        // radiance
+        for (material, albedo) in [("white", 1.0), ("grey", 0.5)] {
+            let scene = scene(
+                5,
+                "Infinite { intensity: Color(0.8, 0.8, 0.8) }",
+                "sphere: Sphere { origin: Point(0, 0, 0), radius: 1 }",
+                &format!("Shape {{ shape: 'sphere', material: '{}' }}", material),
+            );
+            let ray = Ray::new(Point(0.3, 0.2, -10.0), Vector(0.0, 0.0, 1.0));
+            assert_converges(&scene, ray, albedo * 0.8);
+        }
+    }
+
+    #[test]
+    fn point_light_over_plane() {
+        // A Lambertian plane at distance d from a point light with intensity I
+        // reflects albedo / π * I * cos θ / d²
+        let scene = scene(
+            5,
+            "Point { origin: Point(0, 2, 0), intensity: Color(3, 3, 3) }",
+            "ground: Disk { origin: Point(0, 0, 0), radius: 100, rotate_x: -90 }",
+            "Shape { shape: 'ground', material: 'grey' }",
+        );
+        for x in [0.0, 1.0, 3.0] {
+            let ray = Ray::new(Point(x, 1.0, -1.0), Vector(0.0, -1.0, 1.0).normalized());
+            let distance_squared = x * x + 4.0;
+            let cos_theta = 2.0 / distance_squared.sqrt();
+            let expected = 0.5 / PI * 3.0 * cos_theta / distance_squared;
+            assert_converges(&scene, ray, expected);
+        }
+    }
+
+    #[test]
+    fn disk_light_over_plane() {
+        // A disk with radius r and radiance L, at height h above a point,
+        // produces an irradiance of π L r² / (h² + r²) at it
+        let scene = scene(
+            5,
+            "",
+            "ground: Disk { origin: Point(0, 0, 0), radius: 100, rotate_x: -90 },
+             light: Disk { origin: Point(0, 2, 0), radius: 1, rotate_x: 90 }",
+            "Shape { shape: 'ground', material: 'grey' },
+             Shape { shape: 'light', emittance: Color(4, 4, 4) }",
+        );
+        let ray = Ray::new(Point(0.0, 1.0, -1.0), Vector(0.0, -1.0, 1.0).normalized());
+        let irradiance = PI * 4.0 / (4.0 + 1.0);
+        assert_converges(&scene, ray, 0.5 / PI * irradiance);
+    }
+
+    #[test]
+    fn sphere_light_over_plane() {
+        // A sphere with radius r and radiance L, whose center is at a height
+        // h above a point, produces an irradiance of π L r² / h² at it
+        let scene = scene(
+            5,
+            "",
+            "ground: Disk { origin: Point(0, 0, 0), radius: 100, rotate_x: -90 },
+             light: Sphere { origin: Point(0, 3, 0), radius: 1 }",
+            "Shape { shape: 'ground', material: 'grey' },
+             Shape { shape: 'light', emittance: Color(4, 4, 4) }",
+        );
+        let ray = Ray::new(Point(0.0, 1.0, -1.0), Vector(0.0, -1.0, 1.0).normalized());
+        let irradiance = PI * 4.0 / 9.0;
+        assert_converges(&scene, ray, 0.5 / PI * irradiance);
+    }
+
+    #[test]
+    fn integrating_sphere() {
+        // A point light with intensity I at the center of a sphere with radius
+        // R produces an irradiance of I / R² on its inner surface. Any patch of
+        // a diffuse sphere receives the same fraction of the light reflected by
+        // the rest of it, so each bounce multiplies the radiance by the albedo.
+        let max_depth = 5;
+        let scene = scene(
+            max_depth,
+            "Point { origin: Point(0, 0, 0), intensity: Color(8, 8, 8) }",
+            "sphere: Sphere { origin: Point(0, 0, 0), radius: 2 }",
+            "Shape { shape: 'sphere', material: 'grey' }",
+        );
+        let irradiance = 8.0 / 4.0;
+        let expected: f64 = (1..=max_depth)
+            .map(|bounces| irradiance / PI * 0.5f64.powi(bounces as i32))
+            .sum();
+        let ray = Ray::new(Point(0.5, 0.0, 0.0), Vector(0.0, 0.6, 0.8));
+        assert_converges(&scene, ray, expected);
+    }
+}
@@ -0,0 +1,132 @@
+mod shading {
+    use std::sync::Arc;
+
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        color::Color,
+        geometry::{normal::Normal, traits::DotProduct, X, Y, Z},
+        intersection::PrimitiveIntersection,
+        material::{Material, Perturbation},
+        mesh::TriangleMesh,
+        n, p,
+        primitive::Primitive,
+        ray::Ray,
+        shape::Shape,
+        texture::Texture,
+        v,
+    };
+
+    fn matte() -> Material {
+        Material::new_matte(Texture::constant(Color::WHITE), Texture::constant(0.0))
+    }
+
+    // Hits a unit sphere at (1, 0, 0), where dpdu points along +y
+    fn intersect_sphere(material: Material) -> PrimitiveIntersection<'static> {
+        let primitive = Box::leak(Box::new(Primitive::new(
+            Arc::new(Shape::new_sphere(p!(0, 0, 0), 1.0)),
+            Arc::new(material),
+        )));
+        primitive
+            .intersect(&mut Ray::new(p!(5, 0, 0), -X))
+            .expect("Expected an intersection")
+    }
+
+    fn assert_normal_eq(a: Normal, b: Normal) {
+        assert_abs_diff_eq!(a.x(), b.x(), epsilon = 1e-6);
+        assert_abs_diff_eq!(a.y(), b.y(), epsilon = 1e-6);
+        assert_abs_diff_eq!(a.z(), b.z(), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn unperturbed() {
+        let intersection = intersect_sphere(matte());
+        assert_normal_eq(intersection.normal, n!(1, 0, 0));
+        assert_normal_eq(intersection.shading.normal, n!(1, 0, 0));
+    }
+
+    #[test]
+    fn constant_bump_map() {
+        let material =
+            Material::new_perturbed(matte(), Perturbation::BumpMap(Texture::constant(0.5)));
+        let intersection = intersect_sphere(material);
+        assert_normal_eq(intersection.normal, n!(1, 0, 0));
+        assert_normal_eq(intersection.shading.normal, n!(1, 0, 0));
+    }
+
+    #[test]
+    fn flat_normal_map() {
+        let material = Material::new_perturbed(
+            matte(),
+            Perturbation::NormalMap(Texture::constant(Color {
+                r: 0.5,
+                g: 0.5,
+                b: 1.0,
+            })),
+        );
+        let intersection = intersect_sphere(material);
+        assert_normal_eq(intersection.shading.normal, n!(1, 0, 0));
+    }
+
+    #[test]
+    fn tangent_normal_map() {
+        // A normal map pointing along the tangent tilts the shading normal to
+        // dpdu, without affecting the geometric normal
+        let material = Material::new_perturbed(
+            matte(),
+            Perturbation::NormalMap(Texture::constant(Color {
+                r: 1.0,
+                g: 0.5,
+                b: 0.5,
+            })),
+        );
+        let intersection = intersect_sphere(material);
+        assert_normal_eq(intersection.normal, n!(1, 0, 0));
+        assert_normal_eq(intersection.shading.normal, n!(0, 1, 0));
+        assert_abs_diff_eq!(
+            intersection.shading.dpdu.dot(&intersection.shading.normal),
+            0.0,
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn light_leaks() {
+        let material = Material::new_perturbed(
+            matte(),
+            Perturbation::NormalMap(Texture::constant(Color {
+                r: 1.0,
+                g: 0.5,
+                b: 0.5,
+            })),
+        );
+        let intersection = intersect_sphere(material);
+        let w_o = (X + Y).normalized();
+        // Above both the geometric and shading surfaces
+        assert!(intersection.normals_agree(&w_o, &(X + Y * 2.0).normalized()));
+        // Below the geometric surface but above the shading surface
+        assert!(!intersection.normals_agree(&w_o, &(Y - X * 0.1).normalized()));
+        // Below both surfaces
+        assert!(intersection.normals_agree(&w_o, &(-Y - X * 0.1).normalized()));
+    }
+
+    #[test]
+    fn triangle_shading_normals() {
+        // Vertex normals tilted away from the face normal are interpolated for
+        // shading, while the geometric normal stays perpendicular to the face
+        let tilted = v!(1, 0, 1).normalized();
+        let triangle = Shape::new_mesh_triangle(TriangleMesh::new(
+            vec![[0, 1, 2]],
+            vec![p!(0, 0, 0), p!(0, 1, 0), p!(1, 0, 0)],
+            vec![tilted; 3],
+            vec![(0.0, 0.0), (0.0, 1.0), (1.0, 0.0)],
+            vec![],
+        ))
+        .unwrap();
+        let intersection = triangle
+            .intersect(&mut Ray::new(p!(0.25, 0.25, 1), -Z))
+            .unwrap();
+        assert_normal_eq(intersection.normal, n!(0, 0, 1));
+        let tilted = Normal(tilted.x(), tilted.y(), tilted.z());
+        assert_normal_eq(intersection.shading_normal, tilted);
+    }
+}
@@ -0,0 +1,401 @@
+use std::{f64::consts::PI, sync::Arc};
+
+use craytracer::{
+    color::Color,
+    geometry::{normal::Normal, traits::DotProduct, vector::Vector},
+    material::Material,
+    n,
+    pdf::Pdf,
+    sampling::{
+        samplers::{Sample1d, Sample2d},
+        sampling_fns::sample_hemisphere,
+    },
+    texture::{Texture, TextureCoordinates},
+    v,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn matte(reflectance: f64) -> Arc<Material> {
+    Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE * reflectance),
+        Texture::constant(0.0),
+    ))
+}
+
+fn pdf_value(pdf: Pdf) -> f64 {
+    match pdf {
+        Pdf::NonDelta(pdf) => pdf,
+        Pdf::Delta => panic!("Expected a non-delta pdf"),
+    }
+}
+
+/// Estimates the fraction of light arriving from `w_o` that is reflected, by
+/// sampling the material
+fn sampled_albedo(material: &Material, w_o: &Vector, normal: &Normal, n: usize) -> Color {
+    let uv = TextureCoordinates::new(0.5, 0.5);
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut total = Color::BLACK;
+    for _ in 0..n {
+        let samples = (
+            Sample1d::new(rng.gen()),
+            Sample2d::new(rng.gen(), rng.gen()),
+        );
+        if let Some(sample) = material.sample(samples, w_o, normal, &uv) {
+            let pdf = match sample.pdf {
+                Pdf::NonDelta(pdf) => pdf,
+                Pdf::Delta => 1.0,
+            };
+            total += sample.f * sample.w_i.dot(normal).abs() / pdf;
+        }
+    }
+    total / n as f64
+}
+
+mod mix {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn blends_f_and_pdf() {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, 1).normalized();
+        let w_i = v!(0, 1, 2).normalized();
+
+        let mix = Material::new_mix(matte(0.2), matte(0.6), Texture::constant(0.25));
+        assert_abs_diff_eq!(
+            mix.f(&w_o, &w_i, &normal, &uv).r,
+            (0.2 * 0.75 + 0.6 * 0.25) / PI,
+            epsilon = 1e-9
+        );
+        // Both materials are sampled the same way
+        assert_abs_diff_eq!(
+            pdf_value(mix.pdf(&w_o, &w_i, &normal, &uv)),
+            pdf_value(matte(0.2).pdf(&w_o, &w_i, &normal, &uv)),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn specular() {
+        // Mixing a mirror with a black material halves the reflected light
+        let mirror = Arc::new(Material::new_metal(
+            Texture::constant(Color::BLACK),
+            Texture::constant(Color::WHITE * 1e6),
+        ));
+        let mix = Material::new_mix(mirror, matte(0.0), Texture::constant(0.5));
+        let albedo = sampled_albedo(&mix, &v!(1, 0, 1).normalized(), &n!(0, 0, 1), 10000);
+        assert_abs_diff_eq!(albedo.r, 0.5, epsilon = 0.02);
+    }
+}
+
+mod coated {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    fn coated(base: Arc<Material>, thickness: f64, albedo: Color) -> Material {
+        Material::new_coated(
+            base,
+            1.5,
+            Texture::constant(thickness),
+            Texture::constant(albedo),
+            100,
+            1,
+        )
+    }
+
+    /// Estimates the albedo by integrating `f` over the hemisphere, adding
+    /// the specular reflection off the coat which `f` doesn't include
+    fn integrated_albedo(material: &Material, w_o: &Vector, normal: &Normal, n: usize) -> Color {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut total = Color::BLACK;
+        for _ in 0..n {
+            let w_i = sample_hemisphere(Sample2d::new(rng.gen(), rng.gen()), normal);
+            total += material.f(w_o, &w_i, normal, &uv) * w_i.dot(normal) * 2.0 * PI;
+        }
+        let reflectance = craytracer::bxdf::fresnel_dielectric(1.0, 1.5, w_o.dot(normal));
+        total / n as f64 + Color::WHITE * reflectance
+    }
+
+    #[test]
+    fn white_furnace() {
+        // Without absorption, all light eventually leaves the layer
+        let material = coated(matte(1.0), 1e-6, Color::BLACK);
+        let normal = n!(0, 0, 1);
+        for w_o in [
+            v!(0, 0, 1),
+            v!(1, 0, 1).normalized(),
+            v!(3, 0, 1).normalized(),
+        ] {
+            let albedo = sampled_albedo(&material, &w_o, &normal, 20000);
+            assert_abs_diff_eq!(albedo.r, 1.0, epsilon = 0.03);
+            let albedo = integrated_albedo(&material, &w_o, &normal, 20000);
+            assert_abs_diff_eq!(albedo.r, 1.0, epsilon = 0.03);
+        }
+    }
+
+    #[test]
+    fn scattering_medium() {
+        // A white medium over a black base still reflects some light, and
+        // sampling and evaluating the material should agree on how much
+        let material = coated(matte(0.0), 1.0, Color::WHITE);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, 2).normalized();
+        let sampled = sampled_albedo(&material, &w_o, &normal, 20000);
+        let integrated = integrated_albedo(&material, &w_o, &normal, 20000);
+        assert!(sampled.r > 0.1 && sampled.r < 0.9, "{}", sampled.r);
+        assert_abs_diff_eq!(sampled.r, integrated.r, epsilon = 0.03);
+    }
+
+    #[test]
+    fn two_sided() {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let material = coated(matte(0.5), 0.01, Color::BLACK);
+        let w_o = v!(1, 0, 1).normalized();
+        let w_i = v!(0, 1, 2).normalized();
+        let front = material.f(&w_o, &w_i, &n!(0, 0, 1), &uv);
+        let back = material.f(&w_o, &w_i, &n!(0, 0, -1), &uv);
+        assert_eq!(front, back);
+        // The base is opaque
+        assert!(material.f(&w_o, &-w_i, &n!(0, 0, 1), &uv).is_black());
+    }
+
+    #[test]
+    fn deterministic() {
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let material = coated(matte(0.5), 0.01, Color::WHITE * 0.5);
+        let w_o = v!(1, 0, 1).normalized();
+        let w_i = v!(0, 1, 2).normalized();
+        let normal = n!(0, 0, 1);
+        assert_eq!(
+            material.f(&w_o, &w_i, &normal, &uv),
+            material.f(&w_o, &w_i, &normal, &uv)
+        );
+        assert_eq!(
+            pdf_value(material.pdf(&w_o, &w_i, &normal, &uv)),
+            pdf_value(material.pdf(&w_o, &w_i, &normal, &uv))
+        );
+    }
+}
+
+mod subsurface {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        geometry::point::Point, p, ray::Ray, sampling::sampling_fns::cosine_sample_hemisphere,
+        scene::Scene, scene_parser::scene_parser::parse_scene,
+    };
+
+    /// A scene with a single subsurface sphere at the origin
+    fn sphere_scene(radius: f64, albedo: f64, mean_free_path: f64) -> Scene {
+        parse_scene(&format!(
+            "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, -10),
+        target: Point(0, 0, 0),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+    }},
+    lights: [ Point {{ origin: Point(0, 0, -10), intensity: Color(1, 1, 1) }} ],
+    materials: {{
+        material: Subsurface {{
+            albedo: Color({albedo}, {albedo}, {albedo}),
+            mean_free_path: Color({mean_free_path}, {mean_free_path}, {mean_free_path}),
+        }},
+    }},
+    shapes: {{ ball: Sphere {{ origin: Point(0, 0, 0), radius: {radius} }} }},
+    primitives: [ Shape {{ shape: 'ball', material: 'material' }} ],
+}}
+",
+            radius = radius,
+            albedo = albedo,
+            mean_free_path = mean_free_path,
+        ))
+        .unwrap()
+    }
+
+    /// Averages the light carried by walks entering the top of the sphere
+    /// with cosine distributed directions, checking that they leave through
+    /// its surface
+    fn average_throughput(scene: &Scene, radius: f64, n: usize) -> Color {
+        let mut ray = Ray::new(p!(0, radius + 1.0, 0), v!(0, -1, 0));
+        let entry = scene
+            .intersect(&mut ray)
+            .expect("Expected to hit the sphere");
+        let subsurface = entry
+            .material
+            .subsurface()
+            .expect("Expected a subsurface material");
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut total = Color::BLACK;
+        for _ in 0..n {
+            let w_i = cosine_sample_hemisphere(Sample2d::new(rng.gen(), rng.gen()), &n!(0, -1, 0));
+            let ray = Ray::new(entry.location, w_i);
+            if let Some(exit) =
+                subsurface.random_walk(scene, &ray, &entry.uv, Sample1d::new(rng.gen()))
+            {
+                let location = exit.intersection.location;
+                assert_abs_diff_eq!(
+                    (location - Point(0.0, 0.0, 0.0)).magnitude(),
+                    radius,
+                    epsilon = 1e-6
+                );
+                assert!(exit.w_o.dot(&exit.intersection.normal) > 0.0);
+                total += exit.throughput;
+            }
+        }
+        total / n as f64
+    }
+
+    #[test]
+    fn energy_conservation() {
+        // Without absorption, every walk eventually leaves the object
+        let scene = sphere_scene(1.0, 1.0, 0.1);
+        let throughput = average_throughput(&scene, 1.0, 2000);
+        assert_abs_diff_eq!(throughput.r, 1.0, epsilon = 0.02);
+    }
+
+    #[test]
+    fn multiple_scattering_albedo() {
+        // In a medium much larger than its mean free path, the fraction of
+        // light leaving the surface is roughly the albedo
+        for albedo in [0.2, 0.5, 0.8] {
+            let scene = sphere_scene(1000.0, albedo, 1.0);
+            let throughput = average_throughput(&scene, 1000.0, 4000);
+            assert_abs_diff_eq!(throughput.r, albedo, epsilon = 0.05);
+        }
+    }
+
+    #[test]
+    fn surface() {
+        let material = Material::new_subsurface(
+            Texture::constant(Color::WHITE),
+            Texture::constant(Color::WHITE),
+            1.33,
+        );
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, 1).normalized();
+        // Light is either reflected or transmitted into the object
+        let albedo = sampled_albedo(&material, &w_o, &normal, 1000);
+        assert_abs_diff_eq!(albedo.r, 1.0, epsilon = 1e-9);
+        // The only non-specular scattering is transmission
+        assert!(material
+            .f(&w_o, &v!(0, 1, 1).normalized(), &normal, &uv)
+            .is_black());
+        assert!(!material
+            .f(&w_o, &v!(0, 1, -1).normalized(), &normal, &uv)
+            .is_black());
+    }
+}
+
+mod measured {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use craytracer::measured::MeasuredBRDF;
+
+    const TABLE_SIZE: usize = 90 * 90 * 180;
+
+    fn lambertian(reflectance: f64) -> Material {
+        Material::Measured(MeasuredBRDF::new(vec![
+            Color::WHITE * reflectance / PI;
+            TABLE_SIZE
+        ]))
+    }
+
+    /// A glossy BRDF, which is brighter for small theta half angles
+    fn glossy() -> Material {
+        let values = (0..TABLE_SIZE)
+            .map(|i| {
+                let theta_half_index = i / (90 * 180);
+                Color::WHITE * if theta_half_index < 20 { 2.0 } else { 0.05 }
+            })
+            .collect();
+        Material::Measured(MeasuredBRDF::new(values))
+    }
+
+    fn merl_file(dimensions: [i32; 3], values: [f64; 3]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for dimension in dimensions {
+            bytes.extend_from_slice(&dimension.to_le_bytes());
+        }
+        for value in values {
+            for _ in 0..TABLE_SIZE {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn read_merl() {
+        let brdf = MeasuredBRDF::from_reader(&merl_file([90, 90, 180], [1500.0, 1500.0, -1.0])[..])
+            .unwrap();
+        let normal = n!(0, 0, 1);
+        let f = brdf.f(
+            &v!(1, 0, 1).normalized(),
+            &v!(0, 1, 2).normalized(),
+            &normal,
+        );
+        assert_abs_diff_eq!(f.r, 1.0, epsilon = 1e-9);
+        assert_abs_diff_eq!(f.g, 1.15, epsilon = 1e-9);
+        // Missing measurements are black
+        assert_abs_diff_eq!(f.b, 0.0, epsilon = 1e-9);
+
+        let error =
+            MeasuredBRDF::from_reader(&merl_file([90, 90, 90], [1.0, 1.0, 1.0])[..]).unwrap_err();
+        assert!(error.contains("dimensions"), "{}", error);
+    }
+
+    #[test]
+    fn lambertian_albedo() {
+        let material = lambertian(0.5);
+        let normal = n!(0, 0, 1);
+        for w_o in [
+            v!(0, 0, 1),
+            v!(1, 0, 1).normalized(),
+            v!(0, -3, 1).normalized(),
+        ] {
+            let albedo = sampled_albedo(&material, &w_o, &normal, 20000);
+            assert_abs_diff_eq!(albedo.r, 0.5, epsilon = 0.02);
+        }
+        // Back faces are shaded like front faces
+        let albedo = sampled_albedo(&material, &v!(1, 0, -1).normalized(), &normal, 20000);
+        assert_abs_diff_eq!(albedo.r, 0.5, epsilon = 0.02);
+    }
+
+    #[test]
+    fn sampling_matches_f_and_pdf() {
+        let material = glossy();
+        let uv = TextureCoordinates::new(0.5, 0.5);
+        let normal = n!(0, 0, 1);
+        let w_o = v!(1, 0, 2).normalized();
+
+        // Integrate f and the pdf over the hemisphere with uniform samples
+        let mut rng = StdRng::seed_from_u64(3);
+        let n = 100000;
+        let mut integrated = Color::BLACK;
+        let mut pdf_integral = 0.0;
+        for _ in 0..n {
+            let w_i = sample_hemisphere(Sample2d::new(rng.gen(), rng.gen()), &normal);
+            integrated += material.f(&w_o, &w_i, &normal, &uv) * w_i.dot(&normal) * 2.0 * PI;
+            pdf_integral += pdf_value(material.pdf(&w_o, &w_i, &normal, &uv)) * 2.0 * PI;
+        }
+        let integrated = integrated / n as f64;
+        let pdf_integral = pdf_integral / n as f64;
+
+        // Some half vectors reflect w_o below the surface, so the pdf doesn't
+        // integrate to one over the hemisphere
+        assert!(
+            pdf_integral > 0.5 && pdf_integral < 1.01,
+            "{}",
+            pdf_integral
+        );
+        let sampled = sampled_albedo(&material, &w_o, &normal, 20000);
+        assert_abs_diff_eq!(sampled.r, integrated.r, epsilon = 0.03);
+    }
+}
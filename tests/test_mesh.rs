@@ -0,0 +1,247 @@
+use approx::assert_abs_diff_eq;
+use pretty_assertions::assert_eq;
+use std::sync::Arc;
+
+use craytracer::{
+    bounds::Bounds,
+    cache::Cache,
+    color::Color,
+    geometry::{point::Point, vector::Vector, Z},
+    material::Material,
+    mesh::{TriangleMesh, VertexMotion},
+    obj::{load_deforming_obj, load_obj, load_obj_with_cache},
+    p,
+    primitive::{Mesh, Primitive},
+    ray::Ray,
+    shape::Shape,
+    texture::Texture,
+    transformation::{Transformable, Transformation},
+    v,
+};
+
+/// Unit square in the XY plane, split into two triangles along its diagonal
+fn quad() -> TriangleMesh {
+    TriangleMesh::new(
+        vec![[0, 1, 2], [0, 2, 3]],
+        vec![p!(0, 0, 0), p!(0, 1, 0), p!(1, 1, 0), p!(1, 0, 0)],
+        vec![v!(0, 0, -1); 4],
+        vec![(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)],
+        vec![],
+    )
+}
+
+fn material() -> Arc<Material> {
+    Arc::new(Material::new_matte(
+        Texture::constant(Color::WHITE),
+        Texture::constant(0.0),
+    ))
+}
+
+#[test]
+fn shared_vertices() {
+    let mesh = quad();
+    assert_eq!(mesh.num_triangles(), 2);
+    assert_eq!(
+        mesh.triangle_vertices(1),
+        [p!(0, 0, 0), p!(1, 1, 0), p!(1, 0, 0)]
+    );
+    assert_eq!(mesh.area(0) + mesh.area(1), 1.0);
+
+    // Each point of the square is on exactly one of the triangles, which
+    // interpolate the shared attributes
+    for (x, y) in [(0.25, 0.5), (0.75, 0.5), (0.5, 0.25), (0.5, 0.75)] {
+        let hits: Vec<_> = (0..2)
+            .filter_map(|index| mesh.intersect(index, &mut Ray::new(Point(x, y, -1.0), Z)))
+            .collect();
+        assert_eq!(hits.len(), 1);
+        assert_abs_diff_eq!(hits[0].uv.0, x, epsilon = 1e-12);
+        assert_abs_diff_eq!(hits[0].uv.1, y, epsilon = 1e-12);
+        assert_eq!(Vector::from(hits[0].shading_normal), v!(0, 0, -1));
+    }
+}
+
+#[test]
+fn degenerate_triangles() {
+    let mesh = TriangleMesh::new(
+        vec![[0, 1, 2], [0, 1, 1]],
+        vec![p!(0, 0, 0), p!(0, 1, 0), p!(1, 1, 0)],
+        vec![],
+        vec![],
+        vec![],
+    );
+    assert!(!mesh.is_degenerate(0));
+    assert!(mesh.is_degenerate(1));
+
+    let mesh = TriangleMesh {
+        normals: vec![v!(0, 0, -1), v!(0, 0, 0), v!(0, 0, -1)],
+        ..mesh
+    };
+    assert!(mesh.is_degenerate(0));
+}
+
+#[test]
+fn matches_shape_triangles() {
+    // Triangles referring to a mesh are hit like standalone triangle shapes
+    let mesh = Arc::new(Mesh {
+        triangles: Arc::new(quad()),
+        material: material(),
+        alpha_mask: None,
+    });
+    for index in 0..2 {
+        let triangle = Primitive::new_mesh_triangle(Arc::clone(&mesh), index);
+        let [v0, v1, v2] = mesh.triangles.triangle_vertices(index);
+        let shape = Primitive::new(
+            Arc::new(Shape::new_triangle(v0, v1, v2).unwrap()),
+            material(),
+        );
+        assert_eq!(triangle.bounds(), shape.bounds());
+
+        for (x, y) in [(0.25, 0.5), (0.75, 0.5), (0.5, 1.5)] {
+            let ray = Ray::new(Point(x, y, -1.0), Z);
+            assert_eq!(triangle.intersects(&ray), shape.intersects(&ray));
+            let hit = triangle.intersect(&mut Ray::new(ray.origin, ray.direction));
+            let expected = shape.intersect(&mut Ray::new(ray.origin, ray.direction));
+            assert_eq!(
+                hit.map(|hit| hit.location),
+                expected.map(|expected| expected.location)
+            );
+        }
+    }
+}
+
+#[test]
+fn transform() {
+    let mesh = Transformation::translate(1.0, 2.0, 3.0).transform(&quad());
+    assert_eq!(
+        mesh.triangle_vertices(0),
+        [p!(1, 2, 3), p!(1, 3, 3), p!(2, 3, 3)]
+    );
+    assert_eq!(mesh.normals, vec![v!(0, 0, -1); 4]);
+    assert_eq!(mesh.uvs, quad().uvs);
+}
+
+#[test]
+fn loaded_meshes_share_buffers() {
+    let primitives = load_obj("objs/triangle.obj", material(), &Transformation::I);
+    assert_eq!(primitives.len(), 1);
+    match &*primitives[0] {
+        Primitive::MeshTriangle { mesh, index } => {
+            assert_eq!(*index, 0);
+            // The OBJ file is right handed, so z is flipped
+            assert_eq!(
+                mesh.triangles.vertices,
+                vec![p!(1, 0, 0), p!(0, 1, 0), p!(0, 0, -1)]
+            );
+            assert!(mesh.triangles.tangents.is_empty());
+        }
+        primitive => panic!("Expected a mesh triangle, found {:?}", primitive),
+    }
+}
+
+#[test]
+fn cached_obj() {
+    let directory = std::env::temp_dir().join(format!("craytracer-test-{}", std::process::id()));
+    let cache = Cache::new(&directory);
+
+    // The first load writes the entry and the second reads it back
+    let vertices = |primitives: Vec<Arc<Primitive>>| match &*primitives[0] {
+        Primitive::MeshTriangle { mesh, .. } => mesh.triangles.vertices.clone(),
+        primitive => panic!("Expected a mesh triangle, found {:?}", primitive),
+    };
+    let transformation = Transformation::translate(0.0, 0.0, 1.0);
+    for _ in 0..2 {
+        let primitives = load_obj_with_cache(
+            "objs/triangle.obj",
+            material(),
+            &transformation,
+            Some(&cache),
+        );
+        assert_eq!(primitives.len(), 1);
+        assert_eq!(
+            vertices(primitives),
+            vec![p!(1, 0, 1), p!(0, 1, 1), p!(0, 0, 0)]
+        );
+    }
+    assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn deforming() {
+    // The quad moves 2 along x and then 2 along y, and is hit where its
+    // vertices are at the ray's time
+    let moved = |offset: Vector| quad().vertices.iter().map(|&v| v + offset).collect();
+    let mesh = quad().with_motion(VertexMotion {
+        times: vec![0.0, 1.0, 2.0],
+        vertices: vec![moved(v!(2, 0, 0)), moved(v!(2, 2, 0))],
+        normals: vec![vec![v!(0, 0, -1); 4], vec![v!(0, 0, -1); 4]],
+    });
+    for (x, y, time, hit) in [
+        (0.5_f64, 0.5_f64, 0.0, true),
+        (0.5, 0.5, 1.0, false),
+        (1.5, 0.5, 0.5, true),
+        (2.5, 0.5, 1.0, true),
+        (2.5, 1.5, 1.5, true),
+        (2.5, 2.5, 2.0, true),
+        (2.5, 2.5, 1.0, false),
+        // The first and last steps are held outside of the motion
+        (0.5, 0.5, -1.0, true),
+        (2.5, 2.5, 3.0, true),
+    ] {
+        let ray = Ray::new(p!(x, y, -1), Z).with_time(time);
+        let index = if x - x.floor() < y - y.floor() { 0 } else { 1 };
+        assert_eq!(mesh.intersects(index, &ray), hit, "{} {} {}", x, y, time);
+        let intersection = mesh.intersect(index, &mut Ray::new(p!(x, y, -1), Z).with_time(time));
+        assert_eq!(intersection.map(|i| i.location), hit.then(|| p!(x, y, 0)));
+    }
+    assert_eq!(mesh.bounds(0), Bounds::new(p!(0, 0, 0), p!(3, 3, 0)));
+}
+
+#[test]
+fn deforming_obj() {
+    let primitives = load_deforming_obj(
+        "objs/triangle.obj",
+        0.0,
+        &[(1.0, "objs/triangle_moved.obj".to_string())],
+        material(),
+        &Transformation::I,
+        None,
+    )
+    .unwrap();
+    assert_eq!(primitives.len(), 1);
+    assert_eq!(
+        primitives[0].bounds(),
+        Bounds::new(p!(0, 0, -1), p!(3, 1, 0))
+    );
+    let hit = |time: f64| {
+        primitives[0]
+            .intersect(&mut Ray::new(p!(2.1, 0.1, -5), Z).with_time(time))
+            .is_some()
+    };
+    assert!(!hit(0.0));
+    assert!(hit(1.0));
+
+    // The steps must come later and have the same triangles
+    for (steps, message) in [
+        (
+            vec![(0.0, "objs/triangle_moved.obj".to_string())],
+            "must increase",
+        ),
+        (
+            vec![(1.0, "objs/anthropic.obj".to_string())],
+            "different triangles",
+        ),
+    ] {
+        let error = load_deforming_obj(
+            "objs/triangle.obj",
+            0.0,
+            &steps,
+            material(),
+            &Transformation::I,
+            None,
+        )
+        .unwrap_err();
+        assert!(error.contains(message), "{}", error);
+    }
+}
@@ -364,10 +364,16 @@ mod parser {
     use pretty_assertions::assert_eq;
     use std::collections::HashMap;
 
+    use approx::assert_abs_diff_eq;
     use craytracer::{
         color::Color,
-        geometry::O,
-        scene_parser::scene_parser::parse_scene,
+        geometry::{point::Point, vector::Vector, O, X, Z},
+        material::Material,
+        p,
+        ray::Ray,
+        sampling::samplers::{IndependentSampler, Sample2d, Sampler},
+        scene::Scene,
+        scene_parser::scene_parser::{parse_scene, parse_scene_frame},
         scene_parser::tokenizer::tokenize,
         scene_parser::{
             parser::{RawValue, RawValueArray, RawValueMap, TypedRawValueMap},
@@ -594,6 +600,8 @@ mod parser {
     // Comment
     max_depth: 3,
     num_samples: 1,
+    bvh_split_method: 'lbvh',
+    bvh_layout: 'binary',
     camera: Perspective {
         origin: Point(0, 0, 0),
         target: Point(0, 0, 1),
@@ -612,6 +620,19 @@ mod parser {
             intensity: Color(1, 1, 1)
         }
     ],
+    textures: {
+        grain: Wood { a: 0.2, b: 0.8, scale: 5 },
+        planks: Mix {
+            a: Color(0.5, 0.3, 0.1),
+            b: Color(0.3, 0.2, 0.1),
+            amount: UvTransform { texture: 'grain', scale_u: 2, rotate: 45 },
+        },
+        heat: Ramp {
+            factor: Invert { texture: 'grain' },
+            positions: [0, 0.5, 1],
+            values: [Color(0, 0, 1), Color(1, 1, 1), Color(1, 0, 0)],
+        },
+    },
     materials: {
         matte: Matte {
             reflectance: Color(1, 1, 1),
@@ -622,15 +643,63 @@ mod parser {
             reflectance: Checkerboard { a: Color(1, 1, 1), b: Color(0, 0, 0), scale: 2.5 },
             sigma: Checkerboard { a: 0, b: 1 }
         },
+        marble: Matte {
+            reflectance: Marble { a: Color(1, 1, 1), b: Color(0.2, 0.2, 0.3), scale: 4, variation: 0.5 },
+            sigma: Fbm { a: 0, b: 20, mapping: 'uv', octaves: 4 }
+        },
+        // Named textures and texture nodes
+        wood: Plastic {
+            diffuse: Multiply { a: 'planks', b: 'heat' },
+            specular: Scale { texture: Color(1, 1, 1), scale: 'grain' },
+            roughness: 'grain'
+        },
+        // Shading normal perturbation
+        bumpy: Matte {
+            reflectance: Color(1, 1, 1),
+            sigma: 0,
+            bump_map: Fbm { a: 0, b: 0.05, octaves: 4 }
+        },
+        dented: Metal {
+            eta: Color(0.2, 0.9, 1.1),
+            k: Color(3.9, 2.4, 2.2),
+            normal_map: Checkerboard { a: Color(0.5, 0.5, 1), b: Color(0.6, 0.5, 0.9) }
+        },
+        // Mix and layered materials
+        worn: Mix { a: 'dented', b: 'matte', amount: 'grain' },
+        varnished: Coated { base: 'wood', thickness: 0.1, albedo: Color(0.8, 0.6, 0.4) },
+        paint: Coated {
+            base: Matte { reflectance: Color(0.6, 0.05, 0.05), sigma: 0 },
+            eta: 1.6,
+            max_depth: 5,
+            num_samples: 2,
+        },
+        // Subsurface scattering
+        wax: Subsurface { albedo: Color(0.9, 0.8, 0.6), mean_free_path: Color(0.5, 0.3, 0.2) },
+        milk: Subsurface { albedo: 'heat', mean_free_path: Color(1, 1, 1), eta: 1.35 },
+        // Thin surfaces
+        pane: ThinGlass { reflectance: Color(1, 1, 1), transmittance: Color(1, 1, 1), eta: 1.5 },
+        leaf: Translucent { reflectance: Color(0.1, 0.3, 0.05), transmittance: Color(0.2, 0.5, 0.1) },
     },
     shapes: {
         ball: Sphere {
             origin: Point(0, 0, 2),
             radius: 1
-        }
+        },
+        // Quadrics
+        tube: Cylinder { origin: Point(0, 0, 4), rotate_x: -90, radius: 1, z_min: -1, z_max: 1, phi_max: 270 },
+        funnel: Cone { origin: Point(0, 0, 4), radius: 1, height: 2 },
+        bowl: Paraboloid { origin: Point(0, 0, 4), rotate_y: 30, radius: 1, z_max: 1 },
+        tower: Hyperboloid { origin: Point(0, 0, 4), p1: Point(1, 0, -1), p2: Point(0, 1, 1) },
     },
     primitives: [
        Shape { shape: 'ball', material: 'matte' },
+       Shape { shape: 'tube', material: 'matte' },
+       Shape { shape: 'funnel', material: 'matte' },
+       Shape { shape: 'bowl', material: 'matte' },
+       Shape { shape: 'tower', emittance: Color(1, 1, 1) },
+       // Alpha masks
+       Shape { shape: 'ball', material: 'wood', alpha: 'grain', alpha_threshold: 0.3 },
+       Shape { shape: 'ball', material: 'bumpy', alpha: Checkerboard { a: 0, b: 1 }, alpha_mode: 'stochastic' },
        Mesh { file_name: 'objs/triangle.obj', fallback_material: 'checks' },
     ]
 }
@@ -638,4 +707,584 @@ mod parser {
         )
         .unwrap();
     }
+
+    #[test]
+    fn transforms() {
+        let input = |shape_transform: &str| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    materials: {{ matte: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }} }},
+    shapes: {{
+        ball: Sphere {{ radius: 1, transform: {} }},
+        pole: Cylinder {{
+            radius: 1,
+            z_min: 0,
+            z_max: 1,
+            transform: [
+                LookAt {{ origin: Point(0, -5, 0), target: Point(0, -6, 0), up: Vector(0, 0, 1) }},
+                Matrix {{ m: [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 30, 0, 0, 0, 1] }},
+            ],
+        }},
+    }},
+    primitives: [
+        Shape {{ shape: 'ball', material: 'matte' }},
+        Shape {{ shape: 'ball', material: 'matte', transform: Translate {{ y: 10 }} }},
+        Shape {{ shape: 'pole', material: 'matte' }},
+        Mesh {{
+            file_name: 'objs/triangle.obj',
+            fallback_material: 'matte',
+            transform: [Rotate {{ axis: Vector(0, 0, 1), angle: 90 }}, Translate {{ z: 20 }}],
+        }},
+    ],
+}}
+",
+                shape_transform
+            )
+        };
+        let hit = |scene: &Scene, origin: Point, direction: Vector| {
+            scene
+                .intersect(&mut Ray::new(origin, direction))
+                .unwrap()
+                .location
+        };
+
+        // Transforms in a list are applied in order, so the ball is stretched
+        // to a radius of 2 before being moved
+        let scene = parse_scene(&input("[Scale { x: 2 }, Translate { x: 5 }]")).unwrap();
+        assert_abs_diff_eq!(hit(&scene, O, X), p!(3, 0, 0), epsilon = 1e-9);
+        // Primitives can move a copy of the shape
+        assert_abs_diff_eq!(hit(&scene, p!(0, 10, 0), X), p!(3, 10, 0), epsilon = 1e-9);
+        // The cylinder's axis points at -y before it is moved up along z
+        assert_abs_diff_eq!(
+            hit(&scene, p!(0, -5.5, 30), X),
+            p!(1, -5.5, 30),
+            epsilon = 1e-9
+        );
+        // The triangle's vertices are rotated to (0, 1, 20), (-1, 0, 20) and
+        // (0, 0, 19)
+        assert_abs_diff_eq!(
+            hit(&scene, p!(-1.0 / 3.0, 1.0 / 3.0, 0), Z),
+            p!(-1.0 / 3.0, 1.0 / 3.0, 59.0 / 3.0),
+            epsilon = 1e-9
+        );
+
+        for (transform, message) in [
+            ("Shear { x: 1 }", "Unknown transform type: Shear"),
+            ("Scale { x: 0 }", "Scale transform is not invertible"),
+            (
+                "Rotate { axis: Vector(0, 0, 0), angle: 30 }",
+                "Rotation axis must not be zero",
+            ),
+            ("Matrix { m: [1, 0, 0] }", "Matrix needs 16 values, found 3"),
+            (
+                "Matrix { m: [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] }",
+                "Matrix transform is not invertible",
+            ),
+            (
+                "[Translate { x: 1 }, LookAt { origin: Point(0, 0, 0), target: Point(0, 0, 0), up: Vector(0, 1, 0) }]",
+                "LookAt transform is not invertible",
+            ),
+        ] {
+            let error = parse_scene(&input(transform)).unwrap_err();
+            assert!(error.message.contains(message), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn instances() {
+        let input = |objects: &str| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    materials: {{
+        matte: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }},
+        metal: Metal {{ eta: Color(0.2, 0.9, 1.1), k: Color(3.9, 2.4, 2.2) }},
+    }},
+    shapes: {{ ball: Sphere {{ radius: 1 }} }},
+    objects: {{ {} }},
+    primitives: [
+        Instance {{ object: 'pair', transform: Translate {{ z: 10 }} }},
+        Instance {{
+            object: 'pair',
+            transform: [Scale {{ x: 2, y: 2, z: 2 }}, Translate {{ z: 20 }}],
+            material: 'metal',
+        }},
+    ],
+}}
+",
+                objects
+            )
+        };
+        let hit = |scene: &Scene, origin: Point| {
+            let intersection = scene.intersect(&mut Ray::new(origin, Z)).unwrap();
+            (
+                intersection.location,
+                Vector::from(intersection.normal),
+                matches!(intersection.material, Material::BSDF(_)),
+            )
+        };
+
+        // Objects can instance other objects, and each instance is moved by
+        // its own transform
+        let scene = parse_scene(&input(
+            "
+            ball: Shape { shape: 'ball', material: 'matte' },
+            pair: Group {
+                primitives: [
+                    Instance { object: 'ball' },
+                    Instance { object: 'ball', transform: Translate { x: 3 } },
+                ],
+            },
+            ",
+        ))
+        .unwrap();
+        let (location, normal, is_metal) = hit(&scene, O);
+        assert_abs_diff_eq!(location, p!(0, 0, 9), epsilon = 1e-9);
+        assert_abs_diff_eq!(normal, v!(0, 0, -1), epsilon = 1e-9);
+        assert!(!is_metal);
+        let (location, _, _) = hit(&scene, p!(3, 0, 0));
+        assert_abs_diff_eq!(location, p!(3, 0, 9), epsilon = 1e-9);
+        // The scaled instance uses its material override
+        let (location, normal, is_metal) = hit(&scene, p!(6, 0, 0));
+        assert_abs_diff_eq!(location, p!(6, 0, 18), epsilon = 1e-9);
+        assert_abs_diff_eq!(normal, v!(0, 0, -1), epsilon = 1e-9);
+        assert!(is_metal);
+        assert!(scene.intersects(&Ray::new(p!(6, 0, 0), Z)));
+        assert!(!scene.intersects(&Ray::new(p!(9, 0, 0), Z)));
+
+        for (objects, message) in [
+            (
+                "pair: Instance { object: 'pair' }",
+                "Cannot find object named 'pair'",
+            ),
+            (
+                "pair: Shape { shape: 'ball', emittance: Color(1, 1, 1) }",
+                "Object 'pair' has emissive primitives",
+            ),
+            (
+                "pair: Group { primitives: [] }",
+                "Object 'pair' has no primitives",
+            ),
+        ] {
+            let error = parse_scene(&input(objects)).unwrap_err();
+            assert!(error.message.contains(message), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let input = |x: f64| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    materials: {{ matte: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }} }},
+    shapes: {{ ball: Sphere {{ radius: 1 }} }},
+    objects: {{
+        pair: Group {{
+            primitives: [
+                Shape {{ shape: 'ball', material: 'matte' }},
+                Shape {{ shape: 'ball', material: 'matte', transform: Translate {{ x: {} }} }},
+            ],
+        }},
+    }},
+    primitives: [ Instance {{ object: 'pair', transform: Translate {{ z: 10 }} }} ],
+}}
+",
+                x
+            )
+        };
+        let hit = |scene: &Scene, x: f64| scene.intersects(&Ray::new(p!(x, 0, 0), Z));
+
+        let scene = parse_scene(&input(3.0)).unwrap();
+        assert!(hit(&scene, 0.0) && hit(&scene, 3.0) && !hit(&scene, -3.0));
+        assert_eq!(scene.objects.len(), 1);
+
+        // The object's BVH is refit to where its primitives have moved
+        let scene = parse_scene_frame(&input(-3.0), scene).unwrap();
+        assert!(hit(&scene, 0.0) && !hit(&scene, 3.0) && hit(&scene, -3.0));
+        let scene = parse_scene_frame(&input(30.0), scene).unwrap();
+        assert!(hit(&scene, 0.0) && hit(&scene, 30.0) && !hit(&scene, -3.0));
+        assert_eq!(scene.objects.len(), 1);
+    }
+
+    #[test]
+    fn motion_blur() {
+        let input = |transform: &str, emittance: &str| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+        shutter_open: 0,
+        shutter_close: 1,
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    materials: {{ matte: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }} }},
+    shapes: {{ ball: Sphere {{ radius: 1 }} }},
+    primitives: [
+        Shape {{ shape: 'ball', material: 'matte', {} transform: {} }},
+    ],
+}}
+",
+                emittance, transform
+            )
+        };
+        let hit_at = |scene: &Scene, x: f64, time: f64| {
+            let ray = &mut Ray::new(p!(x, 0, 0), Z).with_time(time);
+            scene
+                .intersect(ray)
+                .map(|intersection| intersection.location)
+        };
+
+        // The ball moves from x = -4 to x = 4 while the shutter is open
+        let scene = parse_scene(&input(
+            "Animated {
+                keyframes: [
+                    Keyframe { time: 0, transform: Translate { x: -4, z: 10 } },
+                    Keyframe { time: 1, transform: Translate { x: 4, z: 10 } },
+                ],
+            },",
+            "",
+        ))
+        .unwrap();
+        assert_abs_diff_eq!(
+            hit_at(&scene, -4.0, 0.0).unwrap(),
+            p!(-4, 0, 9),
+            epsilon = 1e-9
+        );
+        assert_abs_diff_eq!(
+            hit_at(&scene, 2.0, 0.75).unwrap(),
+            p!(2, 0, 9),
+            epsilon = 1e-9
+        );
+        assert_eq!(hit_at(&scene, 2.0, 0.0), None);
+        assert!(scene.intersects(&Ray::new(p!(0, 0, 0), Z).with_time(0.5)));
+        assert!(!scene.intersects(&Ray::new(p!(0, 0, 0), Z).with_time(1.0)));
+
+        // Camera rays are spread over the shutter interval
+        let mut sampler = IndependentSampler::new(0, 16);
+        let times: Vec<f64> = (0..16)
+            .map(|i| {
+                sampler.start_pixel(0, 0, i);
+                scene
+                    .camera
+                    .sample(
+                        (
+                            sampler.sample_2d(),
+                            sampler.sample_2d(),
+                            sampler.sample_1d(),
+                        ),
+                        0,
+                        0,
+                    )
+                    .time
+            })
+            .collect();
+        assert!(times.iter().all(|&time| (0.0..=1.0).contains(&time)));
+        assert!(times.iter().any(|&time| time < 0.5) && times.iter().any(|&time| time > 0.5));
+
+        let error = parse_scene(&input(
+            "Animated {
+                keyframes: [
+                    Keyframe { time: 0, transform: Translate { x: -4 } },
+                    Keyframe { time: 1, transform: Translate { x: 4 } },
+                ],
+            },",
+            "emittance: Color(1, 1, 1),",
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .message
+                .contains("Emissive primitives cannot be animated"),
+            "{}",
+            error.message
+        );
+        let error = parse_scene(&input("Animated { keyframes: [] },", "")).unwrap_err();
+        assert!(
+            error.message.contains("at least one keyframe"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn deforming_mesh() {
+        let input = |motion_steps: &str| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+        shutter_open: 0,
+        shutter_close: 1,
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    materials: {{ matte: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }} }},
+    shapes: {{}},
+    primitives: [
+        Mesh {{
+            file_name: 'objs/triangle.obj',
+            fallback_material: 'matte',
+            motion_steps: [{}],
+        }},
+    ],
+}}
+",
+                motion_steps
+            )
+        };
+        let hit = |scene: &Scene, time: f64| {
+            scene.intersects(&Ray::new(p!(2.1, 0.1, -5), Z).with_time(time))
+        };
+
+        // The triangle moves 2 along x while the shutter is open
+        let scene = parse_scene(&input(
+            "MotionStep { time: 1, file_name: 'objs/triangle_moved.obj' }",
+        ))
+        .unwrap();
+        assert!(!hit(&scene, 0.0));
+        assert!(hit(&scene, 1.0));
+
+        let error = parse_scene(&input(
+            "Keyframe { time: 1, file_name: 'objs/triangle_moved.obj' }",
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .message
+                .contains("Expected MotionStep, found Keyframe"),
+            "{}",
+            error.message
+        );
+        let error = parse_scene(&input(
+            "MotionStep { time: 1, file_name: 'objs/anthropic.obj' }",
+        ))
+        .unwrap_err();
+        assert!(
+            error.message.contains("different triangles"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn thin_lens() {
+        let input = |lens: &str| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 4 }},
+        {}
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    textures: {{ slit: Checkerboard {{ a: 0, b: 1, scale: 1 }} }},
+    materials: {{ matte: Matte {{ reflectance: Color(1, 1, 1), sigma: 0 }} }},
+    shapes: {{ ball: Sphere {{ radius: 1 }} }},
+    primitives: [
+        Shape {{ shape: 'ball', material: 'matte', transform: Translate {{ z: 10 }} }},
+    ],
+}}
+",
+                lens
+            )
+        };
+        // Returns how far rays through the center of the film spread on the
+        // lens, and where they cross the plane at z
+        let rays = |scene: &Scene, z: f64| {
+            let mut sampler = IndependentSampler::new(0, 64);
+            let mut spread: f64 = 0.0;
+            let mut crossings = Vec::new();
+            for i in 0..64 {
+                sampler.start_pixel(2, 2, i);
+                let ray = scene.camera.sample(
+                    (
+                        Sample2d::new(0.5, 0.5),
+                        sampler.sample_2d(),
+                        sampler.sample_1d(),
+                    ),
+                    2,
+                    2,
+                );
+                spread = spread.max((ray.origin - O).magnitude());
+                crossings.push(ray.at((z - ray.origin.z()) / ray.direction.z()));
+            }
+            (spread, crossings)
+        };
+
+        // A 0.4 focal length at f/2 makes a lens with radius 0.1, focused on
+        // the front of the ball
+        let scene = parse_scene(&input(
+            "f_stop: 2, focal_length: 0.4, focus: Pixel { x: 2, y: 2 },",
+        ))
+        .unwrap();
+        let (spread, crossings) = rays(&scene, 9.0);
+        assert!(spread > 0.09 && spread <= 0.1, "{}", spread);
+        for crossing in &crossings {
+            assert_abs_diff_eq!(*crossing, p!(0, 0, 9), epsilon = 1e-9);
+        }
+
+        // Textured apertures cover the square around the lens
+        for (lens, max_spread) in [
+            (
+                "lens_radius: 1, focus: Point(5, 5, 20), aperture: Polygon { sides: 5 },",
+                1.0,
+            ),
+            (
+                "lens_radius: 1, focus: Point(0, 0, 20), aperture: 'slit',",
+                2f64.sqrt(),
+            ),
+        ] {
+            let scene = parse_scene(&input(lens)).unwrap();
+            let (spread, crossings) = rays(&scene, 20.0);
+            assert!(spread > 0.5 && spread <= max_spread, "{}", spread);
+            for crossing in &crossings {
+                assert_abs_diff_eq!(*crossing, crossings[0], epsilon = 1e-9);
+            }
+        }
+
+        for (lens, message) in [
+            (
+                "lens_radius: 1, f_stop: 2, focal_length: 0.4,",
+                "both lens_radius and f_stop",
+            ),
+            ("f_stop: 2,", "focal_length not found"),
+            (
+                "focal_distance: 10, focus: 'target',",
+                "both focus and focal_distance",
+            ),
+            ("focus: 'ball',", "must be a Point, 'target' or a Pixel"),
+            ("aperture: Polygon { sides: 2 },", "needs at least 3 sides"),
+            ("aperture: 0,", "zero everywhere"),
+            (
+                "aperture: 'missing',",
+                "Cannot find texture named 'missing'",
+            ),
+        ] {
+            let error = parse_scene(&input(lens)).unwrap_err();
+            assert!(error.message.contains(message), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn named_texture_errors() {
+        let scene = |textures: &str, reflectance: &str| {
+            format!(
+                "
+{{
+    camera: Perspective {{
+        origin: Point(0, 0, 0),
+        target: Point(0, 0, 1),
+        up: Vector(0, 1, 0),
+        fov: 60,
+        film: {{ width: 4, height: 3 }},
+    }},
+    lights: [ Point {{ origin: Point(0, 0, 0), intensity: Color(1, 1, 1) }} ],
+    textures: {{ {} }},
+    materials: {{ matte: Matte {{ reflectance: {}, sigma: 0 }} }},
+    shapes: {{ ball: Sphere {{ origin: Point(0, 0, 2), radius: 1 }} }},
+    primitives: [ Shape {{ shape: 'ball', material: 'matte' }} ],
+}}
+",
+                textures, reflectance
+            )
+        };
+
+        parse_scene(&scene(
+            "a: Invert { texture: 'b' }, b: Noise { a: Color(0, 0, 0), b: Color(1, 1, 1) }",
+            "'a'",
+        ))
+        .expect("Textures should be able to reference each other");
+
+        let error = parse_scene(&scene("", "'missing'")).unwrap_err();
+        assert!(
+            error
+                .message
+                .contains("Cannot find texture named 'missing'"),
+            "{}",
+            error.message
+        );
+
+        let error = parse_scene(&scene("a: Invert { texture: 'a' }", "'a'")).unwrap_err();
+        assert!(
+            error.message.contains("Cannot find texture named 'a'"),
+            "{}",
+            error.message
+        );
+
+        let error = parse_scene(&scene(
+            "",
+            "Color(1, 1, 1), sigma: 0 }, mix: Mix { a: 'mix', b: 'matte', amount: 0.5",
+        ))
+        .unwrap_err();
+        assert!(
+            error.message.contains("Cannot find material named 'mix'"),
+            "{}",
+            error.message
+        );
+
+        let error = parse_scene(&scene(
+            "",
+            "Color(1, 1, 1), sigma: 0 }, measured: Measured { file_name: 'missing.binary'",
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .message
+                .contains("Error loading measured BRDF 'missing.binary'"),
+            "{}",
+            error.message
+        );
+
+        let error = parse_scene(&scene(
+            "",
+            "Color(1, 1, 1), normal_map: Color(0.5, 0.5, 1), bump_map: 0",
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .message
+                .contains("cannot have both a normal_map and a bump_map"),
+            "{}",
+            error.message
+        );
+    }
 }
@@ -0,0 +1,186 @@
+mod reference_images {
+    use std::{env, fs, path::PathBuf};
+
+    use craytracer::{
+        render::render_image, sampling::samplers::SobolSampler,
+        scene_parser::scene_parser::parse_scene,
+    };
+    use image::Rgb32FImage;
+
+    /// Width of the rendered images, with the height following the aspect
+    /// ratio of the scene's film
+    const WIDTH: usize = 96;
+    const NUM_SAMPLES: usize = 64;
+    const SEED: usize = 0;
+
+    /// Renders are compared after averaging blocks of this many pixels in
+    /// each dimension, so that small changes to how samples are used, which
+    /// only move noise around, don't fail the tests
+    const BLOCK_SIZE: usize = 4;
+
+    const MAX_BRIGHTNESS_DIFFERENCE: f64 = 0.03;
+
+    /// Set to re-render the reference images, after checking that a change to
+    /// the renders is intended
+    const UPDATE_REFERENCES: &str = "CRAYTRACER_UPDATE_REFERENCES";
+
+    struct Image {
+        width: usize,
+        height: usize,
+        pixels: Vec<f32>,
+    }
+
+    impl Image {
+        fn load(path: &PathBuf) -> Image {
+            let image = image::open(path)
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "Error reading {:?}: {}. Set {}=1 to create it.",
+                        path, e, UPDATE_REFERENCES
+                    )
+                })
+                .into_rgb32f();
+            Image {
+                width: image.width() as usize,
+                height: image.height() as usize,
+                pixels: image.into_raw(),
+            }
+        }
+
+        fn save(&self, path: &PathBuf) {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            Rgb32FImage::from_raw(self.width as u32, self.height as u32, self.pixels.clone())
+                .unwrap()
+                .save(path)
+                .unwrap();
+        }
+
+        fn downsample(&self) -> Image {
+            let (width, height) = (self.width / BLOCK_SIZE, self.height / BLOCK_SIZE);
+            let mut pixels = vec![0.0; width * height * 3];
+            for y in 0..height * BLOCK_SIZE {
+                for x in 0..width * BLOCK_SIZE {
+                    for c in 0..3 {
+                        pixels[((x / BLOCK_SIZE) + (y / BLOCK_SIZE) * width) * 3 + c] += self
+                            .pixels[(x + y * self.width) * 3 + c]
+                            / (BLOCK_SIZE * BLOCK_SIZE) as f32;
+                    }
+                }
+            }
+            Image {
+                width,
+                height,
+                pixels,
+            }
+        }
+    }
+
+    /// Mean squared error relative to the reference's brightness, which
+    /// weighs errors in dark regions as much as those in bright ones
+    ///
+    /// Source: https://www.pbr-book.org/4ed/Monte_Carlo_Integration/Improving_Efficiency
+    fn relative_mse(image: &Image, reference: &Image) -> f64 {
+        let (image, reference) = (image.downsample(), reference.downsample());
+        let sum: f64 = image
+            .pixels
+            .iter()
+            .zip(&reference.pixels)
+            .map(|(&a, &b)| {
+                let (a, b) = (a as f64, b as f64);
+                (a - b).powi(2) / (b * b + 1e-2)
+            })
+            .sum();
+        sum / image.pixels.len() as f64
+    }
+
+    /// Relative difference between the average brightness of two images,
+    /// which catches a small bias across the whole image that is lost in the
+    /// noise of individual pixels
+    fn brightness_difference(image: &Image, reference: &Image) -> f64 {
+        let sum = |image: &Image| image.pixels.iter().map(|&p| p as f64).sum::<f64>();
+        (sum(image) / sum(reference) - 1.0).abs()
+    }
+
+    fn render(name: &str, seed: usize) -> Image {
+        let input = fs::read_to_string(format!("scenes/{}.cry", name)).unwrap();
+        let mut scene = parse_scene(&input).unwrap_or_else(|e| panic!("{:?}", e));
+        let (width, height) = scene.film_bounds();
+        let height = (WIDTH * height + width / 2) / width;
+        scene.camera.set_resolution(WIDTH, height);
+        Image {
+            width: WIDTH,
+            height,
+            pixels: render_image(&scene, SobolSampler::new(seed, NUM_SAMPLES)),
+        }
+    }
+
+    /// Renders a scene and compares it to its reference image. If they differ
+    /// by more than `tolerance`, the render and its difference from the
+    /// reference are written to the target directory.
+    fn assert_matches_reference(name: &str, tolerance: f64) {
+        let reference_path = PathBuf::from(format!("tests/references/{}.exr", name));
+        let image = render(name, SEED);
+        if env::var_os(UPDATE_REFERENCES).is_some() {
+            image.save(&reference_path);
+            return;
+        }
+
+        let reference = Image::load(&reference_path);
+        assert_eq!(
+            (image.width, image.height),
+            (reference.width, reference.height)
+        );
+        let error = relative_mse(&image, &reference);
+        let brightness_error = brightness_difference(&image, &reference);
+        if error > tolerance || brightness_error > MAX_BRIGHTNESS_DIFFERENCE {
+            // Write the render and the absolute difference next to each other
+            // for inspection
+            let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("references");
+            image.save(&output.join(format!("{}.exr", name)));
+            Image {
+                pixels: image
+                    .pixels
+                    .iter()
+                    .zip(&reference.pixels)
+                    .map(|(a, b)| (a - b).abs())
+                    .collect(),
+                ..image
+            }
+            .save(&output.join(format!("{}-diff.exr", name)));
+            panic!(
+                "Render of {} differs from the reference with a relative MSE of {} (tolerance {}) \
+                 and a brightness difference of {} (tolerance {}). The render and difference \
+                 were written to {:?}.",
+                name, error, tolerance, brightness_error, MAX_BRIGHTNESS_DIFFERENCE, output
+            );
+        }
+    }
+
+    // Tolerances are a few times the error between renders with different
+    // seeds. Scenes with caustics are noisier and need looser tolerances.
+
+    #[test]
+    fn simple() {
+        assert_matches_reference("simple", 0.025);
+    }
+
+    #[test]
+    fn test() {
+        assert_matches_reference("test", 5e-4);
+    }
+
+    #[test]
+    fn rounding_error() {
+        assert_matches_reference("rounding-error", 1e-4);
+    }
+
+    #[test]
+    fn materials() {
+        assert_matches_reference("materials", 6e-3);
+    }
+
+    #[test]
+    fn anthropic() {
+        assert_matches_reference("anthropic", 2e-3);
+    }
+}
@@ -137,9 +137,9 @@ mod triangle {
     fn intersect_vertices() {
         // Shoot ray to hit v0
         let t = triangle();
-        match triangle() {
-            Shape::Triangle { v0, e1, e2, .. } => {
-                for point in [v0, v0 + e1, v0 + e2] {
+        match &t {
+            Shape::Triangle { mesh, index } => {
+                for point in mesh.triangle_vertices(*index) {
                     let ray = &mut Ray::new(Point(point.x(), point.y(), -2.0), Z);
                     let intersection = t.intersect(ray).unwrap();
                     assert_eq!(ray.max_distance, 2.0);
@@ -171,8 +171,10 @@ mod triangle {
     fn random_point() {
         let t = triangle();
 
-        match triangle() {
-            Shape::Triangle { v0, e1, e2, .. } => {
+        match &t {
+            Shape::Triangle { mesh, index } => {
+                let [v0, v1, v2] = mesh.triangle_vertices(*index);
+                let (e1, e2) = (v1 - v0, v2 - v0);
                 let mut rng = thread_rng();
                 let u = rng.gen_range(0.0..1.0);
                 let v = rng.gen_range(0.0..1.0);
@@ -198,3 +200,385 @@ mod triangle {
         }
     }
 }
+
+mod quadrics {
+    use std::{f64::consts::PI, sync::Arc};
+
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        color::Color,
+        geometry::{point::Point, traits::DotProduct},
+        intersection::{PrimitiveIntersection, ShapeIntersection},
+        material::Material,
+        p,
+        pdf::Pdf,
+        primitive::Primitive,
+        ray::Ray,
+        sampling::{samplers::Sample2d, sampling_fns::sample_sphere},
+        shape::Shape,
+        texture::Texture,
+        v,
+    };
+
+    /// Quadrics centered on `origin`, with the points on their axes from which
+    /// their whole surface is visible
+    fn quadrics(origin: Point, rotate_x: f64) -> Vec<(&'static str, Shape, Point)> {
+        let axis = |z: f64| {
+            let rotated = v!(0, -rotate_x.to_radians().sin(), rotate_x.to_radians().cos());
+            origin + rotated * z
+        };
+        vec![
+            (
+                "cylinder",
+                Shape::new_cylinder(origin, rotate_x, 0.0, 1.0, -1.0, 1.0, 360.0),
+                axis(0.0),
+            ),
+            (
+                "partial cylinder",
+                Shape::new_cylinder(origin, rotate_x, 0.0, 2.0, 0.5, 1.5, 270.0),
+                axis(1.0),
+            ),
+            (
+                "cone",
+                Shape::new_cone(origin, rotate_x, 0.0, 1.5, 2.0, 360.0),
+                axis(0.5),
+            ),
+            (
+                "paraboloid",
+                Shape::new_paraboloid(origin, rotate_x, 0.0, 1.0, 0.25, 2.0, 360.0),
+                axis(1.0),
+            ),
+            (
+                "hyperboloid",
+                Shape::new_hyperboloid(origin, rotate_x, 0.0, p!(1, 0, -1), p!(0, 1, 1), 360.0)
+                    .unwrap(),
+                axis(0.0),
+            ),
+            (
+                "partial hyperboloid",
+                Shape::new_hyperboloid(origin, rotate_x, 0.0, p!(1, 1, -1), p!(2, 0, 1), 200.0)
+                    .unwrap(),
+                axis(0.0),
+            ),
+        ]
+    }
+
+    pub(super) fn grid(n: usize) -> impl Iterator<Item = (f64, f64)> {
+        (0..n * n).map(move |i| {
+            (
+                ((i % n) as f64 + 0.5) / n as f64,
+                ((i / n) as f64 + 0.5) / n as f64,
+            )
+        })
+    }
+
+    /// Intersects the shape from a point on its axis through a point sampled
+    /// on its surface
+    fn intersect_sample(shape: &Shape, center: Point, (u, v): (f64, f64)) -> ShapeIntersection {
+        let point = shape.sample(Sample2d::new(u, v));
+        let mut ray = Ray::new(center, (point - center).normalized());
+        let intersection = shape
+            .intersect(&mut ray)
+            .expect("Expected to hit the sampled point");
+        assert_abs_diff_eq!(intersection.location, point, epsilon = 1e-9);
+        assert!(shape.intersects(&Ray::new(center, (point - center).normalized())));
+        intersection
+    }
+
+    #[test]
+    fn surface_geometry() {
+        for (origin, rotate_x) in [(p!(0, 0, 0), 0.0), (p!(1, -2, 3), 60.0)] {
+            for (name, shape, center) in quadrics(origin, rotate_x) {
+                let bounds = shape.bounds();
+                for (u, v) in grid(8) {
+                    let intersection = intersect_sample(&shape, center, (u, v));
+                    let normal = intersection.normal;
+                    assert_abs_diff_eq!(normal.magnitude(), 1.0, epsilon = 1e-9);
+                    assert_abs_diff_eq!(normal.dot(&intersection.dpdu), 0.0, epsilon = 1e-9);
+                    assert_abs_diff_eq!(normal.dot(&intersection.dpdv), 0.0, epsilon = 1e-9);
+                    assert!(
+                        bounds.contains(&intersection.location),
+                        "{} at {:?} is outside its bounds",
+                        name,
+                        intersection.location
+                    );
+
+                    // Compare the partial derivatives to the change between
+                    // nearby points
+                    let h = 1e-5;
+                    let other = intersect_sample(&shape, center, (u + h, v + h));
+                    let (du, dv) = (
+                        other.uv.0 - intersection.uv.0,
+                        other.uv.1 - intersection.uv.1,
+                    );
+                    let dp = intersection.dpdu * du + intersection.dpdv * dv;
+                    assert_abs_diff_eq!(
+                        other.location - intersection.location,
+                        dp,
+                        epsilon = 1e-3 * h
+                    );
+                    let dn = intersection.dndu * du + intersection.dndv * dv;
+                    let expected = other.normal - normal;
+                    assert_abs_diff_eq!(dn.x(), expected.x(), epsilon = 1e-3 * h);
+                    assert_abs_diff_eq!(dn.y(), expected.y(), epsilon = 1e-3 * h);
+                    assert_abs_diff_eq!(dn.z(), expected.z(), epsilon = 1e-3 * h);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn outward_normals() {
+        for (name, shape, _) in quadrics(p!(0, 0, 0), 0.0) {
+            // Rays towards the axis hit the outside of the surface
+            for (u, v) in grid(4) {
+                let point = shape.sample(Sample2d::new(u, v));
+                let outward = v!(point.x(), point.y(), 0).normalized();
+                let mut ray = Ray::new(point + outward * 0.1, -outward);
+                let intersection = shape.intersect(&mut ray).unwrap();
+                assert!(intersection.normal.dot(&outward) > 0.0, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_sweeps() {
+        let cylinder = Shape::new_cylinder(p!(0, 0, 0), 0.0, 0.0, 1.0, -1.0, 1.0, 90.0);
+        assert!(cylinder
+            .intersect(&mut Ray::new(p!(3, 0.5, 0), v!(-1, 0, 0)))
+            .is_some());
+        // Passes through the missing part of the sweep and hits the inside
+        let intersection = cylinder
+            .intersect(&mut Ray::new(p!(-3, 0.5, 0), v!(1, 0, 0)))
+            .unwrap();
+        assert_abs_diff_eq!(intersection.location, p!(0.75f64.sqrt(), 0.5, 0));
+        assert!(cylinder
+            .intersect(&mut Ray::new(p!(-3, -0.5, 0), v!(1, 0, 0)))
+            .is_none());
+        // Misses the z range
+        assert!(cylinder
+            .intersect(&mut Ray::new(p!(3, 0.5, 1.5), v!(-1, 0, 0)))
+            .is_none());
+    }
+
+    #[test]
+    fn area() {
+        let origin = p!(0, 0, 0);
+        assert_abs_diff_eq!(
+            Shape::new_cylinder(origin, 0.0, 0.0, 1.0, 0.0, 2.0, 360.0).area(),
+            4.0 * PI,
+            epsilon = 1e-9
+        );
+        assert_abs_diff_eq!(
+            Shape::new_cone(origin, 0.0, 0.0, 1.0, 1.0, 180.0).area(),
+            PI * 2f64.sqrt() / 2.0,
+            epsilon = 1e-9
+        );
+        // z = x² + y² up to a height of 1
+        assert_abs_diff_eq!(
+            Shape::new_paraboloid(origin, 0.0, 0.0, 1.0, 0.0, 1.0, 360.0).area(),
+            PI / 6.0 * (5f64.powf(1.5) - 1.0),
+            epsilon = 1e-9
+        );
+        // A hyperboloid with a vertical line is a cylinder
+        assert_abs_diff_eq!(
+            Shape::new_hyperboloid(origin, 0.0, 0.0, p!(1, 0, 0), p!(1, 0, 1), 360.0)
+                .unwrap()
+                .area(),
+            2.0 * PI,
+            epsilon = 1e-6
+        );
+    }
+
+    /// Checks the pdf of points sampled on a shape from a point that sees its
+    /// whole surface. There, 1 / pdf averaged over sampled points is the solid
+    /// angle covered by the shape, which is also the fraction of directions
+    /// that hit it.
+    pub(super) fn assert_sampling_matches_pdf(name: &str, shape: &Shape, center: Point) {
+        let primitive = Box::leak(Box::new(Primitive::new(
+            Arc::new(Shape::new_disk(p!(0, 0, 0), 0.0, 0.0, 1.0, 0.0)),
+            Arc::new(Material::new_matte(
+                Texture::constant(Color::WHITE),
+                Texture::constant(0.0),
+            )),
+        )));
+        let reference = primitive
+            .intersect(&mut Ray::new(p!(0, 0, 1), v!(0, 0, -1)))
+            .unwrap();
+        let intersection = PrimitiveIntersection {
+            location: center,
+            ..reference
+        };
+
+        let n = 200;
+        let solid_angle = grid(n)
+            .map(|(u, v)| {
+                let (_, _, pdf) = shape.sample_from(Sample2d::new(u, v), &intersection);
+                match pdf {
+                    Pdf::NonDelta(pdf) => 1.0 / pdf,
+                    Pdf::Delta => unreachable!(),
+                }
+            })
+            .sum::<f64>()
+            / (n * n) as f64;
+        let expected = grid(n)
+            .filter(|&(u, v)| {
+                shape.intersects(&Ray::new(center, sample_sphere(Sample2d::new(u, v))))
+            })
+            .count() as f64
+            / (n * n) as f64
+            * 4.0
+            * PI;
+        assert!(
+            (solid_angle - expected).abs() <= 0.01 * expected,
+            "{}: estimated solid angle {}, expected {}",
+            name,
+            solid_angle,
+            expected
+        );
+    }
+
+    #[test]
+    fn sampling_matches_pdf() {
+        for (name, shape, center) in quadrics(p!(1, -2, 3), 30.0) {
+            assert_sampling_matches_pdf(name, &shape, center);
+        }
+    }
+}
+
+mod transforms {
+    use std::f64::consts::PI;
+
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        geometry::{point::Point, traits::DotProduct, O, X, Y, Z},
+        p,
+        ray::Ray,
+        sampling::samplers::Sample2d,
+        shape::Shape,
+        transformation::{Transformable, Transformation},
+        v,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::quadrics::{assert_sampling_matches_pdf, grid};
+
+    /// Shapes stretched by non-uniform scales, with points from which their
+    /// whole surface is visible
+    fn stretched_shapes() -> Vec<(&'static str, Shape, Point)> {
+        let placement = Transformation::translate(1.0, -2.0, 3.0)
+            * Transformation::rotate(40f64.to_radians(), &v!(1, 1, 0));
+        let stretch =
+            |shape: Shape| (&placement * &Transformation::scale(1.0, 2.0, 0.5)).transform(&shape);
+        let center = placement.transform(&O);
+        vec![
+            ("ellipsoid", stretch(Shape::new_sphere(O, 1.0)), center),
+            (
+                "ellipse",
+                stretch(Shape::new_disk(O, 0.0, 0.0, 1.0, 0.0)),
+                placement.transform(&p!(0, 0, 1)),
+            ),
+            (
+                "elliptic cylinder",
+                stretch(Shape::new_cylinder(O, 0.0, 0.0, 1.0, -1.0, 1.0, 360.0)),
+                center,
+            ),
+            (
+                "stretched cone",
+                stretch(Shape::new_cone(O, 0.0, 0.0, 1.0, 2.0, 360.0)),
+                placement.transform(&p!(0, 0, 0.5)),
+            ),
+            (
+                "stretched hyperboloid",
+                stretch(
+                    Shape::new_hyperboloid(O, 0.0, 0.0, p!(1, 0, -1), p!(0, 1, 1), 360.0).unwrap(),
+                ),
+                center,
+            ),
+        ]
+    }
+
+    #[test]
+    fn composes_with_shape_parameters() {
+        assert_eq!(
+            Transformation::translate(1.0, 2.0, 3.0).transform(&Shape::new_sphere(O, 2.0)),
+            Shape::new_sphere(p!(1, 2, 3), 2.0)
+        );
+        assert_eq!(
+            Transformation::rotate(90f64.to_radians(), &X)
+                .transform(&Shape::new_disk(O, 0.0, 0.0, 1.0, 0.5)),
+            Shape::new_disk(O, 90.0, 0.0, 1.0, 0.5)
+        );
+
+        // Triangles are transformed in place
+        let triangle = Transformation::scale(2.0, 1.0, 1.0)
+            .transform(&Shape::new_triangle(O, p!(1, 0, 0), p!(0, 1, 0)).unwrap());
+        assert_eq!(triangle.area(), 1.0);
+        let mut ray = Ray::new(p!(1.5, 0.25, -1), Z);
+        let intersection = triangle.intersect(&mut ray).unwrap();
+        assert_abs_diff_eq!(intersection.location, p!(1.5, 0.25, 0));
+        assert_abs_diff_eq!(intersection.normal.dot(&Z).abs(), 1.0);
+    }
+
+    #[test]
+    fn surface_geometry() {
+        for (name, shape, center) in stretched_shapes() {
+            for (u, v) in grid(8) {
+                let point = shape.sample(Sample2d::new(u, v));
+                let mut ray = Ray::new(center, (point - center).normalized());
+                let intersection = shape
+                    .intersect(&mut ray)
+                    .unwrap_or_else(|| panic!("{}: expected to hit the sampled point", name));
+                assert_abs_diff_eq!(intersection.location, point, epsilon = 1e-9);
+
+                // Normals stay perpendicular to the stretched surface
+                let normal = intersection.normal;
+                assert_abs_diff_eq!(normal.magnitude(), 1.0, epsilon = 1e-9);
+                assert_abs_diff_eq!(
+                    normal.dot(&intersection.dpdu.normalized()),
+                    0.0,
+                    epsilon = 1e-9
+                );
+                assert_abs_diff_eq!(
+                    normal.dot(&intersection.dpdv.normalized()),
+                    0.0,
+                    epsilon = 1e-9
+                );
+                assert!(shape.bounds().contains(&point), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn area() {
+        // Rigid transformations don't change the area
+        let rotated = Transformation::rotate(30f64.to_radians(), &v!(1, 2, 3))
+            .transform(&Shape::new_sphere(p!(1, 2, 3), 2.0));
+        assert_abs_diff_eq!(rotated.area(), 16.0 * PI, epsilon = 1e-9);
+
+        // Uniform scales change it by the square of the scale
+        let scaled = Transformation::scale(3.0, 3.0, 3.0).transform(&Shape::new_sphere(O, 1.0));
+        assert_abs_diff_eq!(scaled.area(), 36.0 * PI, epsilon = 1e-9);
+
+        // Planar shapes are stretched evenly
+        let ellipse =
+            Transformation::scale(2.0, 3.0, 1.0).transform(&Shape::new_disk(O, 0.0, 0.0, 1.0, 0.0));
+        assert_abs_diff_eq!(ellipse.area(), 6.0 * PI, epsilon = 1e-9);
+
+        // Prolate spheroid with semi-axes a, a and c
+        // Source: https://en.wikipedia.org/wiki/Spheroid#Surface_area
+        let (a, c) = (1.0f64, 2.0f64);
+        let e = (1.0 - a * a / (c * c)).sqrt();
+        let spheroid = Transformation::rotate(20f64.to_radians(), &Y)
+            .transform(&Transformation::scale(a, a, c).transform(&Shape::new_sphere(O, 1.0)));
+        let expected = 2.0 * PI * a * a * (1.0 + c / (a * e) * e.asin());
+        assert_abs_diff_eq!(spheroid.area(), expected, epsilon = 1e-3 * expected);
+    }
+
+    #[test]
+    fn sampling_matches_pdf() {
+        for (name, shape, center) in stretched_shapes() {
+            assert_sampling_matches_pdf(name, &shape, center);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+mod mipmap {
+    use approx::assert_abs_diff_eq;
+    use craytracer::texture::{ColorSpace, FilterMethod, MipMap, TextureCoordinates, WrapMode};
+    use image::{DynamicImage, Rgb, RgbImage};
+    use pretty_assertions::assert_eq;
+
+    // A 4x2 image with a horizontal gradient
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(4, 2, |x, _| Rgb([(x * 85) as u8; 3])))
+    }
+
+    fn mipmap(filter_method: FilterMethod, wrap_mode: WrapMode) -> MipMap<f64> {
+        MipMap::new(&gradient(), ColorSpace::Linear, filter_method, wrap_mode)
+    }
+
+    #[test]
+    fn levels() {
+        let mipmap = mipmap(FilterMethod::Trilinear, WrapMode::Repeat);
+        assert_eq!(mipmap.num_levels(), 3);
+        assert_eq!((mipmap.width(), mipmap.height()), (4, 2));
+        // The last level should be the average of the whole image
+        assert_abs_diff_eq!(mipmap.texel(2, 0, 0), 0.5, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn odd_sizes() {
+        // Only the last row and column are bright, so dropping them would
+        // darken the coarser levels
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(5, 3, |x, y| {
+            Rgb([if x == 4 || y == 2 { 255 } else { 0 }; 3])
+        }));
+        let mipmap: MipMap<f64> = MipMap::new(
+            &image,
+            ColorSpace::Linear,
+            FilterMethod::Trilinear,
+            WrapMode::Clamp,
+        );
+        let mean = 7.0 / 15.0;
+        assert_eq!(mipmap.num_levels(), 4);
+        for (level, &(width, height)) in [(5_i64, 3), (3, 2), (2, 1), (1, 1)].iter().enumerate() {
+            let texels: Vec<f64> = (0..width * height)
+                .map(|i| mipmap.texel(level, i % width, i / width))
+                .collect();
+            // Each level covers the whole image, so it has the same average
+            let level_mean = texels.iter().sum::<f64>() / texels.len() as f64;
+            assert_abs_diff_eq!(level_mean, mean, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn wrap_modes() {
+        let repeat = mipmap(FilterMethod::Bilinear, WrapMode::Repeat);
+        assert_eq!(repeat.texel(0, -1, 0), repeat.texel(0, 3, 0));
+        assert_eq!(repeat.texel(0, 4, 0), repeat.texel(0, 0, 0));
+
+        let clamp = mipmap(FilterMethod::Bilinear, WrapMode::Clamp);
+        assert_eq!(clamp.texel(0, -1, 0), clamp.texel(0, 0, 0));
+        assert_eq!(clamp.texel(0, 7, 0), clamp.texel(0, 3, 0));
+
+        let mirror = mipmap(FilterMethod::Bilinear, WrapMode::Mirror);
+        assert_eq!(mirror.texel(0, -1, 0), mirror.texel(0, 0, 0));
+        assert_eq!(mirror.texel(0, 4, 0), mirror.texel(0, 3, 0));
+        assert_eq!(mirror.texel(0, 5, 0), mirror.texel(0, 2, 0));
+    }
+
+    #[test]
+    fn wide_footprint_uses_coarsest_level() {
+        let uv = TextureCoordinates {
+            du_dx: 1.0,
+            dv_dy: 1.0,
+            ..TextureCoordinates::new(0.1, 0.1)
+        };
+        for filter_method in [FilterMethod::Trilinear, FilterMethod::Ewa] {
+            let mipmap = mipmap(filter_method, WrapMode::Repeat);
+            assert_abs_diff_eq!(mipmap.lookup(&uv), 0.5, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn constant_image() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([51, 51, 51])));
+        for filter_method in [
+            FilterMethod::Bilinear,
+            FilterMethod::Trilinear,
+            FilterMethod::Ewa,
+        ] {
+            let mipmap: MipMap<f64> =
+                MipMap::new(&image, ColorSpace::Linear, filter_method, WrapMode::Repeat);
+            for (du_dx, dv_dy) in [(0.0, 0.0), (0.01, 0.01), (0.2, 0.01), (0.01, 0.5)] {
+                let uv = TextureCoordinates {
+                    du_dx,
+                    dv_dy,
+                    ..TextureCoordinates::new(0.3, 0.7)
+                };
+                assert_abs_diff_eq!(mipmap.lookup(&uv), 0.2, epsilon = 1e-6);
+            }
+        }
+    }
+}
+
+mod color_space {
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        color::Color,
+        texture::{ColorSpace, FilterMethod, MipMap, WrapMode},
+    };
+    use image::{DynamicImage, GrayImage, Luma, Rgb, Rgb32FImage, RgbImage};
+    use pretty_assertions::assert_eq;
+
+    fn texel<T: craytracer::texture::Texel>(image: &DynamicImage, color_space: ColorSpace) -> T {
+        MipMap::new(image, color_space, FilterMethod::Bilinear, WrapMode::Clamp).texel(0, 0, 0)
+    }
+
+    #[test]
+    fn srgb_decode() {
+        assert_eq!(ColorSpace::Srgb.to_linear(0.0), 0.0);
+        assert_abs_diff_eq!(ColorSpace::Srgb.to_linear(1.0), 1.0, epsilon = 1e-9);
+        assert_abs_diff_eq!(ColorSpace::Srgb.to_linear(0.5), 0.21404, epsilon = 1e-5);
+        assert_eq!(ColorSpace::Linear.to_linear(0.5), 0.5);
+    }
+
+    #[test]
+    fn default_color_spaces() {
+        let rgb = DynamicImage::ImageRgb8(RgbImage::new(1, 1));
+        let gray = DynamicImage::ImageLuma8(GrayImage::new(1, 1));
+        let hdr = DynamicImage::ImageRgb32F(Rgb32FImage::new(1, 1));
+        assert_eq!(ColorSpace::default_for(&rgb), ColorSpace::Srgb);
+        assert_eq!(ColorSpace::default_for(&gray), ColorSpace::Linear);
+        assert_eq!(ColorSpace::default_for(&hdr), ColorSpace::Linear);
+    }
+
+    #[test]
+    fn hdr_values_are_kept() {
+        let image = DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(1, 1, Rgb([4.0, 0.5, 0.0])));
+        let color: Color = texel(&image, ColorSpace::Linear);
+        assert_eq!(color, Color::from([4.0, 0.5, 0.0]));
+    }
+
+    #[test]
+    fn sixteen_bit_precision() {
+        let image = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(1, 1, Rgb([1u16; 3])));
+        let value: f64 = texel(&image, ColorSpace::Linear);
+        assert_abs_diff_eq!(value, 1.0 / 65535.0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn single_channel() {
+        let image = DynamicImage::ImageLuma8(GrayImage::from_pixel(1, 1, Luma([51])));
+        let value: f64 = texel(&image, ColorSpace::Linear);
+        assert_abs_diff_eq!(value, 0.2, epsilon = 1e-6);
+        let color: Color = texel(&image, ColorSpace::Linear);
+        assert_abs_diff_eq!(color.g, 0.2, epsilon = 1e-6);
+    }
+}
+
+mod procedural {
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        color::Color,
+        geometry::{point::Point, vector::Vector},
+        noise::{fbm, noise, worley},
+        texture::{Pattern, Texture, TextureCoordinates, TextureMapping},
+    };
+    use pretty_assertions::assert_eq;
+
+    fn points() -> impl Iterator<Item = Point> {
+        (0..200).map(|i| {
+            let i = i as f64;
+            Point(i * 0.173, i * -0.311 + 2.0, i * 0.057 - 5.0)
+        })
+    }
+
+    #[test]
+    fn noise_is_zero_at_lattice_points() {
+        for (x, y, z) in [(0.0, 0.0, 0.0), (1.0, -3.0, 7.0), (-12.0, 5.0, 255.0)] {
+            assert_eq!(noise(&Point(x, y, z)), 0.0);
+        }
+    }
+
+    #[test]
+    fn noise_is_continuous() {
+        for p in points() {
+            let offset = Point(p.x() + 1e-6, p.y(), p.z());
+            assert_abs_diff_eq!(noise(&p), noise(&offset), epsilon = 1e-4);
+            assert_abs_diff_eq!(worley(&p), worley(&offset), epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn fbm_filters_octaves() {
+        // With a footprint much larger than the base frequency, no octaves
+        // should be summed
+        let p = Point(0.3, 0.6, 0.2);
+        let wide = Vector(10.0, 0.0, 0.0);
+        assert_eq!(fbm(&p, &wide, &wide, 0.5, 8), 0.0);
+        let zero = Vector(0.0, 0.0, 0.0);
+        assert!(fbm(&p, &zero, &zero, 0.5, 8) != 0.0);
+    }
+
+    #[test]
+    fn patterns_are_in_range() {
+        let zero = Vector(0.0, 0.0, 0.0);
+        for pattern in [
+            Pattern::Noise,
+            Pattern::Fbm {
+                octaves: 8,
+                roughness: 0.5,
+            },
+            Pattern::Turbulence {
+                octaves: 8,
+                roughness: 0.5,
+            },
+            Pattern::Marble {
+                octaves: 8,
+                roughness: 0.5,
+                variation: 0.2,
+            },
+            Pattern::Wood { variation: 0.1 },
+            Pattern::Worley,
+        ] {
+            for p in points() {
+                let t = pattern.eval(&p, &zero, &zero);
+                assert!((0.0..=1.0).contains(&t), "{:?} gave {}", pattern, t);
+            }
+        }
+    }
+
+    #[test]
+    fn mappings() {
+        let uv = TextureCoordinates {
+            p: Point(0.5, 0.5, 0.5),
+            ..TextureCoordinates::new(0.25, 0.75)
+        };
+        let texture =
+            |mapping| Texture::procedural(Color::BLACK, Color::WHITE, Pattern::Noise, mapping, 1.0);
+        let expected = 0.5 + 0.5 * noise(&Point(0.25, 0.75, 0.0));
+        assert_abs_diff_eq!(texture(TextureMapping::Uv).eval(&uv).g, expected);
+        let expected = 0.5 + 0.5 * noise(&Point(0.5, 0.5, 0.5));
+        assert_abs_diff_eq!(texture(TextureMapping::Object).eval(&uv).g, expected);
+    }
+}
+
+mod nodes {
+    use approx::assert_abs_diff_eq;
+    use craytracer::{
+        color::Color,
+        texture::{Texture, TextureCoordinates, UvTransform},
+    };
+    use pretty_assertions::assert_eq;
+
+    fn eval(texture: &Texture<f64>) -> f64 {
+        texture.eval(&TextureCoordinates::new(0.25, 0.5))
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = Texture::constant(0.5);
+        let b = Texture::constant(0.25);
+        assert_eq!(eval(&Texture::scale(a.clone(), b.clone())), 0.125);
+        assert_eq!(eval(&Texture::multiply(a.clone(), b.clone())), 0.125);
+        assert_eq!(eval(&Texture::invert(b.clone())), 0.75);
+        assert_eq!(eval(&Texture::mix(a, b, Texture::constant(0.5))), 0.375);
+
+        let color = Texture::scale(
+            Texture::constant(Color::from([1.0, 0.5, 0.0])),
+            Texture::constant(2.0),
+        );
+        assert_eq!(
+            color.eval(&TextureCoordinates::new(0.0, 0.0)),
+            Color::from([2.0, 1.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn ramp() {
+        let ramp = |t: f64| {
+            eval(
+                &Texture::ramp(
+                    Texture::constant(t),
+                    vec![(1.0, 10.0), (0.0, 0.0), (0.5, 2.0)],
+                )
+                .unwrap(),
+            )
+        };
+        assert_eq!(ramp(-1.0), 0.0);
+        assert_eq!(ramp(0.25), 1.0);
+        assert_eq!(ramp(0.5), 2.0);
+        assert_eq!(ramp(0.75), 6.0);
+        assert_eq!(ramp(2.0), 10.0);
+        assert!(Texture::<f64>::ramp(Texture::constant(0.0), vec![]).is_none());
+    }
+
+    #[test]
+    fn uv_transform() {
+        let uv = TextureCoordinates {
+            du_dx: 0.1,
+            dv_dy: 0.2,
+            ..TextureCoordinates::new(1.0, 0.0)
+        };
+        let transformed = UvTransform::new((0.5, 0.25), (2.0, 3.0), 90.0).apply(&uv);
+        assert_abs_diff_eq!(transformed.u, 0.5);
+        assert_abs_diff_eq!(transformed.v, 2.25);
+        assert_abs_diff_eq!(transformed.du_dx, 0.0);
+        assert_abs_diff_eq!(transformed.dv_dx, 0.2);
+        assert_abs_diff_eq!(transformed.du_dy, -0.6);
+        assert_abs_diff_eq!(transformed.dv_dy, 0.0);
+    }
+}
@@ -73,7 +73,7 @@ pub mod transformation {
     use craytracer::bounds::Bounds;
     use craytracer::geometry::{O, X, Y, Z};
     use craytracer::ray::Ray;
-    use craytracer::transformation::{Transformable, Transformation};
+    use craytracer::transformation::{AnimatedTransformation, Transformable, Transformation};
     use craytracer::{n, p, v};
     use pretty_assertions::assert_eq;
 
@@ -153,6 +153,102 @@ pub mod transformation {
         assert_abs_diff_eq!(ray.direction, v!(0, 1, 0));
     }
 
+    #[test]
+    pub fn rotate() {
+        // Rotating around the coordinate axes matches the dedicated rotations
+        for radians in [0.3, -2.0] {
+            for (axis, expected) in [
+                (X, Transformation::rotate_x(radians)),
+                (Y, Transformation::rotate_y(radians)),
+                (Z, Transformation::rotate_z(radians)),
+            ] {
+                let t = Transformation::rotate(radians, &(axis * 2.0));
+                assert_abs_diff_eq!(t.matrix, expected.matrix, epsilon = 1e-12);
+                assert_abs_diff_eq!(t.inverse, expected.inverse, epsilon = 1e-12);
+            }
+        }
+
+        // A third of a turn around the diagonal cycles the axes
+        let t = Transformation::rotate(120.0_f64.to_radians(), &v!(1, 1, 1));
+        assert_abs_diff_eq!(t.transform(&X), Y, epsilon = 1e-12);
+        assert_abs_diff_eq!(t.transform(&Y), Z, epsilon = 1e-12);
+        assert_abs_diff_eq!(t.transform(&p!(0, 0, 2)), p!(2, 0, 0), epsilon = 1e-12);
+        assert_abs_diff_eq!(t.transform(&n!(0, 0, 1)), n!(1, 0, 0), epsilon = 1e-12);
+    }
+
+    #[test]
+    pub fn animated() {
+        let keyframe = |angle: f64, scale: f64, x: f64| {
+            Transformation::translate(x, 0.0, 0.0)
+                * Transformation::rotate(angle.to_radians(), &v!(1, 1, 0))
+                * Transformation::scale(scale, scale, 1.0)
+        };
+        let t = AnimatedTransformation::new(vec![
+            (2.0, keyframe(160.0, 3.0, 4.0)),
+            (0.0, keyframe(-20.0, 1.0, 0.0)),
+        ]);
+        assert!(t.is_animated());
+
+        // The parts are interpolated separately, so the rotation stays rigid,
+        // and the keyframes are held outside of them
+        for (time, expected) in [
+            (-1.0, keyframe(-20.0, 1.0, 0.0)),
+            (0.0, keyframe(-20.0, 1.0, 0.0)),
+            (0.5, keyframe(25.0, 1.5, 1.0)),
+            (1.0, keyframe(70.0, 2.0, 2.0)),
+            (2.0, keyframe(160.0, 3.0, 4.0)),
+            (3.0, keyframe(160.0, 3.0, 4.0)),
+        ] {
+            let transformation = t.at(time);
+            assert_abs_diff_eq!(transformation.matrix, expected.matrix, epsilon = 1e-9);
+            assert_abs_diff_eq!(transformation.inverse, expected.inverse, epsilon = 1e-9);
+        }
+
+        // Rotations take the shorter way around, even past half a turn
+        let t = AnimatedTransformation::new(vec![
+            (0.0, Transformation::rotate_z(170.0_f64.to_radians())),
+            (1.0, Transformation::rotate_z(-170.0_f64.to_radians())),
+        ]);
+        assert_abs_diff_eq!(
+            t.at(0.5).matrix,
+            Transformation::rotate_z(180.0_f64.to_radians()).matrix,
+            epsilon = 1e-9
+        );
+
+        // Mirrored keyframes keep their mirroring
+        let mirror = Transformation::scale(-1.0, 1.0, 1.0) * Transformation::rotate_y(0.5);
+        let t = AnimatedTransformation::new(vec![(0.0, mirror.clone()), (1.0, mirror.clone())]);
+        assert_abs_diff_eq!(t.at(0.5).matrix, mirror.matrix, epsilon = 1e-9);
+    }
+
+    #[test]
+    pub fn motion_bounds() {
+        let t = AnimatedTransformation::new(vec![
+            (0.0, Transformation::I),
+            (1.0, Transformation::rotate_z(90.0_f64.to_radians())),
+            (2.0, Transformation::translate(0.0, 0.0, 5.0)),
+        ]);
+        let bounds = Bounds::new(p!(1, -0.5, 0), p!(3, 0.5, 1));
+        let motion_bounds = t.motion_bounds(&bounds);
+        // The bounds sweep around the origin, which their corners don't pass
+        // through at any keyframe
+        for i in 0..=200 {
+            let moved = t.at(i as f64 / 100.0).transform(&bounds);
+            for corner in [moved.min, moved.max] {
+                assert!(motion_bounds.contains(&corner), "{:?}", corner);
+            }
+        }
+        assert!(motion_bounds.contains(&p!(2.1, 2.1, 0.5)));
+
+        // Still transformations just transform the bounds
+        let t: AnimatedTransformation = Transformation::translate(1.0, 0.0, 0.0).into();
+        assert!(!t.is_animated());
+        assert_eq!(
+            t.motion_bounds(&bounds),
+            Bounds::new(p!(2, -0.5, 0), p!(4, 0.5, 1))
+        );
+    }
+
     #[test]
     pub fn look_at() {
         // Look along x axis with z axis as the up direction